@@ -0,0 +1,65 @@
+use std::env;
+use std::error::Error;
+use std::path::PathBuf;
+
+use tracing_subscriber::{self, EnvFilter};
+
+use libwebauthn::proto::ctap2::Ctap2;
+use libwebauthn::transport::remote::{CommandAllowlist, RemoteClient, RemoteServer};
+use libwebauthn::transport::DeviceManager;
+
+const DEFAULT_SOCKET_NAME: &str = "libwebauthn-remote.sock";
+
+fn setup_logging() {
+    tracing_subscriber::fmt()
+        .with_env_filter(EnvFilter::from_default_env())
+        .without_time()
+        .init();
+}
+
+/// Defaults to a socket in the user's private runtime directory.
+fn default_socket_path() -> Option<PathBuf> {
+    env::var_os("XDG_RUNTIME_DIR").map(|dir| PathBuf::from(dir).join(DEFAULT_SOCKET_NAME))
+}
+
+// Usage: remote_forward serve|connect [SOCKET_PATH]
+// The socket can be forwarded to another machine with e.g. `ssh -R`.
+#[tokio::main]
+pub async fn main() -> Result<(), Box<dyn Error>> {
+    setup_logging();
+
+    let mode = env::args().nth(1).unwrap_or_else(|| "connect".to_owned());
+    let Some(path) = env::args()
+        .nth(2)
+        .map(PathBuf::from)
+        .or_else(default_socket_path)
+    else {
+        println!("XDG_RUNTIME_DIR is not set, please pass a socket path");
+        return Ok(());
+    };
+
+    if mode == "serve" {
+        let devices = DeviceManager::new().list_devices().await;
+        println!("Forwarding {} device(s) on {:?}", devices.len(), path);
+        let mut server = RemoteServer::new(devices, CommandAllowlist::allow_all());
+        server.serve_unix(&path).await?;
+        return Ok(());
+    }
+
+    let mut client = RemoteClient::connect_unix(&path).await?;
+    let devices = client.list_devices().await?;
+    println!("Remote devices: {:?}", devices);
+    let device = match devices.first() {
+        Some(device) => device,
+        None => {
+            println!("No remote devices available");
+            return Ok(());
+        }
+    };
+
+    let mut channel = client.select(device).await?;
+    println!("Selected remote authenticator: {}", &channel);
+    let info = channel.ctap2_get_info().await?;
+    println!("GetInfo response: {:?}", info);
+    Ok(())
+}
@@ -1,10 +1,11 @@
 use std::error::Error;
+use std::sync::Arc;
 use std::time::Duration;
 
 use tracing_subscriber::{self, EnvFilter};
 
 use libwebauthn::ops::u2f::{RegisterRequest, SignRequest};
-use libwebauthn::transport::ble::list_devices;
+use libwebauthn::transport::ble::{list_devices, StdinPromptPairingProvider};
 use libwebauthn::transport::Device;
 use libwebauthn::u2f::U2F;
 
@@ -25,6 +26,7 @@ pub async fn main() -> Result<(), Box<dyn Error>> {
     println!("Found {} devices.", devices.len());
 
     for mut device in devices {
+        device.set_pairing_provider(Arc::new(StdinPromptPairingProvider::new()));
         let mut channel = device.channel().await?;
 
         const APP_ID: &str = "https://foo.example.org";
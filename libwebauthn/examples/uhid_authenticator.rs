@@ -0,0 +1,34 @@
+use std::env;
+use std::error::Error;
+
+use tracing_subscriber::{self, EnvFilter};
+
+use libwebauthn::authenticator::store::CredentialStore;
+use libwebauthn::authenticator::{CtapHidDevice, SoftwareAuthenticator, UhidDevice};
+
+const DEFAULT_STORE_PATH: &str = "software-authenticator.cbor";
+
+fn setup_logging() {
+    tracing_subscriber::fmt()
+        .with_env_filter(EnvFilter::from_default_env())
+        .without_time()
+        .init();
+}
+
+// Requires write access to /dev/uhid. The device will show up as /dev/hidraw* until this
+// process exits. Usage: uhid_authenticator [STORE_PATH]
+pub fn main() -> Result<(), Box<dyn Error>> {
+    setup_logging();
+
+    let store_path = env::args()
+        .nth(1)
+        .unwrap_or_else(|| DEFAULT_STORE_PATH.to_owned());
+    println!("Using credential store: {}", store_path);
+
+    let store = CredentialStore::open(&store_path)?;
+    let device = CtapHidDevice::new(SoftwareAuthenticator::new(store));
+    let mut uhid = UhidDevice::create(device)?;
+    println!("Software authenticator is running. Press Ctrl+C to stop.");
+    uhid.run()?;
+    Ok(())
+}
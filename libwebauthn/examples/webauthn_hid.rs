@@ -6,16 +6,17 @@ use rand::{thread_rng, Rng};
 use tracing_subscriber::{self, EnvFilter};
 
 use libwebauthn::ops::webauthn::{
-    GetAssertionRequest, MakeCredentialRequest, UserVerificationRequirement,
+    GetAssertionRequest, MakeCredentialRequest, ResidentKeyRequirement, UserVerificationRequirement,
 };
 use libwebauthn::pin::{PinProvider, StdinPromptPinProvider};
 use libwebauthn::proto::ctap2::{
     Ctap2CredentialType, Ctap2PublicKeyCredentialDescriptor, Ctap2PublicKeyCredentialRpEntity,
     Ctap2PublicKeyCredentialUserEntity,
 };
+use libwebauthn::session::AuthenticatorSession;
 use libwebauthn::transport::hid::list_devices;
 use libwebauthn::transport::Device;
-use libwebauthn::webauthn::{Error as WebAuthnError, WebAuthn};
+use libwebauthn::webauthn::WebAuthn;
 
 const TIMEOUT: Duration = Duration::from_secs(10);
 
@@ -42,7 +43,9 @@ pub async fn main() -> Result<(), Box<dyn Error>> {
         println!("Selected HID authenticator: {}", &device);
         device.wink(TIMEOUT).await?;
 
-        let mut channel = device.channel().await?;
+        // Both ceremonies share the session, so that the PIN is not requested again whilst the
+        // pinUvAuthToken is valid.
+        let mut channel = AuthenticatorSession::new(device.channel().await?);
 
         // Make Credentials ceremony
         let make_credentials_request = MakeCredentialRequest {
@@ -50,7 +53,7 @@ pub async fn main() -> Result<(), Box<dyn Error>> {
             hash: Vec::from(challenge),
             relying_party: Ctap2PublicKeyCredentialRpEntity::new("example.org", "example.org"),
             user: Ctap2PublicKeyCredentialUserEntity::new(&user_id, "mario.rossi", "Mario Rossi"),
-            require_resident_key: false,
+            resident_key: ResidentKeyRequirement::Discouraged,
             user_verification: UserVerificationRequirement::Preferred,
             algorithms: vec![Ctap2CredentialType::default()],
             exclude: None,
@@ -58,26 +61,14 @@ pub async fn main() -> Result<(), Box<dyn Error>> {
             timeout: TIMEOUT,
         };
 
-        let response = loop {
-            match channel
-                .webauthn_make_credential(&make_credentials_request, &pin_provider)
-                .await
-            {
-                Ok(response) => break Ok(response),
-                Err(WebAuthnError::Ctap(ctap_error)) => {
-                    if ctap_error.is_retryable_user_error() {
-                        println!("Oops, try again! Error: {}", ctap_error);
-                        continue;
-                    }
-                    break Err(WebAuthnError::Ctap(ctap_error));
-                }
-                Err(err) => break Err(err),
-            };
-        }
-        .unwrap();
+        let response = channel
+            .webauthn_make_credential(&make_credentials_request, &pin_provider)
+            .await
+            .unwrap();
         println!("WebAuthn MakeCredential response: {:?}", response);
 
-        let credential: Ctap2PublicKeyCredentialDescriptor = (&response).try_into().unwrap();
+        let credential: Ctap2PublicKeyCredentialDescriptor =
+            (&response.attestation_object).try_into().unwrap();
         let get_assertion = GetAssertionRequest {
             relying_party_id: "example.org".to_owned(),
             hash: Vec::from(challenge),
@@ -87,23 +78,10 @@ pub async fn main() -> Result<(), Box<dyn Error>> {
             timeout: TIMEOUT,
         };
 
-        let response = loop {
-            match channel
-                .webauthn_get_assertion(&get_assertion, &pin_provider)
-                .await
-            {
-                Ok(response) => break Ok(response),
-                Err(WebAuthnError::Ctap(ctap_error)) => {
-                    if ctap_error.is_retryable_user_error() {
-                        println!("Oops, try again! Error: {}", ctap_error);
-                        continue;
-                    }
-                    break Err(WebAuthnError::Ctap(ctap_error));
-                }
-                Err(err) => break Err(err),
-            };
-        }
-        .unwrap();
+        let response = channel
+            .webauthn_get_assertion(&get_assertion, &pin_provider)
+            .await
+            .unwrap();
         println!("WebAuthn GetAssertion response: {:?}", response);
     }
 
@@ -0,0 +1,269 @@
+//! Choosing which account to sign in with, when an authenticator returns assertions for several
+//! discoverable credentials of the relying party.
+
+use async_trait::async_trait;
+use tracing::debug;
+
+use crate::backend::AccountDescription;
+use crate::proto::ctap2::{Ctap2CredentialManagementResponse, Ctap2GetAssertionResponse};
+
+#[async_trait]
+pub trait AccountSelector: Send + Sync {
+    /// Asks which account to sign in with, returning the index of the chosen one. Returning
+    /// `None` cancels the operation.
+    async fn select_account(&self, accounts: &[AccountDescription]) -> Option<usize>;
+
+    /// Whether to look up the names which the authenticator left out of its assertions, through
+    /// credential management. Authenticators only return names after user verification, so this
+    /// may ask for the PIN.
+    fn look_up_users(&self) -> bool {
+        false
+    }
+}
+
+pub struct StdinPromptAccountSelector {
+    look_up_users: bool,
+}
+
+impl StdinPromptAccountSelector {
+    pub fn new() -> Self {
+        Self {
+            look_up_users: false,
+        }
+    }
+
+    /// Looks up missing names through credential management before prompting.
+    pub fn with_user_lookup() -> Self {
+        Self {
+            look_up_users: true,
+        }
+    }
+}
+
+#[async_trait]
+impl AccountSelector for StdinPromptAccountSelector {
+    async fn select_account(&self, accounts: &[AccountDescription]) -> Option<usize> {
+        use std::io::{self, Write};
+        use text_io::read;
+
+        println!("Accounts:");
+        for (index, account) in accounts.iter().enumerate() {
+            println!("  {}. {}", index + 1, account_label(account));
+        }
+        print!("Please choose an account (1-{}): ", accounts.len());
+        io::stdout().flush().unwrap();
+        let choice: String = read!("{}\n");
+
+        match choice.trim().parse::<usize>() {
+            Ok(choice) if choice >= 1 && choice <= accounts.len() => Some(choice - 1),
+            _ => {
+                println!("No account chosen, cancelling operation.");
+                None
+            }
+        }
+    }
+
+    fn look_up_users(&self) -> bool {
+        self.look_up_users
+    }
+}
+
+fn account_label(account: &AccountDescription) -> String {
+    match (&account.display_name, &account.name) {
+        (Some(display_name), Some(name)) => format!("{} ({})", display_name, name),
+        (Some(label), None) | (None, Some(label)) => label.clone(),
+        (None, None) => hex::encode(&account.id),
+    }
+}
+
+/// The accounts of the given assertions, as reported by the authenticator.
+pub fn accounts(assertions: &[Ctap2GetAssertionResponse]) -> Vec<AccountDescription> {
+    assertions
+        .iter()
+        .map(|assertion| match &assertion.user {
+            Some(user) => AccountDescription {
+                id: user.id.to_vec(),
+                name: user.name.clone(),
+                display_name: user.display_name.clone(),
+            },
+            None => AccountDescription {
+                id: vec![],
+                name: None,
+                display_name: None,
+            },
+        })
+        .collect()
+}
+
+/// Whether any of the accounts is missing both its name and display name.
+pub(crate) fn has_unnamed(accounts: &[AccountDescription]) -> bool {
+    accounts
+        .iter()
+        .any(|account| account.name.is_none() && account.display_name.is_none())
+}
+
+/// Fills in the names missing from the accounts of the given assertions, from the credentials
+/// enumerated through credential management.
+pub(crate) fn fill_in_users(
+    accounts: &mut [AccountDescription],
+    assertions: &[Ctap2GetAssertionResponse],
+    credentials: &[Ctap2CredentialManagementResponse],
+) {
+    for (account, assertion) in accounts.iter_mut().zip(assertions) {
+        let credential = credentials.iter().find(|credential| {
+            match (&assertion.credential_id, &credential.credential_id) {
+                (Some(asserted), Some(enumerated)) => asserted.id == enumerated.id,
+                _ => false,
+            }
+        });
+        let credential = credential.or_else(|| {
+            credentials.iter().find(|credential| {
+                let user = credential.user.as_ref();
+                !account.id.is_empty() && user.is_some_and(|user| user.id[..] == account.id[..])
+            })
+        });
+        let Some(user) = credential.and_then(|credential| credential.user.as_ref()) else {
+            debug!("No enumerated credential matches the assertion");
+            continue;
+        };
+        if account.id.is_empty() {
+            account.id = user.id.to_vec();
+        }
+        if account.name.is_none() {
+            account.name = user.name.clone();
+        }
+        if account.display_name.is_none() {
+            account.display_name = user.display_name.clone();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use async_trait::async_trait;
+    use serde_bytes::ByteBuf;
+
+    use crate::account::{accounts, fill_in_users, AccountSelector};
+    use crate::authenticator::store::CredentialStore;
+    use crate::authenticator::{SoftwareAuthenticator, SoftwareChannel};
+    use crate::backend::AccountDescription;
+    use crate::ops::webauthn::{
+        GetAssertionRequest, MakeCredentialRequest, ResidentKeyRequirement,
+    };
+    use crate::pin::{PinProvider, StaticPinProvider};
+    use crate::proto::ctap2::{
+        Ctap2CredentialManagementResponse, Ctap2GetAssertionResponse,
+        Ctap2PublicKeyCredentialDescriptor, Ctap2PublicKeyCredentialType,
+        Ctap2PublicKeyCredentialUserEntity,
+    };
+    use crate::session::AuthenticatorSession;
+    use crate::webauthn::WebAuthn;
+
+    struct ChooseAccount {
+        index: usize,
+        shown: Arc<Mutex<Vec<AccountDescription>>>,
+    }
+
+    #[async_trait]
+    impl AccountSelector for ChooseAccount {
+        async fn select_account(&self, accounts: &[AccountDescription]) -> Option<usize> {
+            *self.shown.lock().unwrap() = accounts.to_vec();
+            Some(self.index)
+        }
+    }
+
+    fn descriptor(id: &[u8]) -> Ctap2PublicKeyCredentialDescriptor {
+        Ctap2PublicKeyCredentialDescriptor {
+            r#type: Ctap2PublicKeyCredentialType::PublicKey,
+            id: ByteBuf::from(id),
+            transports: None,
+        }
+    }
+
+    fn assertion(credential_id: &[u8], user_id: &[u8]) -> Ctap2GetAssertionResponse {
+        Ctap2GetAssertionResponse {
+            credential_id: Some(descriptor(credential_id)),
+            authenticator_data: ByteBuf::new(),
+            signature: ByteBuf::new(),
+            user: Some(Ctap2PublicKeyCredentialUserEntity {
+                id: ByteBuf::from(user_id),
+                name: None,
+                display_name: None,
+            }),
+            credentials_count: None,
+            user_selected: None,
+        }
+    }
+
+    fn enumerated(
+        credential_id: &[u8],
+        user_id: &[u8],
+        name: &str,
+    ) -> Ctap2CredentialManagementResponse {
+        Ctap2CredentialManagementResponse {
+            existing_resident_credentials_count: None,
+            max_remaining_resident_credentials_count: None,
+            rp: None,
+            rp_id_hash: None,
+            total_rps: None,
+            user: Some(Ctap2PublicKeyCredentialUserEntity::new(user_id, name, name)),
+            credential_id: Some(descriptor(credential_id)),
+            public_key: None,
+            total_credentials: None,
+            cred_protect: None,
+            large_blob_key: None,
+        }
+    }
+
+    #[test]
+    fn users_filled_in_from_credential_management() {
+        let assertions = vec![assertion(&[1], &[0x11]), assertion(&[2], &[0x22])];
+        let mut accounts = accounts(&assertions);
+        assert_eq!(accounts[0].name, None);
+
+        let credentials = vec![
+            enumerated(&[2], &[0x22], "luigi"),
+            enumerated(&[1], &[0x11], "mario"),
+        ];
+        fill_in_users(&mut accounts, &assertions, &credentials);
+        assert_eq!(accounts[0].id, vec![0x11]);
+        assert_eq!(accounts[0].name.as_deref(), Some("mario"));
+        assert_eq!(accounts[1].display_name.as_deref(), Some("luigi"));
+    }
+
+    #[tokio::test]
+    async fn chosen_assertion_returned() {
+        let channel =
+            SoftwareChannel::new(SoftwareAuthenticator::new(CredentialStore::temporary()));
+        let mut session = AuthenticatorSession::new(channel);
+        let pin_provider: Box<dyn PinProvider> = Box::new(StaticPinProvider::new("1234"));
+
+        for (id, name) in [(1u8, "mario"), (2u8, "luigi")] {
+            let mut request = MakeCredentialRequest::dummy();
+            request.resident_key = ResidentKeyRequirement::Required;
+            request.user = Ctap2PublicKeyCredentialUserEntity::new(&[id], name, name);
+            session
+                .webauthn_make_credential(&request, &pin_provider)
+                .await
+                .unwrap();
+        }
+
+        let request = GetAssertionRequest::dummy();
+        let shown = Arc::new(Mutex::new(vec![]));
+        let selector: Box<dyn AccountSelector> = Box::new(ChooseAccount {
+            index: 1,
+            shown: shown.clone(),
+        });
+        let assertion = session
+            .webauthn_get_assertion_for_account(&request, &pin_provider, &selector)
+            .await
+            .unwrap();
+        // Most recently created first.
+        let shown = shown.lock().unwrap();
+        assert_eq!(shown.len(), 2);
+        assert_eq!(shown[1].name.as_deref(), Some("mario"));
+        assert_eq!(assertion.user.unwrap().id.to_vec(), vec![1]);
+    }
+}
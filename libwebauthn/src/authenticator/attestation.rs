@@ -0,0 +1,162 @@
+use p256::ecdsa::signature::Signer;
+use p256::ecdsa::{Signature, SigningKey};
+
+// DER-encoded OIDs, including tag and length.
+const OID_ECDSA_WITH_SHA256: &[u8] = &[0x06, 0x08, 0x2A, 0x86, 0x48, 0xCE, 0x3D, 0x04, 0x03, 0x02];
+const OID_EC_PUBLIC_KEY: &[u8] = &[0x06, 0x07, 0x2A, 0x86, 0x48, 0xCE, 0x3D, 0x02, 0x01];
+const OID_PRIME256V1: &[u8] = &[0x06, 0x08, 0x2A, 0x86, 0x48, 0xCE, 0x3D, 0x03, 0x01, 0x07];
+const OID_COMMON_NAME: &[u8] = &[0x06, 0x03, 0x55, 0x04, 0x03];
+const OID_COUNTRY: &[u8] = &[0x06, 0x03, 0x55, 0x04, 0x06];
+const OID_ORGANIZATION: &[u8] = &[0x06, 0x03, 0x55, 0x04, 0x0A];
+const OID_ORGANIZATIONAL_UNIT: &[u8] = &[0x06, 0x03, 0x55, 0x04, 0x0B];
+const OID_BASIC_CONSTRAINTS: &[u8] = &[0x06, 0x03, 0x55, 0x1D, 0x13];
+
+const TAG_BOOLEAN: u8 = 0x01;
+const TAG_INTEGER: u8 = 0x02;
+const TAG_BIT_STRING: u8 = 0x03;
+const TAG_OCTET_STRING: u8 = 0x04;
+const TAG_UTF8_STRING: u8 = 0x0C;
+const TAG_PRINTABLE_STRING: u8 = 0x13;
+const TAG_UTC_TIME: u8 = 0x17;
+const TAG_SEQUENCE: u8 = 0x30;
+const TAG_SET: u8 = 0x31;
+const TAG_VERSION: u8 = 0xA0;
+const TAG_EXTENSIONS: u8 = 0xA3;
+
+const X509_V3: u8 = 0x02;
+
+// Subject as required for packed attestation certificates, WebAuthn §8.2.1.
+const ATTESTATION_COUNTRY: &str = "US";
+const ATTESTATION_ORGANIZATION: &str = "libwebauthn";
+const ATTESTATION_ORGANIZATIONAL_UNIT: &str = "Authenticator Attestation";
+const ATTESTATION_COMMON_NAME: &str = "libwebauthn Software Authenticator";
+const NOT_BEFORE: &str = "200101000000Z";
+const NOT_AFTER: &str = "491231235959Z";
+
+fn der_tlv(tag: u8, content: &[u8]) -> Vec<u8> {
+    let mut out = vec![tag];
+    let len = content.len();
+    if len < 0x80 {
+        out.push(len as u8);
+    } else if len <= 0xFF {
+        out.extend(&[0x81, len as u8]);
+    } else {
+        out.extend(&[0x82, (len >> 8) as u8, len as u8]);
+    }
+    out.extend(content);
+    out
+}
+
+fn der_sequence(items: &[&[u8]]) -> Vec<u8> {
+    der_tlv(TAG_SEQUENCE, &items.concat())
+}
+
+fn der_attribute(oid: &[u8], tag: u8, value: &str) -> Vec<u8> {
+    let attribute = der_sequence(&[oid, &der_tlv(tag, value.as_bytes())]);
+    der_tlv(TAG_SET, &attribute)
+}
+
+fn der_attestation_name() -> Vec<u8> {
+    der_sequence(&[
+        &der_attribute(OID_COUNTRY, TAG_PRINTABLE_STRING, ATTESTATION_COUNTRY),
+        &der_attribute(OID_ORGANIZATION, TAG_UTF8_STRING, ATTESTATION_ORGANIZATION),
+        &der_attribute(
+            OID_ORGANIZATIONAL_UNIT,
+            TAG_UTF8_STRING,
+            ATTESTATION_ORGANIZATIONAL_UNIT,
+        ),
+        &der_attribute(OID_COMMON_NAME, TAG_UTF8_STRING, ATTESTATION_COMMON_NAME),
+    ])
+}
+
+/// A critical basicConstraints extension, with the CA component left to its default of false.
+fn der_extensions() -> Vec<u8> {
+    let basic_constraints = der_sequence(&[
+        OID_BASIC_CONSTRAINTS,
+        &der_tlv(TAG_BOOLEAN, &[0xFF]),
+        &der_tlv(TAG_OCTET_STRING, &der_sequence(&[])),
+    ]);
+    der_tlv(TAG_EXTENSIONS, &der_sequence(&[&basic_constraints]))
+}
+
+fn der_bit_string(bytes: &[u8]) -> Vec<u8> {
+    let mut content = vec![0x00]; // No unused bits
+    content.extend(bytes);
+    der_tlv(TAG_BIT_STRING, &content)
+}
+
+/// Builds a self-signed X.509 v3 certificate for the given attestation key, meeting the
+/// requirements on packed attestation certificates.
+pub fn self_signed_certificate(key: &SigningKey) -> Vec<u8> {
+    let public_key = key.verifying_key().to_encoded_point(false);
+    let algorithm = der_sequence(&[OID_ECDSA_WITH_SHA256]);
+    let name = der_attestation_name();
+    let validity = der_sequence(&[
+        &der_tlv(TAG_UTC_TIME, NOT_BEFORE.as_bytes()),
+        &der_tlv(TAG_UTC_TIME, NOT_AFTER.as_bytes()),
+    ]);
+    let spki = der_sequence(&[
+        &der_sequence(&[OID_EC_PUBLIC_KEY, OID_PRIME256V1]),
+        &der_bit_string(public_key.as_bytes()),
+    ]);
+    let tbs = der_sequence(&[
+        &der_tlv(TAG_VERSION, &der_tlv(TAG_INTEGER, &[X509_V3])),
+        &der_tlv(TAG_INTEGER, &[0x01]),
+        &algorithm,
+        &name,
+        &validity,
+        &name,
+        &spki,
+        &der_extensions(),
+    ]);
+
+    let signature: Signature = key.sign(&tbs);
+    der_sequence(&[
+        &tbs,
+        &algorithm,
+        &der_bit_string(signature.to_der().as_bytes()),
+    ])
+}
+
+#[cfg(test)]
+mod tests {
+    use p256::ecdsa::SigningKey;
+    use rand::rngs::OsRng;
+    use x509_parser::prelude::{X509Certificate, X509Version};
+    use x509_parser::traits::FromDer;
+
+    use super::self_signed_certificate;
+
+    #[test]
+    fn certificate_is_parseable() {
+        let key = SigningKey::random(&mut OsRng);
+        let certificate = self_signed_certificate(&key);
+        let (remaining, parsed) = X509Certificate::from_der(&certificate).unwrap();
+        assert!(remaining.is_empty());
+        assert_eq!(
+            parsed.public_key().subject_public_key.data,
+            key.verifying_key().to_encoded_point(false).as_bytes()
+        );
+    }
+
+    #[test]
+    fn certificate_meets_packed_attestation_requirements() {
+        let key = SigningKey::random(&mut OsRng);
+        let certificate = self_signed_certificate(&key);
+        let (_, parsed) = X509Certificate::from_der(&certificate).unwrap();
+        assert_eq!(parsed.version(), X509Version::V3);
+
+        let subject = parsed.subject();
+        let country = subject.iter_country().next().unwrap();
+        assert_eq!(country.as_str().unwrap(), "US");
+        let organization = subject.iter_organization().next().unwrap();
+        assert_eq!(organization.as_str().unwrap(), "libwebauthn");
+        let unit = subject.iter_organizational_unit().next().unwrap();
+        assert_eq!(unit.as_str().unwrap(), "Authenticator Attestation");
+        assert!(subject.iter_common_name().next().is_some());
+
+        let (critical, basic_constraints) = parsed.tbs_certificate.basic_constraints().unwrap();
+        assert!(critical);
+        assert!(!basic_constraints.ca);
+    }
+}
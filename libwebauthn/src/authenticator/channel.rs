@@ -0,0 +1,92 @@
+use std::fmt::{Display, Formatter};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use tracing::{debug, trace};
+
+use crate::proto::ctap1::apdu::{ApduRequest, ApduResponse};
+use crate::proto::ctap2::cbor::{CborRequest, CborResponse};
+use crate::transport::device::SupportedProtocols;
+use crate::transport::error::{Error, TransportError};
+use crate::transport::{Channel, ChannelStatus};
+
+use super::SoftwareAuthenticator;
+
+/// In-process channel to a [`SoftwareAuthenticator`], bypassing CTAPHID framing entirely.
+pub struct SoftwareChannel {
+    authenticator: Mutex<SoftwareAuthenticator>,
+    response: Mutex<Option<Vec<u8>>>,
+}
+
+impl SoftwareChannel {
+    pub fn new(authenticator: SoftwareAuthenticator) -> Self {
+        Self {
+            authenticator: Mutex::new(authenticator),
+            response: Mutex::new(None),
+        }
+    }
+
+    fn take_response(&self) -> Result<Vec<u8>, Error> {
+        self.response
+            .lock()
+            .unwrap()
+            .take()
+            .ok_or(Error::Transport(TransportError::Timeout))
+    }
+}
+
+impl Display for SoftwareChannel {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Software authenticator")
+    }
+}
+
+#[async_trait]
+impl Channel for SoftwareChannel {
+    async fn supported_protocols(&self) -> Result<SupportedProtocols, Error> {
+        Ok(SupportedProtocols {
+            u2f: true,
+            fido2: true,
+        })
+    }
+
+    async fn status(&self) -> ChannelStatus {
+        ChannelStatus::Ready
+    }
+
+    async fn close(&self) {}
+
+    async fn apdu_send(&self, request: &ApduRequest, _timeout: Duration) -> Result<(), Error> {
+        debug!("Sending APDU request to software authenticator");
+        trace!(?request);
+        let apdu_raw = request
+            .raw_long()
+            .or(Err(Error::Transport(TransportError::InvalidFraming)))?;
+        let response = self.authenticator.lock().unwrap().handle_apdu(&apdu_raw);
+        *self.response.lock().unwrap() = Some(response);
+        Ok(())
+    }
+
+    async fn apdu_recv(&self, _timeout: Duration) -> Result<ApduResponse, Error> {
+        let response = self.take_response()?;
+        ApduResponse::try_from(&response).or(Err(Error::Transport(TransportError::InvalidFraming)))
+    }
+
+    async fn cbor_send(&self, request: &CborRequest, _timeout: Duration) -> Result<(), Error> {
+        debug!("Sending CBOR request to software authenticator");
+        trace!(?request);
+        let response = self
+            .authenticator
+            .lock()
+            .unwrap()
+            .handle_cbor(&request.ctap_hid_data());
+        *self.response.lock().unwrap() = Some(response);
+        Ok(())
+    }
+
+    async fn cbor_recv(&self, _timeout: Duration) -> Result<CborResponse, Error> {
+        let response = self.take_response()?;
+        CborResponse::try_from(&response).or(Err(Error::Transport(TransportError::InvalidFraming)))
+    }
+}
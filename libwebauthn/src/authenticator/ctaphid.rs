@@ -0,0 +1,258 @@
+use std::collections::{HashMap, HashSet};
+use std::convert::TryInto;
+
+use rand::{thread_rng, Rng};
+use tracing::{debug, info, instrument, trace, warn};
+
+use crate::transport::hid::framing::{
+    HidCommand, HidMessage, HidMessageParser, HidMessageParserState, BROADCAST_CID,
+};
+
+use super::SoftwareAuthenticator;
+
+pub const PACKET_SIZE: usize = 64;
+
+const INIT_NONCE_LEN: usize = 8;
+const CTAPHID_PROTOCOL_VERSION: u8 = 2;
+const VERSION_MAJOR: u8 = 0;
+const VERSION_MINOR: u8 = 1;
+const VERSION_BUILD: u8 = 0;
+const CAPABILITY_WINK: u8 = 0x01;
+const CAPABILITY_CBOR: u8 = 0x04;
+
+const KEEPALIVE_STATUS_PROCESSING: u8 = 0x01;
+
+const ERR_INVALID_CMD: u8 = 0x01;
+const ERR_INVALID_LEN: u8 = 0x03;
+const ERR_INVALID_SEQ: u8 = 0x04;
+const ERR_INVALID_CHANNEL: u8 = 0x0B;
+
+/// Device side of the CTAPHID protocol: reassembles host reports into messages, allocates
+/// channel IDs and dispatches requests to a [`SoftwareAuthenticator`].
+pub struct CtapHidDevice {
+    authenticator: SoftwareAuthenticator,
+    channels: HashSet<u32>,
+    parsers: HashMap<u32, HidMessageParser>,
+}
+
+impl CtapHidDevice {
+    pub fn new(authenticator: SoftwareAuthenticator) -> Self {
+        Self {
+            authenticator,
+            channels: HashSet::new(),
+            parsers: HashMap::new(),
+        }
+    }
+
+    /// Ingests a single output report from the host, returning the input reports to be sent back.
+    #[instrument(skip_all)]
+    pub fn handle_report(&mut self, report: &[u8]) -> Vec<Vec<u8>> {
+        trace!(?report);
+        if report.len() < 5 {
+            warn!({ len = report.len() }, "Ignoring report which is too short");
+            return vec![];
+        }
+
+        let cid = u32::from_be_bytes(report[0..4].try_into().unwrap());
+        let is_initial_packet = report[4] & 0x80 != 0;
+        if is_initial_packet {
+            if self.parsers.remove(&cid).is_some() {
+                warn!(
+                    { cid },
+                    "New transaction started before the previous one completed"
+                );
+            }
+            self.parsers.insert(cid, HidMessageParser::new());
+        }
+
+        let Some(parser) = self.parsers.get_mut(&cid) else {
+            debug!({ cid }, "Ignoring spurious continuation packet");
+            return vec![];
+        };
+
+        match parser.update(report) {
+            Ok(HidMessageParserState::MorePacketsExpected) => vec![],
+            Ok(HidMessageParserState::Done) => {
+                let parser = self.parsers.remove(&cid).unwrap();
+                match parser.message() {
+                    Ok(message) => self.dispatch(message),
+                    Err(_) => self.error(cid, ERR_INVALID_CMD),
+                }
+            }
+            Err(_) => {
+                self.parsers.remove(&cid);
+                self.error(cid, ERR_INVALID_SEQ)
+            }
+        }
+    }
+
+    fn dispatch(&mut self, request: HidMessage) -> Vec<Vec<u8>> {
+        let cid = request.cid;
+        debug!({ cid, cmd = ?request.cmd, len = request.payload.len() }, "Received CTAPHID request");
+
+        if request.cmd == HidCommand::Init {
+            return self.init(request);
+        }
+        if !self.channels.contains(&cid) {
+            warn!({ cid }, "Request on unallocated channel");
+            return self.error(cid, ERR_INVALID_CHANNEL);
+        }
+
+        match request.cmd {
+            HidCommand::Ping => self.reply(cid, HidCommand::Ping, &request.payload),
+            HidCommand::Wink => {
+                info!("Wink! 😉");
+                self.reply(cid, HidCommand::Wink, &[])
+            }
+            HidCommand::Cbor => {
+                let mut reports =
+                    self.reply(cid, HidCommand::KeepAlive, &[KEEPALIVE_STATUS_PROCESSING]);
+                let response = self.authenticator.handle_cbor(&request.payload);
+                reports.extend(self.reply(cid, HidCommand::Cbor, &response));
+                reports
+            }
+            HidCommand::Msg => {
+                let response = self.authenticator.handle_apdu(&request.payload);
+                self.reply(cid, HidCommand::Msg, &response)
+            }
+            HidCommand::Cancel => {
+                // Requests are processed synchronously, so there is never anything to cancel.
+                debug!({ cid }, "Ignoring cancel request");
+                vec![]
+            }
+            cmd => {
+                warn!(?cmd, "Unsupported CTAPHID command");
+                self.error(cid, ERR_INVALID_CMD)
+            }
+        }
+    }
+
+    fn init(&mut self, request: HidMessage) -> Vec<Vec<u8>> {
+        if request.payload.len() != INIT_NONCE_LEN {
+            return self.error(request.cid, ERR_INVALID_LEN);
+        }
+
+        let allocated = if request.cid == BROADCAST_CID {
+            let cid = loop {
+                let cid: u32 = thread_rng().gen();
+                if cid != 0 && cid != BROADCAST_CID && !self.channels.contains(&cid) {
+                    break cid;
+                }
+            };
+            self.channels.insert(cid);
+            info!({ cid }, "Allocated new channel");
+            cid
+        } else {
+            debug!({ cid = request.cid }, "Resynchronising existing channel");
+            request.cid
+        };
+
+        let mut payload = request.payload.clone();
+        payload.extend(&allocated.to_be_bytes());
+        payload.extend(&[
+            CTAPHID_PROTOCOL_VERSION,
+            VERSION_MAJOR,
+            VERSION_MINOR,
+            VERSION_BUILD,
+            CAPABILITY_WINK | CAPABILITY_CBOR,
+        ]);
+        self.reply(request.cid, HidCommand::Init, &payload)
+    }
+
+    fn error(&self, cid: u32, code: u8) -> Vec<Vec<u8>> {
+        self.reply(cid, HidCommand::Error, &[code])
+    }
+
+    fn reply(&self, cid: u32, cmd: HidCommand, payload: &[u8]) -> Vec<Vec<u8>> {
+        let Ok(packets) = HidMessage::new(cid, cmd, payload).packets(PACKET_SIZE) else {
+            warn!({ len = payload.len() }, "Response payload is too large");
+            return self.error(cid, ERR_INVALID_LEN);
+        };
+        packets
+            .into_iter()
+            .map(|mut packet| {
+                packet.resize(PACKET_SIZE, 0);
+                packet
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CtapHidDevice, PACKET_SIZE};
+    use crate::authenticator::store::CredentialStore;
+    use crate::authenticator::SoftwareAuthenticator;
+    use crate::transport::hid::framing::{
+        HidCommand, HidMessage, HidMessageParser, HidMessageParserState,
+    };
+
+    fn device() -> CtapHidDevice {
+        CtapHidDevice::new(SoftwareAuthenticator::new(CredentialStore::temporary()))
+    }
+
+    fn transact(device: &mut CtapHidDevice, request: HidMessage) -> HidMessage {
+        let mut reports = vec![];
+        for mut packet in request.packets(PACKET_SIZE).unwrap() {
+            packet.resize(PACKET_SIZE, 0);
+            reports.extend(device.handle_report(&packet));
+        }
+
+        let mut parser = HidMessageParser::new();
+        for report in reports {
+            if let HidMessageParserState::Done = parser.update(&report).unwrap() {
+                let message = parser.message().unwrap();
+                if message.cmd != HidCommand::KeepAlive {
+                    return message;
+                }
+                parser = HidMessageParser::new();
+            }
+        }
+        panic!("No response received");
+    }
+
+    fn init(device: &mut CtapHidDevice) -> u32 {
+        let nonce = [0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08];
+        let response = transact(device, HidMessage::broadcast(HidCommand::Init, &nonce));
+        assert_eq!(response.cmd, HidCommand::Init);
+        assert_eq!(&response.payload[0..8], &nonce);
+        u32::from_be_bytes([
+            response.payload[8],
+            response.payload[9],
+            response.payload[10],
+            response.payload[11],
+        ])
+    }
+
+    #[test]
+    fn init_allocates_distinct_channels() {
+        let mut device = device();
+        let first = init(&mut device);
+        let second = init(&mut device);
+        assert_ne!(first, second);
+    }
+
+    #[test]
+    fn ping_multiple_packets() {
+        let mut device = device();
+        let cid = init(&mut device);
+        let payload: Vec<u8> = (0..200).map(|i| i as u8).collect();
+        let response = transact(
+            &mut device,
+            HidMessage::new(cid, HidCommand::Ping, &payload),
+        );
+        assert_eq!(response.cmd, HidCommand::Ping);
+        assert_eq!(response.payload, payload);
+    }
+
+    #[test]
+    fn unallocated_channel() {
+        let mut device = device();
+        let response = transact(
+            &mut device,
+            HidMessage::new(0x42, HidCommand::Ping, &[0x01]),
+        );
+        assert_eq!(response.cmd, HidCommand::Error);
+        assert_eq!(response.payload, vec![0x0B]);
+    }
+}
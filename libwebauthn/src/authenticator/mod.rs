@@ -0,0 +1,745 @@
+//! Software authenticator, for end-to-end testing of platforms and relying parties.
+//!
+//! The authenticator implements the device side of CTAP2 and CTAP1/U2F, and can be exposed to
+//! other programs as a real HID device via [`uhid::UhidDevice`], or used in-process via
+//! [`SoftwareChannel`].
+
+pub mod channel;
+pub mod ctaphid;
+pub mod store;
+pub mod uhid;
+
+mod attestation;
+
+use std::collections::BTreeMap;
+use std::io::{Cursor as IOCursor, Read};
+
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use p256::ecdsa::signature::Signer;
+use p256::ecdsa::{Signature, SigningKey};
+use rand::rngs::OsRng;
+use rand::{thread_rng, Rng};
+use serde_bytes::ByteBuf;
+use serde_cbor::Value;
+use sha2::{Digest, Sha256};
+use tracing::{debug, info, instrument, warn};
+
+use crate::proto::ctap2::{Ctap2COSEAlgorithmIdentifier, Ctap2CommandCode};
+use crate::proto::CtapError;
+
+use self::attestation::self_signed_certificate;
+use self::store::{CredentialStore, StoredCredential};
+
+pub use self::channel::SoftwareChannel;
+pub use self::ctaphid::CtapHidDevice;
+pub use self::uhid::UhidDevice;
+
+const AAGUID: [u8; 16] = [
+    0x6c, 0x69, 0x62, 0x77, 0x65, 0x62, 0x61, 0x75, 0x74, 0x68, 0x6e, 0x2d, 0x73, 0x77, 0x00, 0x01,
+];
+const CREDENTIAL_ID_LEN: usize = 32;
+const MAX_CREDENTIAL_COUNT_IN_LIST: usize = 4;
+const MAX_CREDENTIAL_ID_LENGTH: usize = 128;
+const MAX_MSG_SIZE: u32 = 1200;
+
+const FLAG_USER_PRESENT: u8 = 0x01;
+const FLAG_ATTESTED_CREDENTIAL_DATA: u8 = 0x40;
+
+const U2F_REGISTER: u8 = 0x01;
+const U2F_AUTHENTICATE: u8 = 0x02;
+const U2F_VERSION: u8 = 0x03;
+const U2F_REGISTER_RESERVED: u8 = 0x05;
+
+const CONTROL_BYTE_CHECK_ONLY: u8 = 0x07;
+const CONTROL_BYTE_ENFORCE_UP_AND_SIGN: u8 = 0x03;
+
+const SW_NO_ERROR: u16 = 0x9000;
+const SW_CONDITIONS_NOT_SATISFIED: u16 = 0x6985;
+const SW_WRONG_DATA: u16 = 0x6A80;
+const SW_WRONG_LENGTH: u16 = 0x6700;
+const SW_INS_NOT_SUPPORTED: u16 = 0x6D00;
+
+#[derive(Debug, Clone)]
+struct PendingAssertion {
+    rp_id_hash: Vec<u8>,
+    credential_id: Vec<u8>,
+    client_data_hash: Vec<u8>,
+    user_present: bool,
+}
+
+/// A CTAP2 and CTAP1/U2F authenticator backed by a file-based [`CredentialStore`].
+///
+/// User presence is always confirmed automatically, so this should only be used for testing.
+pub struct SoftwareAuthenticator {
+    store: CredentialStore,
+    attestation_certificate: Vec<u8>,
+    pending_assertions: Vec<PendingAssertion>,
+    discoverable_capacity: Option<usize>,
+    omit_single_credential: bool,
+}
+
+impl SoftwareAuthenticator {
+    pub fn new(store: CredentialStore) -> Self {
+        let attestation_certificate = self_signed_certificate(store.attestation_key());
+        Self {
+            store,
+            attestation_certificate,
+            pending_assertions: vec![],
+            discoverable_capacity: None,
+            omit_single_credential: false,
+        }
+    }
+
+    /// Limits how many discoverable credentials can be stored, like the storage of a hardware
+    /// authenticator. Unlimited by default.
+    pub fn with_discoverable_capacity(mut self, capacity: usize) -> Self {
+        self.discoverable_capacity = Some(capacity);
+        self
+    }
+
+    /// Omits the credential from GetAssertion responses when the allowList held a single one, as
+    /// the CTAP2 specification allows authenticators to.
+    pub fn with_single_credential_omitted(mut self) -> Self {
+        self.omit_single_credential = true;
+        self
+    }
+
+    fn discoverable_store_full(&self) -> bool {
+        let stored = self
+            .store
+            .credentials()
+            .iter()
+            .filter(|credential| credential.discoverable)
+            .count();
+        self.discoverable_capacity
+            .is_some_and(|capacity| stored >= capacity)
+    }
+
+    pub fn aaguid(&self) -> &[u8] {
+        &AAGUID
+    }
+
+    /// Handles a CTAP2 request (command byte followed by CBOR parameters), returning the status
+    /// code followed by the CBOR-encoded response.
+    #[instrument(skip_all)]
+    pub fn handle_cbor(&mut self, request: &[u8]) -> Vec<u8> {
+        let Some((&command, parameters)) = request.split_first() else {
+            return vec![CtapError::InvalidLength.into()];
+        };
+
+        let result = match Ctap2CommandCode::try_from(command) {
+            Ok(Ctap2CommandCode::AuthenticatorGetInfo) => Ok(self.get_info()),
+            Ok(Ctap2CommandCode::AuthenticatorMakeCredential) => {
+                parse_map(parameters).and_then(|map| self.make_credential(&map))
+            }
+            Ok(Ctap2CommandCode::AuthenticatorGetAssertion) => {
+                parse_map(parameters).and_then(|map| self.get_assertion(&map))
+            }
+            Ok(Ctap2CommandCode::AuthenticatorGetNextAssertion) => self.get_next_assertion(),
+            Ok(Ctap2CommandCode::AuthenticatorSelection) => {
+                info!("Automatically confirming user presence for selection");
+                return vec![CtapError::Ok.into()];
+            }
+            Ok(command) => {
+                warn!(?command, "Unsupported CTAP2 command");
+                Err(CtapError::InvalidCommand)
+            }
+            Err(_) => {
+                warn!({ command }, "Unknown CTAP2 command");
+                Err(CtapError::InvalidCommand)
+            }
+        };
+
+        match result {
+            Ok(value) => {
+                let mut response = vec![CtapError::Ok.into()];
+                response.extend(serde_cbor::to_vec(&value).unwrap());
+                response
+            }
+            Err(error) => {
+                debug!(?error, "CTAP2 request failed");
+                vec![error.into()]
+            }
+        }
+    }
+
+    /// Handles a CTAP1/U2F APDU, in either short or extended length encoding, returning the
+    /// response data followed by the status word.
+    #[instrument(skip_all)]
+    pub fn handle_apdu(&mut self, apdu: &[u8]) -> Vec<u8> {
+        let (data, status) = match parse_apdu(apdu) {
+            Some((U2F_VERSION, _, _)) => (b"U2F_V2".to_vec(), SW_NO_ERROR),
+            Some((U2F_REGISTER, _, data)) => self.u2f_register(&data),
+            Some((U2F_AUTHENTICATE, control, data)) => self.u2f_authenticate(control, &data),
+            Some((ins, _, _)) => {
+                warn!({ ins }, "Unsupported U2F instruction");
+                (vec![], SW_INS_NOT_SUPPORTED)
+            }
+            None => (vec![], SW_WRONG_LENGTH),
+        };
+        let mut response = data;
+        response.write_u16::<BigEndian>(status).unwrap();
+        response
+    }
+
+    fn get_info(&self) -> Value {
+        let mut options = BTreeMap::new();
+        options.insert(text("rk"), Value::Bool(true));
+        options.insert(text("up"), Value::Bool(true));
+        options.insert(text("plat"), Value::Bool(false));
+
+        let mut info = BTreeMap::new();
+        info.insert(
+            int(0x01),
+            Value::Array(vec![text("U2F_V2"), text("FIDO_2_0")]),
+        );
+        info.insert(int(0x03), Value::Bytes(AAGUID.to_vec()));
+        info.insert(int(0x04), Value::Map(options));
+        info.insert(int(0x05), int(MAX_MSG_SIZE as i128));
+        info.insert(int(0x07), int(MAX_CREDENTIAL_COUNT_IN_LIST as i128));
+        info.insert(int(0x08), int(MAX_CREDENTIAL_ID_LENGTH as i128));
+        Value::Map(info)
+    }
+
+    fn make_credential(&mut self, request: &BTreeMap<Value, Value>) -> Result<Value, CtapError> {
+        let client_data_hash = get_bytes(request, 0x01)?;
+        let rp = get_map(request, 0x02)?;
+        let user = get_map(request, 0x03)?;
+        let algorithms = get_array(request, 0x04)?;
+        let rp_id = get_text_field(rp, "id")?;
+
+        let es256 = Ctap2COSEAlgorithmIdentifier::ES256 as i128;
+        if !algorithms.iter().any(|alg| {
+            as_map(alg)
+                .ok()
+                .and_then(|alg| alg.get(&text("alg")))
+                .is_some_and(|alg| *alg == Value::Integer(es256))
+        }) {
+            warn!("None of the requested algorithms is supported");
+            return Err(CtapError::UnsupportedAlgorithm);
+        }
+
+        let options = optional_map(request, 0x07)?;
+        if option_enabled(options, "uv", false) {
+            warn!("User verification was requested, but it is not supported");
+            return Err(CtapError::UnsupportedOption);
+        }
+        if request.contains_key(&int(0x08)) {
+            warn!("PIN/UV auth param provided, but no PIN is set");
+            return Err(CtapError::PINNotSet);
+        }
+        let discoverable = option_enabled(options, "rk", false);
+
+        let rp_id_hash = sha256(rp_id.as_bytes());
+        if let Some(exclude) = optional_array(request, 0x05)? {
+            check_list_length(exclude)?;
+            for descriptor in exclude {
+                let id = get_text_keyed_bytes(as_map(descriptor)?, "id")?;
+                if self.store.find(&rp_id_hash, &id).is_some() {
+                    info!("Credential in excludeList is already registered");
+                    return Err(CtapError::CredentialExcluded);
+                }
+            }
+        }
+
+        if discoverable && self.discoverable_store_full() {
+            warn!("No space left for discoverable credentials");
+            return Err(CtapError::KeyStoreFull);
+        }
+
+        info!(%rp_id, %discoverable, "Automatically confirming user presence for MakeCredential");
+        let signing_key = SigningKey::random(&mut OsRng);
+        let credential_id: [u8; CREDENTIAL_ID_LEN] = thread_rng().gen();
+        let credential = StoredCredential {
+            id: ByteBuf::from(credential_id.to_vec()),
+            rp_id_hash: ByteBuf::from(rp_id_hash.clone()),
+            rp_id: Some(rp_id.to_owned()),
+            user_id: Some(ByteBuf::from(get_text_keyed_bytes(user, "id")?)),
+            user_name: optional_text_field(user, "name"),
+            user_display_name: optional_text_field(user, "displayName"),
+            private_key: ByteBuf::from(signing_key.to_bytes().to_vec()),
+            sign_count: 0,
+            discoverable,
+        };
+
+        let mut auth_data = authenticator_data(
+            &rp_id_hash,
+            FLAG_USER_PRESENT | FLAG_ATTESTED_CREDENTIAL_DATA,
+            0,
+        );
+        auth_data.extend(&AAGUID);
+        auth_data
+            .write_u16::<BigEndian>(CREDENTIAL_ID_LEN as u16)
+            .unwrap();
+        auth_data.extend(&credential_id);
+        auth_data.extend(cose_public_key(&signing_key));
+
+        self.store
+            .insert(credential)
+            .or(Err(CtapError::KeyStoreFull))?;
+
+        let mut signed = auth_data.clone();
+        signed.extend(&client_data_hash);
+        let signature: Signature = self.store.attestation_key().sign(&signed);
+
+        let mut att_stmt = BTreeMap::new();
+        att_stmt.insert(text("alg"), int(es256));
+        att_stmt.insert(
+            text("sig"),
+            Value::Bytes(signature.to_der().as_bytes().to_vec()),
+        );
+        att_stmt.insert(
+            text("x5c"),
+            Value::Array(vec![Value::Bytes(self.attestation_certificate.clone())]),
+        );
+
+        let mut response = BTreeMap::new();
+        response.insert(int(0x01), text("packed"));
+        response.insert(int(0x02), Value::Bytes(auth_data));
+        response.insert(int(0x03), Value::Map(att_stmt));
+        Ok(Value::Map(response))
+    }
+
+    fn get_assertion(&mut self, request: &BTreeMap<Value, Value>) -> Result<Value, CtapError> {
+        let rp_id = get_text(request, 0x01)?;
+        let client_data_hash = get_bytes(request, 0x02)?;
+        let options = optional_map(request, 0x05)?;
+        if option_enabled(options, "uv", false) {
+            warn!("User verification was requested, but it is not supported");
+            return Err(CtapError::UnsupportedOption);
+        }
+        if request.contains_key(&int(0x06)) {
+            warn!("PIN/UV auth param provided, but no PIN is set");
+            return Err(CtapError::PINNotSet);
+        }
+        let user_present = option_enabled(options, "up", true);
+
+        let rp_id_hash = sha256(rp_id.as_bytes());
+        let single_allowed = optional_array(request, 0x03)?.is_some_and(|allow| allow.len() == 1);
+        let credential_ids: Vec<Vec<u8>> = match optional_array(request, 0x03)? {
+            Some(allow) if !allow.is_empty() => {
+                check_list_length(allow)?;
+                let mut matching = None;
+                for descriptor in allow {
+                    let id = get_text_keyed_bytes(as_map(descriptor)?, "id")?;
+                    if self.store.find(&rp_id_hash, &id).is_some() {
+                        matching = Some(id);
+                        break;
+                    }
+                }
+                matching.into_iter().collect()
+            }
+            _ => self
+                .store
+                .discoverable(&rp_id_hash)
+                .iter()
+                .rev() // Most recently created first
+                .map(|c| c.id.to_vec())
+                .collect(),
+        };
+
+        if credential_ids.is_empty() {
+            debug!(%rp_id, "No matching credentials found");
+            return Err(CtapError::NoCredentials);
+        }
+
+        if user_present {
+            info!(%rp_id, "Automatically confirming user presence for GetAssertion");
+        }
+        let count = credential_ids.len();
+        let mut pending: Vec<PendingAssertion> = credential_ids
+            .into_iter()
+            .map(|credential_id| PendingAssertion {
+                rp_id_hash: rp_id_hash.clone(),
+                credential_id,
+                client_data_hash: client_data_hash.clone(),
+                user_present,
+            })
+            .collect();
+        let first = pending.remove(0);
+        self.pending_assertions = pending;
+
+        let mut response = self.assertion(&first)?;
+        if single_allowed && self.omit_single_credential {
+            response.remove(&int(0x01));
+        }
+        if count > 1 {
+            response.insert(int(0x05), int(count as i128));
+        }
+        Ok(Value::Map(response))
+    }
+
+    fn get_next_assertion(&mut self) -> Result<Value, CtapError> {
+        if self.pending_assertions.is_empty() {
+            return Err(CtapError::NotAllowed);
+        }
+        let next = self.pending_assertions.remove(0);
+        Ok(Value::Map(self.assertion(&next)?))
+    }
+
+    fn assertion(
+        &mut self,
+        pending: &PendingAssertion,
+    ) -> Result<BTreeMap<Value, Value>, CtapError> {
+        let sign_count = self
+            .store
+            .increment_sign_count(&pending.rp_id_hash, &pending.credential_id)
+            .or(Err(CtapError::InvalidCredential))?;
+        let credential = self
+            .store
+            .find(&pending.rp_id_hash, &pending.credential_id)
+            .ok_or(CtapError::InvalidCredential)?;
+        let signing_key = credential
+            .signing_key()
+            .or(Err(CtapError::InvalidCredential))?;
+
+        let flags = if pending.user_present {
+            FLAG_USER_PRESENT
+        } else {
+            0
+        };
+        let auth_data = authenticator_data(&pending.rp_id_hash, flags, sign_count);
+        let mut signed = auth_data.clone();
+        signed.extend(&pending.client_data_hash);
+        let signature: Signature = signing_key.sign(&signed);
+
+        let mut descriptor = BTreeMap::new();
+        descriptor.insert(text("id"), Value::Bytes(pending.credential_id.clone()));
+        descriptor.insert(text("type"), text("public-key"));
+
+        let mut response = BTreeMap::new();
+        response.insert(int(0x01), Value::Map(descriptor));
+        response.insert(int(0x02), Value::Bytes(auth_data));
+        response.insert(
+            int(0x03),
+            Value::Bytes(signature.to_der().as_bytes().to_vec()),
+        );
+        if credential.discoverable {
+            let mut user = BTreeMap::new();
+            if let Some(user_id) = &credential.user_id {
+                user.insert(text("id"), Value::Bytes(user_id.to_vec()));
+            }
+            if let Some(name) = &credential.user_name {
+                user.insert(text("name"), text(name));
+            }
+            if let Some(display_name) = &credential.user_display_name {
+                user.insert(text("displayName"), text(display_name));
+            }
+            response.insert(int(0x04), Value::Map(user));
+        }
+        Ok(response)
+    }
+
+    fn u2f_register(&mut self, data: &[u8]) -> (Vec<u8>, u16) {
+        if data.len() != 64 {
+            return (vec![], SW_WRONG_LENGTH);
+        }
+        let (challenge, app_id_hash) = data.split_at(32);
+
+        info!("Automatically confirming user presence for U2F registration");
+        let signing_key = SigningKey::random(&mut OsRng);
+        let public_key = signing_key.verifying_key().to_encoded_point(false);
+        let key_handle: [u8; CREDENTIAL_ID_LEN] = thread_rng().gen();
+        let credential = StoredCredential {
+            id: ByteBuf::from(key_handle.to_vec()),
+            rp_id_hash: ByteBuf::from(app_id_hash.to_vec()),
+            rp_id: None,
+            user_id: None,
+            user_name: None,
+            user_display_name: None,
+            private_key: ByteBuf::from(signing_key.to_bytes().to_vec()),
+            sign_count: 0,
+            discoverable: false,
+        };
+        if self.store.insert(credential).is_err() {
+            return (vec![], SW_CONDITIONS_NOT_SATISFIED);
+        }
+
+        let mut signed = vec![0x00];
+        signed.extend(app_id_hash);
+        signed.extend(challenge);
+        signed.extend(&key_handle);
+        signed.extend(public_key.as_bytes());
+        let signature: Signature = self.store.attestation_key().sign(&signed);
+
+        let mut response = vec![U2F_REGISTER_RESERVED];
+        response.extend(public_key.as_bytes());
+        response.push(key_handle.len() as u8);
+        response.extend(&key_handle);
+        response.extend(&self.attestation_certificate);
+        response.extend(signature.to_der().as_bytes());
+        (response, SW_NO_ERROR)
+    }
+
+    fn u2f_authenticate(&mut self, control: u8, data: &[u8]) -> (Vec<u8>, u16) {
+        if data.len() < 65 || data.len() != 65 + data[64] as usize {
+            return (vec![], SW_WRONG_LENGTH);
+        }
+        let challenge = &data[0..32];
+        let app_id_hash = &data[32..64];
+        let key_handle = &data[65..];
+
+        if self.store.find(app_id_hash, key_handle).is_none() {
+            return (vec![], SW_WRONG_DATA);
+        }
+        if control == CONTROL_BYTE_CHECK_ONLY {
+            // Key handle is valid: per the U2F spec, this is reported as "test of user presence required".
+            return (vec![], SW_CONDITIONS_NOT_SATISFIED);
+        }
+
+        let Ok(counter) = self.store.increment_sign_count(app_id_hash, key_handle) else {
+            return (vec![], SW_WRONG_DATA);
+        };
+        let Some(Ok(signing_key)) = self
+            .store
+            .find(app_id_hash, key_handle)
+            .map(|c| c.signing_key())
+        else {
+            return (vec![], SW_WRONG_DATA);
+        };
+
+        let user_presence: u8 = if control == CONTROL_BYTE_ENFORCE_UP_AND_SIGN {
+            info!("Automatically confirming user presence for U2F authentication");
+            0x01
+        } else {
+            0x00
+        };
+        let mut signed = app_id_hash.to_vec();
+        signed.push(user_presence);
+        signed.write_u32::<BigEndian>(counter).unwrap();
+        signed.extend(challenge);
+        let signature: Signature = signing_key.sign(&signed);
+
+        let mut response = vec![user_presence];
+        response.write_u32::<BigEndian>(counter).unwrap();
+        response.extend(signature.to_der().as_bytes());
+        (response, SW_NO_ERROR)
+    }
+}
+
+fn authenticator_data(rp_id_hash: &[u8], flags: u8, sign_count: u32) -> Vec<u8> {
+    let mut auth_data = rp_id_hash.to_vec();
+    auth_data.push(flags);
+    auth_data.write_u32::<BigEndian>(sign_count).unwrap();
+    auth_data
+}
+
+fn cose_public_key(key: &SigningKey) -> Vec<u8> {
+    let point = key.verifying_key().to_encoded_point(false);
+    let x: heapless::Vec<u8, 32> =
+        heapless::Vec::from_slice(point.x().expect("Not the identity point")).unwrap();
+    let y: heapless::Vec<u8, 32> =
+        heapless::Vec::from_slice(point.y().expect("Not identity nor compressed")).unwrap();
+    let cose_key = cosey::PublicKey::P256Key(cosey::P256PublicKey {
+        x: x.into(),
+        y: y.into(),
+    });
+    serde_cbor::to_vec(&cose_key).unwrap()
+}
+
+fn sha256(data: &[u8]) -> Vec<u8> {
+    let mut hasher = Sha256::default();
+    hasher.update(data);
+    hasher.finalize().to_vec()
+}
+
+/// Parses an APDU in either extended or short length encoding, returning (INS, P1, data).
+fn parse_apdu(apdu: &[u8]) -> Option<(u8, u8, Vec<u8>)> {
+    if apdu.len() < 4 {
+        return None;
+    }
+    let (ins, p1) = (apdu[1], apdu[2]);
+    if apdu.len() == 4 {
+        return Some((ins, p1, vec![]));
+    }
+
+    let mut cursor = IOCursor::new(&apdu[4..]);
+    let length = match cursor.read_u8().ok()? {
+        0x00 if apdu.len() >= 7 => cursor.read_u16::<BigEndian>().ok()? as usize,
+        0x00 => 0,
+        short => short as usize,
+    };
+    let mut data = vec![0u8; length];
+    cursor.read_exact(&mut data).ok()?;
+    Some((ins, p1, data))
+}
+
+fn int(value: i128) -> Value {
+    Value::Integer(value)
+}
+
+fn text(value: &str) -> Value {
+    Value::Text(value.to_owned())
+}
+
+fn parse_map(parameters: &[u8]) -> Result<BTreeMap<Value, Value>, CtapError> {
+    match serde_cbor::from_slice(parameters) {
+        Ok(Value::Map(map)) => Ok(map),
+        Ok(_) => Err(CtapError::InvalidCborType),
+        Err(_) => Err(CtapError::InvalidCbor),
+    }
+}
+
+fn as_map(value: &Value) -> Result<&BTreeMap<Value, Value>, CtapError> {
+    match value {
+        Value::Map(map) => Ok(map),
+        _ => Err(CtapError::InvalidCborType),
+    }
+}
+
+fn get_map(map: &BTreeMap<Value, Value>, key: i128) -> Result<&BTreeMap<Value, Value>, CtapError> {
+    as_map(map.get(&int(key)).ok_or(CtapError::MissingParameter)?)
+}
+
+fn optional_map(
+    map: &BTreeMap<Value, Value>,
+    key: i128,
+) -> Result<Option<&BTreeMap<Value, Value>>, CtapError> {
+    map.get(&int(key)).map(as_map).transpose()
+}
+
+fn get_array(map: &BTreeMap<Value, Value>, key: i128) -> Result<&Vec<Value>, CtapError> {
+    optional_array(map, key)?.ok_or(CtapError::MissingParameter)
+}
+
+fn check_list_length(list: &[Value]) -> Result<(), CtapError> {
+    if list.len() > MAX_CREDENTIAL_COUNT_IN_LIST {
+        warn!(
+            count = list.len(),
+            "Credential list is longer than supported"
+        );
+        return Err(CtapError::LimitExceeded);
+    }
+    Ok(())
+}
+
+fn optional_array(
+    map: &BTreeMap<Value, Value>,
+    key: i128,
+) -> Result<Option<&Vec<Value>>, CtapError> {
+    match map.get(&int(key)) {
+        None => Ok(None),
+        Some(Value::Array(array)) => Ok(Some(array)),
+        Some(_) => Err(CtapError::InvalidCborType),
+    }
+}
+
+fn get_bytes(map: &BTreeMap<Value, Value>, key: i128) -> Result<Vec<u8>, CtapError> {
+    match map.get(&int(key)) {
+        None => Err(CtapError::MissingParameter),
+        Some(Value::Bytes(bytes)) => Ok(bytes.clone()),
+        Some(_) => Err(CtapError::InvalidCborType),
+    }
+}
+
+fn get_text(map: &BTreeMap<Value, Value>, key: i128) -> Result<&str, CtapError> {
+    match map.get(&int(key)) {
+        None => Err(CtapError::MissingParameter),
+        Some(Value::Text(text)) => Ok(text),
+        Some(_) => Err(CtapError::InvalidCborType),
+    }
+}
+
+fn get_text_field<'a>(map: &'a BTreeMap<Value, Value>, key: &str) -> Result<&'a str, CtapError> {
+    match map.get(&text(key)) {
+        None => Err(CtapError::MissingParameter),
+        Some(Value::Text(text)) => Ok(text),
+        Some(_) => Err(CtapError::InvalidCborType),
+    }
+}
+
+fn optional_text_field(map: &BTreeMap<Value, Value>, key: &str) -> Option<String> {
+    match map.get(&text(key)) {
+        Some(Value::Text(text)) => Some(text.clone()),
+        _ => None,
+    }
+}
+
+fn get_text_keyed_bytes(map: &BTreeMap<Value, Value>, key: &str) -> Result<Vec<u8>, CtapError> {
+    match map.get(&text(key)) {
+        None => Err(CtapError::MissingParameter),
+        Some(Value::Bytes(bytes)) => Ok(bytes.clone()),
+        Some(_) => Err(CtapError::InvalidCborType),
+    }
+}
+
+fn option_enabled(options: Option<&BTreeMap<Value, Value>>, name: &str, default: bool) -> bool {
+    match options.and_then(|options| options.get(&text(name))) {
+        Some(Value::Bool(value)) => *value,
+        _ => default,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryFrom;
+    use std::time::Duration;
+
+    use serde_bytes::ByteBuf;
+
+    use super::store::CredentialStore;
+    use super::SoftwareAuthenticator;
+    use crate::ops::webauthn::{GetAssertionRequest, MakeCredentialRequest};
+    use crate::proto::ctap2::cbor::{CborRequest, CborResponse};
+    use crate::proto::ctap2::Ctap2GetAssertionResponse;
+    use crate::proto::ctap2::{
+        Ctap2GetAssertionRequest, Ctap2MakeCredentialRequest, Ctap2MakeCredentialResponse,
+        Ctap2PublicKeyCredentialDescriptor,
+    };
+    use crate::proto::CtapError;
+
+    fn authenticator() -> SoftwareAuthenticator {
+        SoftwareAuthenticator::new(CredentialStore::temporary())
+    }
+
+    fn transact(authenticator: &mut SoftwareAuthenticator, request: CborRequest) -> CborResponse {
+        let response = authenticator.handle_cbor(&request.ctap_hid_data());
+        CborResponse::try_from(&response).unwrap()
+    }
+
+    #[test]
+    fn make_credential_then_get_assertion() {
+        let mut authenticator = authenticator();
+        let make_credential: Ctap2MakeCredentialRequest = (&MakeCredentialRequest::dummy()).into();
+        let response = transact(&mut authenticator, (&make_credential).into());
+        assert_eq!(response.status_code, CtapError::Ok);
+        let response: Ctap2MakeCredentialResponse =
+            serde_cbor::from_slice(&response.data.unwrap()).unwrap();
+        let credential = Ctap2PublicKeyCredentialDescriptor::try_from(&response).unwrap();
+
+        let get_assertion: Ctap2GetAssertionRequest = (&GetAssertionRequest {
+            relying_party_id: make_credential.relying_party.id.clone(),
+            hash: vec![0; 32],
+            allow: vec![credential.clone()],
+            extensions_cbor: None,
+            user_verification: crate::ops::webauthn::UserVerificationRequirement::Discouraged,
+            timeout: Duration::from_secs(10),
+        })
+            .into();
+        let response = transact(&mut authenticator, (&get_assertion).into());
+        assert_eq!(response.status_code, CtapError::Ok);
+        let response: Ctap2GetAssertionResponse =
+            serde_cbor::from_slice(&response.data.unwrap()).unwrap();
+        assert_eq!(response.credential_id.unwrap().id, credential.id);
+    }
+
+    #[test]
+    fn get_assertion_unknown_credential() {
+        let mut authenticator = authenticator();
+        let get_assertion: Ctap2GetAssertionRequest = (&GetAssertionRequest {
+            relying_party_id: String::from("example.org"),
+            hash: vec![0; 32],
+            allow: vec![Ctap2PublicKeyCredentialDescriptor {
+                r#type: crate::proto::ctap2::Ctap2PublicKeyCredentialType::PublicKey,
+                id: ByteBuf::from(vec![0x42; 32]),
+                transports: None,
+            }],
+            extensions_cbor: None,
+            user_verification: crate::ops::webauthn::UserVerificationRequirement::Discouraged,
+            timeout: Duration::from_secs(10),
+        })
+            .into();
+        let response = transact(&mut authenticator, (&get_assertion).into());
+        assert_eq!(response.status_code, CtapError::NoCredentials);
+    }
+}
@@ -0,0 +1,238 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{Error as IOError, ErrorKind as IOErrorKind, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+
+use p256::ecdsa::SigningKey;
+use rand::rngs::OsRng;
+use serde_bytes::ByteBuf;
+use serde_derive::{Deserialize, Serialize};
+use tracing::{debug, info, instrument, warn};
+
+const STORE_FORMAT_VERSION: u32 = 1;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StoredCredential {
+    pub id: ByteBuf,
+    pub rp_id_hash: ByteBuf,
+    pub rp_id: Option<String>,
+    pub user_id: Option<ByteBuf>,
+    pub user_name: Option<String>,
+    pub user_display_name: Option<String>,
+    pub private_key: ByteBuf,
+    pub sign_count: u32,
+    pub discoverable: bool,
+}
+
+impl StoredCredential {
+    pub fn signing_key(&self) -> Result<SigningKey, IOError> {
+        SigningKey::from_bytes(&self.private_key).or(Err(IOError::new(
+            IOErrorKind::InvalidData,
+            "Stored credential has an invalid private key",
+        )))
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct StoreContents {
+    version: u32,
+    attestation_key: ByteBuf,
+    credentials: Vec<StoredCredential>,
+}
+
+/// Credential store for the software authenticator, persisted as CBOR to a single file.
+///
+/// Every mutation is written back to disk before the authenticator replies, so that a crash
+/// never leaves a relying party holding a credential the authenticator has forgotten.
+pub struct CredentialStore {
+    path: PathBuf,
+    attestation_key: SigningKey,
+    credentials: Vec<StoredCredential>,
+    #[cfg(test)]
+    temp_dir: Option<TempDir>,
+}
+
+impl CredentialStore {
+    #[instrument(skip_all, fields(path = ?path.as_ref()))]
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, IOError> {
+        let path = path.as_ref().to_path_buf();
+        if !path.exists() {
+            info!("Credential store not found, creating a new one");
+            let store = Self {
+                path,
+                attestation_key: SigningKey::random(&mut OsRng),
+                credentials: vec![],
+                #[cfg(test)]
+                temp_dir: None,
+            };
+            store.persist()?;
+            return Ok(store);
+        }
+
+        let file = File::open(&path)?;
+        let contents: StoreContents = serde_cbor::from_reader(file).or(Err(IOError::new(
+            IOErrorKind::InvalidData,
+            "Invalid credential store",
+        )))?;
+        if contents.version != STORE_FORMAT_VERSION {
+            warn!(
+                { version = contents.version },
+                "Unsupported credential store version"
+            );
+            return Err(IOError::new(
+                IOErrorKind::InvalidData,
+                format!("Unsupported credential store version: {}", contents.version),
+            ));
+        }
+        let attestation_key = SigningKey::from_bytes(&contents.attestation_key).or(Err(
+            IOError::new(IOErrorKind::InvalidData, "Invalid attestation key"),
+        ))?;
+        debug!(
+            { count = contents.credentials.len() },
+            "Loaded credential store"
+        );
+        Ok(Self {
+            path,
+            attestation_key,
+            credentials: contents.credentials,
+            #[cfg(test)]
+            temp_dir: None,
+        })
+    }
+
+    pub fn attestation_key(&self) -> &SigningKey {
+        &self.attestation_key
+    }
+
+    pub fn credentials(&self) -> &[StoredCredential] {
+        &self.credentials
+    }
+
+    pub fn find(&self, rp_id_hash: &[u8], credential_id: &[u8]) -> Option<&StoredCredential> {
+        self.credentials
+            .iter()
+            .find(|c| c.rp_id_hash.as_slice() == rp_id_hash && c.id.as_slice() == credential_id)
+    }
+
+    pub fn discoverable(&self, rp_id_hash: &[u8]) -> Vec<&StoredCredential> {
+        self.credentials
+            .iter()
+            .filter(|c| c.discoverable && c.rp_id_hash.as_slice() == rp_id_hash)
+            .collect()
+    }
+
+    pub fn insert(&mut self, credential: StoredCredential) -> Result<(), IOError> {
+        if credential.discoverable {
+            // A new discoverable credential replaces any existing one for the same user account.
+            self.credentials.retain(|c| {
+                !(c.discoverable
+                    && c.rp_id_hash == credential.rp_id_hash
+                    && c.user_id == credential.user_id)
+            });
+        }
+        self.credentials.push(credential);
+        self.persist()
+    }
+
+    /// Increments the signature counter for the given credential, returning the new value.
+    pub fn increment_sign_count(
+        &mut self,
+        rp_id_hash: &[u8],
+        credential_id: &[u8],
+    ) -> Result<u32, IOError> {
+        let Some(credential) = self
+            .credentials
+            .iter_mut()
+            .find(|c| c.rp_id_hash.as_slice() == rp_id_hash && c.id.as_slice() == credential_id)
+        else {
+            return Err(IOError::new(IOErrorKind::NotFound, "Unknown credential"));
+        };
+        credential.sign_count = credential.sign_count.wrapping_add(1);
+        let sign_count = credential.sign_count;
+        self.persist()?;
+        Ok(sign_count)
+    }
+
+    fn persist(&self) -> Result<(), IOError> {
+        let contents = StoreContents {
+            version: STORE_FORMAT_VERSION,
+            attestation_key: ByteBuf::from(self.attestation_key.to_bytes().to_vec()),
+            credentials: self.credentials.clone(),
+        };
+        let encoded = serde_cbor::to_vec(&contents).or(Err(IOError::new(
+            IOErrorKind::InvalidData,
+            "Failed to encode store",
+        )))?;
+
+        // Write to a temporary file first, then atomically replace the store. The store holds
+        // private keys, so only its owner may read it.
+        let mut tmp_path = self.path.clone().into_os_string();
+        tmp_path.push(".tmp");
+        match fs::remove_file(&tmp_path) {
+            Err(err) if err.kind() != IOErrorKind::NotFound => return Err(err),
+            _ => (),
+        }
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(&tmp_path)?;
+        file.write_all(&encoded)?;
+        file.sync_all()?;
+        fs::rename(&tmp_path, &self.path)?;
+        debug!(
+            { count = self.credentials.len() },
+            "Persisted credential store"
+        );
+        Ok(())
+    }
+}
+
+/// A directory deleted with its contents once dropped.
+#[cfg(test)]
+struct TempDir(PathBuf);
+
+#[cfg(test)]
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+#[cfg(test)]
+impl CredentialStore {
+    /// Creates an empty store in a new temporary directory, which is deleted with the store.
+    pub fn temporary() -> Self {
+        let dir = std::env::temp_dir().join(format!("libwebauthn-test-{}", uuid::Uuid::new_v4()));
+        fs::create_dir(&dir).unwrap();
+        let temp_dir = TempDir(dir);
+        let mut store = Self::open(temp_dir.0.join("store.cbor")).unwrap();
+        store.temp_dir = Some(temp_dir);
+        store
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+
+    use super::CredentialStore;
+
+    #[test]
+    fn temporary_store_deleted_on_drop() {
+        let store = CredentialStore::temporary();
+        let path = store.path.clone();
+        assert!(path.exists());
+        drop(store);
+        assert!(!path.exists());
+        assert!(!path.parent().unwrap().exists());
+    }
+
+    #[test]
+    fn store_only_readable_by_owner() {
+        let store = CredentialStore::temporary();
+        let mode = fs::metadata(&store.path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+    }
+}
@@ -0,0 +1,210 @@
+use std::fs::{File, OpenOptions};
+use std::io::{Error as IOError, ErrorKind as IOErrorKind, Read, Write};
+use std::path::Path;
+
+use tracing::{debug, info, instrument, trace, warn};
+
+use super::ctaphid::{CtapHidDevice, PACKET_SIZE};
+
+pub const UHID_PATH: &str = "/dev/uhid";
+
+// Event types, from linux/uhid.h
+const UHID_DESTROY: u32 = 1;
+const UHID_START: u32 = 2;
+const UHID_STOP: u32 = 3;
+const UHID_OPEN: u32 = 4;
+const UHID_CLOSE: u32 = 5;
+const UHID_OUTPUT: u32 = 6;
+const UHID_GET_REPORT: u32 = 9;
+const UHID_GET_REPORT_REPLY: u32 = 10;
+const UHID_CREATE2: u32 = 11;
+const UHID_INPUT2: u32 = 12;
+const UHID_SET_REPORT: u32 = 13;
+const UHID_SET_REPORT_REPLY: u32 = 14;
+
+// sizeof(struct uhid_event): 4-byte type, followed by the largest union member (uhid_create2_req).
+const UHID_EVENT_SIZE: usize = 4 + 4372;
+const UHID_DATA_MAX: usize = 4096;
+
+const UHID_CREATE2_NAME_OFFSET: usize = 4;
+const UHID_CREATE2_RD_SIZE_OFFSET: usize = 260;
+const UHID_CREATE2_BUS_OFFSET: usize = 262;
+const UHID_CREATE2_VENDOR_OFFSET: usize = 264;
+const UHID_CREATE2_PRODUCT_OFFSET: usize = 268;
+const UHID_CREATE2_RD_DATA_OFFSET: usize = 280;
+const UHID_OUTPUT_SIZE_OFFSET: usize = 4 + UHID_DATA_MAX;
+
+const BUS_USB: u16 = 0x03;
+const EIO: u16 = 5;
+
+pub const DEFAULT_VENDOR_ID: u32 = 0x1209;
+pub const DEFAULT_PRODUCT_ID: u32 = 0x0001;
+pub const DEFAULT_NAME: &str = "libwebauthn Software Authenticator";
+
+// HID report descriptor for a FIDO authenticator: usage page 0xF1D0, usage 0x01 (CTAPHID),
+// with 64-byte input and output reports.
+const FIDO_REPORT_DESCRIPTOR: &[u8] = &[
+    0x06, 0xD0, 0xF1, // Usage Page (FIDO Alliance)
+    0x09, 0x01, // Usage (CTAPHID)
+    0xA1, 0x01, // Collection (Application)
+    0x09, 0x20, //   Usage (Input Report Data)
+    0x15, 0x00, //   Logical Minimum (0)
+    0x26, 0xFF, 0x00, //   Logical Maximum (255)
+    0x75, 0x08, //   Report Size (8)
+    0x95, 0x40, //   Report Count (64)
+    0x81, 0x02, //   Input (Data, Variable, Absolute)
+    0x09, 0x21, //   Usage (Output Report Data)
+    0x15, 0x00, //   Logical Minimum (0)
+    0x26, 0xFF, 0x00, //   Logical Maximum (255)
+    0x75, 0x08, //   Report Size (8)
+    0x95, 0x40, //   Report Count (64)
+    0x91, 0x02, //   Output (Data, Variable, Absolute)
+    0xC0, // End Collection
+];
+
+/// Exports a [`CtapHidDevice`] to the kernel via `/dev/uhid`, so that it shows up as a regular
+/// `/dev/hidraw*` FIDO device to browsers and other platforms.
+pub struct UhidDevice {
+    file: File,
+    device: CtapHidDevice,
+}
+
+impl UhidDevice {
+    pub fn create(device: CtapHidDevice) -> Result<Self, IOError> {
+        Self::create_with(
+            UHID_PATH,
+            DEFAULT_NAME,
+            DEFAULT_VENDOR_ID,
+            DEFAULT_PRODUCT_ID,
+            device,
+        )
+    }
+
+    #[instrument(skip(path, device), fields(path = ?path.as_ref()))]
+    pub fn create_with<P: AsRef<Path>>(
+        path: P,
+        name: &str,
+        vendor_id: u32,
+        product_id: u32,
+        device: CtapHidDevice,
+    ) -> Result<Self, IOError> {
+        let file = OpenOptions::new().read(true).write(true).open(path)?;
+        let mut uhid = Self { file, device };
+
+        let mut event = new_event(UHID_CREATE2);
+        let name = name.as_bytes();
+        let name_len = name.len().min(127); // NUL-terminated
+        event[UHID_CREATE2_NAME_OFFSET..UHID_CREATE2_NAME_OFFSET + name_len]
+            .copy_from_slice(&name[..name_len]);
+        write_u16(
+            &mut event,
+            UHID_CREATE2_RD_SIZE_OFFSET,
+            FIDO_REPORT_DESCRIPTOR.len() as u16,
+        );
+        write_u16(&mut event, UHID_CREATE2_BUS_OFFSET, BUS_USB);
+        write_u32(&mut event, UHID_CREATE2_VENDOR_OFFSET, vendor_id);
+        write_u32(&mut event, UHID_CREATE2_PRODUCT_OFFSET, product_id);
+        event[UHID_CREATE2_RD_DATA_OFFSET
+            ..UHID_CREATE2_RD_DATA_OFFSET + FIDO_REPORT_DESCRIPTOR.len()]
+            .copy_from_slice(FIDO_REPORT_DESCRIPTOR);
+        uhid.write_event(&event)?;
+        info!("Created UHID FIDO device");
+        Ok(uhid)
+    }
+
+    /// Processes events from the kernel until the device is destroyed. This call blocks, so it
+    /// should be run on a dedicated thread (e.g. via `tokio::task::spawn_blocking`).
+    #[instrument(skip_all)]
+    pub fn run(&mut self) -> Result<(), IOError> {
+        let mut event = vec![0u8; UHID_EVENT_SIZE];
+        loop {
+            let read = self.file.read(&mut event)?;
+            if read < 4 {
+                return Err(IOError::new(IOErrorKind::UnexpectedEof, "Short UHID event"));
+            }
+
+            match read_u32(&event, 0) {
+                UHID_START => debug!("UHID device started"),
+                UHID_STOP => {
+                    info!("UHID device stopped");
+                    return Ok(());
+                }
+                UHID_OPEN => debug!("UHID device opened by a client"),
+                UHID_CLOSE => debug!("UHID device closed by all clients"),
+                UHID_OUTPUT => {
+                    let size =
+                        (read_u16(&event, UHID_OUTPUT_SIZE_OFFSET) as usize).min(UHID_DATA_MAX);
+                    let mut report = &event[4..4 + size];
+                    // Writes via hidraw are prefixed with the report number, which is always
+                    // zero since the FIDO report descriptor does not use report IDs.
+                    if report.len() > PACKET_SIZE {
+                        report = &report[1..];
+                    }
+                    for response in self.device.handle_report(report) {
+                        self.send_input(&response)?;
+                    }
+                }
+                UHID_GET_REPORT => {
+                    let mut reply = new_event(UHID_GET_REPORT_REPLY);
+                    write_u32(&mut reply, 4, read_u32(&event, 4)); // id
+                    write_u16(&mut reply, 8, EIO);
+                    self.write_event(&reply)?;
+                }
+                UHID_SET_REPORT => {
+                    let mut reply = new_event(UHID_SET_REPORT_REPLY);
+                    write_u32(&mut reply, 4, read_u32(&event, 4)); // id
+                    write_u16(&mut reply, 8, EIO);
+                    self.write_event(&reply)?;
+                }
+                other => trace!({ event = other }, "Ignoring UHID event"),
+            }
+        }
+    }
+
+    fn send_input(&mut self, report: &[u8]) -> Result<(), IOError> {
+        let mut event = new_event(UHID_INPUT2);
+        write_u16(&mut event, 4, report.len() as u16);
+        event[6..6 + report.len()].copy_from_slice(report);
+        self.write_event(&event)
+    }
+
+    fn write_event(&mut self, event: &[u8]) -> Result<(), IOError> {
+        self.file.write_all(event)
+    }
+}
+
+impl Drop for UhidDevice {
+    #[instrument(skip_all)]
+    fn drop(&mut self) {
+        if let Err(err) = self.write_event(&new_event(UHID_DESTROY)) {
+            warn!(%err, "Failed to destroy UHID device");
+        }
+    }
+}
+
+fn new_event(event_type: u32) -> Vec<u8> {
+    let mut event = vec![0u8; UHID_EVENT_SIZE];
+    write_u32(&mut event, 0, event_type);
+    event
+}
+
+fn read_u16(buf: &[u8], offset: usize) -> u16 {
+    u16::from_ne_bytes([buf[offset], buf[offset + 1]])
+}
+
+fn read_u32(buf: &[u8], offset: usize) -> u32 {
+    u32::from_ne_bytes([
+        buf[offset],
+        buf[offset + 1],
+        buf[offset + 2],
+        buf[offset + 3],
+    ])
+}
+
+fn write_u16(buf: &mut [u8], offset: usize, value: u16) {
+    buf[offset..offset + 2].copy_from_slice(&value.to_ne_bytes());
+}
+
+fn write_u32(buf: &mut [u8], offset: usize, value: u32) {
+    buf[offset..offset + 4].copy_from_slice(&value.to_ne_bytes());
+}
@@ -0,0 +1,565 @@
+//! `org.freedesktop.impl.portal.Credentials`, through which the credentials portal delegates all
+//! user interaction to a desktop-specific backend, such as a GTK or KDE dialog.
+//!
+//! [`Backend`] is implemented by backends, and by [`BackendClient`] which calls a backend over
+//! D-Bus. The conversions between D-Bus arguments and their Rust types are shared by both sides.
+
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use dbus::arg::{prop_cast, PropMap, RefArg, Variant};
+use dbus::channel::Sender;
+use dbus::nonblock::{MethodReply, Proxy, SyncConnection};
+use dbus::{Message, Path};
+use tracing::{debug, instrument, warn};
+
+use crate::account::AccountSelector;
+use crate::pin::{Pin, PinProvider, PinPurpose, PinRequest};
+use crate::Transport;
+
+pub const BACKEND_INTERFACE: &str = "org.freedesktop.impl.portal.Credentials";
+pub const BACKEND_REQUEST_INTERFACE: &str = "org.freedesktop.impl.portal.Request";
+pub const BACKEND_PATH: &str = "/org/freedesktop/portal/desktop";
+
+// Interactions last for as long as the user takes, up to the longest ceremony timeout.
+const INTERACTION_TIMEOUT: Duration = Duration::from_secs(600);
+
+/// The ceremony which the user is asked to confirm.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operation {
+    /// WebAuthn MakeCredential.
+    Create,
+    /// WebAuthn GetAssertion.
+    Get,
+    /// U2F Register.
+    Register,
+    /// U2F Sign.
+    Sign,
+}
+
+impl Operation {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Operation::Create => "create",
+            Operation::Get => "get",
+            Operation::Register => "register",
+            Operation::Sign => "sign",
+        }
+    }
+}
+
+impl FromStr for Operation {
+    type Err = ();
+
+    fn from_str(operation: &str) -> Result<Self, Self::Err> {
+        match operation {
+            "create" => Ok(Operation::Create),
+            "get" => Ok(Operation::Get),
+            "register" => Ok(Operation::Register),
+            "sign" => Ok(Operation::Sign),
+            _ => Err(()),
+        }
+    }
+}
+
+/// The portal request on whose behalf the user is asked.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Interaction {
+    /// Handle of the portal request, at which backends export their own request object.
+    pub handle: Path<'static>,
+    /// Application ID of the caller, empty if unknown.
+    pub app_id: String,
+    /// Identifier of the caller's window, empty if unknown.
+    pub parent_window: String,
+}
+
+impl Interaction {
+    pub fn new(handle: Path<'static>) -> Self {
+        Self {
+            handle,
+            app_id: String::new(),
+            parent_window: String::new(),
+        }
+    }
+}
+
+/// An authenticator which the user can choose.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeviceDescription {
+    pub id: String,
+    pub name: String,
+    pub transport: Transport,
+}
+
+impl DeviceDescription {
+    pub fn to_dict(&self) -> PropMap {
+        dict(vec![
+            ("id", Box::new(self.id.clone())),
+            ("name", Box::new(self.name.clone())),
+            (
+                "transport",
+                Box::new(transport_name(self.transport).to_owned()),
+            ),
+        ])
+    }
+
+    pub fn from_dict(dict: &PropMap) -> Option<Self> {
+        Some(Self {
+            id: prop_cast::<String>(dict, "id")?.clone(),
+            name: prop_cast::<String>(dict, "name")?.clone(),
+            transport: transport_from_name(prop_cast::<String>(dict, "transport")?)?,
+        })
+    }
+}
+
+/// An account which the user can sign in with, from a discoverable credential.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AccountDescription {
+    /// The user handle.
+    pub id: Vec<u8>,
+    pub name: Option<String>,
+    pub display_name: Option<String>,
+}
+
+impl AccountDescription {
+    pub fn to_dict(&self) -> PropMap {
+        let mut entries: Vec<(&str, Box<dyn RefArg>)> = vec![("id", Box::new(self.id.clone()))];
+        if let Some(name) = &self.name {
+            entries.push(("name", Box::new(name.clone())));
+        }
+        if let Some(display_name) = &self.display_name {
+            entries.push(("display_name", Box::new(display_name.clone())));
+        }
+        dict(entries)
+    }
+
+    pub fn from_dict(dict: &PropMap) -> Option<Self> {
+        Some(Self {
+            id: prop_cast::<Vec<u8>>(dict, "id")?.clone(),
+            name: prop_cast::<String>(dict, "name").cloned(),
+            display_name: prop_cast::<String>(dict, "display_name").cloned(),
+        })
+    }
+}
+
+/// Options of `EnterPin`, describing the PIN request.
+pub fn pin_request_to_dict(request: &PinRequest) -> PropMap {
+    let mut entries: Vec<(&str, Box<dyn RefArg>)> = vec![
+        ("device", Box::new(request.device.clone())),
+        (
+            "purpose",
+            Box::new(purpose_name(request.purpose).to_owned()),
+        ),
+        ("min_length", Box::new(request.min_length as u32)),
+        (
+            "previous_attempt_failed",
+            Box::new(request.previous_attempt_failed),
+        ),
+        (
+            "power_cycle_required",
+            Box::new(request.power_cycle_required),
+        ),
+    ];
+    if let Some(attempts_left) = request.attempts_left {
+        entries.push(("attempts_left", Box::new(attempts_left)));
+    }
+    if let Some(max_length) = request.max_length {
+        entries.push(("max_length", Box::new(max_length as u32)));
+    }
+    dict(entries)
+}
+
+pub fn pin_request_from_dict(dict: &PropMap) -> Option<PinRequest> {
+    let device = prop_cast::<String>(dict, "device")?;
+    let purpose = match prop_cast::<String>(dict, "purpose")?.as_str() {
+        "entry" => PinPurpose::Entry,
+        "set" => PinPurpose::Set,
+        "change" => PinPurpose::Change,
+        _ => return None,
+    };
+    let mut request = PinRequest::new(device, purpose);
+    if let Some(min_length) = prop_cast::<u32>(dict, "min_length") {
+        request.min_length = *min_length as usize;
+    }
+    request.max_length = prop_cast::<u32>(dict, "max_length").map(|length| *length as usize);
+    request.attempts_left = prop_cast::<u32>(dict, "attempts_left").copied();
+    request.previous_attempt_failed = prop_cast::<bool>(dict, "previous_attempt_failed")
+        .copied()
+        .unwrap_or(false);
+    request.power_cycle_required = prop_cast::<bool>(dict, "power_cycle_required")
+        .copied()
+        .unwrap_or(false);
+    Some(request)
+}
+
+/// User interaction needed by ceremonies. Every method returns once the user made a choice, or
+/// returns `None` (or `false`) if the user cancelled or the interaction otherwise ended.
+#[async_trait]
+pub trait Backend: Send + Sync {
+    /// Asks which authenticator to use, returning the `id` of the chosen one.
+    async fn choose_device(
+        &self,
+        interaction: &Interaction,
+        devices: &[DeviceDescription],
+    ) -> Option<String>;
+
+    async fn enter_pin(&self, interaction: &Interaction, request: &PinRequest) -> Option<Pin>;
+
+    /// Asks whether the application may use the user's authenticators for the relying party.
+    async fn confirm_relying_party(
+        &self,
+        interaction: &Interaction,
+        operation: Operation,
+        relying_party_id: &str,
+        origin: &str,
+    ) -> bool;
+
+    /// Asks which account to sign in with, returning the index of the chosen one.
+    async fn choose_account(
+        &self,
+        interaction: &Interaction,
+        accounts: &[AccountDescription],
+    ) -> Option<usize>;
+
+    /// Tells the user that the ceremony failed.
+    async fn show_error(&self, interaction: &Interaction, message: &str);
+}
+
+/// Asks for PINs through a backend, on behalf of a portal request.
+pub struct BackendPinProvider {
+    backend: Arc<dyn Backend>,
+    interaction: Interaction,
+}
+
+impl BackendPinProvider {
+    pub fn new(backend: Arc<dyn Backend>, interaction: Interaction) -> Self {
+        Self {
+            backend,
+            interaction,
+        }
+    }
+}
+
+#[async_trait]
+impl PinProvider for BackendPinProvider {
+    async fn provide_pin(&self, request: &PinRequest) -> Option<Pin> {
+        self.backend.enter_pin(&self.interaction, request).await
+    }
+}
+
+/// Asks which account to sign in with through a backend, on behalf of a portal request.
+pub struct BackendAccountSelector {
+    backend: Arc<dyn Backend>,
+    interaction: Interaction,
+}
+
+impl BackendAccountSelector {
+    pub fn new(backend: Arc<dyn Backend>, interaction: Interaction) -> Self {
+        Self {
+            backend,
+            interaction,
+        }
+    }
+}
+
+#[async_trait]
+impl AccountSelector for BackendAccountSelector {
+    async fn select_account(&self, accounts: &[AccountDescription]) -> Option<usize> {
+        self.backend
+            .choose_account(&self.interaction, accounts)
+            .await
+    }
+
+    // Accounts without names can not be told apart in a dialog.
+    fn look_up_users(&self) -> bool {
+        true
+    }
+}
+
+/// Calls a backend exported on the bus, under the given name.
+///
+/// Dropping a pending call closes the backend's request, so that its dialog goes away.
+#[derive(Clone)]
+pub struct BackendClient {
+    connection: Arc<SyncConnection>,
+    destination: String,
+}
+
+impl BackendClient {
+    pub fn new(connection: Arc<SyncConnection>, destination: &str) -> Self {
+        Self {
+            connection,
+            destination: destination.to_owned(),
+        }
+    }
+
+    fn call<A: dbus::arg::AppendAll>(
+        &self,
+        method: &'static str,
+        args: A,
+    ) -> MethodReply<(u32, PropMap)> {
+        Proxy::new(
+            self.destination.as_str(),
+            BACKEND_PATH,
+            INTERACTION_TIMEOUT,
+            self.connection.as_ref(),
+        )
+        .method_call(BACKEND_INTERFACE, method, args)
+    }
+
+    /// Waits for the backend's response, returning its results if the user made a choice.
+    async fn results(
+        &self,
+        method: &'static str,
+        interaction: &Interaction,
+        reply: MethodReply<(u32, PropMap)>,
+    ) -> Option<PropMap> {
+        let mut pending = PendingInteraction {
+            client: self,
+            interaction,
+            pending: true,
+        };
+        let result = reply.await;
+        pending.pending = false;
+        match result {
+            Ok((0, results)) => Some(results),
+            Ok((1, _)) => {
+                debug!(%method, "Interaction cancelled by the user");
+                None
+            }
+            Ok((response, _)) => {
+                debug!(%method, %response, "Interaction ended");
+                None
+            }
+            Err(err) => {
+                warn!(%method, %err, "Failed to call the portal backend");
+                None
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl Backend for BackendClient {
+    #[instrument(skip_all, fields(handle = %interaction.handle))]
+    async fn choose_device(
+        &self,
+        interaction: &Interaction,
+        devices: &[DeviceDescription],
+    ) -> Option<String> {
+        let reply = self.call(
+            "ChooseDevice",
+            (
+                interaction.handle.clone(),
+                interaction.app_id.as_str(),
+                interaction.parent_window.as_str(),
+                devices
+                    .iter()
+                    .map(DeviceDescription::to_dict)
+                    .collect::<Vec<_>>(),
+                PropMap::new(),
+            ),
+        );
+        let results = self.results("ChooseDevice", interaction, reply).await?;
+        prop_cast::<String>(&results, "device").cloned()
+    }
+
+    #[instrument(skip_all, fields(handle = %interaction.handle))]
+    async fn enter_pin(&self, interaction: &Interaction, request: &PinRequest) -> Option<Pin> {
+        let reply = self.call(
+            "EnterPin",
+            (
+                interaction.handle.clone(),
+                interaction.app_id.as_str(),
+                interaction.parent_window.as_str(),
+                pin_request_to_dict(request),
+            ),
+        );
+        let mut results = self.results("EnterPin", interaction, reply).await?;
+        let mut pin = results.remove("pin")?.0;
+        // Moved out rather than copied, so that the PIN is zeroed once dropped.
+        let pin = pin.as_mut().as_any_mut().downcast_mut::<String>()?;
+        Some(Pin::new(std::mem::take(pin)))
+    }
+
+    #[instrument(skip_all, fields(handle = %interaction.handle))]
+    async fn confirm_relying_party(
+        &self,
+        interaction: &Interaction,
+        operation: Operation,
+        relying_party_id: &str,
+        origin: &str,
+    ) -> bool {
+        let reply = self.call(
+            "ConfirmRelyingParty",
+            (
+                interaction.handle.clone(),
+                interaction.app_id.as_str(),
+                interaction.parent_window.as_str(),
+                operation.as_str(),
+                relying_party_id,
+                origin,
+                PropMap::new(),
+            ),
+        );
+        self.results("ConfirmRelyingParty", interaction, reply)
+            .await
+            .is_some()
+    }
+
+    #[instrument(skip_all, fields(handle = %interaction.handle))]
+    async fn choose_account(
+        &self,
+        interaction: &Interaction,
+        accounts: &[AccountDescription],
+    ) -> Option<usize> {
+        let reply = self.call(
+            "ChooseAccount",
+            (
+                interaction.handle.clone(),
+                interaction.app_id.as_str(),
+                interaction.parent_window.as_str(),
+                accounts
+                    .iter()
+                    .map(AccountDescription::to_dict)
+                    .collect::<Vec<_>>(),
+                PropMap::new(),
+            ),
+        );
+        let results = self.results("ChooseAccount", interaction, reply).await?;
+        let index = *prop_cast::<u32>(&results, "account")? as usize;
+        if index >= accounts.len() {
+            warn!(%index, "Backend chose an account which does not exist");
+            return None;
+        }
+        Some(index)
+    }
+
+    #[instrument(skip_all, fields(handle = %interaction.handle))]
+    async fn show_error(&self, interaction: &Interaction, message: &str) {
+        let reply = self.call(
+            "ShowError",
+            (
+                interaction.handle.clone(),
+                interaction.app_id.as_str(),
+                interaction.parent_window.as_str(),
+                message,
+                PropMap::new(),
+            ),
+        );
+        self.results("ShowError", interaction, reply).await;
+    }
+}
+
+/// Closes the backend's request on drop, unless the backend has responded.
+struct PendingInteraction<'a> {
+    client: &'a BackendClient,
+    interaction: &'a Interaction,
+    pending: bool,
+}
+
+impl Drop for PendingInteraction<'_> {
+    fn drop(&mut self) {
+        if !self.pending {
+            return;
+        }
+        let Ok(mut close) = Message::new_method_call(
+            self.client.destination.as_str(),
+            self.interaction.handle.clone(),
+            BACKEND_REQUEST_INTERFACE,
+            "Close",
+        ) else {
+            return;
+        };
+        close.set_no_reply(true);
+        if self.client.connection.send(close).is_err() {
+            warn!(handle = %self.interaction.handle, "Failed to close the backend request");
+        }
+        debug!(handle = %self.interaction.handle, "Closed the backend request");
+    }
+}
+
+pub(crate) fn dict(entries: Vec<(&str, Box<dyn RefArg>)>) -> PropMap {
+    entries
+        .into_iter()
+        .map(|(key, value)| (key.to_owned(), Variant(value)))
+        .collect()
+}
+
+pub(crate) fn transport_name(transport: Transport) -> &'static str {
+    match transport {
+        Transport::Usb => "usb",
+        Transport::Ble => "ble",
+        Transport::Nfc => "nfc",
+    }
+}
+
+fn transport_from_name(name: &str) -> Option<Transport> {
+    match name {
+        "usb" => Some(Transport::Usb),
+        "ble" => Some(Transport::Ble),
+        "nfc" => Some(Transport::Nfc),
+        _ => None,
+    }
+}
+
+fn purpose_name(purpose: PinPurpose) -> &'static str {
+    match purpose {
+        PinPurpose::Entry => "entry",
+        PinPurpose::Set => "set",
+        PinPurpose::Change => "change",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::backend::{
+        pin_request_from_dict, pin_request_to_dict, AccountDescription, DeviceDescription,
+        Operation,
+    };
+    use crate::pin::{PinPurpose, PinRequest};
+    use crate::Transport;
+
+    #[test]
+    fn descriptions_round_trip() {
+        let device = DeviceDescription {
+            id: String::from("0"),
+            name: String::from("Security Key"),
+            transport: Transport::Ble,
+        };
+        assert_eq!(
+            DeviceDescription::from_dict(&device.to_dict()),
+            Some(device)
+        );
+
+        let account = AccountDescription {
+            id: vec![1, 2, 3],
+            name: Some(String::from("mario.rossi")),
+            display_name: None,
+        };
+        assert_eq!(
+            AccountDescription::from_dict(&account.to_dict()),
+            Some(account)
+        );
+        assert_eq!("sign".parse(), Ok(Operation::Sign));
+    }
+
+    #[test]
+    fn pin_request_round_trip() {
+        let mut request = PinRequest::new("Security Key", PinPurpose::Change);
+        request.attempts_left = Some(3);
+        request.max_length = Some(32);
+        request.previous_attempt_failed = true;
+
+        let parsed = pin_request_from_dict(&pin_request_to_dict(&request)).unwrap();
+        assert_eq!(parsed.device, "Security Key");
+        assert_eq!(parsed.purpose, PinPurpose::Change);
+        assert_eq!(parsed.attempts_left, Some(3));
+        assert_eq!(parsed.min_length, request.min_length);
+        assert_eq!(parsed.max_length, Some(32));
+        assert!(parsed.previous_attempt_failed);
+        assert!(!parsed.power_cycle_required);
+    }
+}
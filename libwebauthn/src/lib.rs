@@ -1,12 +1,18 @@
 #![feature(let_else)]
 #![feature(option_get_or_insert_default)]
 
+pub mod account;
+pub mod authenticator;
+pub mod backend;
 pub mod fido;
 pub mod ops;
 pub mod pin;
 pub mod proto;
+pub mod secret;
+pub mod session;
 pub mod transport;
 pub mod u2f;
+pub mod ui;
 pub mod webauthn;
 
 #[macro_use]
@@ -15,12 +21,14 @@ extern crate num_derive;
 #[macro_use]
 extern crate bitflags;
 
-#[derive(Debug)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
 pub enum Transport {
     Usb,
     Ble,
+    Nfc,
 }
 
-pub fn available_transports() -> Vec<Transport> {
-    vec![Transport::Usb, Transport::Ble]
+/// Transports which are usable on this system, see `DeviceManager::available_transports`.
+pub async fn available_transports() -> Vec<Transport> {
+    transport::DeviceManager::new().available_transports().await
 }
@@ -8,7 +8,9 @@ use tracing::{error, trace};
 use x509_parser::nom::AsBytes;
 
 use super::webauthn::MakeCredentialRequest;
-use crate::ops::webauthn::{GetAssertionResponse, MakeCredentialResponse};
+use crate::ops::webauthn::{
+    CredentialPropertiesOutput, GetAssertionResponse, MakeCredentialResponse,
+};
 use crate::proto::ctap1::{Ctap1RegisterRequest, Ctap1SignRequest};
 use crate::proto::ctap1::{Ctap1RegisterResponse, Ctap1SignResponse};
 use crate::proto::ctap2::{
@@ -37,6 +39,7 @@ impl SignRequest {
             key_handle: Vec::from(key_handle),
             timeout,
             require_user_presence: true,
+            check_only: false,
         }
     }
 }
@@ -142,10 +145,15 @@ impl UpgradableResponse<MakeCredentialResponse, MakeCredentialRequest> for Regis
         // * Set "authData" to authenticatorData.
         // * Set "fmt" to "fido-u2f".
         // * Set "attStmt" to attestationStatement.
-        Ok(Ctap2MakeCredentialResponse {
+        let attestation_object = Ctap2MakeCredentialResponse {
             format: String::from("fido-u2f"),
             authenticator_data: ByteBuf::from(auth_data),
             attestation_statement: attestation_statement,
+        };
+        // U2F credentials are never discoverable.
+        Ok(MakeCredentialResponse {
+            attestation_object,
+            cred_props: CredentialPropertiesOutput { rk: Some(false) },
         })
     }
 }
@@ -1,3 +1,4 @@
+use std::fmt::{Debug, Formatter};
 use std::time::Duration;
 
 use sha2::{Digest, Sha256};
@@ -19,7 +20,36 @@ use super::u2f::{RegisterRequest, SignRequest};
 
 // FIDO2 operations can be mapped by default to their respective CTAP2 requests.
 
-pub type MakeCredentialResponse = Ctap2MakeCredentialResponse;
+#[derive(Debug, Clone)]
+pub struct MakeCredentialResponse {
+    pub attestation_object: Ctap2MakeCredentialResponse,
+    /// credProps client extension output
+    pub cred_props: CredentialPropertiesOutput,
+}
+
+/// Properties of the created credential, as reported by the credProps client extension.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CredentialPropertiesOutput {
+    /// Whether the credential is discoverable, if known.
+    pub rk: Option<bool>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResidentKeyRequirement {
+    Required,
+    Preferred,
+    Discouraged,
+}
+
+impl ResidentKeyRequirement {
+    /// Check if a discoverable credential is strictly required for this request
+    pub fn is_required(&self) -> bool {
+        match self {
+            Self::Required => true,
+            Self::Preferred | Self::Discouraged => false,
+        }
+    }
+}
 
 #[derive(Debug, Clone, Copy)]
 pub enum UserVerificationRequirement {
@@ -46,7 +76,7 @@ impl UserVerificationRequirement {
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Clone)]
 pub struct MakeCredentialRequest {
     pub hash: Vec<u8>,
     pub origin: String,
@@ -54,7 +84,8 @@ pub struct MakeCredentialRequest {
     pub relying_party: Ctap2PublicKeyCredentialRpEntity,
     /// userEntity
     pub user: Ctap2PublicKeyCredentialUserEntity,
-    pub require_resident_key: bool,
+    /// residentKey
+    pub resident_key: ResidentKeyRequirement,
     pub user_verification: UserVerificationRequirement,
     /// credTypesAndPubKeyAlgs
     pub algorithms: Vec<Ctap2CredentialType>,
@@ -65,7 +96,7 @@ pub struct MakeCredentialRequest {
     pub timeout: Duration,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Clone)]
 pub struct GetAssertionRequest {
     pub relying_party_id: String,
     pub hash: Vec<u8>,
@@ -75,6 +106,35 @@ pub struct GetAssertionRequest {
     pub timeout: Duration,
 }
 
+// Extensions may carry secrets, such as credBlob contents, and are left out of debug output.
+impl Debug for MakeCredentialRequest {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("MakeCredentialRequest")
+            .field("hash", &self.hash)
+            .field("origin", &self.origin)
+            .field("relying_party", &self.relying_party)
+            .field("user", &self.user)
+            .field("resident_key", &self.resident_key)
+            .field("user_verification", &self.user_verification)
+            .field("algorithms", &self.algorithms)
+            .field("exclude", &self.exclude)
+            .field("timeout", &self.timeout)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Debug for GetAssertionRequest {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("GetAssertionRequest")
+            .field("relying_party_id", &self.relying_party_id)
+            .field("hash", &self.hash)
+            .field("allow", &self.allow)
+            .field("user_verification", &self.user_verification)
+            .field("timeout", &self.timeout)
+            .finish_non_exhaustive()
+    }
+}
+
 impl MakeCredentialRequest {
     #[cfg(test)]
     pub fn dummy() -> Self {
@@ -86,7 +146,21 @@ impl MakeCredentialRequest {
             exclude: None,
             extensions_cbor: vec![],
             origin: "example.org".to_owned(),
-            require_resident_key: false,
+            resident_key: ResidentKeyRequirement::Discouraged,
+            user_verification: UserVerificationRequirement::Preferred,
+            timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+impl GetAssertionRequest {
+    #[cfg(test)]
+    pub fn dummy() -> Self {
+        Self {
+            relying_party_id: String::from(".dummy"),
+            hash: vec![0; 32],
+            allow: vec![],
+            extensions_cbor: None,
             user_verification: UserVerificationRequirement::Preferred,
             timeout: Duration::from_secs(10),
         }
@@ -114,6 +188,25 @@ impl From<Ctap2GetAssertionResponse> for GetAssertionResponse {
     }
 }
 
+/// The credentials of an allowList found on an authenticator by probing, without user presence.
+///
+/// Authenticators only report one credential per request, so at most one match is reported for
+/// each batch of the allowList which was probed.
+#[derive(Debug, Clone, Default)]
+pub struct ProbeResponse {
+    pub credentials: Vec<Ctap2PublicKeyCredentialDescriptor>,
+    /// Whether the authenticator holds a discoverable credential for the relying party, when
+    /// probing with an empty allowList.
+    pub discoverable: bool,
+}
+
+impl ProbeResponse {
+    /// Whether the authenticator can answer the request.
+    pub fn has_match(&self) -> bool {
+        self.discoverable || !self.credentials.is_empty()
+    }
+}
+
 pub trait DowngradableRequest<T> {
     fn is_downgradable(&self) -> bool;
     fn try_downgrade(&self) -> Result<T, CtapError>;
@@ -135,8 +228,9 @@ impl DowngradableRequest<RegisterRequest> for MakeCredentialRequest {
             return false;
         }
 
-        // Options must not include "rk" set to true.
-        if self.require_resident_key {
+        // Options must not include "rk" set to true. Preferred discoverable credentials fall back
+        // to non-discoverable ones.
+        if self.resident_key.is_required() {
             debug!("Not downgradable: request requires resident key");
             return false;
         }
@@ -244,7 +338,8 @@ impl DowngradableRequest<Vec<SignRequest>> for GetAssertionRequest {
 #[cfg(test)]
 mod tests {
     use crate::ops::webauthn::{
-        DowngradableRequest, MakeCredentialRequest, UserVerificationRequirement,
+        DowngradableRequest, MakeCredentialRequest, ResidentKeyRequirement,
+        UserVerificationRequirement,
     };
     use crate::proto::ctap2::{
         Ctap2COSEAlgorithmIdentifier, Ctap2CredentialType, Ctap2PublicKeyCredentialType,
@@ -254,7 +349,7 @@ mod tests {
     fn ctap2_make_credential_downgradable() {
         let mut request = MakeCredentialRequest::dummy();
         request.algorithms = vec![Ctap2CredentialType::default()];
-        request.require_resident_key = false;
+        request.resident_key = ResidentKeyRequirement::Discouraged;
         assert!(request.is_downgradable());
     }
 
@@ -262,10 +357,18 @@ mod tests {
     fn ctap2_make_credential_downgradable_unsupported_rk() {
         let mut request = MakeCredentialRequest::dummy();
         request.algorithms = vec![Ctap2CredentialType::default()];
-        request.require_resident_key = true;
+        request.resident_key = ResidentKeyRequirement::Required;
         assert!(!request.is_downgradable());
     }
 
+    #[test]
+    fn ctap2_make_credential_downgradable_preferred_rk() {
+        let mut request = MakeCredentialRequest::dummy();
+        request.algorithms = vec![Ctap2CredentialType::default()];
+        request.resident_key = ResidentKeyRequirement::Preferred;
+        assert!(request.is_downgradable());
+    }
+
     #[test]
     fn ctap2_make_credential_downgradable_unsupported_uv() {
         let mut request = MakeCredentialRequest::dummy();
@@ -13,8 +13,10 @@ use rand::{rngs::OsRng, thread_rng, Rng};
 use sha2::{Digest, Sha256};
 use tracing::{error, info, instrument, warn};
 use x509_parser::nom::AsBytes;
+use zeroize::Zeroizing;
 
 use crate::proto::{ctap2::Ctap2PinUvAuthProtocol, CtapError};
+use crate::secret::{SecretBytes, SecretString};
 
 type Aes256CbcEncryptor = cbc::Encryptor<aes::Aes256>;
 type Aes256CbcDecryptor = cbc::Decryptor<aes::Aes256>;
@@ -36,27 +38,99 @@ impl Default for PinUvAuthToken {
     }
 }
 
+/// A PIN, zeroed from memory once dropped and redacted when printed.
+pub type Pin = SecretString;
+
+// Minimum PIN length, unless the authenticator reports a different one in getInfo.
+pub const DEFAULT_MIN_PIN_LENGTH: usize = 4;
+// PINs are at most 63 bytes long, once UTF-8 encoded.
+pub const MAX_PIN_LENGTH_BYTES: usize = 63;
+// Line buffer for PINs typed on stdin, with room to spare for over-long input.
+const STDIN_PIN_CAPACITY: usize = 256;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PinPurpose {
+    /// The PIN is needed to verify the user.
+    Entry,
+    /// A PIN is being set on an authenticator which has none.
+    Set,
+    /// The current PIN is being changed.
+    Change,
+}
+
+/// Everything known about a PIN request, to be shown to the user.
+#[derive(Debug, Clone)]
+pub struct PinRequest {
+    /// Description of the authenticator requesting the PIN.
+    pub device: String,
+    pub purpose: PinPurpose,
+    /// Attempts left before the PIN is blocked, if reported by the authenticator.
+    pub attempts_left: Option<u32>,
+    /// Minimum PIN length, in Unicode code points.
+    pub min_length: usize,
+    /// Maximum PIN length, in Unicode code points. The PIN is also limited to
+    /// `MAX_PIN_LENGTH_BYTES` once UTF-8 encoded.
+    pub max_length: Option<usize>,
+    /// Whether the previously provided PIN was rejected, either by the authenticator or because
+    /// of its length.
+    pub previous_attempt_failed: bool,
+    /// Whether the authenticator must be power cycled (e.g. unplugged and plugged back in) before
+    /// another PIN can be tried.
+    pub power_cycle_required: bool,
+}
+
+impl PinRequest {
+    pub fn new(device: &str, purpose: PinPurpose) -> Self {
+        Self {
+            device: device.to_owned(),
+            purpose,
+            attempts_left: None,
+            min_length: DEFAULT_MIN_PIN_LENGTH,
+            max_length: None,
+            previous_attempt_failed: false,
+            power_cycle_required: false,
+        }
+    }
+
+    /// Whether the PIN has an acceptable length. PINs which do not are rejected without being
+    /// sent to the authenticator, so that no attempt is spent on them.
+    pub fn is_valid_length(&self, pin: &str) -> bool {
+        let length = pin.chars().count();
+        length >= self.min_length
+            && self
+                .max_length
+                .is_none_or(|max_length| length <= max_length)
+            && pin.len() <= MAX_PIN_LENGTH_BYTES
+    }
+}
+
 #[async_trait]
 pub trait PinProvider: Send + Sync {
-    async fn provide_pin(&self, attempts_left: Option<u32>) -> Option<String>;
+    /// Asks for the PIN. Returning `None` cancels the operation.
+    async fn provide_pin(&self, request: &PinRequest) -> Option<Pin>;
+
+    /// Built-in user verification, such as a fingerprint match, failed. The user may be asked to
+    /// try again, with `attempts_left` before it is blocked and the PIN is needed instead.
+    async fn uv_failed(&self, _attempts_left: Option<u32>) {}
 }
 
 #[derive(Debug, Clone)]
 pub struct StaticPinProvider {
-    pin: String,
+    pin: Pin,
 }
 
 impl StaticPinProvider {
     pub fn new(pin: &str) -> Self {
         Self {
-            pin: pin.to_owned(),
+            pin: Pin::from(pin),
         }
     }
 }
 
 #[async_trait]
 impl PinProvider for StaticPinProvider {
-    async fn provide_pin(&self, attempts_left: Option<u32>) -> Option<String> {
+    async fn provide_pin(&self, request: &PinRequest) -> Option<Pin> {
+        let attempts_left = request.attempts_left;
         if attempts_left.map_or(false, |no| no <= 1) {
             warn!(
                 ?attempts_left,
@@ -64,8 +138,12 @@ impl PinProvider for StaticPinProvider {
             );
             return None;
         }
+        if request.previous_attempt_failed {
+            warn!("Refusing to provide static PIN, as it was rejected");
+            return None;
+        }
 
-        info!({ pin = %self.pin, ?attempts_left }, "Providing static PIN");
+        info!(?attempts_left, "Providing static PIN");
         Some(self.pin.clone())
     }
 }
@@ -80,23 +158,50 @@ impl StdinPromptPinProvider {
 
 #[async_trait]
 impl PinProvider for StdinPromptPinProvider {
-    async fn provide_pin(&self, attempts_left: Option<u32>) -> Option<String> {
+    async fn provide_pin(&self, request: &PinRequest) -> Option<Pin> {
         use std::io::{self, Write};
-        use text_io::read;
 
-        if let Some(attempts_left) = attempts_left {
+        if request.power_cycle_required {
+            println!("PIN: Too many failed attempts, please unplug and replug your authenticator.");
+        }
+        if request.previous_attempt_failed {
+            println!("PIN: The PIN was rejected.");
+        }
+        if let Some(attempts_left) = request.attempts_left {
             println!("PIN: {} attempts left.", attempts_left);
         }
-        print!("PIN: Please enter the PIN for your authenticator: ");
+        let prompt = match request.purpose {
+            PinPurpose::Entry => "Please enter the PIN for",
+            PinPurpose::Set => "Please choose a new PIN for",
+            PinPurpose::Change => "Please choose a new PIN for",
+        };
+        print!(
+            "PIN: {} {} (at least {} characters): ",
+            prompt, request.device, request.min_length
+        );
         io::stdout().flush().unwrap();
-        let pin_raw = read!("{}\n");
+        // Reserved up front, so that reading the line does not reallocate and leave copies of
+        // the PIN behind.
+        let mut line = Zeroizing::new(String::with_capacity(STDIN_PIN_CAPACITY));
+        io::stdin().read_line(&mut line).ok()?;
+        let pin_raw = Pin::from(line.trim_end_matches(&['\r', '\n'][..]));
 
-        if &pin_raw == "" {
+        if pin_raw.is_empty() {
             println!("PIN: No PIN provided, cancelling operation.");
             return None;
         }
 
-        return Some(pin_raw);
+        Some(pin_raw)
+    }
+
+    async fn uv_failed(&self, attempts_left: Option<u32>) {
+        match attempts_left {
+            Some(attempts_left) => println!(
+                "UV: User verification failed, {} attempts left.",
+                attempts_left
+            ),
+            None => println!("UV: User verification failed."),
+        }
     }
 }
 
@@ -109,7 +214,7 @@ pub trait PinUvAuthProtocol: Send + Sync {
     fn encapsulate(
         &self,
         peer_public_key: &cosey::PublicKey,
-    ) -> Result<(cosey::PublicKey, Vec<u8>), Error>;
+    ) -> Result<(cosey::PublicKey, SecretBytes), Error>;
 
     // encrypt(key, demPlaintext) → ciphertext
     //   Encrypts a plaintext to produce a ciphertext, which may be longer than the plaintext.
@@ -118,7 +223,7 @@ pub trait PinUvAuthProtocol: Send + Sync {
 
     // decrypt(key, ciphertext) → plaintext | error
     //   Decrypts a ciphertext and returns the plaintext.
-    fn decrypt(&self, key: &[u8], ciphertext: &[u8]) -> Result<Vec<u8>, Error>;
+    fn decrypt(&self, key: &[u8], ciphertext: &[u8]) -> Result<SecretBytes, Error>;
 
     // authenticate(key, message) → signature
     //   Computes a MAC of the given message.
@@ -128,15 +233,15 @@ pub trait PinUvAuthProtocol: Send + Sync {
 trait ECPrivateKeyPinUvAuthProtocol {
     fn private_key(&self) -> &EphemeralSecret;
     fn public_key(&self) -> &P256PublicKey;
-    fn kdf(&self, bytes: &[u8]) -> Vec<u8>;
+    fn kdf(&self, bytes: &[u8]) -> SecretBytes;
 }
 /// Common functionality between ECDH-based PIN/UV auth protocols (1 & 2)
 trait ECDHPinUvAuthProtocol {
-    fn ecdh(&self, peer_public_key: &cosey::PublicKey) -> Result<Vec<u8>, Error>;
+    fn ecdh(&self, peer_public_key: &cosey::PublicKey) -> Result<SecretBytes, Error>;
     fn encapsulate(
         &self,
         peer_public_key: &cosey::PublicKey,
-    ) -> Result<(cosey::PublicKey, Vec<u8>), Error>;
+    ) -> Result<(cosey::PublicKey, SecretBytes), Error>;
     fn get_public_key(&self) -> cosey::PublicKey;
 }
 
@@ -166,10 +271,10 @@ impl ECPrivateKeyPinUvAuthProtocol for PinUvAuthProtocolOne {
     }
 
     /// kdf(Z) → sharedSecret
-    fn kdf(&self, bytes: &[u8]) -> Vec<u8> {
+    fn kdf(&self, bytes: &[u8]) -> SecretBytes {
         let mut hasher = Sha256::default();
         hasher.update(bytes);
-        hasher.finalize().to_vec()
+        SecretBytes::from(&hasher.finalize()[..])
     }
 }
 
@@ -181,7 +286,7 @@ where
     fn encapsulate(
         &self,
         peer_public_key: &cosey::PublicKey,
-    ) -> Result<(cosey::PublicKey, Vec<u8>), Error> {
+    ) -> Result<(cosey::PublicKey, SecretBytes), Error> {
         // Let sharedSecret be the result of calling ecdh(peerCoseKey). Return any resulting error.
         let shared_secret = self.ecdh(peer_public_key)?;
 
@@ -190,7 +295,7 @@ where
     }
 
     /// ecdh(peerCoseKey) → sharedSecret | error
-    fn ecdh(&self, peer_public_key: &cosey::PublicKey) -> Result<Vec<u8>, Error> {
+    fn ecdh(&self, peer_public_key: &cosey::PublicKey) -> Result<SecretBytes, Error> {
         // Parse peerCoseKey as specified for getPublicKey, below, and produce a P-256 point, Y.
         // If unsuccessful, or if the resulting point is not on the curve, return error.
         let cosey::PublicKey::EcdhEsHkdf256Key(peer_public_key) = peer_public_key else {
@@ -242,7 +347,7 @@ impl PinUvAuthProtocol for PinUvAuthProtocolOne {
         // (No padding is performed as the size of demPlaintext is required to be a multiple of the AES block length.)
         let iv: &[u8] = &[0; 16];
         let Ok(enc) = Aes256CbcEncryptor::new_from_slices(key, iv) else {
-            error!(?key, "Invalid key for AES-256 encryption");
+            error!({ len = key.len() }, "Invalid key for AES-256 encryption");
             return Err(Error::Ctap(CtapError::Other));
         };
         Ok(enc.encrypt_padded_vec_mut::<NoPadding>(plaintext))
@@ -256,12 +361,12 @@ impl PinUvAuthProtocol for PinUvAuthProtocolOne {
     }
 
     #[instrument(skip_all)]
-    fn decrypt(&self, key: &[u8], ciphertext: &[u8]) -> Result<Vec<u8>, Error> {
+    fn decrypt(&self, key: &[u8], ciphertext: &[u8]) -> Result<SecretBytes, Error> {
         // If the size of demCiphertext is not a multiple of the AES block length, return error.
         // Otherwise return the AES-256-CBC decryption of demCiphertext using an all-zero IV.
         if ciphertext.len() % 16 != 0 {
             error!(
-                ?ciphertext,
+                { len = ciphertext.len() },
                 "Ciphertext length is not a multiple of AES block length"
             );
             return Err(Error::Ctap(CtapError::Other));
@@ -269,20 +374,20 @@ impl PinUvAuthProtocol for PinUvAuthProtocolOne {
 
         let iv: &[u8] = &[0; 16];
         let Ok(dec) = Aes256CbcDecryptor::new_from_slices(key, iv) else {
-            error!(?key, "Invalid key for AES-256 decryption");
+            error!({ len = key.len() }, "Invalid key for AES-256 decryption");
             return Err(Error::Ctap(CtapError::Other));
         };
         let Ok(plaintext) = dec.decrypt_padded_vec_mut::<NoPadding>(ciphertext) else {
             error!("Unpad error while decrypting");
             return Err(Error::Ctap(CtapError::Other));
         };
-        Ok(plaintext)
+        Ok(SecretBytes::from(plaintext))
     }
 
     fn encapsulate(
         &self,
         peer_public_key: &cosey::PublicKey,
-    ) -> Result<(cosey::PublicKey, Vec<u8>), Error> {
+    ) -> Result<(cosey::PublicKey, SecretBytes), Error> {
         <Self as ECDHPinUvAuthProtocol>::encapsulate(self, peer_public_key)
     }
 }
@@ -313,14 +418,14 @@ impl ECPrivateKeyPinUvAuthProtocol for PinUvAuthProtocolTwo {
     }
 
     /// kdf(Z) → sharedSecret
-    fn kdf(&self, ikm: &[u8]) -> Vec<u8> {
+    fn kdf(&self, ikm: &[u8]) -> SecretBytes {
         // Returns:
         //   HKDF-SHA-256(salt = 32 zero bytes, IKM = Z, L = 32, info = "CTAP2 HMAC key") ||
         //   HKDF-SHA-256(salt = 32 zero bytes, IKM = Z, L = 32, info = "CTAP2 AES key")
         let salt: &[u8] = &[0u8; 32];
-        let mut output = hkdf_sha256(salt, ikm, "CTAP2 HMAC key".as_bytes());
-        output.extend(hkdf_sha256(salt, ikm, "CTAP2 AES key".as_bytes()));
-        output
+        let hmac_key = hkdf_sha256(salt, ikm, "CTAP2 HMAC key".as_bytes());
+        let aes_key = hkdf_sha256(salt, ikm, "CTAP2 AES key".as_bytes());
+        SecretBytes::from([&hmac_key[..], &aes_key[..]].concat())
     }
 }
 
@@ -333,7 +438,7 @@ impl PinUvAuthProtocol for PinUvAuthProtocolTwo {
     fn encapsulate(
         &self,
         peer_public_key: &cosey::PublicKey,
-    ) -> Result<(cosey::PublicKey, Vec<u8>), Error> {
+    ) -> Result<(cosey::PublicKey, SecretBytes), Error> {
         <Self as ECDHPinUvAuthProtocol>::encapsulate(self, peer_public_key)
     }
 
@@ -347,7 +452,7 @@ impl PinUvAuthProtocol for PinUvAuthProtocolTwo {
         // Let ct be the AES-256-CBC encryption of demPlaintext using key and iv.
         // (No padding is performed as the size of demPlaintext is required to be a multiple of the AES block length.)
         let Ok(enc) = Aes256CbcEncryptor::new_from_slices(key, &iv) else {
-            error!(?key, "Invalid key for AES-256 encryption");
+            error!({ len = key.len() }, "Invalid key for AES-256 encryption");
             return Err(Error::Ctap(CtapError::Other));
         };
         let ct = enc.encrypt_padded_vec_mut::<NoPadding>(plaintext);
@@ -358,7 +463,7 @@ impl PinUvAuthProtocol for PinUvAuthProtocolTwo {
         Ok(out)
     }
 
-    fn decrypt(&self, key: &[u8], ciphertext: &[u8]) -> Result<Vec<u8>, Error> {
+    fn decrypt(&self, key: &[u8], ciphertext: &[u8]) -> Result<SecretBytes, Error> {
         // Discard the first 32 bytes of key. (This selects the AES-key portion of the shared secret.)
         let key = &key[32..];
 
@@ -373,14 +478,14 @@ impl PinUvAuthProtocol for PinUvAuthProtocolTwo {
 
         // Return the AES-256-CBC decryption of ct using key and iv.
         let Ok(dec) = Aes256CbcDecryptor::new_from_slices(key, iv) else {
-            error!(?key, "Invalid key for AES-256 decryption");
+            error!({ len = key.len() }, "Invalid key for AES-256 decryption");
             return Err(Error::Ctap(CtapError::Other));
         };
         let Ok(plaintext) = dec.decrypt_padded_vec_mut::<NoPadding>(ciphertext) else {
             error!("Unpad error while decrypting");
             return Err(Error::Ctap(CtapError::Other));
         };
-        Ok(plaintext)
+        Ok(SecretBytes::from(plaintext))
     }
 
     fn authenticate(&self, key: &[u8], message: &[u8]) -> Vec<u8> {
@@ -394,11 +499,10 @@ impl PinUvAuthProtocol for PinUvAuthProtocolTwo {
 }
 
 /// hash(pin) -> LEFT(SHA-256(pin), 16)
-pub fn pin_hash(pin: &[u8]) -> Vec<u8> {
+pub fn pin_hash(pin: &[u8]) -> SecretBytes {
     let mut hasher = Sha256::default();
     hasher.update(pin);
-    let hashed = hasher.finalize().to_vec();
-    Vec::from(&hashed[..16])
+    SecretBytes::from(&hasher.finalize()[..16])
 }
 
 pub fn hmac_sha256(key: &[u8], message: &[u8]) -> Vec<u8> {
@@ -407,10 +511,36 @@ pub fn hmac_sha256(key: &[u8], message: &[u8]) -> Vec<u8> {
     hmac.finalize().into_bytes().to_vec()
 }
 
-pub fn hkdf_sha256(salt: &[u8], ikm: &[u8], info: &[u8]) -> Vec<u8> {
+pub fn hkdf_sha256(salt: &[u8], ikm: &[u8], info: &[u8]) -> SecretBytes {
     let hk = Hkdf::<Sha256>::new(Some(salt), &ikm);
-    let mut okm = [0u8; 32]; // fixed L = 32
+    let mut okm = SecretBytes::new(vec![0u8; 32]); // fixed L = 32
     hk.expand(info, &mut okm)
         .expect("32 is a valid length for Sha256 to output");
-    Vec::from(okm)
+    okm
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::pin::{PinPurpose, PinRequest};
+
+    #[test]
+    fn pin_length_counted_in_code_points() {
+        let mut request = PinRequest::new("Test device", PinPurpose::Entry);
+        request.min_length = 4;
+        assert!(!request.is_valid_length("123"));
+        assert!(request.is_valid_length("1234"));
+        // Three code points, but nine bytes once UTF-8 encoded.
+        assert!(!request.is_valid_length("ピン番"));
+        assert!(request.is_valid_length("ピン番号"));
+    }
+
+    #[test]
+    fn pin_length_limited() {
+        let mut request = PinRequest::new("Test device", PinPurpose::Entry);
+        assert!(request.is_valid_length(&"1".repeat(63)));
+        assert!(!request.is_valid_length(&"1".repeat(64)));
+        request.max_length = Some(8);
+        assert!(request.is_valid_length("12345678"));
+        assert!(!request.is_valid_length("123456789"));
+    }
 }
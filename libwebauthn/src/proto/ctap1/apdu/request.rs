@@ -15,17 +15,17 @@ const U2F_REGISTER: u8 = 0x01;
 const U2F_AUTHENTICATE: u8 = 0x02;
 const U2F_VERSION: u8 = 0x03;
 
-const _CONTROL_BYTE_CHECK_ONLY: u8 = 0x07;
+const CONTROL_BYTE_CHECK_ONLY: u8 = 0x07;
 const CONTROL_BYTE_ENFORCE_UP_AND_SIGN: u8 = 0x03;
 const CONTROL_BYTE_DONT_ENFORCE_UP_AND_SIGN: u8 = 0x08;
 
 #[derive(Debug)]
 pub struct ApduRequest {
-    ins: u8,
-    p1: u8,
-    p2: u8,
-    data: Option<Vec<u8>>,
-    response_max_length: Option<usize>,
+    pub ins: u8,
+    pub p1: u8,
+    pub p2: u8,
+    pub data: Option<Vec<u8>>,
+    pub response_max_length: Option<usize>,
 }
 
 impl ApduRequest {
@@ -142,7 +142,9 @@ impl From<&Ctap1VersionRequest> for ApduRequest {
 
 impl From<&Ctap1SignRequest> for ApduRequest {
     fn from(request: &Ctap1SignRequest) -> Self {
-        let p1 = if request.require_user_presence {
+        let p1 = if request.check_only {
+            CONTROL_BYTE_CHECK_ONLY
+        } else if request.require_user_presence {
             CONTROL_BYTE_ENFORCE_UP_AND_SIGN
         } else {
             CONTROL_BYTE_DONT_ENFORCE_UP_AND_SIGN
@@ -157,7 +159,10 @@ impl From<&Ctap1SignRequest> for ApduRequest {
 
 #[cfg(test)]
 mod tests {
+    use std::time::Duration;
+
     use crate::proto::ctap1::apdu::ApduRequest;
+    use crate::proto::ctap1::Ctap1SignRequest;
 
     #[test]
     fn apdu_raw_short_no_data() {
@@ -220,4 +225,12 @@ mod tests {
         );
         assert_eq!(&serialized[7..519], data.as_slice());
     }
+
+    #[test]
+    fn apdu_sign_check_only() {
+        let request =
+            Ctap1SignRequest::new_check_only(&[0; 32], &[0x42; 64], Duration::from_secs(1));
+        let apdu: ApduRequest = (&request).into();
+        assert_eq!(apdu.p1, 0x07);
+    }
 }
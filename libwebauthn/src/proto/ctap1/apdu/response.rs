@@ -31,6 +31,14 @@ impl ApduResponse {
         }
     }
 
+    /// Serializes the response back to its wire format: response data followed by SW1 and SW2.
+    pub fn raw(&self) -> Vec<u8> {
+        let mut raw = self.data.clone().unwrap_or_default();
+        raw.push(self.sw1);
+        raw.push(self.sw2);
+        raw
+    }
+
     pub fn status(&self) -> Result<ApduResponseStatus, IOError> {
         let mut cursor = IOCursor::new(vec![self.sw1, self.sw2]);
         let code = cursor.read_u16::<BigEndian>().unwrap() as u16;
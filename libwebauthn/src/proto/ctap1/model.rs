@@ -156,6 +156,10 @@ pub struct Ctap1SignRequest {
     pub key_handle: Vec<u8>,
     pub timeout: Duration,
     pub require_user_presence: bool,
+    /// Only checks whether the key handle is valid, without signing: see [`Ctap1::ctap1_check_only`].
+    ///
+    /// [`Ctap1::ctap1_check_only`]: crate::proto::ctap1::Ctap1::ctap1_check_only
+    pub check_only: bool,
 }
 
 impl Ctap1SignRequest {
@@ -176,6 +180,7 @@ impl Ctap1SignRequest {
             key_handle: Vec::from(key_handle),
             timeout,
             require_user_presence,
+            check_only: false,
         }
     }
 
@@ -191,6 +196,18 @@ impl Ctap1SignRequest {
             key_handle: Vec::from(key_handle),
             timeout,
             require_user_presence: false,
+            check_only: false,
+        }
+    }
+
+    pub fn new_check_only(app_id_hash: &[u8], key_handle: &[u8], timeout: Duration) -> Self {
+        Ctap1SignRequest {
+            app_id_hash: Vec::from(app_id_hash),
+            challenge: vec![0u8; 32],
+            key_handle: Vec::from(key_handle),
+            timeout,
+            require_user_presence: false,
+            check_only: true,
         }
     }
 }
@@ -26,6 +26,9 @@ pub trait Ctap1 {
         op: &Ctap1RegisterRequest,
     ) -> Result<Ctap1RegisterResponse, Error>;
     async fn ctap1_sign(&mut self, op: &Ctap1SignRequest) -> Result<Ctap1SignResponse, Error>;
+    /// Sends a check-only sign request, returning whether the key handle was issued by the
+    /// authenticator for the AppID. The user is not asked for their presence.
+    async fn ctap1_check_only(&mut self, op: &Ctap1SignRequest) -> Result<bool, Error>;
 }
 
 #[async_trait]
@@ -79,7 +82,7 @@ where
             return Err(Error::Ctap(CtapError::from(status)));
         }
 
-        let response: Ctap1RegisterResponse = apdu_response.try_into().unwrap();
+        let response: Ctap1RegisterResponse = apdu_response.try_into().or(Err(CtapError::Other))?;
         debug!("CTAP1 register response");
         trace!(?response);
         Ok(response)
@@ -98,11 +101,40 @@ where
             return Err(Error::Ctap(CtapError::from(status)));
         }
 
-        let response: Ctap1SignResponse = apdu_response.try_into().unwrap();
+        let response: Ctap1SignResponse = apdu_response.try_into().or(Err(CtapError::Other))?;
         debug!({ ?response.user_presence_verified }, "CTAP1 sign response received");
         trace!(?response);
         Ok(response)
     }
+
+    #[instrument(skip_all)]
+    async fn ctap1_check_only(&mut self, request: &Ctap1SignRequest) -> Result<bool, Error> {
+        trace!(?request);
+        if !request.check_only {
+            error!("Check-only requests must set the check-only control byte");
+            return Err(Error::Ctap(CtapError::InvalidParameter));
+        }
+
+        let apdu_request: ApduRequest = request.into();
+        self.apdu_send(&apdu_request, request.timeout).await?;
+        let apdu_response = self.apdu_recv(request.timeout).await?;
+        let status = apdu_response.status().or(Err(CtapError::Other))?;
+        // Valid key handles are reported as a failed test of user presence.
+        match status {
+            ApduResponseStatus::UserPresenceTestFailed => {
+                debug!("Key handle is valid");
+                Ok(true)
+            }
+            ApduResponseStatus::InvalidKeyHandle => {
+                debug!("Key handle is not valid");
+                Ok(false)
+            }
+            status => {
+                warn!(?status, "Unexpected status for check-only request");
+                Err(Error::Ctap(CtapError::from(status)))
+            }
+        }
+    }
 }
 
 async fn send_apdu_request_wait_uv<'c, C: Channel>(
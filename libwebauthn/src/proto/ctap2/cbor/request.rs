@@ -6,6 +6,7 @@ use std::io::Error as IOError;
 
 use crate::proto::ctap2::model::Ctap2ClientPinRequest;
 use crate::proto::ctap2::model::Ctap2CommandCode;
+use crate::proto::ctap2::model::Ctap2CredentialManagementRequest;
 use crate::proto::ctap2::model::Ctap2GetAssertionRequest;
 use crate::proto::ctap2::model::Ctap2MakeCredentialRequest;
 
@@ -62,3 +63,12 @@ impl From<&Ctap2ClientPinRequest> for CborRequest {
         }
     }
 }
+
+impl From<&Ctap2CredentialManagementRequest> for CborRequest {
+    fn from(request: &Ctap2CredentialManagementRequest) -> CborRequest {
+        CborRequest {
+            command: Ctap2CommandCode::AuthenticatorCredentialManagement,
+            encoded_data: to_vec(request).unwrap(),
+        }
+    }
+}
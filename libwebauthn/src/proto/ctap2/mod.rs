@@ -8,11 +8,14 @@ mod protocol;
 pub use model::Ctap2GetInfoResponse;
 pub use model::{
     ClientPinRequestPermissions, Ctap2AttestationStatement, Ctap2COSEAlgorithmIdentifier,
-    Ctap2ClientPinRequest, Ctap2CommandCode, Ctap2CredentialType, Ctap2MakeCredentialOptions,
-    Ctap2PinUvAuthProtocol, Ctap2PublicKeyCredentialDescriptor, Ctap2PublicKeyCredentialRpEntity,
+    Ctap2ClientPinRequest, Ctap2ClientPinResponse, Ctap2CommandCode,
+    Ctap2CredentialManagementCommand, Ctap2CredentialManagementParams,
+    Ctap2CredentialManagementRequest, Ctap2CredentialManagementResponse, Ctap2CredentialType,
+    Ctap2MakeCredentialOptions, Ctap2PinUvAuthProtocol, Ctap2PinUvAuthProtocolCommand,
+    Ctap2PublicKeyCredentialDescriptor, Ctap2PublicKeyCredentialRpEntity,
     Ctap2PublicKeyCredentialType, Ctap2PublicKeyCredentialUserEntity, Ctap2Transport,
     Ctap2UserVerifiableRequest, Ctap2UserVerificationOperation, FidoU2fAttestationStmt,
 };
-pub use model::{Ctap2GetAssertionRequest, Ctap2GetAssertionResponse};
+pub use model::{Ctap2GetAssertionOptions, Ctap2GetAssertionRequest, Ctap2GetAssertionResponse};
 pub use model::{Ctap2MakeCredentialRequest, Ctap2MakeCredentialResponse};
 pub use protocol::Ctap2;
@@ -15,6 +15,7 @@ use tracing::warn;
 use crate::ops::webauthn::GetAssertionRequest;
 use crate::ops::webauthn::MakeCredentialRequest;
 use crate::proto::ctap1::Ctap1Transport;
+use crate::secret::SecretBytes;
 use crate::transport::error::CtapError;
 
 // 32 (rpIdHash) + 1 (flags) + 4 (signCount) + 16 (aaguid)
@@ -28,6 +29,7 @@ pub enum Ctap2CommandCode {
     AuthenticatorGetInfo = 0x04,
     AuthenticatorClientPin = 0x06,
     AuthenticatorGetNextAssertion = 0x08,
+    AuthenticatorCredentialManagement = 0x0A,
     AuthenticatorSelection = 0x0B,
 }
 
@@ -232,7 +234,7 @@ impl Ctap2GetAssertionOptions {
     }
 }
 
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct PackedAttestationStmt {
     #[serde(rename = "alg")]
     pub algorithm: Ctap2COSEAlgorithmIdentifier,
@@ -244,7 +246,7 @@ pub struct PackedAttestationStmt {
     pub certificates: Vec<ByteBuf>,
 }
 
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct FidoU2fAttestationStmt {
     #[serde(rename = "alg")]
     pub algorithm: Ctap2COSEAlgorithmIdentifier,
@@ -256,7 +258,7 @@ pub struct FidoU2fAttestationStmt {
     pub certificates: Vec<ByteBuf>,
 }
 
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct TpmAttestationStmt {
     #[serde(rename = "ver")]
     pub version: String,
@@ -277,7 +279,7 @@ pub struct TpmAttestationStmt {
     pub public_area: ByteBuf,
 }
 
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(untagged)]
 pub enum Ctap2AttestationStatement {
     PackedOrAndroid(PackedAttestationStmt),
@@ -346,7 +348,7 @@ impl From<&MakeCredentialRequest> for Ctap2MakeCredentialRequest {
                 Some(op.extensions_cbor.clone())
             },
             options: Some(Ctap2MakeCredentialOptions {
-                require_resident_key: if op.require_resident_key {
+                require_resident_key: if op.resident_key.is_required() {
                     Some(true)
                 } else {
                     None
@@ -558,38 +560,47 @@ pub struct Ctap2GetInfoResponse {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub force_pin_change: Option<bool>,
 
+    /// minPINLength (0x0D)
     #[serde(default)]
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub max_pin_length: Option<u32>,
+    pub min_pin_length: Option<u32>,
 
+    /// firmwareVersion (0x0E)
     #[serde(default)]
     #[serde(skip_serializing_if = "Option::is_none")]
     pub firmware_version: Option<u32>,
 
+    /// maxCredBlobLength (0x0F)
     #[serde(default)]
     #[serde(skip_serializing_if = "Option::is_none")]
     pub max_cred_blob_length: Option<u32>,
 
+    /// maxRPIDsForSetMinPINLength (0x10)
     #[serde(default)]
     #[serde(skip_serializing_if = "Option::is_none")]
     pub max_rpids_for_setminpinlength: Option<u32>,
 
+    /// preferredPlatformUvAttempts (0x11)
     #[serde(default)]
     #[serde(skip_serializing_if = "Option::is_none")]
     pub preferred_platform_uv_attempts: Option<u32>,
 
+    /// uvModality (0x12)
     #[serde(default)]
     #[serde(skip_serializing_if = "Option::is_none")]
     pub uv_modality: Option<u32>,
 
+    /// certifications (0x13)
     #[serde(default)]
     #[serde(skip_serializing_if = "Option::is_none")]
     pub certifications: Option<HashMap<String, u32>>,
 
+    /// remainingDiscoverableCredentials (0x14)
     #[serde(default)]
     #[serde(skip_serializing_if = "Option::is_none")]
     pub remaining_discoverable_creds: Option<u32>,
 
+    /// vendorPrototypeConfigCommands (0x15)
     #[serde(default)]
     #[serde(skip_serializing_if = "Option::is_none")]
     pub vendor_proto_config_cmds: Option<Vec<u32>>,
@@ -616,6 +627,21 @@ impl Ctap2GetInfoResponse {
         self.versions.iter().any(|v| v == "FIDO_2_1")
     }
 
+    pub fn supports_credential_management(&self) -> bool {
+        self.option_enabled("credMgmt")
+    }
+
+    /// Whether a discoverable credential can be created: the authenticator supports them, and
+    /// does not report its storage as full.
+    pub fn can_store_discoverable_credential(&self) -> bool {
+        self.option_enabled("rk") && self.remaining_discoverable_creds != Some(0)
+    }
+
+    /// Whether a persistent pinUvAuthToken can be obtained with the pcmr permission.
+    pub fn supports_persistent_credential_management(&self) -> bool {
+        self.option_enabled("perCredMgmtRO")
+    }
+
     /// Implements check for "Protected by some form of User Verification":
     ///   Either or both clientPin or built-in user verification methods are supported and enabled.
     ///   I.e., in the authenticatorGetInfo response the pinUvAuthToken option ID is present and set to true,
@@ -635,20 +661,32 @@ impl Ctap2GetInfoResponse {
                 debug!("Deprecated FIDO 2.0 behaviour: populating 'uv' flag");
                 return Ctap2UserVerificationOperation::None;
             }
-        } else {
-            // !uv
-            if self.option_enabled("pinUvAuthToken") {
-                assert!(self.option_enabled("clientPin"));
-                debug!("getPinUvAuthTokenUsingPinWithPermissions");
-                return Ctap2UserVerificationOperation::GetPinUvAuthTokenUsingPinWithPermissions;
-            } else {
-                // !pinUvAuthToken
-                assert!(self.option_enabled("clientPin"));
-                debug!("getPinToken");
-                return Ctap2UserVerificationOperation::GetPinToken;
+        }
+        // !uv
+        match self.pin_operation() {
+            Some(operation) => operation,
+            None => {
+                warn!("Neither built-in user verification nor clientPin are enabled");
+                Ctap2UserVerificationOperation::None
             }
         }
     }
+
+    /// The operation to obtain a token using the PIN, if one is set. This is also the fallback for
+    /// authenticators which support both built-in user verification and clientPin, once built-in
+    /// user verification is blocked.
+    pub fn pin_operation(&self) -> Option<Ctap2UserVerificationOperation> {
+        if !self.option_enabled("clientPin") {
+            return None;
+        }
+        if self.option_enabled("pinUvAuthToken") {
+            debug!("getPinUvAuthTokenUsingPinWithPermissions");
+            Some(Ctap2UserVerificationOperation::GetPinUvAuthTokenUsingPinWithPermissions)
+        } else {
+            debug!("getPinToken");
+            Some(Ctap2UserVerificationOperation::GetPinToken)
+        }
+    }
 }
 
 #[derive(Debug, Clone, SerializeIndexed)]
@@ -671,11 +709,11 @@ pub struct Ctap2ClientPinRequest {
 
     /// newPinEnc (0x05)
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub new_pin_encrypted: Option<ByteBuf>,
+    pub new_pin_encrypted: Option<SecretBytes>,
 
     /// pinHashEnc (0x06)
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub pin_hash_encrypted: Option<ByteBuf>,
+    pub pin_hash_encrypted: Option<SecretBytes>,
 
     #[serde(skip_serializing)]
     pub unused_07: (),
@@ -719,7 +757,7 @@ impl Ctap2ClientPinRequest {
             key_agreement: Some(public_key),
             uv_auth_param: None,
             new_pin_encrypted: None,
-            pin_hash_encrypted: Some(ByteBuf::from(pin_hash_enc)),
+            pin_hash_encrypted: Some(SecretBytes::from(pin_hash_enc)),
             unused_07: (),
             unused_08: (),
             permissions: None,
@@ -742,12 +780,27 @@ impl Ctap2ClientPinRequest {
         }
     }
 
+    pub fn new_get_uv_retries() -> Self {
+        Self {
+            protocol: None,
+            command: Ctap2PinUvAuthProtocolCommand::GetUvRetries,
+            key_agreement: None,
+            uv_auth_param: None,
+            new_pin_encrypted: None,
+            pin_hash_encrypted: None,
+            unused_07: (),
+            unused_08: (),
+            permissions: None,
+            permissions_rpid: None,
+        }
+    }
+
     pub fn new_get_pin_token_with_perm(
         protocol: Ctap2PinUvAuthProtocol,
         public_key: PublicKey,
         pin_hash_enc: &[u8],
         permissions: ClientPinRequestPermissions,
-        permissions_rpid: &str,
+        permissions_rpid: Option<&str>,
     ) -> Self {
         Self {
             protocol: Some(protocol),
@@ -755,11 +808,11 @@ impl Ctap2ClientPinRequest {
             key_agreement: Some(public_key),
             uv_auth_param: None,
             new_pin_encrypted: None,
-            pin_hash_encrypted: Some(ByteBuf::from(pin_hash_enc)),
+            pin_hash_encrypted: Some(SecretBytes::from(pin_hash_enc)),
             unused_07: (),
             unused_08: (),
             permissions: Some(permissions.bits()),
-            permissions_rpid: Some(permissions_rpid.to_owned()),
+            permissions_rpid: permissions_rpid.map(str::to_owned),
         }
     }
 
@@ -767,7 +820,7 @@ impl Ctap2ClientPinRequest {
         protocol: Ctap2PinUvAuthProtocol,
         public_key: PublicKey,
         permissions: ClientPinRequestPermissions,
-        permissions_rpid: &str,
+        permissions_rpid: Option<&str>,
     ) -> Self {
         Self {
             protocol: Some(protocol),
@@ -779,7 +832,7 @@ impl Ctap2ClientPinRequest {
             unused_07: (),
             unused_08: (),
             permissions: Some(permissions.bits()),
-            permissions_rpid: Some(permissions_rpid.to_owned()),
+            permissions_rpid: permissions_rpid.map(str::to_owned),
         }
     }
 }
@@ -792,6 +845,9 @@ bitflags! {
         const BIO_ENROLLMENT = 0x08;
         const LARGE_BLOB_WRITE = 0x10;
         const AUTHENTICATOR_CONFIGURATION = 0x20;
+        /// pcmr: read-only credential management with a persistent token (CTAP 2.2). It can not
+        /// be combined with any other permission.
+        const PERSISTENT_CREDENTIAL_MANAGEMENT_READ_ONLY = 0x40;
     }
 }
 
@@ -826,7 +882,7 @@ pub struct Ctap2ClientPinResponse {
     /// pinUvAuthToken (0x02)
     #[serde(default)]
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub pin_uv_auth_token: Option<ByteBuf>,
+    pub pin_uv_auth_token: Option<SecretBytes>,
 
     /// pinRetries (0x03)
     #[serde(default)]
@@ -843,3 +899,212 @@ pub struct Ctap2ClientPinResponse {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub uv_retries: Option<u32>,
 }
+
+#[repr(u32)]
+#[derive(Debug, Clone, Copy, FromPrimitive, PartialEq, Serialize_repr, Deserialize_repr)]
+pub enum Ctap2CredentialManagementCommand {
+    GetCredsMetadata = 0x01,
+    EnumerateRPsBegin = 0x02,
+    EnumerateRPsGetNextRP = 0x03,
+    EnumerateCredentialsBegin = 0x04,
+    EnumerateCredentialsGetNextCredential = 0x05,
+    DeleteCredential = 0x06,
+    UpdateUserInformation = 0x07,
+}
+
+#[derive(Debug, Clone, SerializeIndexed)]
+#[serde_indexed(offset = 1)]
+pub struct Ctap2CredentialManagementParams {
+    /// rpIDHash (0x01)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rp_id_hash: Option<ByteBuf>,
+}
+
+// https://fidoalliance.org/specs/fido-v2.1-ps-20210615/fido-client-to-authenticator-protocol-v2.1-ps-20210615.html#authenticatorCredentialManagement
+#[derive(Debug, Clone, SerializeIndexed)]
+#[serde_indexed(offset = 1)]
+pub struct Ctap2CredentialManagementRequest {
+    /// subCommand (0x01)
+    pub command: Ctap2CredentialManagementCommand,
+
+    /// subCommandParams (0x02)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub params: Option<Ctap2CredentialManagementParams>,
+
+    /// pinUvAuthProtocol (0x03)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub protocol: Option<Ctap2PinUvAuthProtocol>,
+
+    /// pinUvAuthParam (0x04)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub uv_auth_param: Option<ByteBuf>,
+}
+
+impl Ctap2CredentialManagementRequest {
+    pub fn new_enumerate_credentials_begin(rp_id_hash: &[u8]) -> Self {
+        Self {
+            command: Ctap2CredentialManagementCommand::EnumerateCredentialsBegin,
+            params: Some(Ctap2CredentialManagementParams {
+                rp_id_hash: Some(ByteBuf::from(rp_id_hash)),
+            }),
+            protocol: None,
+            uv_auth_param: None,
+        }
+    }
+
+    pub fn new_enumerate_credentials_get_next() -> Self {
+        Self {
+            command: Ctap2CredentialManagementCommand::EnumerateCredentialsGetNextCredential,
+            params: None,
+            protocol: None,
+            uv_auth_param: None,
+        }
+    }
+
+    /// The message authenticated by pinUvAuthParam: subCommand, followed by subCommandParams.
+    pub fn uv_auth_message(&self) -> Vec<u8> {
+        let mut message = vec![self.command as u8];
+        if let Some(params) = &self.params {
+            message.extend(serde_cbor::to_vec(params).unwrap());
+        }
+        message
+    }
+
+    pub fn set_uv_auth(&mut self, proto: Ctap2PinUvAuthProtocol, param: &[u8]) {
+        self.protocol = Some(proto);
+        self.uv_auth_param = Some(ByteBuf::from(param));
+    }
+}
+
+#[derive(Debug, Clone, DeserializeIndexed)]
+#[serde_indexed(offset = 1)]
+pub struct Ctap2CredentialManagementResponse {
+    /// existingResidentCredentialsCount (0x01)
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub existing_resident_credentials_count: Option<u32>,
+
+    /// maxPossibleRemainingResidentCredentialsCount (0x02)
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_remaining_resident_credentials_count: Option<u32>,
+
+    /// rp (0x03), left undecoded: authenticators may leave out its name.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rp: Option<serde_cbor::Value>,
+
+    /// rpIDHash (0x04)
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rp_id_hash: Option<ByteBuf>,
+
+    /// totalRPs (0x05)
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub total_rps: Option<u32>,
+
+    /// user (0x06)
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user: Option<Ctap2PublicKeyCredentialUserEntity>,
+
+    /// credentialID (0x07)
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub credential_id: Option<Ctap2PublicKeyCredentialDescriptor>,
+
+    /// publicKey (0x08), left undecoded as it is not used.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub public_key: Option<serde_cbor::Value>,
+
+    /// totalCredentials (0x09)
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub total_credentials: Option<u32>,
+
+    /// credProtect (0x0A)
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cred_protect: Option<u32>,
+
+    /// largeBlobKey (0x0B)
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub large_blob_key: Option<SecretBytes>,
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use serde_cbor::Value;
+
+    use super::{Ctap2GetInfoResponse, Ctap2UserVerificationOperation};
+
+    fn get_info(options: &[(&str, bool)]) -> Ctap2GetInfoResponse {
+        let options = options
+            .iter()
+            .map(|(name, enabled)| (Value::Text(name.to_string()), Value::Bool(*enabled)))
+            .collect();
+        let mut info = BTreeMap::new();
+        info.insert(
+            Value::Integer(0x01),
+            Value::Array(vec![Value::Text("FIDO_2_1".to_owned())]),
+        );
+        info.insert(Value::Integer(0x03), Value::Bytes(vec![0; 16]));
+        info.insert(Value::Integer(0x04), Value::Map(options));
+        serde_cbor::from_slice(&serde_cbor::to_vec(&Value::Map(info)).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn discoverable_credential_storage() {
+        let mut info = get_info(&[("rk", true)]);
+        assert!(info.can_store_discoverable_credential());
+        info.remaining_discoverable_creds = Some(0);
+        assert!(!info.can_store_discoverable_credential());
+        assert!(!get_info(&[("rk", false)]).can_store_discoverable_credential());
+    }
+
+    #[test]
+    fn uv_operation_with_uv_and_client_pin() {
+        let info = get_info(&[("uv", true), ("clientPin", true), ("pinUvAuthToken", true)]);
+        assert!(matches!(
+            info.uv_operation(),
+            Ctap2UserVerificationOperation::GetPinUvAuthTokenUsingUvWithPermissions
+        ));
+        assert!(matches!(
+            info.pin_operation(),
+            Some(Ctap2UserVerificationOperation::GetPinUvAuthTokenUsingPinWithPermissions)
+        ));
+    }
+
+    #[test]
+    fn uv_operation_with_uv_only() {
+        let info = get_info(&[("uv", true), ("clientPin", false), ("pinUvAuthToken", true)]);
+        assert!(matches!(
+            info.uv_operation(),
+            Ctap2UserVerificationOperation::GetPinUvAuthTokenUsingUvWithPermissions
+        ));
+        assert!(info.pin_operation().is_none());
+    }
+
+    #[test]
+    fn uv_operation_with_legacy_client_pin() {
+        let info = get_info(&[("clientPin", true)]);
+        assert!(matches!(
+            info.uv_operation(),
+            Ctap2UserVerificationOperation::GetPinToken
+        ));
+    }
+
+    #[test]
+    fn uv_operation_without_user_verification() {
+        let info = get_info(&[("pinUvAuthToken", true)]);
+        assert!(matches!(
+            info.uv_operation(),
+            Ctap2UserVerificationOperation::None
+        ));
+    }
+}
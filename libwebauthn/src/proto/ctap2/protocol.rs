@@ -1,6 +1,7 @@
 use std::time::Duration;
 
 use async_trait::async_trait;
+use serde::de::DeserializeOwned;
 use serde_cbor::from_slice;
 use tracing::{debug, instrument, trace, warn};
 
@@ -11,8 +12,9 @@ use crate::transport::Channel;
 
 use super::model::Ctap2ClientPinResponse;
 use super::{
-    Ctap2ClientPinRequest, Ctap2GetAssertionRequest, Ctap2GetAssertionResponse,
-    Ctap2GetInfoResponse, Ctap2MakeCredentialRequest, Ctap2MakeCredentialResponse,
+    Ctap2ClientPinRequest, Ctap2CredentialManagementRequest, Ctap2CredentialManagementResponse,
+    Ctap2GetAssertionRequest, Ctap2GetAssertionResponse, Ctap2GetInfoResponse,
+    Ctap2MakeCredentialRequest, Ctap2MakeCredentialResponse,
 };
 
 const TIMEOUT_GET_INFO: Duration = Duration::from_millis(250);
@@ -40,6 +42,11 @@ pub trait Ctap2 {
         timeout: Duration,
     ) -> Result<Ctap2GetAssertionResponse, Error>;
     async fn ctap2_selection(&mut self, timeout: Duration) -> Result<(), Error>;
+    async fn ctap2_credential_management(
+        &mut self,
+        request: &Ctap2CredentialManagementRequest,
+        timeout: Duration,
+    ) -> Result<Ctap2CredentialManagementResponse, Error>;
 }
 
 #[async_trait]
@@ -56,7 +63,7 @@ where
             CtapError::Ok => (),
             error => return Err(Error::Ctap(error)),
         };
-        let ctap_response: Ctap2GetInfoResponse = from_slice(&cbor_response.data.unwrap()).unwrap();
+        let ctap_response: Ctap2GetInfoResponse = parse_response(cbor_response.data)?;
         debug!("CTAP2 GetInfo successful");
         trace!(?ctap_response);
         Ok(ctap_response)
@@ -75,8 +82,7 @@ where
             CtapError::Ok => (),
             error => return Err(Error::Ctap(error)),
         };
-        let ctap_response: Ctap2MakeCredentialResponse =
-            from_slice(&cbor_response.data.unwrap()).unwrap();
+        let ctap_response: Ctap2MakeCredentialResponse = parse_response(cbor_response.data)?;
         debug!("CTAP2 MakeCredential successful");
         trace!(?ctap_response);
         Ok(ctap_response)
@@ -91,8 +97,11 @@ where
         trace!(?request);
         self.cbor_send(&request.into(), TIMEOUT_GET_INFO).await?;
         let cbor_response = self.cbor_recv(TIMEOUT_GET_INFO).await?;
-        let ctap_response: Ctap2GetAssertionResponse =
-            from_slice(&cbor_response.data.unwrap()).unwrap();
+        match cbor_response.status_code {
+            CtapError::Ok => (),
+            error => return Err(Error::Ctap(error)),
+        };
+        let ctap_response: Ctap2GetAssertionResponse = parse_response(cbor_response.data)?;
         debug!("CTAP2 GetAssertion successful");
         trace!(?ctap_response);
         Ok(ctap_response)
@@ -108,8 +117,11 @@ where
         let cbor_request = CborRequest::new(Ctap2CommandCode::AuthenticatorGetNextAssertion);
         self.cbor_send(&cbor_request, TIMEOUT_GET_INFO).await?;
         let cbor_response = self.cbor_recv(TIMEOUT_GET_INFO).await?;
-        let ctap_response: Ctap2GetAssertionResponse =
-            from_slice(&cbor_response.data.unwrap()).unwrap();
+        match cbor_response.status_code {
+            CtapError::Ok => (),
+            error => return Err(Error::Ctap(error)),
+        };
+        let ctap_response: Ctap2GetAssertionResponse = parse_response(cbor_response.data)?;
         debug!("CTAP2 GetNextAssertion successful");
         trace!(?ctap_response);
         Ok(ctap_response)
@@ -148,10 +160,39 @@ where
             CtapError::Ok => (),
             error => return Err(Error::Ctap(error)),
         };
-        let ctap_response: Ctap2ClientPinResponse =
-            from_slice(&cbor_response.data.unwrap()).unwrap();
+        let ctap_response: Ctap2ClientPinResponse = parse_response(cbor_response.data)?;
         debug!("CTAP2 ClientPin successful");
         trace!(?ctap_response);
         Ok(ctap_response)
     }
+
+    #[instrument(skip_all, fields(command = ?request.command))]
+    async fn ctap2_credential_management(
+        &mut self,
+        request: &Ctap2CredentialManagementRequest,
+        timeout: Duration,
+    ) -> Result<Ctap2CredentialManagementResponse, Error> {
+        trace!(?request);
+        self.cbor_send(&request.into(), timeout).await?;
+        let cbor_response = self.cbor_recv(timeout).await?;
+        match cbor_response.status_code {
+            CtapError::Ok => (),
+            error => return Err(Error::Ctap(error)),
+        };
+        let ctap_response: Ctap2CredentialManagementResponse = parse_response(cbor_response.data)?;
+        debug!("CTAP2 CredentialManagement successful");
+        trace!(?ctap_response);
+        Ok(ctap_response)
+    }
+}
+
+fn parse_response<T: DeserializeOwned>(data: Option<Vec<u8>>) -> Result<T, Error> {
+    let Some(data) = data else {
+        warn!("Response is missing its CBOR payload");
+        return Err(Error::Ctap(CtapError::InvalidCbor));
+    };
+    from_slice(&data).map_err(|err| {
+        warn!(%err, "Failed to parse CBOR response");
+        Error::Ctap(CtapError::InvalidCbor)
+    })
 }
@@ -1,10 +1,13 @@
 use num_enum::{IntoPrimitive, TryFromPrimitive};
+use serde_repr::{Deserialize_repr, Serialize_repr};
 
 use crate::proto::ctap1::apdu::ApduResponseStatus;
 
 // https://fidoalliance.org/specs/fido-v2.0-ps-20190130/fido-client-to-authenticator-protocol-v2.0-ps-20190130.html#error-responses
 
-#[derive(Debug, IntoPrimitive, TryFromPrimitive, Copy, Clone, PartialEq)]
+#[derive(
+    Debug, IntoPrimitive, TryFromPrimitive, Copy, Clone, PartialEq, Serialize_repr, Deserialize_repr,
+)]
 #[repr(u8)]
 pub enum CtapError {
     Ok = 0x00,                   // CTAP1_ERR_SUCCESS, CTAP2_OK
@@ -48,6 +51,7 @@ pub enum CtapError {
     RequestTooLarge = 0x39,      // CTAP2_ERR_REQUEST_TOO_LARGE
     ActionTimeout = 0x3A,        // CTAP2_ERR_ACTION_TIMEOUT
     UserPresenceRequired = 0x3B, // CTAP2_ERR_UP_REQUIRED
+    UVBlocked = 0x3C,            // CTAP2_ERR_UV_BLOCKED
     UVInvalid = 0x3F,            // CTAP2_ERR_UV_INVALID
     Other = 0x7F,                // CTAP1_ERR_OTHER
 }
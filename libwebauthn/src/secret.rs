@@ -0,0 +1,150 @@
+//! Wrappers for secret material, such as PINs, shared secrets and pinUvAuthTokens. Their contents
+//! are zeroed from memory once dropped, and never printed, so that they can not reach logs.
+
+use std::fmt::{Debug, Display, Formatter};
+use std::ops::{Deref, DerefMut};
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde_bytes::ByteBuf;
+use zeroize::Zeroizing;
+
+const REDACTED: &str = "<redacted>";
+
+/// Secret bytes, such as a shared secret or a pinUvAuthToken.
+#[derive(Clone, PartialEq, Eq, Default)]
+pub struct SecretBytes(Zeroizing<Vec<u8>>);
+
+impl SecretBytes {
+    pub fn new(bytes: Vec<u8>) -> Self {
+        Self(Zeroizing::new(bytes))
+    }
+}
+
+impl From<Vec<u8>> for SecretBytes {
+    fn from(bytes: Vec<u8>) -> Self {
+        Self::new(bytes)
+    }
+}
+
+impl From<&[u8]> for SecretBytes {
+    fn from(bytes: &[u8]) -> Self {
+        Self::new(bytes.to_vec())
+    }
+}
+
+impl Deref for SecretBytes {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl DerefMut for SecretBytes {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        &mut self.0
+    }
+}
+
+impl AsRef<[u8]> for SecretBytes {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl Debug for SecretBytes {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "SecretBytes({})", REDACTED)
+    }
+}
+
+impl Display for SecretBytes {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(REDACTED)
+    }
+}
+
+impl Serialize for SecretBytes {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_bytes(&self.0)
+    }
+}
+
+impl<'de> Deserialize<'de> for SecretBytes {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let bytes = ByteBuf::deserialize(deserializer)?;
+        Ok(Self::new(bytes.into_vec()))
+    }
+}
+
+/// A secret string, such as a PIN.
+#[derive(Clone, PartialEq, Eq, Default)]
+pub struct SecretString(Zeroizing<String>);
+
+impl SecretString {
+    pub fn new(string: String) -> Self {
+        Self(Zeroizing::new(string))
+    }
+}
+
+impl From<String> for SecretString {
+    fn from(string: String) -> Self {
+        Self::new(string)
+    }
+}
+
+impl From<&str> for SecretString {
+    fn from(string: &str) -> Self {
+        Self::new(string.to_owned())
+    }
+}
+
+impl Deref for SecretString {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl Debug for SecretString {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "SecretString({})", REDACTED)
+    }
+}
+
+impl Display for SecretString {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(REDACTED)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::secret::{SecretBytes, SecretString};
+
+    #[test]
+    fn secrets_are_redacted() {
+        let bytes = SecretBytes::from(vec![0x42; 32]);
+        assert_eq!(format!("{:?}", bytes), "SecretBytes(<redacted>)");
+        assert_eq!(format!("{}", bytes), "<redacted>");
+        assert_eq!(&bytes[..], &[0x42; 32]);
+
+        let string = SecretString::from("1234");
+        assert_eq!(format!("{:?}", string), "SecretString(<redacted>)");
+        assert_eq!(format!("{}", string), "<redacted>");
+        assert_eq!(&*string, "1234");
+    }
+
+    #[test]
+    fn secret_bytes_serialized_as_bytes() {
+        let bytes = SecretBytes::from(vec![1, 2, 3]);
+        let cbor = serde_cbor::to_vec(&bytes).unwrap();
+        assert_eq!(
+            cbor,
+            serde_cbor::to_vec(&serde_bytes::ByteBuf::from([1, 2, 3])).unwrap()
+        );
+        let parsed: SecretBytes = serde_cbor::from_slice(&cbor).unwrap();
+        assert_eq!(parsed, bytes);
+    }
+}
@@ -0,0 +1,1356 @@
+//! Authenticator sessions keep the state negotiated with an authenticator across operations, so
+//! that consecutive requests neither repeat round trips nor prompt the user for their PIN again.
+
+use std::fmt::{Display, Formatter};
+use std::time::{Duration, Instant};
+
+use cosey::PublicKey;
+use serde_bytes::ByteBuf;
+use sha2::{Digest, Sha256};
+use tracing::{debug, error, info, instrument, warn};
+
+use crate::ops::webauthn::UserVerificationRequirement;
+use crate::pin::{
+    pin_hash, Pin, PinProvider, PinPurpose, PinRequest, PinUvAuthProtocol, PinUvAuthProtocolOne,
+    PinUvAuthProtocolTwo,
+};
+use crate::proto::ctap2::{
+    ClientPinRequestPermissions, Ctap2, Ctap2ClientPinRequest, Ctap2ClientPinResponse,
+    Ctap2CredentialManagementRequest, Ctap2CredentialManagementResponse, Ctap2GetAssertionOptions,
+    Ctap2GetAssertionRequest, Ctap2GetInfoResponse, Ctap2PinUvAuthProtocol,
+    Ctap2PinUvAuthProtocolCommand, Ctap2PublicKeyCredentialDescriptor, Ctap2UserVerifiableRequest,
+    Ctap2UserVerificationOperation,
+};
+use crate::secret::SecretBytes;
+use crate::transport::error::{CtapError, Error};
+use crate::transport::Channel;
+
+// Authenticators accept a pinUvAuthToken for at least 30 seconds after it was last used, and
+// may expire it at any point afterwards.
+const TOKEN_USAGE_PERIOD: Duration = Duration::from_secs(30);
+
+// Built-in user verification attempts per token, unless the authenticator prefers otherwise.
+const DEFAULT_PLATFORM_UV_ATTEMPTS: u32 = 1;
+
+struct SharedSecret {
+    protocol: Box<dyn PinUvAuthProtocol>,
+    public_key: PublicKey,
+    secret: SecretBytes,
+}
+
+/// How the user is verified for a request, as decided from the relying party's requirement
+/// and the authenticator's capabilities.
+enum UserVerificationMethod {
+    /// No user verification is performed.
+    Skipped,
+    /// The deprecated uv option is set on the request.
+    UvOption,
+    /// A pinUvAuthToken is obtained, and the request carries a pinUvAuthParam.
+    Token,
+}
+
+struct CachedToken {
+    token: SecretBytes,
+    permissions: ClientPinRequestPermissions,
+    rpid: Option<String>,
+    last_used: Instant,
+    uses: u32,
+}
+
+impl CachedToken {
+    fn new(
+        token: SecretBytes,
+        permissions: ClientPinRequestPermissions,
+        rpid: Option<&str>,
+    ) -> Self {
+        Self {
+            token,
+            permissions,
+            rpid: rpid.map(str::to_owned),
+            last_used: Instant::now(),
+            uses: 0,
+        }
+    }
+
+    fn is_persistent(&self) -> bool {
+        self.permissions == ClientPinRequestPermissions::PERSISTENT_CREDENTIAL_MANAGEMENT_READ_ONLY
+    }
+
+    fn covers(
+        &self,
+        permissions: ClientPinRequestPermissions,
+        rpid: Option<&str>,
+        now: Instant,
+    ) -> bool {
+        // Persistent tokens survive power cycles, and only expire when the PIN changes.
+        if !self.is_persistent() && now.duration_since(self.last_used) >= TOKEN_USAGE_PERIOD {
+            return false;
+        }
+        if !self.permissions.contains(permissions) {
+            return false;
+        }
+        match (&self.rpid, rpid) {
+            (None, _) => true,
+            (Some(cached), Some(rpid)) => cached == rpid,
+            (Some(_), None) => false,
+        }
+    }
+}
+
+/// A session with an authenticator, on top of a [`Channel`].
+///
+/// The session caches the authenticator's getInfo response, the selected PIN/UV auth protocol
+/// and shared secret, and the last pinUvAuthToken with its permissions and RP ID. The token is
+/// reused for as long as it is valid and has the permissions needed by the next request.
+///
+/// Requests sent through [`Self::channel_mut`] bypass the session: [`Self::invalidate`] must be
+/// called after changing the PIN or the authenticator's configuration through it.
+pub struct AuthenticatorSession<C: Channel> {
+    pub(crate) channel: C,
+    info: Option<Ctap2GetInfoResponse>,
+    shared_secret: Option<SharedSecret>,
+    token: Option<CachedToken>,
+    // Kept apart from `token`, so that WebAuthn operations do not evict it.
+    persistent_token: Option<CachedToken>,
+}
+
+impl<C: Channel> AuthenticatorSession<C> {
+    pub fn new(channel: C) -> Self {
+        Self {
+            channel,
+            info: None,
+            shared_secret: None,
+            token: None,
+            persistent_token: None,
+        }
+    }
+
+    pub fn channel(&self) -> &C {
+        &self.channel
+    }
+
+    pub fn channel_mut(&mut self) -> &mut C {
+        &mut self.channel
+    }
+
+    pub fn into_channel(self) -> C {
+        self.channel
+    }
+
+    /// The authenticator's getInfo response, requested once per session.
+    pub async fn get_info(&mut self) -> Result<Ctap2GetInfoResponse, Error> {
+        if let Some(info) = &self.info {
+            debug!("Using cached getInfo response");
+            return Ok(info.clone());
+        }
+        let info = self.channel.ctap2_get_info().await?;
+        self.info = Some(info.clone());
+        Ok(info)
+    }
+
+    /// Drops all cached state, so that it is requested again from the authenticator.
+    pub fn invalidate(&mut self) {
+        debug!("Invalidating session state");
+        self.info = None;
+        self.forget_shared_secret();
+    }
+
+    fn forget_shared_secret(&mut self) {
+        // Tokens are always used with the protocol which was used to obtain them.
+        self.shared_secret = None;
+        self.token = None;
+        self.persistent_token = None;
+    }
+
+    /// Sends a ClientPin request. Setting or changing the PIN invalidates the session's tokens,
+    /// as well as getInfo, which reports whether a PIN is set.
+    #[instrument(skip_all, fields(command = ?request.command))]
+    pub async fn client_pin(
+        &mut self,
+        request: &Ctap2ClientPinRequest,
+        timeout: Duration,
+    ) -> Result<Ctap2ClientPinResponse, Error> {
+        let result = self.channel.ctap2_client_pin(request, timeout).await;
+        match (&request.command, &result) {
+            (Ctap2PinUvAuthProtocolCommand::SetPin, Ok(_))
+            | (Ctap2PinUvAuthProtocolCommand::ChangePin, Ok(_)) => self.invalidate(),
+            // The authenticator generates a new key agreement key after a PIN mismatch.
+            (_, Err(_)) => self.forget_shared_secret(),
+            _ => (),
+        }
+        result
+    }
+
+    /// Computes pinUvAuthParam for the given message, using a pinUvAuthToken with the given
+    /// permissions and RP ID. The cached token is used if it covers them, otherwise a new token is
+    /// obtained, prompting the user for their PIN if needed.
+    ///
+    /// The pcmr permission yields a persistent token, which is cached separately.
+    #[instrument(skip_all, fields(?permissions, ?rpid))]
+    pub async fn pin_uv_auth_param(
+        &mut self,
+        permissions: ClientPinRequestPermissions,
+        rpid: Option<&str>,
+        message: &[u8],
+        pin_provider: &Box<dyn PinProvider>,
+        timeout: Duration,
+    ) -> Result<(Ctap2PinUvAuthProtocol, Vec<u8>), Error> {
+        let info = self.get_info().await?;
+        if !info.is_uv_protected() {
+            warn!("Authenticator is not protected by user verification");
+            return Err(Error::Ctap(CtapError::PINNotSet));
+        }
+        let uv_operation = info.uv_operation();
+        if let Ctap2UserVerificationOperation::None = uv_operation {
+            warn!("Authenticator does not support pinUvAuthTokens");
+            return Err(Error::Ctap(CtapError::UnsupportedOption));
+        }
+
+        let pcmr = ClientPinRequestPermissions::PERSISTENT_CREDENTIAL_MANAGEMENT_READ_ONLY;
+        let persistent = permissions.contains(pcmr);
+        if persistent && permissions != pcmr {
+            error!("The pcmr permission can not be combined with other permissions");
+            return Err(Error::Ctap(CtapError::InvalidParameter));
+        }
+        if persistent && !info.supports_persistent_credential_management() {
+            warn!("Authenticator does not support persistent pinUvAuthTokens");
+            return Err(Error::Ctap(CtapError::UnsupportedOption));
+        }
+
+        let now = Instant::now();
+        let cached = match persistent {
+            true => &self.persistent_token,
+            false => &self.token,
+        };
+        if cached
+            .as_ref()
+            .is_some_and(|token| token.covers(permissions, rpid, now))
+        {
+            debug!("Reusing cached pinUvAuthToken");
+        } else {
+            let token = self
+                .obtain_token(
+                    &info,
+                    uv_operation,
+                    permissions,
+                    rpid,
+                    pin_provider,
+                    timeout,
+                )
+                .await?;
+            match persistent {
+                true => self.persistent_token = Some(token),
+                false => self.token = Some(token),
+            };
+        }
+
+        let token = match persistent {
+            true => self.persistent_token.as_mut(),
+            false => self.token.as_mut(),
+        }
+        .expect("A token was cached above");
+        token.last_used = now;
+        token.uses += 1;
+        let shared_secret = self
+            .shared_secret
+            .as_ref()
+            .expect("Tokens are cached along with their shared secret");
+        let protocol = &shared_secret.protocol;
+        Ok((
+            protocol.version(),
+            protocol.authenticate(&token.token, message),
+        ))
+    }
+
+    /// Handles an error returned by the authenticator for a request authenticated by the session.
+    /// Returns true if the request was rejected because of a cached token, in which case it can be
+    /// retried: a new token will be obtained.
+    pub(crate) fn token_rejected(&mut self, err: &Error) -> bool {
+        cached_token_rejected(&mut self.token, err)
+    }
+
+    /// Enumerates the discoverable credentials of a relying party, through credential management.
+    /// A persistent read-only token is used if the authenticator supports it, so that the PIN is
+    /// only asked for once.
+    #[instrument(skip_all, fields(%rp_id))]
+    pub async fn enumerate_credentials(
+        &mut self,
+        rp_id: &str,
+        pin_provider: &Box<dyn PinProvider>,
+        timeout: Duration,
+    ) -> Result<Vec<Ctap2CredentialManagementResponse>, Error> {
+        let info = self.get_info().await?;
+        if !info.supports_credential_management() {
+            warn!("Authenticator does not support credential management");
+            return Err(Error::Ctap(CtapError::UnsupportedOption));
+        }
+        let persistent = info.supports_persistent_credential_management();
+        let permissions = match persistent {
+            true => ClientPinRequestPermissions::PERSISTENT_CREDENTIAL_MANAGEMENT_READ_ONLY,
+            false => ClientPinRequestPermissions::CREDENTIAL_MANAGEMENT,
+        };
+        let rp_id_hash = Sha256::digest(rp_id.as_bytes());
+
+        let first = loop {
+            let mut request =
+                Ctap2CredentialManagementRequest::new_enumerate_credentials_begin(&rp_id_hash);
+            let (protocol, param) = self
+                .pin_uv_auth_param(
+                    permissions,
+                    None,
+                    &request.uv_auth_message(),
+                    pin_provider,
+                    timeout,
+                )
+                .await?;
+            request.set_uv_auth(protocol, &param);
+            let result = self
+                .channel
+                .ctap2_credential_management(&request, timeout)
+                .await;
+            let cached = match persistent {
+                true => &mut self.persistent_token,
+                false => &mut self.token,
+            };
+            match result {
+                Err(err) if cached_token_rejected(cached, &err) => continue,
+                Err(Error::Ctap(CtapError::NoCredentials)) => {
+                    debug!("No discoverable credentials for the relying party");
+                    return Ok(vec![]);
+                }
+                result => break result?,
+            }
+        };
+
+        let count = first.total_credentials.unwrap_or(1);
+        let mut credentials = vec![first];
+        for i in 1..count {
+            debug!({ i }, "Fetching additional credential");
+            let request = Ctap2CredentialManagementRequest::new_enumerate_credentials_get_next();
+            credentials.push(
+                self.channel
+                    .ctap2_credential_management(&request, timeout)
+                    .await?,
+            );
+        }
+        Ok(credentials)
+    }
+
+    /// Fits a list of credentials in what the authenticator accepts in a single request: drops
+    /// the credential IDs longer than maxCredentialIdLength, and lists longer than
+    /// maxCredentialCountInList are narrowed down to the credentials which the authenticator
+    /// holds, see [`Self::preflight_credentials`]. Only the credentials found are then sent with
+    /// the user-present request. When that request will carry a pinUvAuthParam anyway, the token
+    /// is obtained up front so that the pre-flights also see credentials protected by credProtect.
+    #[instrument(skip_all, fields(rp_id = %request.permissions_rpid(), count = credentials.len()))]
+    pub(crate) async fn fit_credentials<R>(
+        &mut self,
+        request: &R,
+        credentials: &[Ctap2PublicKeyCredentialDescriptor],
+        user_verification: UserVerificationRequirement,
+        pin_provider: &Box<dyn PinProvider>,
+        timeout: Duration,
+    ) -> Result<Vec<Ctap2PublicKeyCredentialDescriptor>, Error>
+    where
+        R: Ctap2UserVerifiableRequest,
+    {
+        let (credentials, batch_size) = self.supported_credentials(credentials).await?;
+        let batch_size = match batch_size {
+            Some(batch_size) if credentials.len() > batch_size => batch_size,
+            _ => return Ok(credentials),
+        };
+        let pin_uv_auth = match self.user_verification_method(user_verification).await? {
+            UserVerificationMethod::Token => Some(
+                self.pin_uv_auth_param(
+                    request.permissions() | ClientPinRequestPermissions::GET_ASSERTION,
+                    Some(request.permissions_rpid()),
+                    request.client_data_hash(),
+                    pin_provider,
+                    timeout,
+                )
+                .await?,
+            ),
+            _ => None,
+        };
+        let mut found = self
+            .preflight_credentials(
+                request.permissions_rpid(),
+                request.client_data_hash(),
+                &credentials,
+                pin_uv_auth,
+                timeout,
+            )
+            .await?;
+        // One credential is found per batch, so there may still be too many of them.
+        found.truncate(batch_size);
+        Ok(found)
+    }
+
+    /// Finds which of the credentials the authenticator holds for the relying party, with silent
+    /// (up=false) GetAssertion requests over batches of at most maxCredentialCountInList, or with
+    /// a single request when the authenticator reports no limit. Authenticators only report one
+    /// credential per request, so at most one is found per batch.
+    #[instrument(skip_all, fields(%rp_id, count = credentials.len()))]
+    pub(crate) async fn preflight_credentials(
+        &mut self,
+        rp_id: &str,
+        client_data_hash: &[u8],
+        credentials: &[Ctap2PublicKeyCredentialDescriptor],
+        pin_uv_auth: Option<(Ctap2PinUvAuthProtocol, Vec<u8>)>,
+        timeout: Duration,
+    ) -> Result<Vec<Ctap2PublicKeyCredentialDescriptor>, Error> {
+        let (credentials, batch_size) = self.supported_credentials(credentials).await?;
+        let batch_size = batch_size.unwrap_or(credentials.len()).max(1);
+        let mut found = vec![];
+        for batch in credentials.chunks(batch_size) {
+            let mut request = Ctap2GetAssertionRequest {
+                relying_party_id: rp_id.to_owned(),
+                client_data_hash: ByteBuf::from(client_data_hash),
+                allow: batch.to_vec(),
+                extensions_cbor: None,
+                options: Some(Ctap2GetAssertionOptions {
+                    require_user_presence: false,
+                    require_user_verification: false,
+                }),
+                pin_auth_param: None,
+                pin_auth_proto: None,
+            };
+            if let Some((protocol, param)) = &pin_uv_auth {
+                request.set_uv_auth(*protocol, param);
+            }
+            let assertion = match self.channel.ctap2_get_assertion(&request, timeout).await {
+                Ok(assertion) => assertion,
+                Err(Error::Ctap(CtapError::NoCredentials)) => {
+                    debug!(size = batch.len(), "No credentials found in batch");
+                    continue;
+                }
+                Err(err) => return Err(err),
+            };
+            // The credential may be omitted when the allowList held a single one.
+            let credential = match assertion.credential_id {
+                Some(credential) => batch.iter().find(|candidate| candidate.id == credential.id),
+                None => batch.first(),
+            };
+            match credential {
+                Some(credential) => found.push(credential.clone()),
+                None => warn!("Authenticator returned a credential which was not in the batch"),
+            }
+        }
+        debug!(found = found.len(), "Pre-flight complete");
+        Ok(found)
+    }
+
+    /// Drops the credentials longer than the authenticator supports, returning the remaining ones
+    /// along with how many fit in a single request, if the authenticator reports a limit.
+    async fn supported_credentials(
+        &mut self,
+        credentials: &[Ctap2PublicKeyCredentialDescriptor],
+    ) -> Result<(Vec<Ctap2PublicKeyCredentialDescriptor>, Option<usize>), Error> {
+        let info = self.get_info().await?;
+        let batch_size = info.max_credential_count.map(|count| count.max(1) as usize);
+        let supported = credentials
+            .iter()
+            .filter(|credential| match info.max_credential_id_length {
+                Some(max) if credential.id.len() > max as usize => {
+                    debug!(
+                        length = credential.id.len(),
+                        "Dropping credential longer than the authenticator supports"
+                    );
+                    false
+                }
+                _ => true,
+            })
+            .cloned()
+            .collect();
+        Ok((supported, batch_size))
+    }
+
+    #[instrument(skip_all)]
+    pub(crate) async fn user_verification<R>(
+        &mut self,
+        user_verification: UserVerificationRequirement,
+        ctap2_request: &mut R,
+        pin_provider: &Box<dyn PinProvider>,
+        timeout: Duration,
+    ) -> Result<(), Error>
+    where
+        R: Ctap2UserVerifiableRequest,
+    {
+        match self.user_verification_method(user_verification).await? {
+            UserVerificationMethod::Skipped => Ok(()),
+            UserVerificationMethod::UvOption => {
+                ctap2_request.ensure_uv_set();
+                Ok(())
+            }
+            UserVerificationMethod::Token => {
+                // The platform obtains a pinUvAuthToken from the authenticator, with the mc (and
+                // likely also with the ga) permission (see "pre-flight"), and creates the
+                // pinUvAuthParam parameter by calling authenticate(pinUvAuthToken, clientDataHash).
+                let (protocol, uv_auth_param) = self
+                    .pin_uv_auth_param(
+                        ctap2_request.permissions(),
+                        Some(ctap2_request.permissions_rpid()),
+                        ctap2_request.client_data_hash(),
+                        pin_provider,
+                        timeout,
+                    )
+                    .await?;
+
+                // Sets the pinUvAuthProtocol parameter to the value as selected when it obtained the shared secret.
+                ctap2_request.set_uv_auth(protocol, uv_auth_param.as_slice());
+                Ok(())
+            }
+        }
+    }
+
+    async fn user_verification_method(
+        &mut self,
+        user_verification: UserVerificationRequirement,
+    ) -> Result<UserVerificationMethod, Error> {
+        let get_info_response = self.get_info().await?;
+
+        let rp_uv_preferred = user_verification.is_preferred();
+        let dev_uv_protected = get_info_response.is_uv_protected();
+        let uv = rp_uv_preferred || dev_uv_protected;
+        debug!(%rp_uv_preferred, %dev_uv_protected, %uv, "Checking if user verification is required");
+
+        if !uv {
+            debug!("User verification not requested by either RP nor authenticator. Ignoring.");
+            return Ok(UserVerificationMethod::Skipped);
+        }
+
+        if !dev_uv_protected && user_verification.is_required() {
+            error!(
+                "Request requires user verification, but device user verification is not available."
+            );
+            return Err(Error::Ctap(CtapError::PINNotSet));
+        };
+
+        if !dev_uv_protected && user_verification.is_preferred() {
+            warn!("User verification is preferred, but not device user verification is not available. Ignoring.");
+            return Ok(UserVerificationMethod::Skipped);
+        }
+
+        if let Ctap2UserVerificationOperation::None = get_info_response.uv_operation() {
+            debug!("No client operation. Setting deprecated request options.uv flag to true.");
+            return Ok(UserVerificationMethod::UvOption);
+        }
+
+        Ok(UserVerificationMethod::Token)
+    }
+
+    async fn obtain_token(
+        &mut self,
+        get_info_response: &Ctap2GetInfoResponse,
+        uv_operation: Ctap2UserVerificationOperation,
+        permissions: ClientPinRequestPermissions,
+        rpid: Option<&str>,
+        pin_provider: &Box<dyn PinProvider>,
+        timeout: Duration,
+    ) -> Result<CachedToken, Error> {
+        let pin_operation = match uv_operation {
+            Ctap2UserVerificationOperation::None => unreachable!(),
+            Ctap2UserVerificationOperation::GetPinUvAuthTokenUsingUvWithPermissions => {
+                match self
+                    .obtain_uv_token(get_info_response, permissions, rpid, pin_provider, timeout)
+                    .await
+                {
+                    Err(err @ Error::Ctap(CtapError::UVBlocked | CtapError::UVInvalid)) => {
+                        let Some(pin_operation) = get_info_response.pin_operation() else {
+                            warn!("Built-in user verification failed, and no PIN is set");
+                            return Err(err);
+                        };
+                        info!("Built-in user verification failed, falling back to PIN");
+                        pin_operation
+                    }
+                    result => return result,
+                }
+            }
+            pin_operation => pin_operation,
+        };
+
+        let mut previous_attempt_failed = false;
+        loop {
+            // For operations that include a PIN, we want to fetch one before obtaining a shared
+            // secret. This prevents the shared secret from expiring whilst we wait for the user to
+            // enter a PIN.
+            let pin = self
+                .obtain_pin(
+                    get_info_response,
+                    previous_attempt_failed,
+                    pin_provider,
+                    timeout,
+                )
+                .await?;
+
+            // In preparation for obtaining pinUvAuthToken, the platform obtains a shared secret.
+            // After a PIN mismatch, the authenticator requires a new one.
+            self.ensure_shared_secret(get_info_response, timeout)
+                .await?;
+            let shared_secret = self.shared_secret.as_ref().unwrap();
+            let uv_proto = &shared_secret.protocol;
+            let pin_hash_enc =
+                uv_proto.encrypt(&shared_secret.secret, &pin_hash(pin.as_bytes()))?;
+
+            let (token_request, permissions, rpid) = match pin_operation {
+                // Tokens obtained with getPinToken implicitly have the mc and ga permissions.
+                Ctap2UserVerificationOperation::GetPinToken => (
+                    Ctap2ClientPinRequest::new_get_pin_token(
+                        uv_proto.version(),
+                        shared_secret.public_key.clone(),
+                        &pin_hash_enc,
+                    ),
+                    ClientPinRequestPermissions::MAKE_CREDENTIAL
+                        | ClientPinRequestPermissions::GET_ASSERTION,
+                    None,
+                ),
+                Ctap2UserVerificationOperation::GetPinUvAuthTokenUsingPinWithPermissions => (
+                    Ctap2ClientPinRequest::new_get_pin_token_with_perm(
+                        uv_proto.version(),
+                        shared_secret.public_key.clone(),
+                        &pin_hash_enc,
+                        permissions,
+                        rpid,
+                    ),
+                    permissions,
+                    rpid,
+                ),
+                _ => unreachable!(),
+            };
+
+            match self.client_pin(&token_request, timeout).await {
+                Ok(token_response) => return self.decrypt_token(token_response, permissions, rpid),
+                Err(Error::Ctap(CtapError::PINInvalid)) => {
+                    warn!("PIN rejected by the authenticator, asking again");
+                    previous_attempt_failed = true;
+                }
+                Err(err @ Error::Ctap(CtapError::PINAuthBlocked)) => {
+                    warn!(
+                        "Too many consecutive PIN mismatches, authenticator must be power cycled"
+                    );
+                    return Err(err);
+                }
+                Err(err @ Error::Ctap(CtapError::PINBlocked)) => {
+                    warn!("PIN is blocked, authenticator must be reset");
+                    return Err(err);
+                }
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    /// Obtains a token using built-in user verification, trying up to
+    /// preferredPlatformUvAttempts times. Fails with UVBlocked once the authenticator reports no
+    /// retries left, or with UVInvalid once all attempts failed.
+    async fn obtain_uv_token(
+        &mut self,
+        get_info_response: &Ctap2GetInfoResponse,
+        permissions: ClientPinRequestPermissions,
+        rpid: Option<&str>,
+        pin_provider: &Box<dyn PinProvider>,
+        timeout: Duration,
+    ) -> Result<CachedToken, Error> {
+        let max_attempts = get_info_response
+            .preferred_platform_uv_attempts
+            .filter(|&attempts| attempts > 0)
+            .unwrap_or(DEFAULT_PLATFORM_UV_ATTEMPTS);
+        let mut uv_retries = self.uv_retries(timeout).await?;
+        let mut attempts = 0;
+        loop {
+            if uv_retries == Some(0) {
+                warn!("Built-in user verification is blocked");
+                return Err(Error::Ctap(CtapError::UVBlocked));
+            }
+            if attempts == max_attempts {
+                warn!(
+                    { attempts },
+                    "Built-in user verification failed too many times"
+                );
+                return Err(Error::Ctap(CtapError::UVInvalid));
+            }
+            attempts += 1;
+
+            self.ensure_shared_secret(get_info_response, timeout)
+                .await?;
+            let shared_secret = self.shared_secret.as_ref().unwrap();
+            let token_request = Ctap2ClientPinRequest::new_get_uv_token_with_perm(
+                shared_secret.protocol.version(),
+                shared_secret.public_key.clone(),
+                permissions,
+                rpid,
+            );
+            debug!({ attempts, ?uv_retries }, "Requesting built-in user verification");
+            match self.client_pin(&token_request, timeout).await {
+                Ok(token_response) => return self.decrypt_token(token_response, permissions, rpid),
+                Err(Error::Ctap(CtapError::UVInvalid)) => {
+                    uv_retries = self.uv_retries(timeout).await?;
+                    info!(?uv_retries, "Built-in user verification failed");
+                    pin_provider.uv_failed(uv_retries).await;
+                }
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    fn decrypt_token(
+        &self,
+        token_response: Ctap2ClientPinResponse,
+        permissions: ClientPinRequestPermissions,
+        rpid: Option<&str>,
+    ) -> Result<CachedToken, Error> {
+        let Some(encrypted_pin_uv_auth_token) = token_response.pin_uv_auth_token else {
+            error!("Client PIN response did not include a PIN UV auth token");
+            return Err(Error::Ctap(CtapError::Other));
+        };
+
+        let shared_secret = self.shared_secret.as_ref().unwrap();
+        let token = shared_secret
+            .protocol
+            .decrypt(&shared_secret.secret, &encrypted_pin_uv_auth_token)?;
+        debug!(?permissions, ?rpid, "Obtained pinUvAuthToken");
+        Ok(CachedToken::new(token, permissions, rpid))
+    }
+
+    async fn ensure_shared_secret(
+        &mut self,
+        get_info_response: &Ctap2GetInfoResponse,
+        timeout: Duration,
+    ) -> Result<(), Error> {
+        if self.shared_secret.is_some() {
+            debug!("Reusing shared secret");
+            return Ok(());
+        }
+        self.shared_secret = Some(
+            self.obtain_shared_secret(get_info_response, timeout)
+                .await?,
+        );
+        Ok(())
+    }
+
+    async fn uv_retries(&mut self, timeout: Duration) -> Result<Option<u32>, Error> {
+        let uv_retries = self
+            .channel
+            .ctap2_client_pin(&Ctap2ClientPinRequest::new_get_uv_retries(), timeout)
+            .await?
+            .uv_retries;
+        Ok(uv_retries)
+    }
+
+    async fn obtain_shared_secret(
+        &mut self,
+        get_info_response: &Ctap2GetInfoResponse,
+        timeout: Duration,
+    ) -> Result<SharedSecret, Error> {
+        let protocol = select_uv_proto(get_info_response)?;
+        let client_pin_request = Ctap2ClientPinRequest::new_get_key_agreement(protocol.version());
+        let client_pin_response = self.client_pin(&client_pin_request, timeout).await?;
+        let Some(public_key) = client_pin_response.key_agreement else {
+            error!("Missing public key from Client PIN response");
+            return Err(Error::Ctap(CtapError::Other));
+        };
+        let (public_key, secret) = protocol.encapsulate(&public_key)?;
+        Ok(SharedSecret {
+            protocol,
+            public_key,
+            secret,
+        })
+    }
+
+    /// Asks the user for their PIN, until one of acceptable length is provided. PINs that are too
+    /// short or too long are never sent to the authenticator, so no retry is spent on them.
+    async fn obtain_pin(
+        &mut self,
+        get_info_response: &Ctap2GetInfoResponse,
+        previous_attempt_failed: bool,
+        pin_provider: &Box<dyn PinProvider>,
+        timeout: Duration,
+    ) -> Result<Pin, Error> {
+        let retries_response = self
+            .channel
+            .ctap2_client_pin(&Ctap2ClientPinRequest::new_get_pin_retries(), timeout)
+            .await?;
+        if retries_response.pin_retries == Some(0) {
+            warn!("PIN is blocked, authenticator must be reset");
+            return Err(Error::Ctap(CtapError::PINBlocked));
+        }
+        let mut request = PinRequest::new(&self.channel.to_string(), PinPurpose::Entry);
+        request.attempts_left = retries_response.pin_retries;
+        request.previous_attempt_failed = previous_attempt_failed;
+        request.power_cycle_required = retries_response.power_cycle_state.unwrap_or(false);
+        if let Some(min_pin_length) = get_info_response.min_pin_length {
+            request.min_length = min_pin_length as usize;
+        }
+
+        loop {
+            let Some(pin) = pin_provider.provide_pin(&request).await else {
+                info!("User cancelled operation: no PIN provided");
+                return Err(Error::Ctap(CtapError::PINRequired));
+            };
+            if request.is_valid_length(&pin) {
+                return Ok(pin);
+            }
+            warn!(
+                { min_length = request.min_length },
+                "PIN rejected before use, invalid length"
+            );
+            request.previous_attempt_failed = true;
+        }
+    }
+}
+
+impl<C: Channel> Display for AuthenticatorSession<C> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        self.channel.fmt(f)
+    }
+}
+
+fn select_uv_proto(
+    get_info_response: &Ctap2GetInfoResponse,
+) -> Result<Box<dyn PinUvAuthProtocol>, Error> {
+    for &protocol in get_info_response.pin_auth_protos.iter().flatten() {
+        match protocol {
+            1 => return Ok(Box::new(PinUvAuthProtocolOne::new())),
+            2 => return Ok(Box::new(PinUvAuthProtocolTwo::new())),
+            _ => (),
+        };
+    }
+
+    error!(?get_info_response.pin_auth_protos, "No supported PIN/UV auth protocols found");
+    Err(Error::Ctap(CtapError::Other))
+}
+
+/// Drops a cached token which the authenticator rejected, returning whether the request can be
+/// retried with a new one. Fresh tokens are not retried, as they would be rejected again.
+fn cached_token_rejected(cached: &mut Option<CachedToken>, err: &Error) -> bool {
+    match err {
+        Error::Ctap(CtapError::PINAuthInvalid) | Error::Ctap(CtapError::PINTokenExpired) => {
+            let Some(token) = cached.take() else {
+                return false;
+            };
+            if token.uses > 1 {
+                info!("Cached pinUvAuthToken was rejected, a new one is needed");
+            }
+            token.uses > 1
+        }
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::{BTreeMap, VecDeque};
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, Instant};
+
+    use async_trait::async_trait;
+    use cosey::{EcdhEsHkdf256PublicKey, PublicKey};
+    use p256::{EncodedPoint, SecretKey};
+    use rand::rngs::OsRng;
+    use serde_bytes::ByteBuf;
+    use serde_cbor::Value;
+
+    use super::{AuthenticatorSession, CachedToken, TOKEN_USAGE_PERIOD};
+    use crate::authenticator::store::CredentialStore;
+    use crate::authenticator::{SoftwareAuthenticator, SoftwareChannel};
+    use crate::ops::webauthn::{GetAssertionRequest, MakeCredentialRequest};
+    use crate::pin::{Pin, PinProvider, PinRequest, StaticPinProvider};
+    use crate::proto::ctap2::cbor::CborRequest;
+    use crate::proto::ctap2::{
+        ClientPinRequestPermissions, Ctap2ClientPinRequest, Ctap2CommandCode,
+        Ctap2GetAssertionRequest, Ctap2PinUvAuthProtocol, Ctap2UserVerifiableRequest,
+    };
+    use crate::secret::SecretBytes;
+    use crate::transport::device::SupportedProtocols;
+    use crate::transport::error::{CtapError, Error};
+    use crate::transport::transcript::{
+        RecordingChannel, RecordingOptions, ReplayChannel, Transcript, TranscriptEntry,
+        TranscriptEvent,
+    };
+    use crate::webauthn::WebAuthn;
+
+    const MC: ClientPinRequestPermissions = ClientPinRequestPermissions::MAKE_CREDENTIAL;
+    const GA: ClientPinRequestPermissions = ClientPinRequestPermissions::GET_ASSERTION;
+    const PCMR: ClientPinRequestPermissions =
+        ClientPinRequestPermissions::PERSISTENT_CREDENTIAL_MANAGEMENT_READ_ONLY;
+
+    #[test]
+    fn token_covers_permissions_and_rpid() {
+        let token = CachedToken::new(SecretBytes::default(), MC | GA, Some("example.org"));
+        let now = Instant::now();
+        assert!(token.covers(GA, Some("example.org"), now));
+        assert!(token.covers(MC | GA, Some("example.org"), now));
+        assert!(!token.covers(GA, Some("example.com"), now));
+        assert!(!token.covers(GA, None, now));
+        assert!(!token.covers(
+            ClientPinRequestPermissions::CREDENTIAL_MANAGEMENT,
+            None,
+            now
+        ));
+
+        let token = CachedToken::new(SecretBytes::default(), MC | GA, None);
+        assert!(token.covers(GA, Some("example.com"), now));
+    }
+
+    #[test]
+    fn token_expires_unless_persistent() {
+        let later = Instant::now() + TOKEN_USAGE_PERIOD + Duration::from_secs(1);
+        let token = CachedToken::new(SecretBytes::default(), GA, None);
+        assert!(!token.covers(GA, None, later));
+
+        let token = CachedToken::new(SecretBytes::default(), PCMR, None);
+        assert!(token.covers(PCMR, None, later));
+        assert!(!token.covers(GA, None, later));
+    }
+
+    fn get_info_count(session: &AuthenticatorSession<RecordingChannel<SoftwareChannel>>) -> usize {
+        let get_info: u8 = Ctap2CommandCode::AuthenticatorGetInfo.into();
+        session
+            .channel()
+            .transcript()
+            .entries
+            .iter()
+            .filter(|entry| {
+                matches!(entry.event, TranscriptEvent::CborRequest { command, .. } if command == get_info)
+            })
+            .count()
+    }
+
+    #[tokio::test]
+    async fn get_info_cached_across_operations() {
+        let channel =
+            SoftwareChannel::new(SoftwareAuthenticator::new(CredentialStore::temporary()));
+        let mut session =
+            AuthenticatorSession::new(RecordingChannel::new(channel, RecordingOptions::default()));
+        let pin_provider: Box<dyn PinProvider> = Box::new(StaticPinProvider::new("1234"));
+
+        let request = MakeCredentialRequest::dummy();
+        session
+            .webauthn_make_credential(&request, &pin_provider)
+            .await
+            .unwrap();
+        session
+            .webauthn_make_credential(&request, &pin_provider)
+            .await
+            .unwrap();
+        assert_eq!(get_info_count(&session), 1);
+
+        session.invalidate();
+        session
+            .webauthn_make_credential(&request, &pin_provider)
+            .await
+            .unwrap();
+        assert_eq!(get_info_count(&session), 2);
+    }
+
+    const TIMEOUT: Duration = Duration::from_secs(1);
+    const RP_ID: &str = "example.org";
+    const PROTOCOL: Ctap2PinUvAuthProtocol = Ctap2PinUvAuthProtocol::One;
+    // Length of pinHashEnc and pinUvAuthParam with PIN/UV auth protocol one.
+    const PROTOCOL_ONE_BLOCK: usize = 16;
+
+    /// Hand-written transcripts, for authenticator features which the software authenticator
+    /// does not implement.
+    struct TranscriptBuilder {
+        transcript: Transcript,
+    }
+
+    impl TranscriptBuilder {
+        fn new(options: &[&str], extra: &[(i128, Value)]) -> Self {
+            let mut info = BTreeMap::new();
+            info.insert(Value::Integer(0x01), Value::Array(vec![text("FIDO_2_1")]));
+            info.insert(Value::Integer(0x03), Value::Bytes(vec![0; 16]));
+            info.insert(
+                Value::Integer(0x04),
+                Value::Map(
+                    options
+                        .iter()
+                        .map(|option| (text(option), Value::Bool(true)))
+                        .collect(),
+                ),
+            );
+            info.insert(Value::Integer(0x06), Value::Array(vec![Value::Integer(1)]));
+            info.extend(
+                extra
+                    .iter()
+                    .cloned()
+                    .map(|(key, value)| (Value::Integer(key), value)),
+            );
+            let mut transcript = Transcript::new("Scripted authenticator", true);
+            transcript.protocols = Some(SupportedProtocols::fido2_only());
+            Self { transcript }.exchange(
+                CborRequest::new(Ctap2CommandCode::AuthenticatorGetInfo),
+                Ok(Value::Map(info)),
+            )
+        }
+
+        fn exchange(mut self, request: CborRequest, response: Result<Value, CtapError>) -> Self {
+            let response = match response {
+                Ok(data) => TranscriptEvent::CborResponse {
+                    status: CtapError::Ok.into(),
+                    data: Some(ByteBuf::from(serde_cbor::to_vec(&data).unwrap())),
+                },
+                Err(err) => TranscriptEvent::CborResponse {
+                    status: err.into(),
+                    data: None,
+                },
+            };
+            let request = TranscriptEvent::CborRequest {
+                command: request.command as u8,
+                data: ByteBuf::from(request.encoded_data),
+            };
+            for event in [request, response] {
+                self.transcript.entries.push(TranscriptEntry {
+                    elapsed_ms: 0,
+                    event,
+                });
+            }
+            self
+        }
+
+        fn client_pin(
+            self,
+            request: Ctap2ClientPinRequest,
+            response: Result<Value, CtapError>,
+        ) -> Self {
+            self.exchange((&request).into(), response)
+        }
+
+        fn pin_retries(self, retries: i128) -> Self {
+            self.client_pin(
+                Ctap2ClientPinRequest::new_get_pin_retries(),
+                Ok(response(0x03, Value::Integer(retries))),
+            )
+        }
+
+        fn key_agreement(self) -> Self {
+            let key = serde_cbor::value::to_value(authenticator_key()).unwrap();
+            self.client_pin(
+                Ctap2ClientPinRequest::new_get_key_agreement(PROTOCOL),
+                Ok(response(0x01, key)),
+            )
+        }
+
+        fn pin_token(self, result: Result<(), CtapError>) -> Self {
+            let request = Ctap2ClientPinRequest::new_get_pin_token(
+                PROTOCOL,
+                authenticator_key(),
+                &[0; PROTOCOL_ONE_BLOCK],
+            );
+            self.client_pin(request, result.map(|_| token()))
+        }
+
+        fn pin_token_with_permissions(self, result: Result<(), CtapError>) -> Self {
+            let request = Ctap2ClientPinRequest::new_get_pin_token_with_perm(
+                PROTOCOL,
+                authenticator_key(),
+                &[0; PROTOCOL_ONE_BLOCK],
+                ClientPinRequestPermissions::GET_ASSERTION,
+                Some(RP_ID),
+            );
+            self.client_pin(request, result.map(|_| token()))
+        }
+
+        fn uv_retries(self, retries: i128) -> Self {
+            self.client_pin(
+                Ctap2ClientPinRequest::new_get_uv_retries(),
+                Ok(response(0x05, Value::Integer(retries))),
+            )
+        }
+
+        fn uv_token(self, result: Result<(), CtapError>) -> Self {
+            let request = Ctap2ClientPinRequest::new_get_uv_token_with_perm(
+                PROTOCOL,
+                authenticator_key(),
+                ClientPinRequestPermissions::GET_ASSERTION,
+                Some(RP_ID),
+            );
+            self.client_pin(request, result.map(|_| token()))
+        }
+
+        /// A GetAssertion request for [`GetAssertionRequest::dummy`], with a pinUvAuthParam.
+        fn get_assertion(self, result: Result<(), CtapError>) -> Self {
+            let mut request: Ctap2GetAssertionRequest = (&GetAssertionRequest::dummy()).into();
+            request.set_uv_auth(PROTOCOL, &[0; PROTOCOL_ONE_BLOCK]);
+            let mut assertion = BTreeMap::new();
+            assertion.insert(Value::Integer(0x02), Value::Bytes(vec![0; 37]));
+            assertion.insert(Value::Integer(0x03), Value::Bytes(vec![0; 70]));
+            self.exchange((&request).into(), result.map(|_| Value::Map(assertion)))
+        }
+
+        fn session(self) -> AuthenticatorSession<ReplayChannel> {
+            AuthenticatorSession::new(ReplayChannel::new(self.transcript))
+        }
+    }
+
+    fn text(value: &str) -> Value {
+        Value::Text(value.to_owned())
+    }
+
+    fn response(key: i128, value: Value) -> Value {
+        Value::Map(BTreeMap::from([(Value::Integer(key), value)]))
+    }
+
+    fn token() -> Value {
+        response(0x02, Value::Bytes(vec![0x42; 32]))
+    }
+
+    /// A key agreement key, which is ignored when replayed requests are compared.
+    fn authenticator_key() -> PublicKey {
+        let point = EncodedPoint::from(SecretKey::random(&mut OsRng).public_key());
+        let x: heapless::Vec<u8, 32> = heapless::Vec::from_slice(point.x().unwrap()).unwrap();
+        let y: heapless::Vec<u8, 32> = heapless::Vec::from_slice(point.y().unwrap()).unwrap();
+        PublicKey::EcdhEsHkdf256Key(EcdhEsHkdf256PublicKey {
+            x: x.into(),
+            y: y.into(),
+        })
+    }
+
+    #[derive(Default)]
+    struct PinProviderLog {
+        requests: Mutex<Vec<PinRequest>>,
+        uv_failures: Mutex<Vec<Option<u32>>>,
+    }
+
+    /// Provides the given PINs in turn, recording what it was asked for.
+    struct ScriptedPinProvider {
+        pins: Mutex<VecDeque<&'static str>>,
+        log: Arc<PinProviderLog>,
+    }
+
+    #[async_trait]
+    impl PinProvider for ScriptedPinProvider {
+        async fn provide_pin(&self, request: &PinRequest) -> Option<Pin> {
+            self.log.requests.lock().unwrap().push(request.clone());
+            self.pins.lock().unwrap().pop_front().map(Pin::from)
+        }
+
+        async fn uv_failed(&self, attempts_left: Option<u32>) {
+            self.log.uv_failures.lock().unwrap().push(attempts_left);
+        }
+    }
+
+    fn scripted_pin_provider(pins: &[&'static str]) -> (Box<dyn PinProvider>, Arc<PinProviderLog>) {
+        let log = Arc::new(PinProviderLog::default());
+        let pin_provider = ScriptedPinProvider {
+            pins: Mutex::new(pins.iter().copied().collect()),
+            log: log.clone(),
+        };
+        (Box::new(pin_provider), log)
+    }
+
+    async fn obtain_param(
+        session: &mut AuthenticatorSession<ReplayChannel>,
+        pin_provider: &Box<dyn PinProvider>,
+    ) -> Result<(Ctap2PinUvAuthProtocol, Vec<u8>), Error> {
+        session
+            .pin_uv_auth_param(
+                ClientPinRequestPermissions::GET_ASSERTION,
+                Some(RP_ID),
+                &[0; 32],
+                pin_provider,
+                TIMEOUT,
+            )
+            .await
+    }
+
+    #[tokio::test]
+    async fn pin_asked_again_after_mismatch() {
+        let mut session = TranscriptBuilder::new(&["clientPin"], &[])
+            .pin_retries(8)
+            .key_agreement()
+            .pin_token(Err(CtapError::PINInvalid))
+            .pin_retries(7)
+            // The authenticator regenerates its key agreement key after a mismatch.
+            .key_agreement()
+            .pin_token(Ok(()))
+            .session();
+        let (pin_provider, log) = scripted_pin_provider(&["0000", "1234"]);
+
+        let (protocol, param) = obtain_param(&mut session, &pin_provider).await.unwrap();
+        assert_eq!(protocol, PROTOCOL);
+        assert_eq!(param.len(), PROTOCOL_ONE_BLOCK);
+        session.channel().assert_finished();
+
+        let requests = log.requests.lock().unwrap();
+        assert_eq!(requests.len(), 2);
+        assert_eq!(requests[0].attempts_left, Some(8));
+        assert!(!requests[0].previous_attempt_failed);
+        assert_eq!(requests[1].attempts_left, Some(7));
+        assert!(requests[1].previous_attempt_failed);
+    }
+
+    #[tokio::test]
+    async fn pin_auth_blocked_after_mismatches() {
+        let mut session = TranscriptBuilder::new(&["clientPin"], &[])
+            .pin_retries(8)
+            .key_agreement()
+            .pin_token(Err(CtapError::PINInvalid))
+            .pin_retries(7)
+            .key_agreement()
+            .pin_token(Err(CtapError::PINAuthBlocked))
+            .session();
+        let (pin_provider, log) = scripted_pin_provider(&["0000", "1111", "1234"]);
+
+        let result = obtain_param(&mut session, &pin_provider).await;
+        assert!(matches!(
+            result,
+            Err(Error::Ctap(CtapError::PINAuthBlocked))
+        ));
+        session.channel().assert_finished();
+        assert_eq!(log.requests.lock().unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn pin_blocked_returned() {
+        // No retries left: the user is not even asked for their PIN.
+        let mut session = TranscriptBuilder::new(&["clientPin"], &[])
+            .pin_retries(0)
+            .session();
+        let (pin_provider, log) = scripted_pin_provider(&["1234"]);
+        let result = obtain_param(&mut session, &pin_provider).await;
+        assert!(matches!(result, Err(Error::Ctap(CtapError::PINBlocked))));
+        session.channel().assert_finished();
+        assert!(log.requests.lock().unwrap().is_empty());
+
+        // The last retry was spent on a mismatch.
+        let mut session = TranscriptBuilder::new(&["clientPin"], &[])
+            .pin_retries(1)
+            .key_agreement()
+            .pin_token(Err(CtapError::PINBlocked))
+            .session();
+        let (pin_provider, log) = scripted_pin_provider(&["0000", "1234"]);
+        let result = obtain_param(&mut session, &pin_provider).await;
+        assert!(matches!(result, Err(Error::Ctap(CtapError::PINBlocked))));
+        session.channel().assert_finished();
+        assert_eq!(log.requests.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn pin_of_invalid_length_not_sent() {
+        let min_pin_length = (0x0D, Value::Integer(6));
+        let mut session = TranscriptBuilder::new(&["clientPin"], &[min_pin_length])
+            .pin_retries(8)
+            .key_agreement()
+            .pin_token(Ok(()))
+            .session();
+        let (pin_provider, log) = scripted_pin_provider(&["1234", "123456"]);
+
+        obtain_param(&mut session, &pin_provider).await.unwrap();
+        session.channel().assert_finished();
+
+        let requests = log.requests.lock().unwrap();
+        assert_eq!(requests.len(), 2);
+        assert_eq!(requests[0].min_length, 6);
+        assert!(!requests[0].previous_attempt_failed);
+        // Rejected before use, so no retry was spent.
+        assert_eq!(requests[1].attempts_left, Some(8));
+        assert!(requests[1].previous_attempt_failed);
+    }
+
+    #[tokio::test]
+    async fn uv_retried_until_success() {
+        let preferred_attempts = (0x11, Value::Integer(3));
+        let mut session = TranscriptBuilder::new(&["uv", "pinUvAuthToken"], &[preferred_attempts])
+            .uv_retries(5)
+            .key_agreement()
+            .uv_token(Err(CtapError::UVInvalid))
+            .uv_retries(4)
+            .key_agreement()
+            .uv_token(Ok(()))
+            .session();
+        let (pin_provider, log) = scripted_pin_provider(&[]);
+
+        obtain_param(&mut session, &pin_provider).await.unwrap();
+        session.channel().assert_finished();
+        assert_eq!(*log.uv_failures.lock().unwrap(), vec![Some(4)]);
+        assert!(log.requests.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn uv_attempts_limited_to_preferred() {
+        let preferred_attempts = (0x11, Value::Integer(2));
+        let mut session = TranscriptBuilder::new(&["uv", "pinUvAuthToken"], &[preferred_attempts])
+            .uv_retries(5)
+            .key_agreement()
+            .uv_token(Err(CtapError::UVInvalid))
+            .uv_retries(4)
+            .key_agreement()
+            .uv_token(Err(CtapError::UVInvalid))
+            .uv_retries(3)
+            .session();
+        let (pin_provider, log) = scripted_pin_provider(&[]);
+
+        let result = obtain_param(&mut session, &pin_provider).await;
+        assert!(matches!(result, Err(Error::Ctap(CtapError::UVInvalid))));
+        session.channel().assert_finished();
+        assert_eq!(*log.uv_failures.lock().unwrap(), vec![Some(4), Some(3)]);
+    }
+
+    #[tokio::test]
+    async fn uv_blocked_falls_back_to_pin() {
+        let mut session = TranscriptBuilder::new(&["uv", "pinUvAuthToken", "clientPin"], &[])
+            .uv_retries(0)
+            .pin_retries(8)
+            .key_agreement()
+            .pin_token_with_permissions(Ok(()))
+            .session();
+        let (pin_provider, log) = scripted_pin_provider(&["1234"]);
+
+        obtain_param(&mut session, &pin_provider).await.unwrap();
+        session.channel().assert_finished();
+        assert_eq!(log.requests.lock().unwrap().len(), 1);
+        assert!(log.uv_failures.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn cached_token_reused_across_ceremonies() {
+        let mut session = TranscriptBuilder::new(&["clientPin"], &[])
+            .pin_retries(8)
+            .key_agreement()
+            .pin_token(Ok(()))
+            .get_assertion(Ok(()))
+            .get_assertion(Ok(()))
+            .session();
+        let (pin_provider, log) = scripted_pin_provider(&["1234", "1234"]);
+
+        for _ in 0..2 {
+            session
+                .webauthn_get_assertion(&GetAssertionRequest::dummy(), &pin_provider)
+                .await
+                .unwrap();
+        }
+        session.channel().assert_finished();
+        assert_eq!(log.requests.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn rejected_cached_token_obtained_again_once() {
+        let mut session = TranscriptBuilder::new(&["clientPin"], &[])
+            .pin_retries(8)
+            .key_agreement()
+            .pin_token(Ok(()))
+            .get_assertion(Ok(()))
+            .get_assertion(Err(CtapError::PINAuthInvalid))
+            // The shared secret is still valid, only the token is obtained again.
+            .pin_retries(8)
+            .pin_token(Ok(()))
+            .get_assertion(Err(CtapError::PINAuthInvalid))
+            .session();
+        let (pin_provider, log) = scripted_pin_provider(&["1234", "1234", "1234"]);
+
+        session
+            .webauthn_get_assertion(&GetAssertionRequest::dummy(), &pin_provider)
+            .await
+            .unwrap();
+        // A fresh token which is rejected too is not retried.
+        let result = session
+            .webauthn_get_assertion(&GetAssertionRequest::dummy(), &pin_provider)
+            .await;
+        assert!(matches!(
+            result,
+            Err(Error::Ctap(CtapError::PINAuthInvalid))
+        ));
+        session.channel().assert_finished();
+        assert_eq!(log.requests.lock().unwrap().len(), 2);
+    }
+}
@@ -0,0 +1,323 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use dbus::channel::{MatchingReceiver, Token};
+use dbus::message::MatchRule;
+use dbus::{MethodErr, Path};
+use dbus_crossroads::{Crossroads, IfaceBuilder};
+use tokio::time::timeout;
+use tracing::{debug, info, instrument, warn, Level};
+
+use super::bus::Bus;
+use super::gatt::{DBUS_CALL_TIMEOUT, DEVICE_INTERFACE};
+use super::Error;
+
+use crate::transport::ble::pairing::PairingProvider;
+
+pub const AGENT_MANAGER_PATH: &str = "/org/bluez";
+pub const AGENT_MANAGER_INTERFACE: &str = "org.bluez.AgentManager1";
+pub const AGENT_INTERFACE: &str = "org.bluez.Agent1";
+pub const AGENT_PATH: &str = "/libwebauthn/PairingAgent";
+// Both passkey entry and display are possible, through the pairing provider.
+pub const AGENT_CAPABILITY: &str = "KeyboardDisplay";
+
+pub const PAIRING_MAX_TIMEOUT_MS: u64 = 60_000;
+
+/// Pairs with the target device, with a timeout. If a pairing provider is given, an agent is
+/// registered for the duration of pairing, so that passkey requests reach the user.
+///
+/// Pairing is canceled if it times out, or if the returned future is dropped before completion.
+#[instrument(level = Level::DEBUG, skip_all, fields(%device))]
+pub(crate) async fn pair(
+    bus: &Bus,
+    device: &Path<'static>,
+    alias: &str,
+    provider: Option<&Arc<dyn PairingProvider>>,
+    pairing_timeout: Duration,
+) -> Result<(), Error> {
+    let _agent = match provider {
+        Some(provider) => Some(PairingAgent::register(bus, device, alias, provider.clone()).await?),
+        None => {
+            debug!("No pairing provider, relying on the default agent");
+            None
+        }
+    };
+
+    let mut pending = PendingPairing {
+        bus,
+        device,
+        pending: true,
+    };
+    // Outlives the pairing timeout, so that a timeout always results in pairing being canceled.
+    let pairing = bus
+        .proxy(device.clone(), pairing_timeout + DBUS_CALL_TIMEOUT)
+        .method_call::<(), _, _, _>(DEVICE_INTERFACE, "Pair", ());
+    let result = match timeout(pairing_timeout, pairing).await {
+        Ok(result) => result,
+        Err(_) => {
+            warn!("Timed out whilst pairing with target device");
+            return Err(Error::Timeout);
+        }
+    };
+    pending.pending = false;
+
+    match result {
+        Ok(()) => {
+            info!("Paired with target device");
+            Ok(())
+        }
+        Err(err) if err.name() == Some("org.bluez.Error.AlreadyExists") => {
+            debug!("Target device is already paired");
+            Ok(())
+        }
+        Err(err) => {
+            warn!(%err, "Failed to pair with target device");
+            match err.name() {
+                Some("org.bluez.Error.AuthenticationCanceled")
+                | Some("org.bluez.Error.AuthenticationRejected") => Err(Error::Canceled),
+                Some("org.bluez.Error.AuthenticationTimeout") => Err(Error::Timeout),
+                _ => Err(Error::ConnectionFailed),
+            }
+        }
+    }
+}
+
+/// Cancels pairing on drop, unless it has completed.
+struct PendingPairing<'a> {
+    bus: &'a Bus,
+    device: &'a Path<'static>,
+    pending: bool,
+}
+
+impl Drop for PendingPairing<'_> {
+    fn drop(&mut self) {
+        if !self.pending {
+            return;
+        }
+        if let Err(err) =
+            self.bus
+                .call_no_reply(self.device.clone(), DEVICE_INTERFACE, "CancelPairing", ())
+        {
+            warn!(%err, "Failed to cancel pairing");
+        }
+        debug!("Canceled pairing");
+    }
+}
+
+/// An `org.bluez.Agent1` object, exported on the given bus until dropped.
+struct PairingAgent<'a> {
+    bus: &'a Bus,
+    token: Token,
+}
+
+impl<'a> PairingAgent<'a> {
+    async fn register(
+        bus: &'a Bus,
+        device: &Path<'static>,
+        alias: &str,
+        provider: Arc<dyn PairingProvider>,
+    ) -> Result<PairingAgent<'a>, Error> {
+        let requests = Arc::new(AgentRequests {
+            device: device.clone(),
+            alias: alias.to_owned(),
+            provider,
+        });
+
+        let mut cr = Crossroads::new();
+        cr.set_async_support(Some((
+            bus.connection(),
+            Box::new(|future| {
+                tokio::spawn(future);
+            }),
+        )));
+        let agent = cr.register(AGENT_INTERFACE, |b: &mut IfaceBuilder<()>| {
+            let r = requests.clone();
+            b.method_with_cr_async(
+                "RequestPasskey",
+                ("device",),
+                ("passkey",),
+                move |mut ctx, _, (device,): (Path<'static>,)| {
+                    let r = r.clone();
+                    async move {
+                        let result = r.request_passkey(&device).await;
+                        ctx.reply(result.map(|passkey| (passkey,)))
+                    }
+                },
+            );
+            let r = requests.clone();
+            b.method_with_cr_async(
+                "DisplayPasskey",
+                ("device", "passkey", "entered"),
+                (),
+                move |mut ctx, _, (device, passkey, entered): (Path<'static>, u32, u16)| {
+                    let r = r.clone();
+                    async move {
+                        let result = r.display_passkey(&device, passkey, entered).await;
+                        ctx.reply(result)
+                    }
+                },
+            );
+            let r = requests.clone();
+            b.method_with_cr_async(
+                "RequestConfirmation",
+                ("device", "passkey"),
+                (),
+                move |mut ctx, _, (device, passkey): (Path<'static>, u32)| {
+                    let r = r.clone();
+                    async move {
+                        let result = r.request_confirmation(&device, passkey).await;
+                        ctx.reply(result)
+                    }
+                },
+            );
+            let r = requests.clone();
+            b.method(
+                "RequestAuthorization",
+                ("device",),
+                (),
+                move |_, _, (device,): (Path<'static>,)| r.check_device(&device),
+            );
+            let r = requests.clone();
+            b.method(
+                "AuthorizeService",
+                ("device", "uuid"),
+                (),
+                move |_, _, (device, _): (Path<'static>, String)| r.check_device(&device),
+            );
+            // Legacy PIN codes are not used by LE devices.
+            b.method(
+                "RequestPinCode",
+                ("device",),
+                ("pincode",),
+                |_, _, _: (Path<'static>,)| -> Result<(String,), MethodErr> { Err(rejected()) },
+            );
+            b.method(
+                "DisplayPinCode",
+                ("device", "pincode"),
+                (),
+                |_, _, _: (Path<'static>, String)| -> Result<(), MethodErr> { Err(rejected()) },
+            );
+            let r = requests.clone();
+            b.method_with_cr_async("Cancel", (), (), move |mut ctx, _, _: ()| {
+                let r = r.clone();
+                async move {
+                    debug!("Agent request was canceled by BlueZ");
+                    r.provider.cancel().await;
+                    ctx.reply(Ok(()))
+                }
+            });
+            b.method("Release", (), (), |_, _, _: ()| {
+                debug!("Agent was released by BlueZ");
+                Ok(())
+            });
+        });
+        cr.insert(AGENT_PATH, &[agent], ());
+
+        let token = bus.start_receive(
+            MatchRule::new_method_call().with_path(AGENT_PATH),
+            Box::new(move |message, connection| {
+                if cr.handle_message(message, connection).is_err() {
+                    warn!("Failed to handle agent request");
+                }
+                true
+            }),
+        );
+        // Unregisters on drop, should registration fail.
+        let agent = PairingAgent { bus, token };
+
+        bus.proxy(AGENT_MANAGER_PATH, DBUS_CALL_TIMEOUT)
+            .method_call::<(), _, _, _>(
+                AGENT_MANAGER_INTERFACE,
+                "RegisterAgent",
+                (Path::from(AGENT_PATH), AGENT_CAPABILITY),
+            )
+            .await
+            .map_err(|err| {
+                warn!(%err, "Failed to register pairing agent");
+                Error::Unavailable
+            })?;
+        debug!("Registered pairing agent");
+        Ok(agent)
+    }
+}
+
+impl Drop for PairingAgent<'_> {
+    fn drop(&mut self) {
+        self.bus.stop_receive(self.token);
+        if let Err(err) = self.bus.call_no_reply(
+            AGENT_MANAGER_PATH,
+            AGENT_MANAGER_INTERFACE,
+            "UnregisterAgent",
+            (Path::from(AGENT_PATH),),
+        ) {
+            warn!(%err, "Failed to unregister pairing agent");
+        }
+        debug!("Unregistered pairing agent");
+    }
+}
+
+/// Routes agent requests for the target device to the pairing provider. Requests for any other
+/// device are rejected.
+struct AgentRequests {
+    device: Path<'static>,
+    alias: String,
+    provider: Arc<dyn PairingProvider>,
+}
+
+impl AgentRequests {
+    fn check_device(&self, device: &Path) -> Result<(), MethodErr> {
+        if device != &self.device {
+            warn!(%device, "Rejecting agent request for another device");
+            return Err(rejected());
+        }
+        Ok(())
+    }
+
+    async fn request_passkey(&self, device: &Path<'_>) -> Result<u32, MethodErr> {
+        self.check_device(device)?;
+        debug!("Requesting passkey");
+        match self.provider.request_passkey(&self.alias).await {
+            Some(passkey) if passkey <= 999_999 => Ok(passkey),
+            Some(_) => {
+                warn!("Pairing provider returned an invalid passkey");
+                Err(rejected())
+            }
+            None => {
+                info!("Passkey request was canceled by the user");
+                Err(canceled())
+            }
+        }
+    }
+
+    async fn display_passkey(
+        &self,
+        device: &Path<'_>,
+        passkey: u32,
+        entered: u16,
+    ) -> Result<(), MethodErr> {
+        self.check_device(device)?;
+        debug!({ entered }, "Displaying passkey");
+        self.provider
+            .display_passkey(&self.alias, passkey, entered)
+            .await;
+        Ok(())
+    }
+
+    async fn request_confirmation(&self, device: &Path<'_>, passkey: u32) -> Result<(), MethodErr> {
+        self.check_device(device)?;
+        debug!("Requesting passkey confirmation");
+        if !self.provider.confirm_passkey(&self.alias, passkey).await {
+            info!("Passkey was rejected by the user");
+            return Err(rejected());
+        }
+        Ok(())
+    }
+}
+
+fn rejected() -> MethodErr {
+    ("org.bluez.Error.Rejected", "Rejected by the user").into()
+}
+
+fn canceled() -> MethodErr {
+    ("org.bluez.Error.Canceled", "Canceled by the user").into()
+}
@@ -0,0 +1,95 @@
+use std::ops::Deref;
+use std::sync::Arc;
+use std::time::Duration;
+
+use dbus::arg::AppendAll;
+use dbus::channel::{Channel, Sender};
+use dbus::nonblock::{Proxy, SyncConnection};
+use dbus::{Message, Path};
+use dbus_tokio::connection::IOResource;
+use tokio::task::JoinHandle;
+use tracing::{debug, warn};
+
+use super::Error;
+
+pub const BLUEZ_SERVICE: &str = "org.bluez";
+
+/// A private D-Bus connection, dispatched by a background task for as long as it is alive.
+///
+/// BlueZ ties discovery sessions and notification subscriptions to the D-Bus client which
+/// started them, so these are released as soon as the bus is dropped.
+pub struct Bus {
+    connection: Arc<SyncConnection>,
+    dispatcher: JoinHandle<()>,
+}
+
+impl Bus {
+    pub fn system() -> Result<Self, Error> {
+        let (resource, connection) = dbus_tokio::connection::new_system_sync().map_err(|err| {
+            warn!(%err, "Failed to connect to the system bus");
+            Error::Unavailable
+        })?;
+        Ok(Self::new(resource, connection))
+    }
+
+    pub(crate) fn new(
+        resource: IOResource<SyncConnection>,
+        connection: Arc<SyncConnection>,
+    ) -> Self {
+        let dispatcher = tokio::spawn(async move {
+            let err = resource.await;
+            debug!(%err, "Lost connection to D-Bus");
+        });
+        Self {
+            connection,
+            dispatcher,
+        }
+    }
+
+    pub fn connection(&self) -> Arc<SyncConnection> {
+        self.connection.clone()
+    }
+
+    pub fn proxy<'a, P: Into<Path<'a>>>(
+        &'a self,
+        path: P,
+        timeout: Duration,
+    ) -> Proxy<'a, &'a SyncConnection> {
+        Proxy::new(BLUEZ_SERVICE, path, timeout, self.connection.as_ref())
+    }
+
+    /// Calls a BlueZ method without waiting for its reply, flushing the request before returning
+    /// so that it is delivered even if the bus is dropped right after. Meant for releasing
+    /// resources on drop.
+    pub fn call_no_reply<'a, P: Into<Path<'a>>, A: AppendAll>(
+        &self,
+        path: P,
+        interface: &str,
+        method: &str,
+        args: A,
+    ) -> Result<(), Error> {
+        let mut message = Message::new_method_call(BLUEZ_SERVICE, path, interface, method)
+            .or(Err(Error::OperationFailed))?;
+        message.append_all(args);
+        message.set_no_reply(true);
+        self.connection
+            .send(message)
+            .or(Err(Error::OperationFailed))?;
+        AsRef::<Channel>::as_ref(self.connection.as_ref()).flush();
+        Ok(())
+    }
+}
+
+impl Deref for Bus {
+    type Target = SyncConnection;
+
+    fn deref(&self) -> &Self::Target {
+        &self.connection
+    }
+}
+
+impl Drop for Bus {
+    fn drop(&mut self) {
+        self.dispatcher.abort();
+    }
+}
@@ -1,3 +1,5 @@
+use dbus::Path;
+
 #[derive(Debug, Clone)]
 pub struct FidoDevice {
     pub path: String,
@@ -19,24 +21,8 @@ impl FidoDevice {
 
 #[derive(Debug, Clone)]
 pub struct FidoEndpoints {
-    pub control_point: String,
-    pub control_point_length: String,
-    pub status: String,
-    pub service_revision_bitfield: String,
-}
-
-impl FidoEndpoints {
-    pub fn new(
-        control_point: &str,
-        control_point_length: &str,
-        status: &str,
-        service_revision_bitfield: &str,
-    ) -> Self {
-        Self {
-            control_point: control_point.into(),
-            control_point_length: control_point_length.into(),
-            status: status.into(),
-            service_revision_bitfield: service_revision_bitfield.into(),
-        }
-    }
+    pub control_point: Path<'static>,
+    pub control_point_length: Path<'static>,
+    pub status: Path<'static>,
+    pub service_revision_bitfield: Path<'static>,
 }
@@ -0,0 +1,208 @@
+use dbus::arg::{prop_cast, PropMap, Variant};
+use dbus::channel::{MatchingReceiver, Token};
+use dbus::message::SignalArgs;
+use dbus::nonblock::stdintf::org_freedesktop_dbus::{
+    ObjectManagerInterfacesAdded, Properties, PropertiesPropertiesChanged,
+};
+use dbus::Path;
+use futures::channel::mpsc::{self, UnboundedReceiver};
+use futures::{Stream, StreamExt};
+use tracing::{debug, instrument, trace, warn};
+
+use super::bus::Bus;
+use super::gatt::{get_managed_objects, ADAPTER_INTERFACE, DBUS_CALL_TIMEOUT, DEVICE_INTERFACE};
+use super::manager::{fido_device, FIDO_PROFILE_UUID};
+use super::{Error, FidoDevice};
+
+bitflags! {
+    /// Flags advertised by authenticators in the FIDO service data.
+    /// https://fidoalliance.org/specs/fido-v2.0-ps-20190130/fido-client-to-authenticator-protocol-v2.0-ps-20190130.html#ble-advertising
+    pub struct ServiceDataFlags: u8 {
+        /// The authenticator is in pairing mode, rather than reconnecting to a paired host.
+        const PAIRING_MODE = 0x80;
+        /// The authenticator requires the passkey entry pairing method.
+        const PASSKEY_ENTRY = 0x40;
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct DiscoveredDevice {
+    pub device: FidoDevice,
+    /// Signal strength of the latest advertisement, in dBm. Not set for cached devices which
+    /// are not currently advertising.
+    pub rssi: Option<i16>,
+    /// Flags from the FIDO service data, if the device advertised any.
+    pub flags: Option<ServiceDataFlags>,
+    /// Bluetooth Class of Device, only set for dual-mode devices.
+    pub class: Option<u32>,
+    /// GAP appearance, as advertised by LE devices.
+    pub appearance: Option<u16>,
+}
+
+impl DiscoveredDevice {
+    pub fn is_pairing_mode(&self) -> bool {
+        self.flags
+            .map(|flags| flags.contains(ServiceDataFlags::PAIRING_MODE))
+            .unwrap_or(false)
+    }
+}
+
+/// A running BlueZ discovery, filtered on the FIDO service UUID. Scanning stops once the session
+/// is dropped.
+pub struct DiscoverySession {
+    bus: Bus,
+    adapter: Path<'static>,
+    matches: Vec<Token>,
+    updates: UnboundedReceiver<Path<'static>>,
+}
+
+impl DiscoverySession {
+    #[instrument(skip_all)]
+    pub(crate) async fn start(bus: Bus) -> Result<Self, Error> {
+        let adapter_path = find_adapter(&bus).await?;
+        let adapter = bus.proxy(adapter_path.clone(), DBUS_CALL_TIMEOUT);
+        let powered: bool = adapter
+            .get(ADAPTER_INTERFACE, "Powered")
+            .await
+            .or(Err(Error::Unavailable))?;
+        if !powered {
+            return Err(Error::PoweredOff);
+        }
+
+        // Subscribe before starting discovery, so that no advertisement can be missed.
+        let (sender, updates) = mpsc::unbounded();
+        let added_sender = sender.clone();
+        let added = bus
+            .add_match(ObjectManagerInterfacesAdded::match_rule(None, None).static_clone())
+            .await
+            .or(Err(Error::Unavailable))?
+            .cb(move |_, added: ObjectManagerInterfacesAdded| {
+                if !added.interfaces.contains_key(DEVICE_INTERFACE) {
+                    return true;
+                }
+                added_sender.unbounded_send(added.object).is_ok()
+            });
+        let changed_sender = sender.clone();
+        let rule = PropertiesPropertiesChanged::match_rule(None, None)
+            .with_namespaced_path(adapter_path.clone())
+            .static_clone();
+        let changed = bus.add_match(rule).await.or(Err(Error::Unavailable))?.cb(
+            move |message, change: PropertiesPropertiesChanged| {
+                if change.interface_name != DEVICE_INTERFACE {
+                    return true;
+                }
+                let Some(path) = message.path() else {
+                    return true;
+                };
+                changed_sender.unbounded_send(path.into_static()).is_ok()
+            },
+        );
+
+        let mut filter = PropMap::new();
+        filter.insert(
+            "UUIDs".into(),
+            Variant(Box::new(vec![FIDO_PROFILE_UUID.to_owned()])),
+        );
+        filter.insert("Transport".into(), Variant(Box::new("le".to_owned())));
+        adapter
+            .method_call::<(), _, _, _>(ADAPTER_INTERFACE, "SetDiscoveryFilter", (filter,))
+            .await
+            .or(Err(Error::OperationFailed))?;
+        adapter
+            .method_call::<(), _, _, _>(ADAPTER_INTERFACE, "StartDiscovery", ())
+            .await
+            .or(Err(Error::Unavailable))?;
+        debug!(adapter = %adapter_path, "Started discovery");
+
+        let session = Self {
+            adapter: adapter_path,
+            matches: vec![added.token(), changed.token()],
+            updates,
+            bus,
+        };
+
+        // Devices which BlueZ already knows about, and which may not be advertising anymore.
+        let objects = get_managed_objects(&session.bus).await?;
+        for (path, interfaces) in objects {
+            if interfaces.contains_key(DEVICE_INTERFACE)
+                && path.starts_with(&format!("{}/", session.adapter))
+            {
+                let _ = sender.unbounded_send(path);
+            }
+        }
+        Ok(session)
+    }
+
+    /// Waits for the next advertisement, or change to a cached device, from a FIDO
+    /// authenticator. The same device is reported again whenever its advertisement changes.
+    pub async fn next(&mut self) -> Option<DiscoveredDevice> {
+        loop {
+            let path = self.updates.next().await?;
+            let properties = match self
+                .bus
+                .proxy(path.clone(), DBUS_CALL_TIMEOUT)
+                .get_all(DEVICE_INTERFACE)
+                .await
+            {
+                Ok(properties) => properties,
+                Err(err) => {
+                    // The device may have been removed since.
+                    debug!(%path, %err, "Failed to read device properties");
+                    continue;
+                }
+            };
+            if let Some(device) = discovered_device(&path, &properties) {
+                trace!(?device, "Discovered FIDO device");
+                return Some(device);
+            }
+        }
+    }
+
+    pub fn into_stream(self) -> impl Stream<Item = DiscoveredDevice> {
+        futures::stream::unfold(self, |mut session| async move {
+            let device = session.next().await?;
+            Some((device, session))
+        })
+    }
+}
+
+impl Drop for DiscoverySession {
+    fn drop(&mut self) {
+        for token in self.matches.drain(..) {
+            self.bus.stop_receive(token);
+        }
+        if let Err(err) =
+            self.bus
+                .call_no_reply(self.adapter.clone(), ADAPTER_INTERFACE, "StopDiscovery", ())
+        {
+            warn!(%err, "Failed to stop discovery");
+        }
+        debug!("Stopped discovery");
+    }
+}
+
+pub(crate) async fn find_adapter(bus: &Bus) -> Result<Path<'static>, Error> {
+    let objects = get_managed_objects(bus).await?;
+    let mut adapters: Vec<_> = objects
+        .into_iter()
+        .filter(|(_, interfaces)| interfaces.contains_key(ADAPTER_INTERFACE))
+        .map(|(path, _)| path)
+        .collect();
+    adapters.sort();
+    adapters.into_iter().next().ok_or(Error::Unavailable)
+}
+
+fn discovered_device(path: &Path, properties: &PropMap) -> Option<DiscoveredDevice> {
+    let device = fido_device(path, properties)?;
+    let flags = prop_cast::<PropMap>(properties, "ServiceData")
+        .and_then(|service_data| prop_cast::<Vec<u8>>(service_data, FIDO_PROFILE_UUID))
+        .and_then(|data| data.first())
+        .map(|flags| ServiceDataFlags::from_bits_truncate(*flags));
+    Some(DiscoveredDevice {
+        device,
+        rssi: prop_cast::<i16>(properties, "RSSI").cloned(),
+        flags,
+        class: prop_cast::<u32>(properties, "Class").cloned(),
+        appearance: prop_cast::<u16>(properties, "Appearance").cloned(),
+    })
+}
@@ -1,31 +1,105 @@
-use blurz::{BluetoothDevice, BluetoothGATTCharacteristic, BluetoothGATTService, BluetoothSession};
+use std::collections::HashMap;
+use std::time::Duration;
 
+use dbus::arg::{prop_cast, PropMap};
+use dbus::nonblock::stdintf::org_freedesktop_dbus::ObjectManager;
+use dbus::Path;
+
+use super::bus::Bus;
 use super::Error;
 
-pub fn get_gatt_characteristic<'a>(
-    session: &'a BluetoothSession,
-    service: &BluetoothGATTService<'a>,
+pub const ADAPTER_INTERFACE: &str = "org.bluez.Adapter1";
+pub const DEVICE_INTERFACE: &str = "org.bluez.Device1";
+pub const GATT_SERVICE_INTERFACE: &str = "org.bluez.GattService1";
+pub const GATT_CHARACTERISTIC_INTERFACE: &str = "org.bluez.GattCharacteristic1";
+
+pub const DBUS_CALL_TIMEOUT: Duration = Duration::from_secs(5);
+
+pub type ManagedObjects = HashMap<Path<'static>, HashMap<String, PropMap>>;
+
+pub async fn get_managed_objects(bus: &Bus) -> Result<ManagedObjects, Error> {
+    bus.proxy("/", DBUS_CALL_TIMEOUT)
+        .get_managed_objects()
+        .await
+        .or(Err(Error::Unavailable))
+}
+
+pub fn get_gatt_service(
+    objects: &ManagedObjects,
+    device: &Path,
     uuid: &str,
-) -> Result<BluetoothGATTCharacteristic<'a>, Error> {
-    service
-        .get_gatt_characteristics()
-        .unwrap()
-        .iter()
-        .map(|char_path| BluetoothGATTCharacteristic::new(session, char_path.to_owned()))
-        .find(|charct| charct.get_uuid().unwrap() == uuid)
-        .ok_or(Error::ConnectionFailed)
+) -> Result<Path<'static>, Error> {
+    find_child(objects, GATT_SERVICE_INTERFACE, "Device", device, uuid)
+}
+
+pub fn get_gatt_characteristic(
+    objects: &ManagedObjects,
+    service: &Path,
+    uuid: &str,
+) -> Result<Path<'static>, Error> {
+    find_child(
+        objects,
+        GATT_CHARACTERISTIC_INTERFACE,
+        "Service",
+        service,
+        uuid,
+    )
+}
+
+pub fn get_flags(objects: &ManagedObjects, characteristic: &Path) -> Vec<String> {
+    objects
+        .get(characteristic)
+        .and_then(|interfaces| interfaces.get(GATT_CHARACTERISTIC_INTERFACE))
+        .and_then(|properties| prop_cast::<Vec<String>>(properties, "Flags"))
+        .cloned()
+        .unwrap_or_default()
+}
+
+pub async fn read_value(bus: &Bus, characteristic: &Path<'_>) -> Result<Vec<u8>, Error> {
+    let (value,): (Vec<u8>,) = bus
+        .proxy(characteristic.clone(), DBUS_CALL_TIMEOUT)
+        .method_call(
+            GATT_CHARACTERISTIC_INTERFACE,
+            "ReadValue",
+            (PropMap::new(),),
+        )
+        .await
+        .or(Err(Error::OperationFailed))?;
+    Ok(value)
+}
+
+pub async fn write_value(
+    bus: &Bus,
+    characteristic: &Path<'_>,
+    value: Vec<u8>,
+    timeout: Duration,
+) -> Result<(), Error> {
+    bus.proxy(characteristic.clone(), timeout)
+        .method_call(
+            GATT_CHARACTERISTIC_INTERFACE,
+            "WriteValue",
+            (value, PropMap::new()),
+        )
+        .await
+        .or(Err(Error::OperationFailed))
 }
 
-pub fn get_gatt_service<'a>(
-    session: &'a BluetoothSession,
-    device: &'a BluetoothDevice,
+fn find_child(
+    objects: &ManagedObjects,
+    interface: &str,
+    parent_property: &str,
+    parent: &Path,
     uuid: &str,
-) -> Result<BluetoothGATTService<'a>, Error> {
-    device
-        .get_gatt_services()
-        .unwrap()
+) -> Result<Path<'static>, Error> {
+    objects
         .iter()
-        .map(|service_path| BluetoothGATTService::new(&session, service_path.to_owned()))
-        .find(|service| service.get_uuid().unwrap() == uuid)
+        .find(|(_, interfaces)| {
+            let Some(properties) = interfaces.get(interface) else {
+                return false;
+            };
+            prop_cast::<Path>(properties, parent_property) == Some(parent)
+                && prop_cast::<String>(properties, "UUID").map(String::as_str) == Some(uuid)
+        })
+        .map(|(path, _)| path.clone())
         .ok_or(Error::ConnectionFailed)
 }
@@ -1,29 +1,44 @@
+use std::fmt;
 use std::io::Cursor as IOCursor;
-use std::thread::sleep;
+use std::os::unix::io::{FromRawFd, IntoRawFd};
+use std::os::unix::net::UnixDatagram as StdUnixDatagram;
+use std::sync::Arc;
 use std::time::Duration;
 
-use tracing::{debug, info, instrument, span, trace, warn, Level};
+use tracing::{debug, info, instrument, trace, warn, Level};
 
+use super::agent::{pair, PAIRING_MAX_TIMEOUT_MS};
+use super::bus::Bus;
 use super::device::{FidoDevice as Device, FidoEndpoints as Endpoints};
-use super::gatt::{get_gatt_characteristic, get_gatt_service};
+use super::discovery::{find_adapter, DiscoverySession};
+use super::gatt::{
+    get_flags, get_gatt_characteristic, get_gatt_service, get_managed_objects, read_value,
+    write_value, DBUS_CALL_TIMEOUT, DEVICE_INTERFACE, GATT_CHARACTERISTIC_INTERFACE,
+};
 use super::Error;
 
 use crate::fido::FidoProtocol;
 use crate::fido::FidoRevision;
 use crate::transport::ble::framing::{
-    BleCommand, BleFrame as Frame, BleFrameParser, BleFrameParserResult,
-};
-
-use blurz::{
-    BluetoothAdapter, BluetoothDevice, BluetoothDiscoverySession, BluetoothEvent,
-    BluetoothGATTCharacteristic, BluetoothSession,
+    BleCommand, BleFrame as Frame, BleFrameParser, BleFrameParserResult, BleKeepaliveStatus,
 };
+use crate::transport::ble::pairing::PairingProvider;
 
 use byteorder::{BigEndian, ReadBytesExt};
-
-pub const WAIT_LOOP_MS: u32 = 250;
-pub const CONNECT_MAX_TIMEOUT_MS: i32 = 30_000;
-pub const SERVICES_DISCOVERY_MAX_TIMEOUT_MS: u32 = 5_000;
+use dbus::arg::{prop_cast, OwnedFd, PropMap};
+use dbus::channel::MatchingReceiver;
+use dbus::message::SignalArgs;
+use dbus::nonblock::stdintf::org_freedesktop_dbus::{Properties, PropertiesPropertiesChanged};
+use dbus::nonblock::MsgMatch;
+use dbus::{Message, Path};
+use futures::channel::mpsc::UnboundedReceiver;
+use futures::StreamExt;
+use tokio::net::UnixDatagram;
+use tokio::sync::{watch, Mutex as AsyncMutex};
+use tokio::time::{timeout, timeout_at, Instant};
+
+pub const CONNECT_MAX_TIMEOUT_MS: u64 = 30_000;
+pub const SERVICES_DISCOVERY_MAX_TIMEOUT_MS: u64 = 5_000;
 pub const DEVICE_RESPONSE_TIMEOUT_MS: u32 = 3_000;
 pub const FIDO_PROFILE_UUID: &str = "0000fffd-0000-1000-8000-00805f9b34fb";
 
@@ -32,6 +47,16 @@ pub const FIDO_STATUS_UUID: &str = "f1d0fff2-deaa-ecee-b42f-c9ba7ed623bb";
 pub const FIDO_CONTROL_POINT_LENGTH_UUID: &str = "f1d0fff3-deaa-ecee-b42f-c9ba7ed623bb";
 pub const FIDO_REVISION_BITFIELD_UUID: &str = "f1d0fff4-deaa-ecee-b42f-c9ba7ed623bb";
 
+// Size of the ATT header, which is not available for the fragment when writing through an
+// acquired socket.
+const ATT_HEADER_LENGTH: usize = 3;
+// Bounds of the control point length.
+// https://fidoalliance.org/specs/fido-v2.0-ps-20190130/fido-client-to-authenticator-protocol-v2.0-ps-20190130.html#ble-fido-service
+const CONTROL_POINT_MIN_LENGTH: usize = 20;
+const CONTROL_POINT_MAX_LENGTH: usize = 512;
+// Shortest fragment which can be framed: an initial fragment header, and one byte of data.
+const FRAGMENT_MIN_LENGTH: usize = 4;
+
 #[derive(Debug, Copy, Clone)]
 pub struct SupportedRevisions {
     pub u2fv11: bool,
@@ -62,197 +87,462 @@ impl SupportedRevisions {
     }
 }
 
-pub async fn start_discovery() -> Result<(), Error> {
-    let span = span!(Level::INFO, "start_discovery");
-    tokio::task::spawn_blocking(move || {
-        let _enter = span.enter();
-        start_discovery_blocking()
-    })
-    .await
-    .unwrap()
+/// Whether BlueZ is running on the system bus, with at least one adapter.
+#[instrument(skip_all)]
+pub async fn is_available() -> bool {
+    let Ok(bus) = Bus::system() else {
+        return false;
+    };
+    match find_adapter(&bus).await {
+        Ok(adapter) => {
+            debug!(%adapter, "BlueZ is available");
+            true
+        }
+        Err(err) => {
+            debug!(?err, "No BlueZ adapter found");
+            false
+        }
+    }
 }
 
+/// Starts scanning for FIDO authenticators, until the returned session is dropped.
+#[instrument(skip_all)]
+pub async fn start_discovery() -> Result<DiscoverySession, Error> {
+    DiscoverySession::start(Bus::system()?).await
+}
+
+#[instrument(skip_all)]
 pub async fn list_devices() -> Result<Vec<Device>, Error> {
-    let span = span!(Level::INFO, "list_devices");
-    tokio::task::spawn_blocking(move || {
-        let _enter = span.enter();
-        list_devices_blocking()
-    })
-    .await
-    .unwrap()
+    list_devices_on(&Bus::system()?).await
 }
 
-pub async fn supported_fido_revisions(target: &Device) -> Result<SupportedRevisions, Error> {
-    let span = span!(Level::DEBUG, "supported_fido_revisions");
-    let target = target.to_owned();
-    tokio::task::spawn_blocking(move || {
-        let _enter = span.enter();
-        supported_fido_revisions_blocking(&target)
-    })
-    .await
-    .unwrap()
+#[instrument(level = Level::DEBUG, skip_all)]
+pub async fn supported_fido_revisions(
+    target: &Device,
+    pairing_provider: Option<&Arc<dyn PairingProvider>>,
+) -> Result<SupportedRevisions, Error> {
+    supported_fido_revisions_on(&Bus::system()?, target, pairing_provider).await
 }
 
-pub async fn connect(device: &Device, revision: &FidoRevision) -> Result<Connection, Error> {
-    let span = span!(Level::DEBUG, "connect");
-    let device = device.to_owned();
-    let revision = revision.to_owned();
-    tokio::task::spawn_blocking(move || {
-        let _enter = span.enter();
-        connect_blocking(&device, &revision)
-    })
-    .await
-    .unwrap()
+/// Connects to the device, pairing with it first if needed. Passkey requests are routed to the
+/// pairing provider, if any; otherwise, to the default BlueZ agent.
+#[instrument(level = Level::DEBUG, skip_all)]
+pub async fn connect(
+    device: &Device,
+    revision: &FidoRevision,
+    pairing_provider: Option<&Arc<dyn PairingProvider>>,
+) -> Result<Connection, Error> {
+    connect_on(Bus::system()?, device, revision, pairing_provider).await
 }
 
-pub async fn frame_send(
-    connection: &Connection,
-    frame: &Frame,
-    timeout: Duration,
-) -> Result<(), Error> {
-    let span = span!(Level::DEBUG, "frame_send");
-    // tokio::task::block_in_place(|| {
-    let _enter = span.enter();
-    frame_send_blocking(connection, frame, timeout)
-    //})
+pub(crate) async fn list_devices_on(bus: &Bus) -> Result<Vec<Device>, Error> {
+    let objects = get_managed_objects(bus).await?;
+    let devices = objects
+        .iter()
+        .filter_map(|(path, interfaces)| fido_device(path, interfaces.get(DEVICE_INTERFACE)?))
+        .collect();
+    Ok(devices)
 }
 
-pub async fn frame_recv(connection: &Connection, timeout: Duration) -> Result<Frame, Error> {
-    let span = span!(Level::DEBUG, "frame_recv");
-    // tokio::task::block_in_place(|| {
-    let _enter = span.enter();
-    frame_recv_blocking(connection, timeout)
-    // })
+/// Builds a device from its BlueZ properties, if it exposes the FIDO service.
+pub(crate) fn fido_device(path: &Path, properties: &PropMap) -> Option<Device> {
+    let is_fido = prop_cast::<Vec<String>>(properties, "UUIDs")
+        .map(|uuids| uuids.iter().any(|uuid| uuid == FIDO_PROFILE_UUID))
+        .unwrap_or(false);
+    if !is_fido {
+        return None;
+    }
+    Some(Device::new(
+        path,
+        prop_cast::<String>(properties, "Alias")
+            .map(String::as_str)
+            .unwrap_or_default(),
+        prop_cast::<bool>(properties, "Paired")
+            .cloned()
+            .unwrap_or(false),
+        prop_cast::<bool>(properties, "Connected")
+            .cloned()
+            .unwrap_or(false),
+    ))
 }
 
-pub async fn notify_start(connection: &Connection) -> Result<(), Error> {
-    let span = span!(Level::DEBUG, "notify_start");
-    tokio::task::block_in_place(|| {
-        let _enter = span.enter();
-        notify_start_blocking(connection)
-    })
+#[derive(Debug)]
+struct AcquiredSocket {
+    socket: UnixDatagram,
+    mtu: u16,
 }
 
-#[instrument(skip_all)]
-pub fn notify_stop(connection: &Connection) -> Result<(), Error> {
-    notify_stop_blocking(connection)
+impl AcquiredSocket {
+    fn new(fd: OwnedFd, mtu: u16) -> Result<Self, Error> {
+        // Safety: the descriptor was received from BlueZ, and is not owned by anyone else.
+        let socket = unsafe { StdUnixDatagram::from_raw_fd(fd.into_raw_fd()) };
+        socket
+            .set_nonblocking(true)
+            .or(Err(Error::OperationFailed))?;
+        let socket = UnixDatagram::from_std(socket).or(Err(Error::OperationFailed))?;
+        Ok(Self { socket, mtu })
+    }
 }
 
-fn start_discovery_blocking() -> Result<(), Error> {
-    let session = BluetoothSession::create_session(None).or(Err(Error::Unavailable))?;
-    let adapter = BluetoothAdapter::init(&session).or(Err(Error::Unavailable))?;
-    if !adapter.is_powered().unwrap() {
-        return Err(Error::PoweredOff);
-    };
-    let discovery_session =
-        BluetoothDiscoverySession::create_session(&session, adapter.get_id()).unwrap();
-    discovery_session
-        .set_discovery_filter(vec![FIDO_PROFILE_UUID.into()], None, None)
-        .unwrap();
-    discovery_session
-        .start_discovery()
-        .or(Err(Error::Unavailable))?;
-    Ok(())
+enum Notifications {
+    /// Notifications are read from a socket obtained through AcquireNotify.
+    Acquired(AcquiredSocket),
+    /// Notifications are delivered as PropertiesChanged signals, following StartNotify.
+    Signals {
+        msg_match: MsgMatch,
+        stream: UnboundedReceiver<(Message, PropertiesPropertiesChanged)>,
+    },
 }
 
-fn list_devices_blocking() -> Result<Vec<Device>, Error> {
-    let session = BluetoothSession::create_session(None).or(Err(Error::Unavailable))?;
-    let adapter = BluetoothAdapter::init(&session).or(Err(Error::Unavailable))?;
-    let devices = adapter
-        .get_device_list()
-        .or(Err(Error::Unavailable))?
-        .iter()
-        .map(|device_path| BluetoothDevice::new(&session, device_path.into()))
-        .filter(|device| {
-            device
-                .get_uuids()
-                .unwrap()
-                .contains(&FIDO_PROFILE_UUID.into())
-        })
-        .map(|device| {
-            Device::new(
-                &device.get_id(),
-                &device.get_alias().unwrap(),
-                device.is_paired().unwrap(),
-                device.is_connected().unwrap(),
-            )
-        })
-        .collect();
-    Ok(devices)
+impl Notifications {
+    async fn next_fragment(&mut self) -> Result<Vec<u8>, Error> {
+        match self {
+            Notifications::Acquired(acquired) => {
+                let mut fragment = vec![0; acquired.mtu as usize];
+                let len = acquired
+                    .socket
+                    .recv(&mut fragment)
+                    .await
+                    .or(Err(Error::OperationFailed))?;
+                if len == 0 {
+                    warn!("Notification socket was closed by BlueZ");
+                    return Err(Error::ConnectionFailed);
+                }
+                fragment.truncate(len);
+                Ok(fragment)
+            }
+            Notifications::Signals { stream, .. } => loop {
+                let Some((_, change)) = stream.next().await else {
+                    warn!("Notification stream ended unexpectedly");
+                    return Err(Error::ConnectionFailed);
+                };
+                if change.interface_name != GATT_CHARACTERISTIC_INTERFACE {
+                    continue;
+                }
+                if let Some(value) = prop_cast::<Vec<u8>>(&change.changed_properties, "Value") {
+                    return Ok(value.clone());
+                }
+            },
+        }
+    }
 }
 
-#[derive(Debug)]
 pub struct Connection {
-    session: BluetoothSession,
+    bus: Bus,
+    device: Path<'static>,
     endpoints: Endpoints,
+    /// Read once when connecting, as the control point length may differ between connections.
+    max_fragment_length: usize,
+    writer: Option<AcquiredSocket>,
+    notifications: AsyncMutex<Option<Notifications>>,
+    // The receiver is kept around so that updates are never rejected.
+    keepalive: (
+        watch::Sender<Option<BleKeepaliveStatus>>,
+        watch::Receiver<Option<BleKeepaliveStatus>>,
+    ),
 }
 
-unsafe impl Send for Connection {}
-unsafe impl Sync for Connection {}
+impl Connection {
+    /// Maximum length of the fragments sent to the device.
+    pub fn max_fragment_length(&self) -> usize {
+        self.max_fragment_length
+    }
+
+    /// Status of the latest keep-alive received whilst waiting for a response, if any.
+    pub fn keepalive_status(&self) -> watch::Receiver<Option<BleKeepaliveStatus>> {
+        self.keepalive.1.clone()
+    }
 
-fn connect_blocking(device: &Device, revision: &FidoRevision) -> Result<Connection, Error> {
-    let session = BluetoothSession::create_session(None).or(Err(Error::Unavailable))?;
-    connect_and_pair(&session, device)?;
-    let endpoints = discover_services(&session, device)?;
-    select_fido_revision(&session, &endpoints, revision)?;
+    fn set_keepalive_status(&self, status: Option<BleKeepaliveStatus>) {
+        if *self.keepalive.1.borrow() != status {
+            let _ = self.keepalive.0.send(status);
+        }
+    }
+}
 
-    Ok(Connection { session, endpoints })
+impl fmt::Debug for Connection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Connection")
+            .field("endpoints", &self.endpoints)
+            .field("max_fragment_length", &self.max_fragment_length)
+            .field("writer", &self.writer)
+            .finish()
+    }
 }
 
-fn frame_send_blocking(
+pub(crate) async fn connect_on(
+    bus: Bus,
+    device: &Device,
+    revision: &FidoRevision,
+    pairing_provider: Option<&Arc<dyn PairingProvider>>,
+) -> Result<Connection, Error> {
+    let device_path = connect_and_pair(&bus, device, pairing_provider).await?;
+    let (endpoints, flags) = discover_services(&bus, &device_path).await?;
+    select_fido_revision(&bus, &endpoints, revision).await?;
+
+    let writer = if flags.iter().any(|flag| flag == "write-without-response") {
+        acquire_write(&bus, &endpoints).await
+    } else {
+        None
+    };
+
+    let mut max_fragment_length = control_point_length(&bus, &endpoints).await?;
+    if let Some(writer) = &writer {
+        max_fragment_length =
+            max_fragment_length.min((writer.mtu as usize).saturating_sub(ATT_HEADER_LENGTH));
+    }
+    if max_fragment_length < FRAGMENT_MIN_LENGTH {
+        warn!(
+            { max_fragment_length },
+            "Control point is too short for any fragment"
+        );
+        return Err(Error::OperationFailed);
+    }
+    debug!({ max_fragment_length }, "Connected to FIDO service");
+
+    Ok(Connection {
+        bus,
+        device: device_path,
+        endpoints,
+        max_fragment_length,
+        writer,
+        notifications: AsyncMutex::new(None),
+        keepalive: watch::channel(None),
+    })
+}
+
+async fn acquire_write(bus: &Bus, endpoints: &Endpoints) -> Option<AcquiredSocket> {
+    let result = bus
+        .proxy(endpoints.control_point.clone(), DBUS_CALL_TIMEOUT)
+        .method_call::<(OwnedFd, u16), _, _, _>(
+            GATT_CHARACTERISTIC_INTERFACE,
+            "AcquireWrite",
+            (PropMap::new(),),
+        )
+        .await;
+    match result {
+        Ok((fd, mtu)) => {
+            debug!({ mtu }, "Acquired control point socket");
+            AcquiredSocket::new(fd, mtu).ok()
+        }
+        Err(err) => {
+            debug!(%err, "Control point cannot be acquired, falling back to WriteValue");
+            None
+        }
+    }
+}
+
+#[instrument(level = Level::DEBUG, skip_all)]
+pub async fn frame_send(
     connection: &Connection,
     frame: &Frame,
-    _timeout: Duration,
+    timeout: Duration,
 ) -> Result<(), Error> {
-    let max_fragment_size = control_point_length(&connection.session, &connection.endpoints)?;
     let fragments = frame
-        .fragments(max_fragment_size)
+        .fragments(connection.max_fragment_length)
         .or(Err(Error::InvalidFraming))?;
 
-    let control_point = BluetoothGATTCharacteristic::new(
-        &connection.session,
-        connection.endpoints.control_point.clone(),
-    );
     for (i, fragment) in fragments.into_iter().enumerate() {
         debug!({ fragment = i, len = fragment.len() }, "Sending fragment");
         trace!(?fragment);
 
-        control_point
-            .write_value(fragment, None)
-            .or(Err(Error::OperationFailed))?;
+        match &connection.writer {
+            Some(writer) => {
+                writer
+                    .socket
+                    .send(&fragment)
+                    .await
+                    .or(Err(Error::OperationFailed))?;
+            }
+            None => {
+                write_value(
+                    &connection.bus,
+                    &connection.endpoints.control_point,
+                    fragment,
+                    timeout,
+                )
+                .await?;
+            }
+        }
     }
 
     Ok(())
 }
 
-fn notify_start_blocking(connection: &Connection) -> Result<(), Error> {
-    let status =
-        BluetoothGATTCharacteristic::new(&connection.session, connection.endpoints.status.clone());
-    status.start_notify().or(Err(Error::OperationFailed))?;
-    debug!("Registered for notifications on FIDO status endpoint");
+/// Receives the next response frame, either a message or a ping. Keep-alives extend the timeout,
+/// and their status is published to `Connection::keepalive_status` until the response arrives.
+#[instrument(level = Level::DEBUG, skip_all)]
+pub async fn frame_recv(connection: &Connection, timeout: Duration) -> Result<Frame, Error> {
+    let mut notifications = connection.notifications.lock().await;
+    let Some(notifications) = notifications.as_mut() else {
+        warn!("Not subscribed to notifications on the FIDO status endpoint");
+        return Err(Error::OperationFailed);
+    };
+
+    let result = recv_response(notifications, connection, timeout).await;
+    connection.set_keepalive_status(None);
+    result
+}
+
+async fn recv_response(
+    notifications: &mut Notifications,
+    connection: &Connection,
+    timeout: Duration,
+) -> Result<Frame, Error> {
+    let mut parser = BleFrameParser::new();
+    let mut deadline = Instant::now() + timeout;
+    loop {
+        let Ok(fragment) = timeout_at(deadline, notifications.next_fragment()).await else {
+            warn!("Timeout waiting for a response from the BLE device");
+            return Err(Error::Timeout);
+        };
+        let fragment = fragment?;
+        trace!(?fragment, "Received fragment");
+
+        let status = parser.update(&fragment).or(Err(Error::InvalidFraming))?;
+        if status == BleFrameParserResult::MoreFragmentsExpected {
+            continue;
+        }
+
+        let frame = parser.frame().or(Err(Error::InvalidFraming))?;
+        parser.reset();
+        trace!(?frame, "Received frame");
+        match frame.cmd {
+            BleCommand::Keepalive => {
+                deadline = Instant::now() + timeout;
+                let status = frame.keepalive_status();
+                debug!(?status, "Received keep-alive from authenticator");
+                if status.is_none() {
+                    warn!(data = ?frame.data, "Ignoring unknown keep-alive status");
+                    continue;
+                }
+                connection.set_keepalive_status(status);
+            }
+            BleCommand::Cancel => {
+                info!("Device canceled operation");
+                return Err(Error::Canceled);
+            }
+            BleCommand::Error => {
+                warn!("Received error frame");
+                return Err(Error::OperationFailed);
+            }
+            BleCommand::Ping => {
+                debug!("Received ping response");
+                return Ok(frame);
+            }
+            BleCommand::Msg => {
+                debug!("Received operation response");
+                return Ok(frame);
+            }
+        }
+    }
+}
+
+/// Sends a ping to the device, which must echo back the same data. Requires notifications to be
+/// started.
+#[instrument(level = Level::DEBUG, skip_all, fields(len = data.len()))]
+pub async fn ping(connection: &Connection, data: &[u8], timeout: Duration) -> Result<(), Error> {
+    let request = Frame::new(BleCommand::Ping, data);
+    frame_send(connection, &request, timeout).await?;
+    let response = frame_recv(connection, timeout).await?;
+    if response.cmd != BleCommand::Ping || response.data != request.data {
+        warn!(cmd = ?response.cmd, "Unexpected response to ping");
+        return Err(Error::InvalidFraming);
+    }
+    debug!("Ping was echoed by the device");
     Ok(())
 }
 
-fn notify_stop_blocking(connection: &Connection) -> Result<(), Error> {
-    let status =
-        BluetoothGATTCharacteristic::new(&connection.session, connection.endpoints.status.clone());
-    status.stop_notify().or(Err(Error::OperationFailed))?;
-    debug!("Unregistered for notifications");
+#[instrument(level = Level::DEBUG, skip_all)]
+pub async fn notify_start(connection: &Connection) -> Result<(), Error> {
+    let status = connection
+        .bus
+        .proxy(connection.endpoints.status.clone(), DBUS_CALL_TIMEOUT);
+
+    let acquired = status
+        .method_call::<(OwnedFd, u16), _, _, _>(
+            GATT_CHARACTERISTIC_INTERFACE,
+            "AcquireNotify",
+            (PropMap::new(),),
+        )
+        .await;
+    let notifications = match acquired {
+        Ok((fd, mtu)) => {
+            debug!(
+                { mtu },
+                "Acquired notification socket on FIDO status endpoint"
+            );
+            Notifications::Acquired(AcquiredSocket::new(fd, mtu)?)
+        }
+        Err(err) => {
+            debug!(%err, "Status endpoint cannot be acquired, falling back to StartNotify");
+            // Subscribe before enabling notifications, so that no fragment can be missed.
+            let rule =
+                PropertiesPropertiesChanged::match_rule(None, Some(&connection.endpoints.status))
+                    .static_clone();
+            let (msg_match, stream) = connection
+                .bus
+                .add_match(rule)
+                .await
+                .or(Err(Error::OperationFailed))?
+                .stream();
+            status
+                .method_call::<(), _, _, _>(GATT_CHARACTERISTIC_INTERFACE, "StartNotify", ())
+                .await
+                .or(Err(Error::OperationFailed))?;
+            Notifications::Signals { msg_match, stream }
+        }
+    };
+
+    *connection.notifications.lock().await = Some(notifications);
+    debug!("Registered for notifications on FIDO status endpoint");
     Ok(())
 }
 
-fn frame_recv_blocking(connection: &Connection, timeout: Duration) -> Result<Frame, Error> {
-    let frame = wait_for_response(&connection.session, &connection.endpoints, timeout)?;
-    Ok(frame)
+/// Stops notifications without waiting for BlueZ, so that it can be called on drop.
+#[instrument(skip_all)]
+pub fn notify_stop(connection: &Connection) -> Result<(), Error> {
+    let mut notifications = connection
+        .notifications
+        .try_lock()
+        .or(Err(Error::OperationFailed))?;
+    match notifications.take() {
+        // Closing the socket is enough for BlueZ to stop notifying.
+        Some(Notifications::Acquired(_)) => {}
+        Some(Notifications::Signals { msg_match, .. }) => {
+            connection.bus.stop_receive(msg_match.token());
+            connection.bus.call_no_reply(
+                connection.endpoints.status.clone(),
+                GATT_CHARACTERISTIC_INTERFACE,
+                "StopNotify",
+                (),
+            )?;
+        }
+        None => return Ok(()),
+    }
+    debug!("Unregistered for notifications");
+    Ok(())
 }
 
-fn control_point_length(session: &BluetoothSession, endpoints: &Endpoints) -> Result<usize, Error> {
-    let control_point_length =
-        BluetoothGATTCharacteristic::new(&session, endpoints.control_point_length.clone());
-    let max_fragment_length = control_point_length
-        .read_value(None)
+/// Stops notifications, then disconnects from the device.
+#[instrument(level = Level::DEBUG, skip_all)]
+pub async fn disconnect(connection: &Connection) -> Result<(), Error> {
+    // Disconnecting also ends notifications, so carry on if they could not be stopped first.
+    if let Err(err) = notify_stop(connection) {
+        debug!(%err, "Failed to stop notifications before disconnecting");
+    }
+    connection
+        .bus
+        .proxy(connection.device.clone(), DBUS_CALL_TIMEOUT)
+        .method_call::<(), _, _, _>(DEVICE_INTERFACE, "Disconnect", ())
+        .await
         .or(Err(Error::OperationFailed))?;
+    info!("Disconnected from device");
+    Ok(())
+}
 
+async fn control_point_length(bus: &Bus, endpoints: &Endpoints) -> Result<usize, Error> {
+    let max_fragment_length = read_value(bus, &endpoints.control_point_length).await?;
     if max_fragment_length.len() != 2 {
         warn!(
             { len = max_fragment_length.len() },
@@ -263,89 +553,161 @@ fn control_point_length(session: &BluetoothSession, endpoints: &Endpoints) -> Re
 
     let mut cursor = IOCursor::new(max_fragment_length);
     let max_fragment_size = cursor.read_u16::<BigEndian>().unwrap() as usize;
-    Ok(max_fragment_size)
+    if !(CONTROL_POINT_MIN_LENGTH..=CONTROL_POINT_MAX_LENGTH).contains(&max_fragment_size) {
+        warn!(
+            { max_fragment_size },
+            "Control point length is out of bounds"
+        );
+    }
+    Ok(max_fragment_size.min(CONTROL_POINT_MAX_LENGTH))
 }
 
 #[instrument(level = Level::DEBUG, skip_all)]
-fn connect_and_pair(session: &BluetoothSession, target: &Device) -> Result<(), Error> {
-    let device = BluetoothDevice::new(session, target.path.clone());
-    if !device.is_paired().or(Err(Error::Unavailable))? {
+async fn connect_and_pair(
+    bus: &Bus,
+    target: &Device,
+    pairing_provider: Option<&Arc<dyn PairingProvider>>,
+) -> Result<Path<'static>, Error> {
+    let path = Path::new(target.path.clone()).or(Err(Error::Unavailable))?;
+    // Subscribe before connecting, so that the ServicesResolved change cannot be missed.
+    let rule = PropertiesPropertiesChanged::match_rule(None, Some(&path)).static_clone();
+    let (msg_match, changes) = bus
+        .add_match(rule)
+        .await
+        .or(Err(Error::Unavailable))?
+        .stream();
+
+    let result = connect_and_resolve(bus, &path, target, pairing_provider, changes).await;
+    if let Err(err) = bus.remove_match(msg_match.token()).await {
+        debug!(%err, "Failed to remove device properties match");
+    }
+    result?;
+    info!("Connected to target device");
+    Ok(path)
+}
+
+async fn connect_and_resolve(
+    bus: &Bus,
+    path: &Path<'static>,
+    target: &Device,
+    pairing_provider: Option<&Arc<dyn PairingProvider>>,
+    changes: UnboundedReceiver<(Message, PropertiesPropertiesChanged)>,
+) -> Result<(), Error> {
+    let device = bus.proxy(path.clone(), Duration::from_millis(CONNECT_MAX_TIMEOUT_MS));
+    let is_paired: bool = device
+        .get(DEVICE_INTERFACE, "Paired")
+        .await
+        .or(Err(Error::Unavailable))?;
+    if !is_paired {
         info!("Sending pairing required to target device");
-        device.pair().or(Err(Error::ConnectionFailed))?;
+        pair(
+            bus,
+            path,
+            &target.alias,
+            pairing_provider,
+            Duration::from_millis(PAIRING_MAX_TIMEOUT_MS),
+        )
+        .await?;
     }
-    if !device.is_connected().or(Err(Error::Unavailable))? {
+
+    let is_connected: bool = device
+        .get(DEVICE_INTERFACE, "Connected")
+        .await
+        .or(Err(Error::Unavailable))?;
+    if !is_connected {
         debug!(
             { timeout_ms = CONNECT_MAX_TIMEOUT_MS },
             "Attempting connection..."
         );
         device
-            .connect(CONNECT_MAX_TIMEOUT_MS)
+            .method_call::<(), _, _, _>(DEVICE_INTERFACE, "Connect", ())
+            .await
             .or(Err(Error::ConnectionFailed))?;
     }
-    wait_until_services_resolved(&device)?;
-    info!("Connected to target device");
+
+    let services_resolved: bool = device
+        .get(DEVICE_INTERFACE, "ServicesResolved")
+        .await
+        .or(Err(Error::Unavailable))?;
+    if !services_resolved {
+        wait_until_services_resolved(changes).await?;
+    }
+    debug!("GATT services resolved");
     Ok(())
 }
 
-fn wait_until_services_resolved(device: &BluetoothDevice) -> Result<(), Error> {
+async fn wait_until_services_resolved(
+    mut changes: UnboundedReceiver<(Message, PropertiesPropertiesChanged)>,
+) -> Result<(), Error> {
     debug!("Waiting until services are resolved for this device");
-    // Unfortunately bluez does not support the ServicesResolved property of BlueZ devices
-    // so we have to get creative - we'll keep enumerating services until at least one is found.
-    let mut waited_for = 0;
-    loop {
-        let services = device
-            .get_gatt_services()
-            .or(Err(Error::ConnectionFailed))?;
-        if !services.is_empty() {
-            debug!({ count = services.len() }, "GATT services discovered");
-            return Ok(());
-        }
-        if waited_for >= SERVICES_DISCOVERY_MAX_TIMEOUT_MS {
-            warn!("Timed out whilst waiting for services to be resolved");
-            return Err(Error::ConnectionFailed);
+    let resolved = async {
+        while let Some((_, change)) = changes.next().await {
+            if change.interface_name != DEVICE_INTERFACE {
+                continue;
+            }
+            match prop_cast::<bool>(&change.changed_properties, "ServicesResolved") {
+                Some(true) => return Ok(()),
+                Some(false) => debug!("Services were invalidated"),
+                None => {}
+            }
+            if prop_cast::<bool>(&change.changed_properties, "Connected") == Some(&false) {
+                warn!("Device disconnected whilst resolving services");
+                return Err(Error::ConnectionFailed);
+            }
         }
-        debug!("Services not yet resolved. Waiting {} ms.", WAIT_LOOP_MS);
-        sleep(Duration::from_millis(WAIT_LOOP_MS as u64));
-        waited_for += WAIT_LOOP_MS;
-    }
+        Err(Error::ConnectionFailed)
+    };
+    timeout(
+        Duration::from_millis(SERVICES_DISCOVERY_MAX_TIMEOUT_MS),
+        resolved,
+    )
+    .await
+    .map_err(|_| {
+        warn!("Timed out whilst waiting for services to be resolved");
+        Error::ConnectionFailed
+    })?
 }
 
 #[instrument(level = Level::DEBUG, skip_all)]
-fn discover_services(session: &BluetoothSession, target: &Device) -> Result<Endpoints, Error> {
+async fn discover_services(
+    bus: &Bus,
+    device: &Path<'static>,
+) -> Result<(Endpoints, Vec<String>), Error> {
     debug!("Attempting to discover FIDO services.");
-    let device = BluetoothDevice::new(session, target.path.clone());
-    let fido_service =
-        get_gatt_service(&session, &device, FIDO_PROFILE_UUID).or(Err(Error::ConnectionFailed))?;
-    debug!({ uuid = FIDO_PROFILE_UUID }, "Discovered FIDO service");
-    trace!(?fido_service);
-
-    let control_point = get_gatt_characteristic(session, &fido_service, FIDO_CONTROL_POINT_UUID)?;
-    let control_point_length =
-        get_gatt_characteristic(session, &fido_service, FIDO_CONTROL_POINT_LENGTH_UUID)?;
-    let status = get_gatt_characteristic(&session, &fido_service, FIDO_STATUS_UUID)?;
-    let service_revision_bitfield =
-        get_gatt_characteristic(session, &fido_service, FIDO_REVISION_BITFIELD_UUID)?;
-    let endpoints = Endpoints::new(
-        &control_point.get_id(),
-        &control_point_length.get_id(),
-        &status.get_id(),
-        &service_revision_bitfield.get_id(),
-    );
-    Ok(endpoints)
-}
-
-fn supported_fido_revisions_blocking(target: &Device) -> Result<SupportedRevisions, Error> {
-    let session = BluetoothSession::create_session(None).or(Err(Error::Unavailable))?;
-    connect_and_pair(&session, target)?;
-    let endpoints = discover_services(&session, target)?;
+    let objects = get_managed_objects(bus).await?;
+    let fido_service = get_gatt_service(&objects, device, FIDO_PROFILE_UUID)?;
+    debug!({ uuid = FIDO_PROFILE_UUID, path = %fido_service }, "Discovered FIDO service");
+
+    let endpoints = Endpoints {
+        control_point: get_gatt_characteristic(&objects, &fido_service, FIDO_CONTROL_POINT_UUID)?,
+        control_point_length: get_gatt_characteristic(
+            &objects,
+            &fido_service,
+            FIDO_CONTROL_POINT_LENGTH_UUID,
+        )?,
+        status: get_gatt_characteristic(&objects, &fido_service, FIDO_STATUS_UUID)?,
+        service_revision_bitfield: get_gatt_characteristic(
+            &objects,
+            &fido_service,
+            FIDO_REVISION_BITFIELD_UUID,
+        )?,
+    };
+    trace!(?endpoints);
+    let control_point_flags = get_flags(&objects, &endpoints.control_point);
+    Ok((endpoints, control_point_flags))
+}
+
+pub(crate) async fn supported_fido_revisions_on(
+    bus: &Bus,
+    target: &Device,
+    pairing_provider: Option<&Arc<dyn PairingProvider>>,
+) -> Result<SupportedRevisions, Error> {
+    let device_path = connect_and_pair(bus, target, pairing_provider).await?;
+    let (endpoints, _) = discover_services(bus, &device_path).await?;
 
     // https://fidoalliance.org/specs/fido-v2.0-ps-20190130/fido-client-to-authenticator-protocol-v2.0-ps-20190130.html#ble-protocol-overview
-    let service_revision_bitfield =
-        BluetoothGATTCharacteristic::new(&session, endpoints.service_revision_bitfield.into());
-    let revision = service_revision_bitfield
-        .read_value(None)
-        .or(Err(Error::OperationFailed))?;
-    let bitfield = revision.iter().next().ok_or(Error::OperationFailed)?;
+    let revision = read_value(bus, &endpoints.service_revision_bitfield).await?;
+    let bitfield = revision.first().ok_or(Error::OperationFailed)?;
     debug!(?revision, "Supported revision bitfield");
 
     let supported = SupportedRevisions {
@@ -357,95 +719,20 @@ fn supported_fido_revisions_blocking(target: &Device) -> Result<SupportedRevisio
     Ok(supported)
 }
 
-fn select_fido_revision(
-    session: &BluetoothSession,
+async fn select_fido_revision(
+    bus: &Bus,
     endpoints: &Endpoints,
     revision: &FidoRevision,
 ) -> Result<(), Error> {
-    let service_revision_bitfield =
-        BluetoothGATTCharacteristic::new(session, endpoints.service_revision_bitfield.clone());
-    let ack: u8 = revision.clone() as u8;
-    service_revision_bitfield
-        .write_value(vec![ack], None)
-        .or(Err(Error::OperationFailed))?;
+    let ack: u8 = *revision as u8;
+    write_value(
+        bus,
+        &endpoints.service_revision_bitfield,
+        vec![ack],
+        DBUS_CALL_TIMEOUT,
+    )
+    .await?;
 
     info!(?revision, "Successfully selected FIDO revision");
     Ok(())
 }
-
-fn wait_for_response(
-    session: &BluetoothSession,
-    endpoints: &Endpoints,
-    timeout: Duration,
-) -> Result<Frame, Error> {
-    let mut waited_for = 0;
-    loop {
-        let fragments = receive_fragments(session, endpoints, WAIT_LOOP_MS);
-        waited_for += WAIT_LOOP_MS;
-        debug!({ count = fragments.len() }, "Received response fragments");
-        trace!(?fragments);
-
-        let mut parser = BleFrameParser::new();
-        for fragment in &fragments {
-            let status = parser.update(fragment).or(Err(Error::InvalidFraming))?;
-            match status {
-                BleFrameParserResult::Done => {
-                    let frame = parser.frame().unwrap();
-                    trace!(?frame, "Received frame");
-                    match frame.cmd {
-                        BleCommand::Keepalive => {
-                            waited_for = 0;
-                            debug!("Received keep-alive from authenticator");
-                            parser.reset();
-                        }
-                        BleCommand::Cancel => {
-                            info!("Device canceled operation");
-                            return Err(Error::Canceled);
-                        }
-                        BleCommand::Error => {
-                            warn!("Received error frame");
-                            return Err(Error::OperationFailed);
-                        }
-                        BleCommand::Ping => {
-                            debug!("Ignoring ping from device");
-                        }
-                        BleCommand::Msg => {
-                            debug!("Received operation response");
-                            return Ok(frame);
-                        }
-                    }
-                }
-                BleFrameParserResult::MoreFragmentsExpected => {}
-            }
-        }
-
-        if waited_for > timeout.as_millis() as u32 {
-            warn!("Timeout waiting for a response from the BLE device");
-            return Err(Error::Timeout);
-        }
-    }
-}
-
-fn receive_fragments(
-    session: &BluetoothSession,
-    endpoints: &Endpoints,
-    wait_for_ms: u32,
-) -> Vec<Vec<u8>> {
-    let fragments: Vec<Vec<u8>> = session
-        .incoming(wait_for_ms)
-        .map(BluetoothEvent::from)
-        .filter(Option::is_some)
-        .flat_map(move |event| match event.unwrap() {
-            BluetoothEvent::Value { object_path, value } => {
-                if object_path == endpoints.status {
-                    Some(value)
-                } else {
-                    None
-                }
-            }
-            _ => None,
-        })
-        .map(move |e| Vec::from(e))
-        .collect();
-    fragments
-}
@@ -0,0 +1,828 @@
+//! A minimal BlueZ object tree, served on a private D-Bus daemon, exposing a single FIDO device
+//! which echoes back every frame written to its control point.
+//!
+//! Requires `dbus-daemon` to be installed, hence the `bluez-mock-tests` feature.
+
+use std::io::{BufRead, BufReader};
+use std::os::unix::io::IntoRawFd;
+use std::os::unix::net::UnixDatagram as StdUnixDatagram;
+use std::process::{Child, Command, Stdio};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use dbus::arg::{OwnedFd, PropMap, Variant};
+use dbus::channel::{Channel, MatchingReceiver, Sender};
+use dbus::message::{MatchRule, SignalArgs};
+use dbus::nonblock::stdintf::org_freedesktop_dbus::PropertiesPropertiesChanged;
+use dbus::nonblock::{Proxy, SyncConnection};
+use dbus::{MethodErr, Path};
+use dbus_crossroads::Crossroads;
+use dbus_tokio::connection::IOResource;
+use tokio::time::sleep;
+
+use super::agent::{AGENT_INTERFACE, AGENT_MANAGER_INTERFACE, AGENT_MANAGER_PATH};
+use super::bus::{Bus, BLUEZ_SERVICE};
+use super::discovery::ServiceDataFlags;
+use super::gatt::{
+    ADAPTER_INTERFACE, DEVICE_INTERFACE, GATT_CHARACTERISTIC_INTERFACE, GATT_SERVICE_INTERFACE,
+};
+use super::manager::{
+    FIDO_CONTROL_POINT_LENGTH_UUID, FIDO_CONTROL_POINT_UUID, FIDO_PROFILE_UUID,
+    FIDO_REVISION_BITFIELD_UUID, FIDO_STATUS_UUID,
+};
+use crate::transport::ble::framing::{
+    BleCommand, BleFrame, BleFrameParser, BleFrameParserResult, BleKeepaliveStatus,
+};
+
+pub const ADAPTER_PATH: &str = "/org/bluez/hci0";
+pub const DEVICE_PATH: &str = "/org/bluez/hci0/dev_00_11_22_33_44_55";
+/// A device which only appears once discovery starts, advertising that it is in pairing mode.
+pub const PAIRING_DEVICE_PATH: &str = "/org/bluez/hci0/dev_66_77_88_99_AA_BB";
+const SERVICE_PATH: &str = "/org/bluez/hci0/dev_00_11_22_33_44_55/service0010";
+const CONTROL_POINT_PATH: &str = "/org/bluez/hci0/dev_00_11_22_33_44_55/service0010/char0011";
+const STATUS_PATH: &str = "/org/bluez/hci0/dev_00_11_22_33_44_55/service0010/char0013";
+const CONTROL_POINT_LENGTH_PATH: &str =
+    "/org/bluez/hci0/dev_00_11_22_33_44_55/service0010/char0015";
+const REVISION_BITFIELD_PATH: &str = "/org/bluez/hci0/dev_00_11_22_33_44_55/service0010/char0017";
+
+pub const DEVICE_ALIAS: &str = "Mock Security Key";
+pub const PAIRING_DEVICE_RSSI: i16 = -42;
+// Generic HID
+pub const APPEARANCE: u16 = 0x03C0;
+pub const CONTROL_POINT_LENGTH: u16 = 20;
+// U2F 1.2 and FIDO2
+pub const REVISION_BITFIELD: u8 = 0x60;
+
+/// A `dbus-daemon` instance, killed on drop.
+pub struct PrivateBus {
+    daemon: Child,
+    address: String,
+}
+
+impl PrivateBus {
+    pub fn start() -> Self {
+        let mut daemon = Command::new("dbus-daemon")
+            .args(["--session", "--nofork", "--print-address"])
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()
+            .expect("dbus-daemon is required to run BlueZ mock tests");
+        let mut address = String::new();
+        BufReader::new(daemon.stdout.take().unwrap())
+            .read_line(&mut address)
+            .unwrap();
+        Self {
+            daemon,
+            address: address.trim().to_owned(),
+        }
+    }
+
+    pub fn connect(&self) -> Bus {
+        let (resource, connection) = self.open();
+        Bus::new(resource, connection)
+    }
+
+    fn open(&self) -> (IOResource<SyncConnection>, Arc<SyncConnection>) {
+        let mut channel = Channel::open_private(&self.address).unwrap();
+        channel.register().unwrap();
+        dbus_tokio::connection::from_channel(channel).unwrap()
+    }
+}
+
+impl Drop for PrivateBus {
+    fn drop(&mut self) {
+        let _ = self.daemon.kill();
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MockOptions {
+    pub paired: bool,
+    /// Whether the status characteristic supports AcquireNotify.
+    pub acquire_notify: bool,
+    /// Passkey which the pairing agent must provide, if pairing requires one.
+    pub passkey: Option<u32>,
+}
+
+#[derive(Debug, Default)]
+pub struct MockState {
+    pub discovering: bool,
+    /// Unique name of the client which registered an agent, and the agent's path.
+    pub agent: Option<(String, Path<'static>)>,
+    pub pairing_canceled: bool,
+    pub paired: bool,
+    pub connected: bool,
+    pub services_resolved: bool,
+    pub selected_revision: Option<u8>,
+    /// Reported by the control point length characteristic, and enforced on writes.
+    pub control_point_length: u16,
+    /// Length of the longest fragment written to the control point.
+    pub longest_fragment: usize,
+    pub notifying: bool,
+    notify_socket: Option<StdUnixDatagram>,
+    parser: Option<BleFrameParser>,
+}
+
+/// Advertisement data of a device object.
+#[derive(Debug, Clone, Copy)]
+struct MockAdvertisement {
+    alias: &'static str,
+    rssi: i16,
+    flags: Option<u8>,
+}
+
+/// Serves the mock object tree as `org.bluez`, for as long as it is alive.
+pub struct MockBlueZ {
+    _bus: Bus,
+    pub state: Arc<Mutex<MockState>>,
+}
+
+impl MockBlueZ {
+    pub async fn serve(bus: &PrivateBus, options: MockOptions) -> Self {
+        let (resource, connection) = bus.open();
+        let server = Bus::new(resource, connection.clone());
+        server
+            .request_name(BLUEZ_SERVICE, false, true, false)
+            .await
+            .unwrap();
+
+        let state = Arc::new(Mutex::new(MockState {
+            paired: options.paired,
+            control_point_length: CONTROL_POINT_LENGTH,
+            ..MockState::default()
+        }));
+        let mut cr = Crossroads::new();
+
+        cr.set_object_manager_support(Some(connection.clone()));
+        cr.set_async_support(Some((
+            connection.clone(),
+            Box::new(|future| {
+                tokio::spawn(future);
+            }),
+        )));
+
+        let agent_manager_state = state.clone();
+        let agent_manager = cr.register(AGENT_MANAGER_INTERFACE, move |b| {
+            let s = agent_manager_state.clone();
+            b.method(
+                "RegisterAgent",
+                ("agent", "capability"),
+                (),
+                move |ctx, _: &mut (), (agent, _): (Path<'static>, String)| {
+                    let owner = ctx.message().sender().unwrap().to_string();
+                    s.lock().unwrap().agent = Some((owner, agent));
+                    Ok(())
+                },
+            );
+            let s = agent_manager_state.clone();
+            b.method(
+                "UnregisterAgent",
+                ("agent",),
+                (),
+                move |_, _, (agent,): (Path<'static>,)| {
+                    let mut state = s.lock().unwrap();
+                    if state.agent.as_ref().map(|(_, path)| path) == Some(&agent) {
+                        state.agent = None;
+                    }
+                    Ok(())
+                },
+            );
+        });
+
+        let device_state = state.clone();
+        let device = cr.register(DEVICE_INTERFACE, move |b| {
+            b.property("Alias")
+                .get(|_, advertisement: &mut MockAdvertisement| Ok(advertisement.alias.to_owned()));
+            b.property("UUIDs")
+                .get(|_, _| Ok(vec![FIDO_PROFILE_UUID.to_owned()]));
+            b.property("RSSI")
+                .get(|_, advertisement| Ok(advertisement.rssi));
+            b.property("Appearance").get(|_, _| Ok(APPEARANCE));
+            b.property("ServiceData").get(|_, advertisement| {
+                let mut service_data = PropMap::new();
+                if let Some(flags) = advertisement.flags {
+                    service_data.insert(FIDO_PROFILE_UUID.into(), Variant(Box::new(vec![flags])));
+                }
+                Ok(service_data)
+            });
+            let s = device_state.clone();
+            b.property("Paired")
+                .get(move |_, _| Ok(s.lock().unwrap().paired));
+            let s = device_state.clone();
+            b.property("Connected")
+                .get(move |_, _| Ok(s.lock().unwrap().connected));
+            let s = device_state.clone();
+            b.property("ServicesResolved")
+                .get(move |_, _| Ok(s.lock().unwrap().services_resolved));
+            let s = device_state.clone();
+            let agent_connection = connection.clone();
+            b.method_with_cr_async("Pair", (), (), move |mut ctx, _, _: ()| {
+                let s = s.clone();
+                let connection = agent_connection.clone();
+                let sender = ctx.message().sender().map(|sender| sender.to_string());
+                async move {
+                    let result = pair(&s, connection, sender, options.passkey).await;
+                    ctx.reply(result)
+                }
+            });
+            let s = device_state.clone();
+            b.method("CancelPairing", (), (), move |_, _, _: ()| {
+                s.lock().unwrap().pairing_canceled = true;
+                Ok(())
+            });
+            let s = device_state.clone();
+            let connection = connection.clone();
+            b.method("Connect", (), (), move |_, _, _: ()| {
+                s.lock().unwrap().connected = true;
+                // As with real devices, services are only resolved after the call returns.
+                let s = s.clone();
+                let connection = connection.clone();
+                tokio::spawn(async move {
+                    sleep(Duration::from_millis(50)).await;
+                    s.lock().unwrap().services_resolved = true;
+                    let mut changed = PropMap::new();
+                    changed.insert("ServicesResolved".into(), Variant(Box::new(true)));
+                    let path = Path::from(DEVICE_PATH);
+                    connection
+                        .send(properties_changed(DEVICE_INTERFACE, changed, &path))
+                        .unwrap();
+                });
+                Ok(())
+            });
+            let s = device_state.clone();
+            b.method("Disconnect", (), (), move |_, _, _: ()| {
+                let mut state = s.lock().unwrap();
+                state.connected = false;
+                state.services_resolved = false;
+                Ok(())
+            });
+        });
+
+        let adapter_state = state.clone();
+        let adapter = cr.register(ADAPTER_INTERFACE, move |b| {
+            b.property("Powered").get(|_, _: &mut ()| Ok(true));
+            b.method(
+                "SetDiscoveryFilter",
+                ("filter",),
+                (),
+                |_, _, _: (PropMap,)| Ok(()),
+            );
+            let s = adapter_state.clone();
+            b.method_with_cr("StartDiscovery", (), (), move |_, cr, _: ()| {
+                s.lock().unwrap().discovering = true;
+                let path = Path::from(PAIRING_DEVICE_PATH);
+                if !cr.has_interface(&path, device) {
+                    cr.insert(
+                        path,
+                        &[device],
+                        MockAdvertisement {
+                            alias: "Mock Pairing Key",
+                            rssi: PAIRING_DEVICE_RSSI,
+                            flags: Some(ServiceDataFlags::PAIRING_MODE.bits()),
+                        },
+                    );
+                }
+                Ok(())
+            });
+            let s = adapter_state.clone();
+            b.method("StopDiscovery", (), (), move |_, _, _: ()| {
+                s.lock().unwrap().discovering = false;
+                Ok(())
+            });
+        });
+
+        let service = cr.register(GATT_SERVICE_INTERFACE, |b| {
+            b.property("UUID")
+                .get(|_, _: &mut ()| Ok(FIDO_PROFILE_UUID.to_owned()));
+            b.property("Device").get(|_, _| Ok(Path::from(DEVICE_PATH)));
+            b.property("Primary").get(|_, _| Ok(true));
+        });
+
+        let characteristic_state = state.clone();
+        let characteristic_connection = server.connection();
+        let characteristic = cr.register(GATT_CHARACTERISTIC_INTERFACE, move |b| {
+            b.property("UUID")
+                .get(|ctx, _: &mut ()| Ok(characteristic_uuid(ctx.path())?.to_owned()));
+            b.property("Service")
+                .get(|_, _| Ok(Path::from(SERVICE_PATH)));
+            b.property("Flags")
+                .get(|ctx, _| Ok(characteristic_flags(ctx.path())));
+            let s = characteristic_state.clone();
+            b.method(
+                "ReadValue",
+                ("options",),
+                ("value",),
+                move |ctx, _, _: (PropMap,)| match &**ctx.path() {
+                    CONTROL_POINT_LENGTH_PATH => Ok((s
+                        .lock()
+                        .unwrap()
+                        .control_point_length
+                        .to_be_bytes()
+                        .to_vec(),)),
+                    REVISION_BITFIELD_PATH => Ok((vec![s
+                        .lock()
+                        .unwrap()
+                        .selected_revision
+                        .unwrap_or(REVISION_BITFIELD)],)),
+                    _ => Err(not_supported()),
+                },
+            );
+            let s = characteristic_state.clone();
+            let connection = characteristic_connection.clone();
+            b.method(
+                "WriteValue",
+                ("value", "options"),
+                (),
+                move |ctx, _, (value, _): (Vec<u8>, PropMap)| match &**ctx.path() {
+                    REVISION_BITFIELD_PATH => {
+                        s.lock().unwrap().selected_revision = Some(value[0]);
+                        Ok(())
+                    }
+                    CONTROL_POINT_PATH => {
+                        let mut state = s.lock().unwrap();
+                        let Some(request) = state.write_fragment(&value)? else {
+                            return Ok(());
+                        };
+                        if request.cmd == BleCommand::Ping {
+                            state.notify(&connection, &request);
+                            return Ok(());
+                        }
+                        // Messages require user presence, and are echoed back after a while.
+                        let keepalive = BleFrame::new(
+                            BleCommand::Keepalive,
+                            &[BleKeepaliveStatus::UpNeeded.into()],
+                        );
+                        state.notify(&connection, &keepalive);
+                        let s = s.clone();
+                        let connection = connection.clone();
+                        tokio::spawn(async move {
+                            sleep(Duration::from_millis(50)).await;
+                            s.lock().unwrap().notify(&connection, &request);
+                        });
+                        Ok(())
+                    }
+                    _ => Err(not_supported()),
+                },
+            );
+            let s = characteristic_state.clone();
+            b.method("StartNotify", (), (), move |ctx, _, _: ()| {
+                if &**ctx.path() != STATUS_PATH {
+                    return Err(not_supported());
+                }
+                s.lock().unwrap().notifying = true;
+                Ok(())
+            });
+            let s = characteristic_state.clone();
+            b.method("StopNotify", (), (), move |_, _, _: ()| {
+                s.lock().unwrap().notifying = false;
+                Ok(())
+            });
+            let s = characteristic_state.clone();
+            b.method(
+                "AcquireNotify",
+                ("options",),
+                ("fd", "mtu"),
+                move |ctx, _, _: (PropMap,)| {
+                    if &**ctx.path() != STATUS_PATH || !options.acquire_notify {
+                        return Err(not_supported());
+                    }
+                    let (ours, theirs) = StdUnixDatagram::pair().unwrap();
+                    let mut state = s.lock().unwrap();
+                    state.notify_socket = Some(ours);
+                    state.notifying = true;
+                    // Safety: the descriptor was just created, and is not owned by anyone else.
+                    let fd = unsafe { OwnedFd::new(theirs.into_raw_fd()) };
+                    Ok((fd, state.control_point_length + 3))
+                },
+            );
+        });
+
+        cr.insert("/", &[cr.object_manager::<()>()], ());
+        cr.insert(AGENT_MANAGER_PATH, &[agent_manager], ());
+        cr.insert(ADAPTER_PATH, &[adapter], ());
+        cr.insert(
+            DEVICE_PATH,
+            &[device],
+            MockAdvertisement {
+                alias: DEVICE_ALIAS,
+                rssi: -70,
+                flags: None,
+            },
+        );
+        cr.insert(SERVICE_PATH, &[service], ());
+        for path in [
+            CONTROL_POINT_PATH,
+            STATUS_PATH,
+            CONTROL_POINT_LENGTH_PATH,
+            REVISION_BITFIELD_PATH,
+        ] {
+            cr.insert(path, &[characteristic], ());
+        }
+
+        server.start_receive(
+            MatchRule::new_method_call(),
+            Box::new(move |message, connection| {
+                cr.handle_message(message, connection).unwrap();
+                true
+            }),
+        );
+
+        Self {
+            _bus: server,
+            state,
+        }
+    }
+}
+
+/// Pairs with the device, asking the agent registered by the caller for a passkey if required.
+async fn pair(
+    state: &Mutex<MockState>,
+    connection: Arc<SyncConnection>,
+    sender: Option<String>,
+    expected_passkey: Option<u32>,
+) -> Result<(), MethodErr> {
+    if let Some(expected_passkey) = expected_passkey {
+        let agent = state.lock().unwrap().agent.clone();
+        let (owner, path) = agent
+            .filter(|(owner, _)| Some(owner) == sender.as_ref())
+            .ok_or_else(|| authentication_error("AuthenticationFailed"))?;
+        let result: Result<(u32,), dbus::Error> =
+            Proxy::new(owner, path, Duration::from_secs(60), connection)
+                .method_call(
+                    AGENT_INTERFACE,
+                    "RequestPasskey",
+                    (Path::from(DEVICE_PATH),),
+                )
+                .await;
+        match result {
+            Ok((passkey,)) if passkey == expected_passkey => {}
+            Ok(_) => return Err(authentication_error("AuthenticationFailed")),
+            Err(err) if err.name() == Some("org.bluez.Error.Canceled") => {
+                return Err(authentication_error("AuthenticationCanceled"))
+            }
+            Err(_) => return Err(authentication_error("AuthenticationRejected")),
+        }
+    }
+    state.lock().unwrap().paired = true;
+    Ok(())
+}
+
+impl MockState {
+    /// Returns the request, once a control point write completes it.
+    fn write_fragment(&mut self, fragment: &[u8]) -> Result<Option<BleFrame>, MethodErr> {
+        if fragment.len() > self.control_point_length as usize {
+            return Err(MethodErr::invalid_arg(
+                "Fragment exceeds the control point length",
+            ));
+        }
+        self.longest_fragment = self.longest_fragment.max(fragment.len());
+
+        let parser = self.parser.get_or_insert_with(BleFrameParser::new);
+        let status = parser
+            .update(fragment)
+            .map_err(|err| MethodErr::invalid_arg(&err))?;
+        if status == BleFrameParserResult::MoreFragmentsExpected {
+            return Ok(None);
+        }
+        let request = parser.frame().map_err(|err| MethodErr::invalid_arg(&err))?;
+        self.parser = None;
+        Ok(Some(request))
+    }
+
+    /// Notifies a frame on the status characteristic, if subscribed.
+    fn notify(&self, connection: &SyncConnection, frame: &BleFrame) {
+        let fragments = frame.fragments(self.control_point_length as usize).unwrap();
+        for fragment in fragments {
+            if let Some(socket) = &self.notify_socket {
+                socket.send(&fragment).unwrap();
+            } else if self.notifying {
+                let mut changed = PropMap::new();
+                changed.insert("Value".into(), Variant(Box::new(fragment)));
+                let path = Path::from(STATUS_PATH);
+                connection
+                    .send(properties_changed(
+                        GATT_CHARACTERISTIC_INTERFACE,
+                        changed,
+                        &path,
+                    ))
+                    .unwrap();
+            }
+        }
+    }
+}
+
+fn characteristic_uuid(path: &Path) -> Result<&'static str, MethodErr> {
+    match &**path {
+        CONTROL_POINT_PATH => Ok(FIDO_CONTROL_POINT_UUID),
+        STATUS_PATH => Ok(FIDO_STATUS_UUID),
+        CONTROL_POINT_LENGTH_PATH => Ok(FIDO_CONTROL_POINT_LENGTH_UUID),
+        REVISION_BITFIELD_PATH => Ok(FIDO_REVISION_BITFIELD_UUID),
+        _ => Err(MethodErr::no_path(path)),
+    }
+}
+
+fn characteristic_flags(path: &Path) -> Vec<String> {
+    let flags: &[&str] = match &**path {
+        CONTROL_POINT_PATH => &["write"],
+        STATUS_PATH => &["notify"],
+        CONTROL_POINT_LENGTH_PATH => &["read"],
+        REVISION_BITFIELD_PATH => &["read", "write"],
+        _ => &[],
+    };
+    flags.iter().map(|flag| flag.to_string()).collect()
+}
+
+fn properties_changed(interface: &str, changed_properties: PropMap, path: &Path) -> dbus::Message {
+    PropertiesPropertiesChanged {
+        interface_name: interface.to_owned(),
+        changed_properties,
+        invalidated_properties: vec![],
+    }
+    .to_emit_message(path)
+}
+
+fn authentication_error(name: &str) -> MethodErr {
+    (format!("org.bluez.Error.{}", name), "Pairing failed").into()
+}
+
+fn not_supported() -> MethodErr {
+    ("org.bluez.Error.NotSupported", "Operation is not supported").into()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    use std::collections::HashMap;
+
+    use async_trait::async_trait;
+    use dbus::Path;
+    use tokio::time::{sleep, timeout};
+
+    use crate::fido::FidoRevision;
+    use crate::transport::ble::bluez::agent::pair;
+    use crate::transport::ble::bluez::discovery::DiscoverySession;
+    use crate::transport::ble::bluez::manager::{
+        connect_on, disconnect, frame_recv, frame_send, list_devices_on, notify_start, notify_stop,
+        ping, supported_fido_revisions_on,
+    };
+    use crate::transport::ble::bluez::Error;
+    use crate::transport::ble::framing::{BleCommand, BleFrame, BleKeepaliveStatus};
+    use crate::transport::ble::pairing::{PairingProvider, StaticPairingProvider};
+
+    use super::{
+        MockBlueZ, MockOptions, MockState, PrivateBus, APPEARANCE, CONTROL_POINT_LENGTH,
+        DEVICE_ALIAS, DEVICE_PATH, PAIRING_DEVICE_PATH, PAIRING_DEVICE_RSSI,
+    };
+
+    const TIMEOUT: Duration = Duration::from_secs(2);
+    const PASSKEY: u32 = 123456;
+
+    /// Refuses to provide a passkey, or never answers at all.
+    struct RefusingPairingProvider {
+        respond: bool,
+    }
+
+    #[async_trait]
+    impl PairingProvider for RefusingPairingProvider {
+        async fn request_passkey(&self, _device: &str) -> Option<u32> {
+            if !self.respond {
+                futures::future::pending::<()>().await;
+            }
+            None
+        }
+
+        async fn display_passkey(&self, _device: &str, _passkey: u32, _entered: u16) {}
+
+        async fn confirm_passkey(&self, _device: &str, _passkey: u32) -> bool {
+            false
+        }
+    }
+
+    async fn wait_until(state: &Mutex<MockState>, condition: impl Fn(&MockState) -> bool) {
+        timeout(TIMEOUT, async {
+            while !condition(&state.lock().unwrap()) {
+                sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+    }
+
+    fn passkey_options() -> MockOptions {
+        MockOptions {
+            passkey: Some(PASSKEY),
+            ..MockOptions::default()
+        }
+    }
+
+    async fn echo(options: MockOptions) {
+        let daemon = PrivateBus::start();
+        let mock = MockBlueZ::serve(&daemon, options).await;
+
+        let devices = list_devices_on(&daemon.connect()).await.unwrap();
+        assert_eq!(devices.len(), 1);
+        assert_eq!(devices[0].path, DEVICE_PATH);
+
+        let connection = connect_on(daemon.connect(), &devices[0], &FidoRevision::V2, None)
+            .await
+            .unwrap();
+        assert!(mock.state.lock().unwrap().services_resolved);
+        assert_eq!(
+            mock.state.lock().unwrap().selected_revision,
+            Some(FidoRevision::V2 as u8)
+        );
+
+        notify_start(&connection).await.unwrap();
+        // Spans three fragments with the mock's 20-byte control point length
+        let request = BleFrame::new(BleCommand::Msg, &[0x42; 40]);
+        frame_send(&connection, &request, TIMEOUT).await.unwrap();
+        let mut keepalive = connection.keepalive_status();
+        let (response, status) = tokio::join!(frame_recv(&connection, TIMEOUT), async {
+            keepalive.changed().await.unwrap();
+            *keepalive.borrow()
+        });
+        let response = response.unwrap();
+        assert_eq!(status, Some(BleKeepaliveStatus::UpNeeded));
+        assert_eq!(*connection.keepalive_status().borrow(), None);
+        assert_eq!(response.cmd, BleCommand::Msg);
+        assert_eq!(response.data, request.data);
+
+        ping(&connection, &[0x17; 17], TIMEOUT).await.unwrap();
+        notify_stop(&connection).unwrap();
+        disconnect(&connection).await.unwrap();
+        assert!(!mock.state.lock().unwrap().connected);
+    }
+
+    #[tokio::test]
+    async fn list_devices() {
+        let daemon = PrivateBus::start();
+        let _mock = MockBlueZ::serve(&daemon, MockOptions::default()).await;
+        let bus = daemon.connect();
+        let devices = list_devices_on(&bus).await.unwrap();
+        assert_eq!(devices.len(), 1);
+        assert_eq!(devices[0].alias, DEVICE_ALIAS);
+        assert!(!devices[0].is_paired);
+        assert!(!devices[0].is_connected);
+    }
+
+    #[tokio::test]
+    async fn discovery_reports_pairing_mode() {
+        let daemon = PrivateBus::start();
+        let mock = MockBlueZ::serve(&daemon, MockOptions::default()).await;
+        let mut session = DiscoverySession::start(daemon.connect()).await.unwrap();
+        assert!(mock.state.lock().unwrap().discovering);
+
+        let mut discovered = HashMap::new();
+        while discovered.len() < 2 {
+            let device = timeout(TIMEOUT, session.next()).await.unwrap().unwrap();
+            discovered.insert(device.device.path.clone(), device);
+        }
+        let cached = &discovered[DEVICE_PATH];
+        assert!(!cached.is_pairing_mode());
+        assert_eq!(cached.flags, None);
+        let pairing = &discovered[PAIRING_DEVICE_PATH];
+        assert!(pairing.is_pairing_mode());
+        assert_eq!(pairing.rssi, Some(PAIRING_DEVICE_RSSI));
+        assert_eq!(pairing.appearance, Some(APPEARANCE));
+
+        drop(session);
+        wait_until(&mock.state, |state| !state.discovering).await;
+    }
+
+    #[tokio::test]
+    async fn supported_revisions_pairs_and_connects() {
+        let daemon = PrivateBus::start();
+        let mock = MockBlueZ::serve(&daemon, MockOptions::default()).await;
+        let bus = daemon.connect();
+        let devices = list_devices_on(&bus).await.unwrap();
+
+        let revisions = supported_fido_revisions_on(&bus, &devices[0], None)
+            .await
+            .unwrap();
+        assert!(revisions.v2 && revisions.u2fv12 && !revisions.u2fv11);
+        let state = mock.state.lock().unwrap();
+        assert!(state.paired && state.connected && state.services_resolved);
+    }
+
+    #[tokio::test]
+    async fn echo_with_notification_signals() {
+        echo(MockOptions {
+            paired: true,
+            acquire_notify: false,
+            passkey: None,
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn echo_with_acquired_notifications() {
+        echo(MockOptions {
+            paired: true,
+            acquire_notify: true,
+            passkey: None,
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn pairs_with_requested_passkey() {
+        let daemon = PrivateBus::start();
+        let mock = MockBlueZ::serve(&daemon, passkey_options()).await;
+        let bus = daemon.connect();
+        let devices = list_devices_on(&bus).await.unwrap();
+
+        let provider: Arc<dyn PairingProvider> = Arc::new(StaticPairingProvider::new(PASSKEY));
+        supported_fido_revisions_on(&bus, &devices[0], Some(&provider))
+            .await
+            .unwrap();
+        assert!(mock.state.lock().unwrap().paired);
+        wait_until(&mock.state, |state| state.agent.is_none()).await;
+    }
+
+    #[tokio::test]
+    async fn pairing_fails_with_wrong_passkey() {
+        let daemon = PrivateBus::start();
+        let mock = MockBlueZ::serve(&daemon, passkey_options()).await;
+        let bus = daemon.connect();
+        let devices = list_devices_on(&bus).await.unwrap();
+
+        let provider: Arc<dyn PairingProvider> = Arc::new(StaticPairingProvider::new(654321));
+        let result = supported_fido_revisions_on(&bus, &devices[0], Some(&provider)).await;
+        assert_eq!(result.unwrap_err(), Error::ConnectionFailed);
+        assert!(!mock.state.lock().unwrap().paired);
+    }
+
+    #[tokio::test]
+    async fn pairing_canceled_by_user() {
+        let daemon = PrivateBus::start();
+        let mock = MockBlueZ::serve(&daemon, passkey_options()).await;
+        let bus = daemon.connect();
+        let devices = list_devices_on(&bus).await.unwrap();
+
+        let provider: Arc<dyn PairingProvider> =
+            Arc::new(RefusingPairingProvider { respond: true });
+        let result = supported_fido_revisions_on(&bus, &devices[0], Some(&provider)).await;
+        assert_eq!(result.unwrap_err(), Error::Canceled);
+        assert!(!mock.state.lock().unwrap().paired);
+    }
+
+    #[tokio::test]
+    async fn pairing_times_out_and_cancels() {
+        let daemon = PrivateBus::start();
+        let mock = MockBlueZ::serve(&daemon, passkey_options()).await;
+        let bus = daemon.connect();
+
+        let provider: Arc<dyn PairingProvider> =
+            Arc::new(RefusingPairingProvider { respond: false });
+        let device = Path::from(DEVICE_PATH);
+        let result = pair(
+            &bus,
+            &device,
+            DEVICE_ALIAS,
+            Some(&provider),
+            Duration::from_millis(200),
+        )
+        .await;
+        assert_eq!(result.unwrap_err(), Error::Timeout);
+        wait_until(&mock.state, |state| {
+            state.pairing_canceled && state.agent.is_none()
+        })
+        .await;
+        assert!(!mock.state.lock().unwrap().paired);
+    }
+
+    #[tokio::test]
+    async fn control_point_length_is_read_per_connection() {
+        let daemon = PrivateBus::start();
+        let mock = MockBlueZ::serve(
+            &daemon,
+            MockOptions {
+                paired: true,
+                ..MockOptions::default()
+            },
+        )
+        .await;
+        let devices = list_devices_on(&daemon.connect()).await.unwrap();
+
+        let connection = connect_on(daemon.connect(), &devices[0], &FidoRevision::V2, None)
+            .await
+            .unwrap();
+        assert_eq!(
+            connection.max_fragment_length(),
+            CONTROL_POINT_LENGTH as usize
+        );
+        drop(connection);
+
+        mock.state.lock().unwrap().control_point_length = 64;
+        let connection = connect_on(daemon.connect(), &devices[0], &FidoRevision::V2, None)
+            .await
+            .unwrap();
+        assert_eq!(connection.max_fragment_length(), 64);
+        notify_start(&connection).await.unwrap();
+        ping(&connection, &[0x42; 100], TIMEOUT).await.unwrap();
+        assert_eq!(mock.state.lock().unwrap().longest_fragment, 64);
+        notify_stop(&connection).unwrap();
+    }
+}
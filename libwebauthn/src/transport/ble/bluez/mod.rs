@@ -1,11 +1,19 @@
+pub mod agent;
+pub mod bus;
 pub mod device;
+pub mod discovery;
 pub mod error;
 pub mod gatt;
 pub mod manager;
 
+#[cfg(all(test, feature = "bluez-mock-tests"))]
+mod mock;
+
+pub use bus::Bus;
 pub use device::FidoDevice;
+pub use discovery::{DiscoveredDevice, DiscoverySession, ServiceDataFlags};
 pub use error::Error;
 pub use manager::{
-    connect, frame_recv, frame_send, list_devices, notify_start, notify_stop, start_discovery,
-    supported_fido_revisions, Connection,
+    connect, disconnect, frame_recv, frame_send, is_available, list_devices, notify_start,
+    notify_stop, ping, start_discovery, supported_fido_revisions, Connection,
 };
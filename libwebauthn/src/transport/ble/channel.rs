@@ -13,34 +13,35 @@ use crate::transport::error::{Error, TransportError};
 
 use super::bluez::manager::SupportedRevisions;
 use super::bluez::Connection;
-use super::framing::{BleCommand, BleFrame};
+use super::framing::{BleCommand, BleFrame, BleKeepaliveStatus};
 use super::BleDevice;
 
 use async_trait::async_trait;
+use tokio::sync::watch;
 use tracing::{debug, instrument, trace, warn, Level};
 
 #[derive(Debug)]
-pub struct BleChannel<'a> {
+pub struct BleChannel {
     status: ChannelStatus,
-    device: &'a BleDevice,
+    device: BleDevice,
     connection: Connection,
     revision: FidoRevision,
 }
 
-impl<'a> BleChannel<'a> {
+impl BleChannel {
     pub async fn new(
-        device: &'a BleDevice,
+        device: &BleDevice,
         revisions: &SupportedRevisions,
-    ) -> Result<BleChannel<'a>, Error> {
+    ) -> Result<BleChannel, Error> {
         let revision = revisions
             .select_protocol(FidoProtocol::U2F)
             .ok_or(Error::Transport(TransportError::NegotiationFailed))?;
-        let connection = bluez::connect(&device.bluez_device, &revision)
+        let connection = bluez::connect(&device.bluez_device, &revision, device.pairing_provider())
             .await
             .or(Err(Error::Transport(TransportError::ConnectionFailed)))?;
         let channel = BleChannel {
             status: ChannelStatus::Ready,
-            device,
+            device: device.clone(),
             connection,
             revision,
         };
@@ -49,9 +50,32 @@ impl<'a> BleChannel<'a> {
             .or(Err(Error::Transport(TransportError::TransportUnavailable)))?;
         Ok(channel)
     }
+
+    /// Sends a ping, which the device must echo back. Pinging with `max_fragment_length() - 3`
+    /// bytes of data exercises a full-size fragment.
+    #[instrument(level = Level::DEBUG, skip_all)]
+    pub async fn ping(&self, data: &[u8], timeout: Duration) -> Result<(), Error> {
+        bluez::ping(&self.connection, data, timeout)
+            .await
+            .map_err(|err| match err {
+                bluez::Error::Timeout => Error::Transport(TransportError::Timeout),
+                _ => Error::Transport(TransportError::ConnectionFailed),
+            })
+    }
+
+    /// Maximum fragment length, as negotiated with the device when connecting.
+    pub fn max_fragment_length(&self) -> usize {
+        self.connection.max_fragment_length()
+    }
+
+    /// Status reported by the device through keep-alives, whilst a response is pending. For
+    /// instance, `UpNeeded` means that the user should be prompted to touch the device.
+    pub fn keepalive_status(&self) -> watch::Receiver<Option<BleKeepaliveStatus>> {
+        self.connection.keepalive_status()
+    }
 }
 
-impl<'a> Drop for BleChannel<'a> {
+impl Drop for BleChannel {
     #[instrument(skip_all, fields(dev = %self.device))]
     fn drop(&mut self) {
         if let Err(err) = bluez::notify_stop(&self.connection) {
@@ -60,25 +84,32 @@ impl<'a> Drop for BleChannel<'a> {
     }
 }
 
-impl Display for BleChannel<'_> {
+impl Display for BleChannel {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         self.device.fmt(f)
     }
 }
 
 #[async_trait]
-impl<'a> Channel for BleChannel<'a> {
+impl Channel for BleChannel {
     async fn supported_protocols(&self) -> Result<SupportedProtocols, Error> {
         Ok(self.revision.into())
     }
 
     async fn status(&self) -> ChannelStatus {
-        self.status
+        let keepalive = *self.connection.keepalive_status().borrow();
+        match keepalive {
+            Some(BleKeepaliveStatus::Processing) => ChannelStatus::Processing,
+            Some(BleKeepaliveStatus::UpNeeded) => ChannelStatus::UserPresenceNeeded,
+            None => self.status,
+        }
     }
 
+    #[instrument(skip_all, fields(dev = %self.device))]
     async fn close(&self) {
-        let _x = self.device;
-        todo!()
+        if let Err(err) = bluez::disconnect(&self.connection).await {
+            warn!(%err, "Failed to disconnect from device");
+        }
     }
 
     #[instrument(level = Level::DEBUG, skip_all)]
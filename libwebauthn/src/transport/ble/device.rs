@@ -1,4 +1,5 @@
 use std::fmt;
+use std::sync::Arc;
 
 use async_trait::async_trait;
 use tracing::{info, instrument};
@@ -7,9 +8,12 @@ use crate::transport::device::{Device, SupportedProtocols};
 use crate::transport::error::{Error, TransportError};
 
 use super::bluez::manager::SupportedRevisions;
-use super::bluez::{supported_fido_revisions, FidoDevice as BlueZFidoDevice};
+use super::bluez::{
+    supported_fido_revisions, DiscoveredDevice, DiscoverySession, FidoDevice as BlueZFidoDevice,
+};
 
 use super::channel::BleChannel;
+use super::pairing::PairingProvider;
 use super::{bluez, Ble};
 
 #[instrument]
@@ -24,13 +28,34 @@ pub async fn list_devices() -> Result<Vec<BleDevice>, Error> {
     Ok(devices)
 }
 
-#[derive(Debug, Clone)]
+/// Starts scanning for nearby FIDO authenticators, until the returned session is dropped.
+#[instrument]
+pub async fn discover() -> Result<DiscoverySession, Error> {
+    let session = bluez::start_discovery()
+        .await
+        .or(Err(Error::Transport(TransportError::TransportUnavailable)))?;
+    info!("Started discovery of nearby BLE devices");
+    Ok(session)
+}
+
+#[derive(Clone)]
 pub struct BleDevice {
     pub bluez_device: BlueZFidoDevice,
     pub revisions: Option<SupportedRevisions>,
+    pairing_provider: Option<Arc<dyn PairingProvider>>,
 }
 
 impl BleDevice {
+    /// Routes passkey requests to the given provider whenever this device needs to be paired.
+    /// Without one, pairing relies on the system's default BlueZ agent.
+    pub fn set_pairing_provider(&mut self, provider: Arc<dyn PairingProvider>) {
+        self.pairing_provider = Some(provider);
+    }
+
+    pub(crate) fn pairing_provider(&self) -> Option<&Arc<dyn PairingProvider>> {
+        self.pairing_provider.as_ref()
+    }
+
     pub fn alias(&self) -> String {
         self.bluez_device.alias.clone()
     }
@@ -49,16 +74,33 @@ impl From<&BlueZFidoDevice> for BleDevice {
         Self {
             bluez_device: bluez_device.clone(),
             revisions: None,
+            pairing_provider: None,
         }
     }
 }
 
+impl From<&DiscoveredDevice> for BleDevice {
+    fn from(discovered: &DiscoveredDevice) -> Self {
+        (&discovered.device).into()
+    }
+}
+
 impl Into<BlueZFidoDevice> for &BleDevice {
     fn into(self) -> BlueZFidoDevice {
         self.bluez_device.clone()
     }
 }
 
+impl fmt::Debug for BleDevice {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BleDevice")
+            .field("bluez_device", &self.bluez_device)
+            .field("revisions", &self.revisions)
+            .field("has_pairing_provider", &self.pairing_provider.is_some())
+            .finish()
+    }
+}
+
 impl fmt::Display for BleDevice {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(f, "{}", self.alias())
@@ -66,8 +108,8 @@ impl fmt::Display for BleDevice {
 }
 
 #[async_trait]
-impl<'d> Device<'d, Ble, BleChannel<'d>> for BleDevice {
-    async fn channel(&'d mut self) -> Result<BleChannel<'d>, Error> {
+impl Device<Ble, BleChannel> for BleDevice {
+    async fn channel(&mut self) -> Result<BleChannel, Error> {
         let revisions = self.supported_revisions().await?;
         let channel = BleChannel::new(self, &revisions).await?;
         Ok(channel)
@@ -84,9 +126,10 @@ impl BleDevice {
     async fn supported_revisions(&mut self) -> Result<SupportedRevisions, Error> {
         let revisions = match self.revisions {
             None => {
-                let revisions = supported_fido_revisions(&self.bluez_device)
-                    .await
-                    .or(Err(Error::Transport(TransportError::NegotiationFailed)))?;
+                let revisions =
+                    supported_fido_revisions(&self.bluez_device, self.pairing_provider())
+                        .await
+                        .or(Err(Error::Transport(TransportError::NegotiationFailed)))?;
                 self.revisions = Some(revisions);
                 revisions
             }
@@ -9,6 +9,9 @@ const INITIAL_FRAGMENT_MIN_LENGTH: usize = INITIAL_FRAGMENT_HEADER_LENGTH;
 const CONT_FRAGMENT_HEADER_LENGTH: usize = 1;
 const CONT_FRAGMENT_MIN_LENGTH: usize = CONT_FRAGMENT_HEADER_LENGTH; // 1B header, 1B data
 
+// Continuation fragments are numbered 0x00 to 0x7F, then wrap around to 0x00.
+const CONT_FRAGMENT_SEQ_MASK: u8 = 0x7F;
+
 // https://fidoalliance.org/specs/fido-v2.0-ps-20190130/fido-client-to-authenticator-protocol-v2.0-ps-20190130.html#ble-constants
 #[derive(Debug, IntoPrimitive, TryFromPrimitive, Copy, Clone, PartialEq)]
 #[repr(u8)]
@@ -20,6 +23,14 @@ pub enum BleCommand {
     Error = 0xBF,
 }
 
+// https://fidoalliance.org/specs/fido-v2.0-ps-20190130/fido-client-to-authenticator-protocol-v2.0-ps-20190130.html#ble-constants
+#[derive(Debug, IntoPrimitive, TryFromPrimitive, Copy, Clone, PartialEq)]
+#[repr(u8)]
+pub enum BleKeepaliveStatus {
+    Processing = 0x01,
+    UpNeeded = 0x02,
+}
+
 #[derive(Debug, Clone)]
 pub struct BleFrame {
     pub cmd: BleCommand,
@@ -71,11 +82,20 @@ impl BleFrame {
                 .collect();
             fragment.append(&mut chunk);
             fragments.push(fragment);
-            seq += 1;
+            seq = (seq + 1) & CONT_FRAGMENT_SEQ_MASK;
         }
 
         Ok(fragments)
     }
+
+    /// Status carried by a keep-alive frame, if this is one.
+    pub fn keepalive_status(&self) -> Option<BleKeepaliveStatus> {
+        if self.cmd != BleCommand::Keepalive {
+            return None;
+        }
+        let status = self.data.first()?;
+        BleKeepaliveStatus::try_from(*status).ok()
+    }
 }
 
 #[derive(Debug, PartialEq)]
@@ -104,6 +124,19 @@ impl BleFrameParser {
             ));
         }
 
+        if !self.fragments.is_empty() {
+            let expected_seq = ((self.fragments.len() - 1) as u8) & CONT_FRAGMENT_SEQ_MASK;
+            if fragment[0] != expected_seq {
+                return Err(IOError::new(
+                    IOErrorKind::InvalidData,
+                    format!(
+                        "Unexpected continuation fragment sequence number: expected {:x}, got {:x}",
+                        expected_seq, fragment[0]
+                    ),
+                ));
+            }
+        }
+
         self.fragments.push(Vec::from(fragment));
         return if self.more_fragments_needed() {
             Ok(BleFrameParserResult::MoreFragmentsExpected)
@@ -172,7 +205,7 @@ impl BleFrameParser {
 #[cfg(test)]
 mod tests {
     use crate::transport::ble::framing::{
-        BleCommand, BleFrame, BleFrameParser, BleFrameParserResult,
+        BleCommand, BleFrame, BleFrameParser, BleFrameParserResult, BleKeepaliveStatus,
     };
 
     #[test]
@@ -229,4 +262,54 @@ mod tests {
             vec![0x0A, 0x0B, 0x0C, 0x0D, 0x0E]
         );
     }
+
+    #[test]
+    fn encode_wraps_sequence_numbers() {
+        // 1 byte in the initial fragment, then 3 bytes in each of 130 continuation fragments
+        let data: Vec<u8> = (0..391).map(|i| i as u8).collect();
+        let frame = BleFrame::new(BleCommand::Msg, &data);
+        let fragments = frame.fragments(4).unwrap();
+        assert_eq!(fragments.len(), 131);
+        assert_eq!(fragments[1][0], 0x00);
+        assert_eq!(fragments[128][0], 0x7F);
+        assert_eq!(fragments[129][0], 0x00);
+        assert_eq!(fragments[130][0], 0x01);
+    }
+
+    #[test]
+    fn parse_wraps_sequence_numbers() {
+        let data: Vec<u8> = (0..391).map(|i| i as u8).collect();
+        let fragments = BleFrame::new(BleCommand::Msg, &data).fragments(4).unwrap();
+        let mut parser = BleFrameParser::new();
+        let (last, fragments) = fragments.split_last().unwrap();
+        for fragment in fragments {
+            assert_eq!(
+                parser.update(fragment).unwrap(),
+                BleFrameParserResult::MoreFragmentsExpected
+            );
+        }
+        assert_eq!(parser.update(last).unwrap(), BleFrameParserResult::Done);
+        assert_eq!(parser.frame().unwrap().data, data);
+    }
+
+    #[test]
+    fn parse_rejects_unexpected_sequence_number() {
+        let mut parser = BleFrameParser::new();
+        parser.update(&[0x83, 0x00, 0x05, 0x0A]).unwrap();
+        parser.update(&[0x00, 0x0B, 0x0C]).unwrap();
+        assert!(parser.update(&[0x02, 0x0D, 0x0E]).is_err());
+    }
+
+    #[test]
+    fn keepalive_status() {
+        let keepalive = BleFrame::new(BleCommand::Keepalive, &[0x02]);
+        assert_eq!(
+            keepalive.keepalive_status(),
+            Some(BleKeepaliveStatus::UpNeeded)
+        );
+        let unknown = BleFrame::new(BleCommand::Keepalive, &[0x7F]);
+        assert_eq!(unknown.keepalive_status(), None);
+        let message = BleFrame::new(BleCommand::Msg, &[0x01]);
+        assert_eq!(message.keepalive_status(), None);
+    }
 }
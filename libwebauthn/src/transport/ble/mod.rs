@@ -4,9 +4,12 @@ pub mod bluez;
 pub mod channel;
 pub mod device;
 pub mod framing;
+pub mod pairing;
 
-pub use device::list_devices;
+pub use bluez::{is_available, DiscoveredDevice, DiscoverySession, ServiceDataFlags};
 pub use device::BleDevice;
+pub use device::{discover, list_devices};
+pub use pairing::{PairingProvider, StaticPairingProvider, StdinPromptPairingProvider};
 
 use super::Transport;
 
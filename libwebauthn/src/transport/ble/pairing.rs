@@ -0,0 +1,119 @@
+use async_trait::async_trait;
+use tracing::{info, warn};
+
+/// Interacts with the user whilst pairing with a BLE authenticator, for the pairing methods
+/// which rely on a 6-digit passkey. Passkeys are always in the range 0..=999999, and should be
+/// shown zero-padded.
+#[async_trait]
+pub trait PairingProvider: Send + Sync {
+    /// Asks for the passkey displayed by the authenticator. Returning `None` cancels pairing.
+    async fn request_passkey(&self, device: &str) -> Option<u32>;
+
+    /// Shows the passkey which needs to be typed on the authenticator. Called again as digits are
+    /// typed, with the number of digits `entered` so far.
+    async fn display_passkey(&self, device: &str, passkey: u32, entered: u16);
+
+    /// Asks whether the authenticator displays the same passkey.
+    async fn confirm_passkey(&self, device: &str, passkey: u32) -> bool;
+
+    /// The pending request was canceled, for instance because pairing timed out.
+    async fn cancel(&self) {}
+}
+
+/// Provides a known passkey, for authenticators with a fixed passkey, and only confirms that same
+/// passkey.
+#[derive(Debug, Clone)]
+pub struct StaticPairingProvider {
+    passkey: u32,
+}
+
+impl StaticPairingProvider {
+    pub fn new(passkey: u32) -> Self {
+        Self { passkey }
+    }
+}
+
+#[async_trait]
+impl PairingProvider for StaticPairingProvider {
+    async fn request_passkey(&self, device: &str) -> Option<u32> {
+        info!({ %device }, "Providing static passkey");
+        Some(self.passkey)
+    }
+
+    async fn display_passkey(&self, device: &str, _passkey: u32, _entered: u16) {
+        warn!({ %device }, "Cannot display passkey, pairing will likely fail");
+    }
+
+    async fn confirm_passkey(&self, device: &str, passkey: u32) -> bool {
+        if passkey != self.passkey {
+            warn!({ %device }, "Passkey does not match the static passkey, rejecting it");
+            return false;
+        }
+        info!({ %device }, "Confirming passkey");
+        true
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct StdinPromptPairingProvider {}
+
+impl StdinPromptPairingProvider {
+    pub fn new() -> Self {
+        Self {}
+    }
+}
+
+#[async_trait]
+impl PairingProvider for StdinPromptPairingProvider {
+    async fn request_passkey(&self, device: &str) -> Option<u32> {
+        use std::io::{self, Write};
+        use text_io::read;
+
+        print!(
+            "Pairing: Please enter the passkey displayed by {}: ",
+            device
+        );
+        io::stdout().flush().unwrap();
+        let passkey_raw: String = read!("{}\n");
+
+        match passkey_raw.trim().parse() {
+            Ok(passkey) if passkey <= 999_999 => Some(passkey),
+            _ => {
+                println!("Pairing: No valid passkey provided, cancelling pairing.");
+                None
+            }
+        }
+    }
+
+    async fn display_passkey(&self, device: &str, passkey: u32, entered: u16) {
+        if entered == 0 {
+            println!("Pairing: Please type {:06} on {}.", passkey, device);
+        }
+    }
+
+    async fn confirm_passkey(&self, device: &str, passkey: u32) -> bool {
+        use std::io::{self, Write};
+        use text_io::read;
+
+        print!("Pairing: Does {} display {:06}? [y/N] ", device, passkey);
+        io::stdout().flush().unwrap();
+        let answer: String = read!("{}\n");
+        answer.trim().eq_ignore_ascii_case("y")
+    }
+
+    async fn cancel(&self) {
+        println!("Pairing: Request was canceled.");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{PairingProvider, StaticPairingProvider};
+
+    #[tokio::test]
+    async fn static_provider_only_confirms_its_passkey() {
+        let provider = StaticPairingProvider::new(123456);
+        assert!(provider.confirm_passkey("device", 123456).await);
+        assert!(!provider.confirm_passkey("device", 654321).await);
+    }
+}
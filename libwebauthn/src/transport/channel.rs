@@ -15,6 +15,7 @@ use super::device::SupportedProtocols;
 pub enum ChannelStatus {
     Ready, // Channels are created asynchrounously, and are always ready.
     Processing,
+    UserPresenceNeeded, // The authenticator is waiting for the user to touch it.
     Closed,
 }
 
@@ -30,3 +31,66 @@ pub trait Channel: Send + Sync + Display {
     async fn cbor_send(&self, request: &CborRequest, timeout: Duration) -> Result<(), Error>;
     async fn cbor_recv(&self, timeout: Duration) -> Result<CborResponse, Error>;
 }
+
+#[async_trait]
+impl<C: Channel + ?Sized> Channel for Box<C> {
+    async fn supported_protocols(&self) -> Result<SupportedProtocols, Error> {
+        (**self).supported_protocols().await
+    }
+
+    async fn status(&self) -> ChannelStatus {
+        (**self).status().await
+    }
+
+    async fn close(&self) {
+        (**self).close().await
+    }
+
+    async fn apdu_send(&self, request: &ApduRequest, timeout: Duration) -> Result<(), Error> {
+        (**self).apdu_send(request, timeout).await
+    }
+
+    async fn apdu_recv(&self, timeout: Duration) -> Result<ApduResponse, Error> {
+        (**self).apdu_recv(timeout).await
+    }
+
+    async fn cbor_send(&self, request: &CborRequest, timeout: Duration) -> Result<(), Error> {
+        (**self).cbor_send(request, timeout).await
+    }
+
+    async fn cbor_recv(&self, timeout: Duration) -> Result<CborResponse, Error> {
+        (**self).cbor_recv(timeout).await
+    }
+}
+
+#[async_trait]
+#[allow(clippy::needless_lifetimes)]
+impl<C: Channel + ?Sized> Channel for &mut C {
+    async fn supported_protocols(&self) -> Result<SupportedProtocols, Error> {
+        (**self).supported_protocols().await
+    }
+
+    async fn status(&self) -> ChannelStatus {
+        (**self).status().await
+    }
+
+    async fn close(&self) {
+        (**self).close().await
+    }
+
+    async fn apdu_send(&self, request: &ApduRequest, timeout: Duration) -> Result<(), Error> {
+        (**self).apdu_send(request, timeout).await
+    }
+
+    async fn apdu_recv(&self, timeout: Duration) -> Result<ApduResponse, Error> {
+        (**self).apdu_recv(timeout).await
+    }
+
+    async fn cbor_send(&self, request: &CborRequest, timeout: Duration) -> Result<(), Error> {
+        (**self).cbor_send(request, timeout).await
+    }
+
+    async fn cbor_recv(&self, timeout: Duration) -> Result<CborResponse, Error> {
+        (**self).cbor_recv(timeout).await
+    }
+}
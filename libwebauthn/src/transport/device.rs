@@ -2,19 +2,22 @@ use std::fmt::Display;
 
 use crate::fido::FidoRevision;
 use async_trait::async_trait;
+use serde_derive::{Deserialize, Serialize};
 
 use crate::transport::ble::bluez::manager::SupportedRevisions;
 use crate::transport::error::Error;
 
 use super::{Channel, Transport};
 
+/// Channels own everything they need from their device, so that they can outlive it, and be
+/// moved into spawned tasks.
 #[async_trait]
-pub trait Device<'d, T, C>: Send + Display
+pub trait Device<T, C>: Send + Display
 where
     T: Transport,
-    C: Channel + 'd,
+    C: Channel + 'static,
 {
-    async fn channel(&'d mut self) -> Result<C, Error>;
+    async fn channel(&mut self) -> Result<C, Error>;
     async fn supported_protocols(&mut self) -> Result<SupportedProtocols, Error>;
 }
 
@@ -31,7 +34,7 @@ where
 }
 */
 
-#[derive(Debug, Copy, Clone, Default)]
+#[derive(Debug, Copy, Clone, Default, PartialEq, Serialize, Deserialize)]
 pub struct SupportedProtocols {
     pub u2f: bool, // Can be split into U2F revisions, if needed.
     pub fido2: bool,
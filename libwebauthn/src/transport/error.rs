@@ -1,6 +1,8 @@
 pub use crate::proto::CtapError;
 
-#[derive(Debug, Copy, Clone, PartialEq)]
+use serde_derive::{Deserialize, Serialize};
+
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
 pub enum TransportError {
     ConnectionFailed,
     ConnectionLost,
@@ -19,7 +21,7 @@ impl std::fmt::Display for TransportError {
     }
 }
 
-#[derive(Debug, Copy, Clone, PartialEq)]
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
 pub enum Error {
     Transport(TransportError),
     Ctap(CtapError),
@@ -0,0 +1,536 @@
+//! Channel decorator which injects faults according to a seeded schedule, to exercise the
+//! library's error handling without misbehaving hardware.
+
+use std::fmt::{Display, Formatter};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use tokio::time::sleep;
+use tracing::{debug, info, warn};
+
+use crate::proto::ctap1::apdu::{ApduRequest, ApduResponse, ApduResponseStatus};
+use crate::proto::ctap2::cbor::{CborRequest, CborResponse};
+use crate::proto::CtapError;
+use crate::transport::channel::{Channel, ChannelStatus};
+use crate::transport::device::SupportedProtocols;
+use crate::transport::error::{Error, TransportError};
+use crate::transport::hid::framing::{
+    HidCommand, HidMessage, HidMessageParser, HidMessageParserState,
+};
+
+// Responses are re-framed as CTAPHID packets to simulate packet-level faults.
+const FAULT_CID: u32 = 0x01020304;
+const FAULT_PACKET_SIZE: usize = 64;
+const GARBAGE_LEN: usize = 32;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FaultTarget {
+    CborSend,
+    CborRecv,
+    ApduSend,
+    ApduRecv,
+}
+
+impl FaultTarget {
+    fn index(&self) -> usize {
+        *self as usize
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Fault {
+    /// Delays the operation.
+    Latency(Duration),
+    /// Swallows a request, so that the device never responds to it.
+    DropRequest,
+    /// Loses one HID packet of the response.
+    DropPacket(usize),
+    /// Delivers one HID packet of the response twice.
+    DuplicatePacket(usize),
+    /// Cuts the response, including its status byte or word, to the given length.
+    Truncate(usize),
+    /// Replaces the CBOR payload of a successful response with random bytes.
+    GarbageCbor,
+    /// Replaces a CBOR response with the given status code.
+    Status(CtapError),
+    /// Replaces an APDU response with the given status word.
+    ApduStatus(ApduResponseStatus),
+    /// Fails this and every subsequent operation, as if the device was unplugged.
+    Disconnect,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FaultTrigger {
+    Always,
+    /// Fires on the n-th operation of the target kind, counting from zero.
+    Nth(usize),
+    /// Fires randomly, using the schedule's seeded generator.
+    Probability(f64),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FaultRule {
+    pub target: FaultTarget,
+    pub trigger: FaultTrigger,
+    pub fault: Fault,
+}
+
+/// A probability trigger outside of `[0, 1]`, rejected when building a [`FaultSchedule`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct InvalidProbability(pub f64);
+
+impl std::error::Error for InvalidProbability {}
+
+impl Display for InvalidProbability {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Fault probability {} is not within [0, 1]", self.0)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FaultSchedule {
+    seed: u64,
+    rules: Vec<FaultRule>,
+}
+
+impl FaultSchedule {
+    pub fn new(seed: u64) -> Self {
+        Self {
+            seed,
+            rules: vec![],
+        }
+    }
+
+    pub fn with(
+        mut self,
+        target: FaultTarget,
+        trigger: FaultTrigger,
+        fault: Fault,
+    ) -> Result<Self, InvalidProbability> {
+        if let FaultTrigger::Probability(p) = trigger {
+            if !(0.0..=1.0).contains(&p) {
+                warn!(p, "Rejecting fault rule with an invalid probability");
+                return Err(InvalidProbability(p));
+            }
+        }
+        self.rules.push(FaultRule {
+            target,
+            trigger,
+            fault,
+        });
+        Ok(self)
+    }
+}
+
+/// A fault which was injected, for assertions in tests.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct InjectedFault {
+    pub target: FaultTarget,
+    /// Index of the operation of this target kind which the fault applied to.
+    pub operation: usize,
+    pub fault: Fault,
+}
+
+struct FaultState {
+    rng: StdRng,
+    operations: [usize; 4],
+    disconnected: bool,
+    request_dropped: bool,
+    injected: Vec<InjectedFault>,
+}
+
+/// Wraps any channel, injecting faults into its traffic according to a [`FaultSchedule`].
+///
+/// Running the same schedule against the same sequence of operations always injects the same
+/// faults, so failures found with random triggers can be reproduced from the seed.
+pub struct FaultInjectingChannel<C: Channel> {
+    inner: C,
+    rules: Vec<FaultRule>,
+    state: Mutex<FaultState>,
+}
+
+impl<C: Channel> FaultInjectingChannel<C> {
+    pub fn new(inner: C, schedule: FaultSchedule) -> Self {
+        Self {
+            inner,
+            rules: schedule.rules,
+            state: Mutex::new(FaultState {
+                rng: StdRng::seed_from_u64(schedule.seed),
+                operations: [0; 4],
+                disconnected: false,
+                request_dropped: false,
+                injected: vec![],
+            }),
+        }
+    }
+
+    pub fn injected(&self) -> Vec<InjectedFault> {
+        self.state.lock().unwrap().injected.clone()
+    }
+
+    pub fn into_inner(self) -> C {
+        self.inner
+    }
+
+    fn faults(&self, target: FaultTarget) -> Result<Vec<Fault>, Error> {
+        let mut state = self.state.lock().unwrap();
+        if state.disconnected {
+            return Err(Error::Transport(TransportError::ConnectionLost));
+        }
+
+        let operation = state.operations[target.index()];
+        state.operations[target.index()] += 1;
+
+        let mut faults = vec![];
+        for rule in self.rules.iter().filter(|rule| rule.target == target) {
+            let fires = match rule.trigger {
+                FaultTrigger::Always => true,
+                FaultTrigger::Nth(n) => n == operation,
+                FaultTrigger::Probability(p) => state.rng.gen_bool(p),
+            };
+            if fires {
+                info!(operation, ?target, fault = ?rule.fault, "Injecting fault");
+                state.injected.push(InjectedFault {
+                    target,
+                    operation,
+                    fault: rule.fault,
+                });
+                faults.push(rule.fault);
+            }
+        }
+        Ok(faults)
+    }
+
+    /// Applies faults which are common to all operations. Returns `false` if the request
+    /// should not be forwarded to the inner channel.
+    async fn before(&self, faults: &[Fault], is_send: bool) -> Result<bool, Error> {
+        let mut forward = true;
+        for fault in faults {
+            match fault {
+                Fault::Latency(delay) => sleep(*delay).await,
+                Fault::Disconnect => {
+                    self.state.lock().unwrap().disconnected = true;
+                    return Err(Error::Transport(TransportError::ConnectionLost));
+                }
+                Fault::DropRequest if is_send => forward = false,
+                _ if is_send => warn!(?fault, "Fault cannot be applied to a request, ignoring"),
+                _ => (),
+            }
+        }
+        if !forward {
+            self.state.lock().unwrap().request_dropped = true;
+        }
+        Ok(forward)
+    }
+
+    async fn check_dropped(&self, timeout: Duration) -> Result<(), Error> {
+        let dropped = std::mem::take(&mut self.state.lock().unwrap().request_dropped);
+        if dropped {
+            debug!("Request was dropped, waiting for the response to time out");
+            sleep(timeout).await;
+            return Err(Error::Transport(TransportError::Timeout));
+        }
+        Ok(())
+    }
+
+    /// Applies faults which alter the response, in its raw wire format.
+    fn mangle(
+        &self,
+        faults: &[Fault],
+        cmd: HidCommand,
+        mut raw: Vec<u8>,
+    ) -> Result<Vec<u8>, Error> {
+        for fault in faults {
+            raw = match fault {
+                Fault::DropPacket(index) => reframe(cmd, &raw, |packets| {
+                    if *index < packets.len() {
+                        packets.remove(*index);
+                    }
+                })?,
+                Fault::DuplicatePacket(index) => reframe(cmd, &raw, |packets| {
+                    if let Some(packet) = packets.get(*index).cloned() {
+                        packets.insert(*index, packet);
+                    }
+                })?,
+                Fault::Truncate(len) => {
+                    raw.truncate(*len);
+                    raw
+                }
+                Fault::GarbageCbor if cmd == HidCommand::Cbor && !raw.is_empty() => {
+                    let mut state = self.state.lock().unwrap();
+                    let mut mangled = vec![CtapError::Ok.into()];
+                    mangled.extend((0..GARBAGE_LEN).map(|_| state.rng.gen::<u8>()));
+                    mangled
+                }
+                Fault::Status(error) if cmd == HidCommand::Cbor => vec![(*error).into()],
+                Fault::ApduStatus(status) if cmd == HidCommand::Msg => {
+                    u16::from(*status).to_be_bytes().to_vec()
+                }
+                _ => raw,
+            };
+        }
+        Ok(raw)
+    }
+}
+
+/// Splits the payload into CTAPHID packets, lets `f` tamper with them, and reassembles them
+/// the way a host would.
+fn reframe<F>(cmd: HidCommand, payload: &[u8], f: F) -> Result<Vec<u8>, Error>
+where
+    F: FnOnce(&mut Vec<Vec<u8>>),
+{
+    let mut packets = HidMessage::new(FAULT_CID, cmd, payload)
+        .packets(FAULT_PACKET_SIZE)
+        .or(Err(Error::Transport(TransportError::InvalidFraming)))?;
+    f(&mut packets);
+
+    let mut parser = HidMessageParser::new();
+    for packet in packets {
+        if let HidMessageParserState::Done = parser
+            .update(&packet)
+            .or(Err(Error::Transport(TransportError::InvalidFraming)))?
+        {
+            let message = parser
+                .message()
+                .or(Err(Error::Transport(TransportError::InvalidFraming)))?;
+            return Ok(message.payload);
+        }
+    }
+    // The host would keep waiting for the missing packets.
+    Err(Error::Transport(TransportError::Timeout))
+}
+
+impl<C: Channel> Display for FaultInjectingChannel<C> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} (fault injection)", self.inner)
+    }
+}
+
+#[async_trait]
+impl<C: Channel> Channel for FaultInjectingChannel<C> {
+    async fn supported_protocols(&self) -> Result<SupportedProtocols, Error> {
+        self.inner.supported_protocols().await
+    }
+
+    async fn status(&self) -> ChannelStatus {
+        if self.state.lock().unwrap().disconnected {
+            return ChannelStatus::Closed;
+        }
+        self.inner.status().await
+    }
+
+    async fn close(&self) {
+        self.inner.close().await
+    }
+
+    async fn apdu_send(&self, request: &ApduRequest, timeout: Duration) -> Result<(), Error> {
+        let faults = self.faults(FaultTarget::ApduSend)?;
+        if !self.before(&faults, true).await? {
+            return Ok(());
+        }
+        self.inner.apdu_send(request, timeout).await
+    }
+
+    async fn apdu_recv(&self, timeout: Duration) -> Result<ApduResponse, Error> {
+        let faults = self.faults(FaultTarget::ApduRecv)?;
+        self.before(&faults, false).await?;
+        self.check_dropped(timeout).await?;
+        let response = self.inner.apdu_recv(timeout).await?;
+        let raw = self.mangle(&faults, HidCommand::Msg, response.raw())?;
+        ApduResponse::try_from(&raw).or(Err(Error::Transport(TransportError::InvalidFraming)))
+    }
+
+    async fn cbor_send(&self, request: &CborRequest, timeout: Duration) -> Result<(), Error> {
+        let faults = self.faults(FaultTarget::CborSend)?;
+        if !self.before(&faults, true).await? {
+            return Ok(());
+        }
+        self.inner.cbor_send(request, timeout).await
+    }
+
+    async fn cbor_recv(&self, timeout: Duration) -> Result<CborResponse, Error> {
+        let faults = self.faults(FaultTarget::CborRecv)?;
+        self.before(&faults, false).await?;
+        self.check_dropped(timeout).await?;
+        let response = self.inner.cbor_recv(timeout).await?;
+        let mut raw = vec![response.status_code.into()];
+        raw.extend(response.data.unwrap_or_default());
+        let raw = self.mangle(&faults, HidCommand::Cbor, raw)?;
+        CborResponse::try_from(&raw).or(Err(Error::Transport(TransportError::InvalidFraming)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::authenticator::store::CredentialStore;
+    use crate::authenticator::{SoftwareAuthenticator, SoftwareChannel};
+    use crate::ops::webauthn::MakeCredentialRequest;
+    use crate::proto::ctap1::apdu::ApduResponseStatus;
+    use crate::proto::ctap1::{Ctap1, Ctap1RegisterRequest, Ctap1RegisteredKey};
+    use crate::proto::ctap2::{Ctap2, Ctap2MakeCredentialRequest};
+    use crate::proto::CtapError;
+    use crate::transport::error::{Error, TransportError};
+    use crate::transport::fault::{
+        Fault, FaultInjectingChannel, FaultSchedule, FaultTarget, FaultTrigger, InvalidProbability,
+    };
+
+    const TIMEOUT: Duration = Duration::from_secs(1);
+
+    fn channel(schedule: FaultSchedule) -> FaultInjectingChannel<SoftwareChannel> {
+        let authenticator = SoftwareAuthenticator::new(CredentialStore::temporary());
+        FaultInjectingChannel::new(SoftwareChannel::new(authenticator), schedule)
+    }
+
+    #[tokio::test]
+    async fn status_override() {
+        let schedule = FaultSchedule::new(0)
+            .with(
+                FaultTarget::CborRecv,
+                FaultTrigger::Nth(0),
+                Fault::Status(CtapError::ChannelBusy),
+            )
+            .unwrap();
+        let mut channel = channel(schedule);
+        assert_eq!(
+            channel.ctap2_get_info().await.unwrap_err(),
+            Error::Ctap(CtapError::ChannelBusy)
+        );
+        assert!(channel.ctap2_get_info().await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn garbage_cbor() {
+        let schedule = FaultSchedule::new(0)
+            .with(
+                FaultTarget::CborRecv,
+                FaultTrigger::Always,
+                Fault::GarbageCbor,
+            )
+            .unwrap();
+        let mut channel = channel(schedule);
+        assert_eq!(
+            channel.ctap2_get_info().await.unwrap_err(),
+            Error::Ctap(CtapError::InvalidCbor)
+        );
+    }
+
+    #[tokio::test]
+    async fn duplicated_packet() {
+        let schedule = FaultSchedule::new(0)
+            .with(
+                FaultTarget::CborRecv,
+                FaultTrigger::Always,
+                Fault::DuplicatePacket(1),
+            )
+            .unwrap();
+        let mut channel = channel(schedule);
+        let request: Ctap2MakeCredentialRequest = (&MakeCredentialRequest::dummy()).into();
+        // The parser does not check sequence numbers, so the message is reassembled with
+        // the repeated continuation and fails to decode.
+        assert_eq!(
+            channel
+                .ctap2_make_credential(&request, TIMEOUT)
+                .await
+                .unwrap_err(),
+            Error::Ctap(CtapError::InvalidCbor)
+        );
+    }
+
+    #[tokio::test]
+    async fn disconnect_is_permanent() {
+        let schedule = FaultSchedule::new(0)
+            .with(
+                FaultTarget::CborSend,
+                FaultTrigger::Nth(1),
+                Fault::Disconnect,
+            )
+            .unwrap();
+        let mut channel = channel(schedule);
+        assert!(channel.ctap2_get_info().await.is_ok());
+        for _ in 0..2 {
+            assert_eq!(
+                channel.ctap2_get_info().await.unwrap_err(),
+                Error::Transport(TransportError::ConnectionLost)
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn register_preflight_error_is_ignored() {
+        let register = |registered_keys| {
+            Ctap1RegisterRequest::new_u2f_v2(
+                "example.org",
+                &[0; 32],
+                registered_keys,
+                TIMEOUT,
+                true,
+            )
+        };
+
+        // The second APDU response is the preflight for the already-registered key handle.
+        let schedule = FaultSchedule::new(0)
+            .with(
+                FaultTarget::ApduRecv,
+                FaultTrigger::Nth(1),
+                Fault::ApduStatus(ApduResponseStatus::InvalidKeyHandle),
+            )
+            .unwrap();
+        let mut channel = channel(schedule);
+        let response = channel.ctap1_register(&register(vec![])).await.unwrap();
+        let excluded = vec![Ctap1RegisteredKey::new_u2f_v2(&response.key_handle)];
+        assert!(channel
+            .ctap1_register(&register(excluded.clone()))
+            .await
+            .is_ok());
+        assert_eq!(
+            channel
+                .ctap1_register(&register(excluded))
+                .await
+                .unwrap_err(),
+            Error::Ctap(CtapError::CredentialExcluded)
+        );
+    }
+
+    #[tokio::test]
+    async fn schedule_is_deterministic() {
+        let schedule = FaultSchedule::new(42)
+            .with(
+                FaultTarget::CborRecv,
+                FaultTrigger::Probability(0.5),
+                Fault::Status(CtapError::ChannelBusy),
+            )
+            .unwrap();
+        let mut first = channel(schedule.clone());
+        let mut second = channel(schedule);
+        for _ in 0..16 {
+            let _ = first.ctap2_get_info().await;
+            let _ = second.ctap2_get_info().await;
+        }
+        assert!(!first.injected().is_empty());
+        assert_eq!(first.injected(), second.injected());
+    }
+
+    #[test]
+    fn invalid_probability_is_rejected() {
+        for p in [-0.1, 1.5, f64::NAN] {
+            let result = FaultSchedule::new(0).with(
+                FaultTarget::CborRecv,
+                FaultTrigger::Probability(p),
+                Fault::GarbageCbor,
+            );
+            assert!(matches!(result, Err(InvalidProbability(_))));
+        }
+        assert!(FaultSchedule::new(0)
+            .with(
+                FaultTarget::CborRecv,
+                FaultTrigger::Probability(1.0),
+                Fault::GarbageCbor,
+            )
+            .is_ok());
+    }
+}
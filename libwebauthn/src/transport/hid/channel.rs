@@ -45,18 +45,18 @@ enum OpenHidDevice {
     VirtualDevice,
 }
 
-pub struct HidChannel<'d> {
+pub struct HidChannel {
     status: ChannelStatus,
-    device: &'d HidDevice,
+    device: HidDevice,
     open_device: OpenHidDevice,
     init: InitResponse,
 }
 
-impl<'d> HidChannel<'d> {
-    pub async fn new(device: &'d HidDevice) -> Result<HidChannel<'d>, Error> {
+impl HidChannel {
+    pub async fn new(device: &HidDevice) -> Result<HidChannel, Error> {
         let mut channel = Self {
             status: ChannelStatus::Ready,
-            device,
+            device: device.clone(),
             open_device: match device.backend {
                 HidBackendDevice::HidApiDevice(_) => {
                     let hidapi_device = Self::hid_open(device)?;
@@ -147,7 +147,7 @@ impl<'d> HidChannel<'d> {
     /*
     #[instrument(level = Level::DEBUG, skip_all)]
     async fn hid_transact(
-        device: &'d HidDevice,
+        device: &HidDevice,
         msg: &HidMessage,
         timeout: Duration,
     ) -> Result<HidMessage, Error> {
@@ -165,7 +165,7 @@ impl<'d> HidChannel<'d> {
 
     /*
     async fn hid_transact_hidapi(
-        device: &'d HidDevice,
+        device: &HidDevice,
         msg: &HidMessage,
         timeout: Duration,
     ) -> Result<HidMessage, Error> {
@@ -334,7 +334,7 @@ impl<'d> HidChannel<'d> {
     }
 }
 
-impl Drop for HidChannel<'_> {
+impl Drop for HidChannel {
     #[instrument(level = Level::DEBUG, skip_all, fields(dev = %self.device))]
     fn drop(&mut self) {
         #[cfg(feature = "virtual-hid-device")]
@@ -351,14 +351,14 @@ impl Drop for HidChannel<'_> {
     }
 }
 
-impl Display for HidChannel<'_> {
+impl Display for HidChannel {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         Display::fmt(&self.device, f)
     }
 }
 
 #[async_trait]
-impl Channel for HidChannel<'_> {
+impl Channel for HidChannel {
     async fn supported_protocols(&self) -> Result<SupportedProtocols, Error> {
         let cbor_supported = self.init.caps.contains(Caps::CBOR);
         let apdu_supported = !self.init.caps.contains(Caps::NO_MSG);
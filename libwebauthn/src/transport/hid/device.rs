@@ -1,4 +1,6 @@
 use std::fmt;
+#[cfg(feature = "virtual-hid-device")]
+use std::sync::Arc;
 use std::time::Duration;
 
 use async_trait::async_trait;
@@ -17,16 +19,17 @@ use crate::transport::device::SupportedProtocols;
 use crate::transport::error::{Error, TransportError};
 use crate::transport::{Channel, Device};
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct HidDevice {
     pub backend: HidBackendDevice,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum HidBackendDevice {
     HidApiDevice(DeviceInfo),
+    // Shared with every channel opened on the device, the key is stopped once all are dropped.
     #[cfg(feature = "virtual-hid-device")]
-    VirtualDevice(SoloVirtualKey),
+    VirtualDevice(Arc<SoloVirtualKey>),
 }
 
 impl From<&DeviceInfo> for HidDevice {
@@ -57,6 +60,26 @@ pub(crate) fn get_hidapi() -> Result<HidApi, Error> {
     HidApi::new().or(Err(Error::Transport(TransportError::TransportUnavailable)))
 }
 
+// Only present once the kernel's hidraw driver is loaded.
+#[cfg(not(feature = "virtual-hid-device"))]
+const HIDRAW_CLASS_PATH: &str = "/sys/class/hidraw";
+
+#[cfg(feature = "virtual-hid-device")]
+pub async fn is_available() -> bool {
+    true
+}
+
+/// Whether hidraw devices can be enumerated.
+#[cfg(not(feature = "virtual-hid-device"))]
+#[instrument]
+pub async fn is_available() -> bool {
+    if !std::path::Path::new(HIDRAW_CLASS_PATH).exists() {
+        debug!("hidraw is not available");
+        return false;
+    }
+    get_hidapi().is_ok()
+}
+
 #[cfg(feature = "virtual-hid-device")]
 #[instrument]
 pub async fn list_devices() -> Result<Vec<HidDevice>, Error> {
@@ -84,7 +107,7 @@ impl HidDevice {
     pub fn new_virtual() -> Self {
         let solo = SoloVirtualKey::default();
         Self {
-            backend: HidBackendDevice::VirtualDevice(solo),
+            backend: HidBackendDevice::VirtualDevice(Arc::new(solo)),
         }
     }
 
@@ -96,8 +119,8 @@ impl HidDevice {
 }
 
 #[async_trait]
-impl<'d> Device<'d, Hid, HidChannel<'d>> for HidDevice {
-    async fn channel(&'d mut self) -> Result<HidChannel<'d>, Error> {
+impl Device<Hid, HidChannel> for HidDevice {
+    async fn channel(&mut self) -> Result<HidChannel, Error> {
         let channel = HidChannel::new(self).await?;
         Ok(channel)
     }
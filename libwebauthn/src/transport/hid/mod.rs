@@ -5,7 +5,7 @@ pub mod device;
 pub mod framing;
 pub mod init;
 
-pub use device::{list_devices, HidDevice};
+pub use device::{is_available, list_devices, HidDevice};
 
 use super::Transport;
 
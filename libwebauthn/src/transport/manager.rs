@@ -0,0 +1,244 @@
+//! A single entry point to the authenticators reachable through every supported transport, so
+//! that consumers do not need one code path per transport.
+
+use std::env;
+use std::ffi::OsString;
+use std::fmt::{Display, Formatter};
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use futures::future::join_all;
+use tokio::net::UnixStream;
+use tracing::{debug, info, instrument, warn};
+
+use crate::ops::webauthn::{GetAssertionRequest, ProbeResponse};
+use crate::transport::ble::{self, BleDevice, PairingProvider};
+use crate::transport::device::SupportedProtocols;
+use crate::transport::error::Error;
+use crate::transport::hid::{self, HidDevice};
+use crate::transport::{Channel, Device};
+use crate::webauthn::WebAuthn;
+use crate::Transport;
+
+// Default path of the pcsc-lite daemon socket, which can be overridden through the environment.
+const PCSCD_SOCKET_PATH: &str = "/run/pcscd/pcscd.comm";
+const PCSCD_SOCKET_PATH_ENV: &str = "PCSCLITE_CSOCK_NAME";
+
+/// An authenticator on any transport.
+#[derive(Debug)]
+pub enum AnyDevice {
+    Hid(HidDevice),
+    Ble(BleDevice),
+}
+
+impl AnyDevice {
+    pub fn transport(&self) -> Transport {
+        match self {
+            AnyDevice::Hid(_) => Transport::Usb,
+            AnyDevice::Ble(_) => Transport::Ble,
+        }
+    }
+
+    /// Human-readable name of the device, as reported by the device or the platform.
+    pub fn name(&self) -> String {
+        self.to_string()
+    }
+
+    /// Protocols supported by the device. This may require connecting to the device.
+    pub async fn supported_protocols(&mut self) -> Result<SupportedProtocols, Error> {
+        match self {
+            AnyDevice::Hid(device) => device.supported_protocols().await,
+            AnyDevice::Ble(device) => device.supported_protocols().await,
+        }
+    }
+
+    /// Opens a channel which owns its connection to the device, see `Device::channel`.
+    pub async fn channel(&mut self) -> Result<Box<dyn Channel>, Error> {
+        let channel: Box<dyn Channel> = match self {
+            AnyDevice::Hid(device) => Box::new(device.channel().await?),
+            AnyDevice::Ble(device) => Box::new(device.channel().await?),
+        };
+        Ok(channel)
+    }
+
+    /// Finds which credentials of the request's allowList the device holds, without requiring
+    /// user presence, see `WebAuthn::webauthn_probe`.
+    #[instrument(skip_all, fields(dev = %self))]
+    pub async fn probe(&mut self, op: &GetAssertionRequest) -> Result<ProbeResponse, Error> {
+        self.channel().await?.webauthn_probe(op).await
+    }
+}
+
+/// Probes all devices concurrently, returning the outcome for each device in order. Devices which
+/// fail to be probed may still be able to answer the ceremony, e.g. when they are not connected.
+pub async fn probe_devices(
+    devices: &mut [AnyDevice],
+    op: &GetAssertionRequest,
+) -> Vec<Result<ProbeResponse, Error>> {
+    let results = join_all(devices.iter_mut().map(|device| device.probe(op))).await;
+    for (device, result) in devices.iter().zip(&results) {
+        match result {
+            Ok(response) => debug!(%device, matches = response.has_match(), "Probed device"),
+            Err(err) => warn!(%device, ?err, "Failed to probe device"),
+        }
+    }
+    results
+}
+
+impl Display for AnyDevice {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AnyDevice::Hid(device) => Display::fmt(device, f),
+            AnyDevice::Ble(device) => Display::fmt(device, f),
+        }
+    }
+}
+
+impl From<HidDevice> for AnyDevice {
+    fn from(device: HidDevice) -> Self {
+        AnyDevice::Hid(device)
+    }
+}
+
+impl From<BleDevice> for AnyDevice {
+    fn from(device: BleDevice) -> Self {
+        AnyDevice::Ble(device)
+    }
+}
+
+/// Enumerates authenticators across all transports which are available at runtime.
+#[derive(Default)]
+pub struct DeviceManager {
+    pairing_provider: Option<Arc<dyn PairingProvider>>,
+}
+
+impl DeviceManager {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set on every BLE device listed from now on, see `BleDevice::set_pairing_provider`.
+    pub fn set_pairing_provider(&mut self, provider: Arc<dyn PairingProvider>) {
+        self.pairing_provider = Some(provider);
+    }
+
+    /// Transports which are usable on this system: hidraw is accessible, BlueZ is running with
+    /// an adapter, or the PC/SC daemon is running.
+    ///
+    /// NFC is reported so that users can be told about it, but there is no NFC transport yet:
+    /// `list_devices` never returns NFC devices.
+    #[instrument(skip_all)]
+    pub async fn available_transports(&self) -> Vec<Transport> {
+        let (usb, ble, nfc) = tokio::join!(
+            hid::is_available(),
+            ble::is_available(),
+            is_pcscd_available(env::var_os(PCSCD_SOCKET_PATH_ENV))
+        );
+        let transports: Vec<_> = [
+            (Transport::Usb, usb),
+            (Transport::Ble, ble),
+            (Transport::Nfc, nfc),
+        ]
+        .into_iter()
+        .filter(|(_, available)| *available)
+        .map(|(transport, _)| transport)
+        .collect();
+        info!(?transports, "Available transports");
+        transports
+    }
+
+    /// Lists devices on all available transports. A transport failing to list its devices is
+    /// skipped, rather than failing the whole listing.
+    #[instrument(skip_all)]
+    pub async fn list_devices(&self) -> Vec<AnyDevice> {
+        let mut devices: Vec<AnyDevice> = vec![];
+        for transport in self.available_transports().await {
+            match self.list_transport_devices(transport).await {
+                Ok(transport_devices) => devices.extend(transport_devices),
+                Err(err) => warn!(?transport, ?err, "Failed to list devices"),
+            }
+        }
+        info!({ count = devices.len() }, "Listing available devices");
+        devices
+    }
+
+    async fn list_transport_devices(&self, transport: Transport) -> Result<Vec<AnyDevice>, Error> {
+        let devices = match transport {
+            Transport::Usb => hid::list_devices()
+                .await?
+                .into_iter()
+                .map(AnyDevice::from)
+                .collect(),
+            Transport::Ble => ble::list_devices()
+                .await?
+                .into_iter()
+                .map(|mut device| {
+                    if let Some(provider) = &self.pairing_provider {
+                        device.set_pairing_provider(provider.clone());
+                    }
+                    AnyDevice::from(device)
+                })
+                .collect(),
+            Transport::Nfc => {
+                debug!("NFC devices are not supported yet");
+                vec![]
+            }
+        };
+        Ok(devices)
+    }
+}
+
+/// Whether the PC/SC daemon is listening, on the socket path taken from `PCSCLITE_CSOCK_NAME`
+/// if given, or on the default one.
+async fn is_pcscd_available(socket_path_env: Option<OsString>) -> bool {
+    let path = socket_path_env
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from(PCSCD_SOCKET_PATH));
+    let available = UnixStream::connect(&path).await.is_ok();
+    debug!(?path, available, "Checked for the PC/SC daemon");
+    available
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::net::UnixListener;
+
+    use super::is_pcscd_available;
+    use crate::authenticator::store::CredentialStore;
+    use crate::authenticator::{SoftwareAuthenticator, SoftwareChannel};
+    use crate::ops::webauthn::MakeCredentialRequest;
+    use crate::pin::{PinProvider, StaticPinProvider};
+    use crate::transport::Channel;
+    use crate::webauthn::WebAuthn;
+
+    #[tokio::test]
+    async fn channel_moves_into_spawned_task() {
+        let mut channel: Box<dyn Channel> = Box::new(SoftwareChannel::new(
+            SoftwareAuthenticator::new(CredentialStore::temporary()),
+        ));
+        let pin_provider: Box<dyn PinProvider> = Box::new(StaticPinProvider::new("1234"));
+
+        let response = tokio::spawn(async move {
+            channel
+                .webauthn_make_credential(&MakeCredentialRequest::dummy(), &pin_provider)
+                .await
+        })
+        .await
+        .unwrap();
+        assert!(response.is_ok());
+    }
+
+    #[tokio::test]
+    async fn pcscd_socket_from_environment() {
+        let dir = std::env::temp_dir().join(format!("pcscd-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("pcscd.comm");
+        let _ = std::fs::remove_file(&path);
+
+        assert!(!is_pcscd_available(Some(path.clone().into())).await);
+        let _listener = UnixListener::bind(&path).unwrap();
+        assert!(is_pcscd_available(Some(path.into())).await);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
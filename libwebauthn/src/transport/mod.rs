@@ -2,11 +2,16 @@ pub(crate) mod error;
 
 pub mod ble;
 pub mod device;
+pub mod fault;
 pub mod hid;
+pub mod manager;
+pub mod remote;
+pub mod transcript;
 
 mod channel;
 mod transport;
 
-pub use channel::Channel;
+pub use channel::{Channel, ChannelStatus};
 pub use device::Device;
+pub use manager::{probe_devices, AnyDevice, DeviceManager};
 pub use transport::Transport;
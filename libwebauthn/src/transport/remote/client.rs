@@ -0,0 +1,485 @@
+use std::fmt::{Display, Formatter};
+use std::path::Path;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use serde_bytes::ByteBuf;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpStream, ToSocketAddrs, UnixStream};
+use tokio::sync::Mutex;
+use tokio::time::timeout as tokio_timeout;
+use tracing::{debug, info, instrument, trace, warn};
+
+use crate::proto::ctap1::apdu::{ApduRequest, ApduResponse};
+use crate::proto::ctap2::cbor::{CborRequest, CborResponse};
+use crate::proto::CtapError;
+use crate::secret::SecretBytes;
+use crate::transport::channel::{Channel, ChannelStatus};
+use crate::transport::device::SupportedProtocols;
+use crate::transport::error::{Error, TransportError};
+
+use super::protocol::{
+    challenge_response, read_message, write_message, RemoteDeviceInfo, RemoteMessage,
+    PROTOCOL_VERSION,
+};
+
+pub trait RemoteStream: AsyncRead + AsyncWrite + Unpin + Send {}
+impl<S> RemoteStream for S where S: AsyncRead + AsyncWrite + Unpin + Send {}
+
+/// A connection to a [`super::RemoteServer`], before a device has been selected.
+pub struct RemoteClient {
+    stream: Box<dyn RemoteStream>,
+}
+
+impl RemoteClient {
+    #[instrument(skip_all, fields(path = ?path.as_ref()))]
+    pub async fn connect_unix<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        let stream = UnixStream::connect(path)
+            .await
+            .or(Err(Error::Transport(TransportError::ConnectionFailed)))?;
+        Self::handshake(Box::new(stream)).await
+    }
+
+    /// Connects to a server over TCP, proving knowledge of the secret shared with it.
+    #[instrument(skip_all)]
+    pub async fn connect_tcp<A: ToSocketAddrs>(
+        addr: A,
+        secret: &SecretBytes,
+    ) -> Result<Self, Error> {
+        let stream = TcpStream::connect(addr)
+            .await
+            .or(Err(Error::Transport(TransportError::ConnectionFailed)))?;
+        let mut client = Self::handshake(Box::new(stream)).await?;
+        client.authenticate(secret).await?;
+        Ok(client)
+    }
+
+    /// Negotiates the protocol version over an already-connected stream.
+    pub async fn handshake(mut stream: Box<dyn RemoteStream>) -> Result<Self, Error> {
+        write_message(
+            &mut stream,
+            &RemoteMessage::Hello {
+                version: PROTOCOL_VERSION,
+            },
+        )
+        .await?;
+        match read_message(&mut stream).await? {
+            Some(RemoteMessage::HelloAck { version }) if version == PROTOCOL_VERSION => {
+                debug!({ version }, "Remote protocol negotiated");
+                Ok(Self { stream })
+            }
+            Some(RemoteMessage::HelloAck { version }) => {
+                warn!({ version }, "Server replied with an unsupported version");
+                Err(Error::Transport(TransportError::NegotiationFailed))
+            }
+            Some(RemoteMessage::Error(code)) => {
+                warn!(?code, "Server rejected hello");
+                Err(code.into())
+            }
+            _ => Err(Error::Transport(TransportError::NegotiationFailed)),
+        }
+    }
+
+    /// Answers the server's challenge with the shared secret.
+    pub async fn authenticate(&mut self, secret: &SecretBytes) -> Result<(), Error> {
+        let nonce = match expect_message(&mut self.stream).await? {
+            RemoteMessage::Challenge { nonce } => nonce,
+            other => return unexpected(other),
+        };
+        let mac = ByteBuf::from(challenge_response(secret, &nonce));
+        write_message(&mut self.stream, &RemoteMessage::Authenticate { mac }).await?;
+        match expect_message(&mut self.stream).await? {
+            RemoteMessage::Authenticated => {
+                debug!("Authenticated to remote server");
+                Ok(())
+            }
+            other => unexpected(other),
+        }
+    }
+
+    #[instrument(skip_all)]
+    pub async fn list_devices(&mut self) -> Result<Vec<RemoteDeviceInfo>, Error> {
+        write_message(&mut self.stream, &RemoteMessage::ListDevices).await?;
+        match expect_message(&mut self.stream).await? {
+            RemoteMessage::Devices(devices) => {
+                info!(
+                    { count = devices.len() },
+                    "Listing available remote devices"
+                );
+                Ok(devices)
+            }
+            other => unexpected(other),
+        }
+    }
+
+    /// Selects a device on the server, turning this connection into a channel to it.
+    #[instrument(skip(self))]
+    pub async fn select(mut self, device: &RemoteDeviceInfo) -> Result<RemoteChannel, Error> {
+        write_message(
+            &mut self.stream,
+            &RemoteMessage::SelectDevice {
+                index: device.index,
+            },
+        )
+        .await?;
+        match expect_message(&mut self.stream).await? {
+            RemoteMessage::DeviceSelected { u2f, fido2 } => Ok(RemoteChannel {
+                status: ChannelStatus::Ready,
+                name: device.name.clone(),
+                protocols: SupportedProtocols { u2f, fido2 },
+                connection: Mutex::new(Connection {
+                    stream: self.stream,
+                    last_id: 0,
+                }),
+            }),
+            other => unexpected(other),
+        }
+    }
+}
+
+/// Client side of a forwarded authenticator, usable anywhere a local channel would be.
+pub struct RemoteChannel {
+    status: ChannelStatus,
+    name: String,
+    protocols: SupportedProtocols,
+    connection: Mutex<Connection>,
+}
+
+struct Connection {
+    stream: Box<dyn RemoteStream>,
+    /// Id of the last request sent, the only one whose response is still awaited.
+    last_id: u32,
+}
+
+impl RemoteChannel {
+    async fn send<F>(&self, request: F) -> Result<(), Error>
+    where
+        F: FnOnce(u32) -> RemoteMessage,
+    {
+        let mut connection = self.connection.lock().await;
+        connection.last_id = connection.last_id.wrapping_add(1);
+        let message = request(connection.last_id);
+        write_message(&mut connection.stream, &message).await
+    }
+
+    async fn recv(&self, timeout: Duration) -> Result<RemoteMessage, Error> {
+        let mut connection = self.connection.lock().await;
+        let Connection { stream, last_id } = &mut *connection;
+        // The server enforces the timeout on the device itself, allow some slack for the link.
+        let message = tokio_timeout(timeout * 2, expect_response(stream, *last_id))
+            .await
+            .or(Err(Error::Transport(TransportError::Timeout)))??;
+        match message {
+            RemoteMessage::Error(code) | RemoteMessage::RequestFailed { error: code, .. } => {
+                warn!(?code, "Remote request failed");
+                Err(code.into())
+            }
+            message => Ok(message),
+        }
+    }
+}
+
+impl Display for RemoteChannel {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} (remote)", self.name)
+    }
+}
+
+#[async_trait]
+impl Channel for RemoteChannel {
+    async fn supported_protocols(&self) -> Result<SupportedProtocols, Error> {
+        Ok(self.protocols)
+    }
+
+    async fn status(&self) -> ChannelStatus {
+        self.status
+    }
+
+    async fn close(&self) {}
+
+    async fn apdu_send(&self, request: &ApduRequest, timeout: Duration) -> Result<(), Error> {
+        debug!("Sending APDU request to remote device");
+        trace!(?request);
+        self.send(|id| RemoteMessage::apdu(id, request, timeout))
+            .await
+    }
+
+    async fn apdu_recv(&self, timeout: Duration) -> Result<ApduResponse, Error> {
+        match self.recv(timeout).await? {
+            RemoteMessage::ApduResponse { data, .. } => {
+                let apdu_response = ApduResponse::try_from(&data.into_vec())
+                    .or(Err(Error::Transport(TransportError::InvalidFraming)))?;
+                debug!("Received APDU response from remote device");
+                trace!(?apdu_response);
+                Ok(apdu_response)
+            }
+            other => unexpected(other),
+        }
+    }
+
+    async fn cbor_send(&self, request: &CborRequest, timeout: Duration) -> Result<(), Error> {
+        debug!("Sending CBOR request to remote device");
+        trace!(?request);
+        self.send(|id| RemoteMessage::cbor(id, request, timeout))
+            .await
+    }
+
+    async fn cbor_recv(&self, timeout: Duration) -> Result<CborResponse, Error> {
+        match self.recv(timeout).await? {
+            RemoteMessage::CborResponse { status, data, .. } => {
+                let status_code = CtapError::try_from(status)
+                    .or(Err(Error::Transport(TransportError::InvalidFraming)))?;
+                let cbor_response = CborResponse {
+                    status_code,
+                    data: data.map(|data| data.into_vec()),
+                };
+                debug!(
+                    { status = ?cbor_response.status_code },
+                    "Received CBOR response from remote device"
+                );
+                trace!(?cbor_response);
+                Ok(cbor_response)
+            }
+            other => unexpected(other),
+        }
+    }
+}
+
+async fn expect_message<S>(stream: &mut S) -> Result<RemoteMessage, Error>
+where
+    S: AsyncRead + Unpin,
+{
+    read_message(stream)
+        .await?
+        .ok_or(Error::Transport(TransportError::ConnectionLost))
+}
+
+/// Reads until the response to request `id`, discarding late responses to earlier requests
+/// which timed out.
+async fn expect_response<S>(stream: &mut S, id: u32) -> Result<RemoteMessage, Error>
+where
+    S: AsyncRead + Unpin,
+{
+    loop {
+        let message = expect_message(stream).await?;
+        match message.response_id() {
+            Some(response_id) if response_id != id => {
+                debug!(
+                    response_id,
+                    id, "Discarding late response to an earlier request"
+                )
+            }
+            _ => return Ok(message),
+        }
+    }
+}
+
+fn unexpected<T>(message: RemoteMessage) -> Result<T, Error> {
+    match message {
+        RemoteMessage::Error(code) => {
+            warn!(?code, "Remote request failed");
+            Err(code.into())
+        }
+        other => {
+            warn!(?other, "Unexpected message from server");
+            Err(Error::Transport(TransportError::InvalidFraming))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fmt::{Display, Formatter};
+    use std::time::Duration;
+
+    use async_trait::async_trait;
+
+    use crate::proto::ctap1::apdu::{ApduRequest, ApduResponse};
+    use crate::proto::ctap2::cbor::{CborRequest, CborResponse};
+    use crate::proto::ctap2::Ctap2CommandCode;
+    use crate::proto::CtapError;
+    use crate::secret::SecretBytes;
+    use crate::transport::channel::{Channel, ChannelStatus};
+    use crate::transport::device::SupportedProtocols;
+    use crate::transport::error::{Error, TransportError};
+    use crate::transport::remote::protocol::{
+        read_message, write_message, CommandAllowlist, RemoteDeviceInfo, RemoteMessage,
+        RemoteTransport,
+    };
+    use crate::transport::remote::server::{accept_hello, authenticate, relay};
+    use crate::transport::remote::RemoteClient;
+
+    /// Answers every CBOR request with an empty successful response.
+    struct EchoChannel;
+
+    impl Display for EchoChannel {
+        fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+            write!(f, "echo")
+        }
+    }
+
+    #[async_trait]
+    impl Channel for EchoChannel {
+        async fn supported_protocols(&self) -> Result<SupportedProtocols, Error> {
+            Ok(SupportedProtocols::fido2_only())
+        }
+        async fn status(&self) -> ChannelStatus {
+            ChannelStatus::Ready
+        }
+        async fn close(&self) {}
+        async fn apdu_send(&self, _: &ApduRequest, _: Duration) -> Result<(), Error> {
+            Ok(())
+        }
+        async fn apdu_recv(&self, _: Duration) -> Result<ApduResponse, Error> {
+            Ok(ApduResponse::new_success(&[]))
+        }
+        async fn cbor_send(&self, _: &CborRequest, _: Duration) -> Result<(), Error> {
+            Ok(())
+        }
+        async fn cbor_recv(&self, _: Duration) -> Result<CborResponse, Error> {
+            Ok(CborResponse {
+                status_code: CtapError::Ok,
+                data: Some(vec![0xA0]),
+            })
+        }
+    }
+
+    async fn connect(allowlist: CommandAllowlist) -> crate::transport::remote::RemoteChannel {
+        let (client, mut server) = tokio::io::duplex(4096);
+        tokio::spawn(async move {
+            let channel = EchoChannel;
+            accept_hello(&mut server).await.unwrap();
+            assert_eq!(
+                read_message(&mut server).await.unwrap(),
+                Some(RemoteMessage::SelectDevice { index: 0 })
+            );
+            let selected = RemoteMessage::device_selected(&SupportedProtocols::fido2_only());
+            write_message(&mut server, &selected).await.unwrap();
+            relay(&mut server, &channel, &allowlist, Duration::from_secs(5))
+                .await
+                .unwrap();
+        });
+
+        let device = RemoteDeviceInfo {
+            index: 0,
+            name: String::from("echo"),
+            transport: RemoteTransport::Hid,
+        };
+        let client = RemoteClient::handshake(Box::new(client)).await.unwrap();
+        client.select(&device).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn cbor_request_is_relayed() {
+        let channel = connect(CommandAllowlist::default()).await;
+        let timeout = Duration::from_secs(1);
+        let request = CborRequest::new(Ctap2CommandCode::AuthenticatorGetInfo);
+        channel.cbor_send(&request, timeout).await.unwrap();
+        let response = channel.cbor_recv(timeout).await.unwrap();
+        assert_eq!(response.status_code, CtapError::Ok);
+        assert_eq!(response.data, Some(vec![0xA0]));
+    }
+
+    #[tokio::test]
+    async fn disallowed_command_is_rejected() {
+        let channel = connect(CommandAllowlist::default()).await;
+        let timeout = Duration::from_secs(1);
+        let request = CborRequest::new(Ctap2CommandCode::AuthenticatorMakeCredential);
+        channel.cbor_send(&request, timeout).await.unwrap();
+        assert_eq!(
+            channel.cbor_recv(timeout).await.unwrap_err(),
+            Error::Ctap(CtapError::InvalidCommand)
+        );
+    }
+
+    #[tokio::test]
+    async fn authentication() {
+        let (client, mut server) = tokio::io::duplex(4096);
+        let secret = SecretBytes::from(vec![0x42; 32]);
+        let server_secret = secret.clone();
+        let server = tokio::spawn(async move {
+            accept_hello(&mut server).await.unwrap();
+            authenticate(&mut server, &server_secret).await
+        });
+
+        let mut client = RemoteClient::handshake(Box::new(client)).await.unwrap();
+        client.authenticate(&secret).await.unwrap();
+        assert!(server.await.unwrap().is_ok());
+    }
+
+    #[tokio::test]
+    async fn wrong_secret_is_rejected() {
+        let (client, mut server) = tokio::io::duplex(4096);
+        let server = tokio::spawn(async move {
+            accept_hello(&mut server).await.unwrap();
+            authenticate(&mut server, &SecretBytes::from(vec![0x42; 32])).await
+        });
+
+        let mut client = RemoteClient::handshake(Box::new(client)).await.unwrap();
+        assert_eq!(
+            client
+                .authenticate(&SecretBytes::from(vec![0x43; 32]))
+                .await
+                .unwrap_err(),
+            Error::Transport(TransportError::NegotiationFailed)
+        );
+        assert!(server.await.unwrap().is_err());
+    }
+
+    #[tokio::test]
+    async fn late_response_is_discarded() {
+        let (client, mut server) = tokio::io::duplex(4096);
+        tokio::spawn(async move {
+            accept_hello(&mut server).await.unwrap();
+            read_message(&mut server).await.unwrap();
+            let selected = RemoteMessage::device_selected(&SupportedProtocols::fido2_only());
+            write_message(&mut server, &selected).await.unwrap();
+
+            // Only answers the first request once the second one has been sent.
+            for _ in 0..2 {
+                read_message(&mut server).await.unwrap();
+            }
+            for (id, data) in [(1, 0xA1), (2, 0xA2)] {
+                let response = CborResponse {
+                    status_code: CtapError::Ok,
+                    data: Some(vec![data]),
+                };
+                let response = RemoteMessage::cbor_response(id, &response);
+                write_message(&mut server, &response).await.unwrap();
+            }
+        });
+
+        let device = RemoteDeviceInfo {
+            index: 0,
+            name: String::from("slow"),
+            transport: RemoteTransport::Hid,
+        };
+        let client = RemoteClient::handshake(Box::new(client)).await.unwrap();
+        let channel = client.select(&device).await.unwrap();
+        let request = CborRequest::new(Ctap2CommandCode::AuthenticatorGetInfo);
+        let timeout = Duration::from_millis(50);
+        channel.cbor_send(&request, timeout).await.unwrap();
+        assert_eq!(
+            channel.cbor_recv(timeout).await.unwrap_err(),
+            Error::Transport(TransportError::Timeout)
+        );
+
+        let timeout = Duration::from_secs(1);
+        channel.cbor_send(&request, timeout).await.unwrap();
+        let response = channel.cbor_recv(timeout).await.unwrap();
+        assert_eq!(response.data, Some(vec![0xA2]));
+    }
+
+    #[tokio::test]
+    async fn idle_client_is_disconnected() {
+        let (_client, mut server) = tokio::io::duplex(4096);
+        let result = relay(
+            &mut server,
+            &EchoChannel,
+            &CommandAllowlist::default(),
+            Duration::from_millis(10),
+        )
+        .await;
+        assert_eq!(result, Err(Error::Transport(TransportError::Timeout)));
+    }
+}
@@ -0,0 +1,12 @@
+//! Forwarding of authenticators over a Unix or TCP socket, in the style of ssh-agent: a
+//! [`RemoteServer`] owns the local devices, and [`RemoteChannel`] exposes one of them to a
+//! remote platform as a regular [`crate::transport::Channel`].
+
+pub mod protocol;
+
+mod client;
+mod server;
+
+pub use client::{RemoteChannel, RemoteClient, RemoteStream};
+pub use protocol::{CommandAllowlist, RemoteDeviceInfo, RemoteTransport};
+pub use server::{LocalDevice, RemoteServer};
@@ -0,0 +1,471 @@
+//! Wire format for forwarding authenticator traffic over a stream socket.
+//!
+//! Every message is a single frame: a 4-byte big-endian length, followed by the CBOR encoding
+//! of a [`RemoteMessage`]. A session always starts with the client sending [`RemoteMessage::Hello`]
+//! and the server answering with the version it will speak, after which the client lists the
+//! server's devices and selects one of them. From then on, the connection carries CTAP requests
+//! for the selected device only. Each request carries an id, which the server repeats in its
+//! response, so that a response arriving after the client gave up on it is not mistaken for the
+//! response to a later request.
+//!
+//! Over TCP, the server then sends a [`RemoteMessage::Challenge`], which the client answers with
+//! an HMAC-SHA256 of the nonce keyed with a secret shared out of band. The link is not encrypted,
+//! and should only cross untrusted networks inside a tunnel.
+
+use std::time::Duration;
+
+use hmac::{Hmac, Mac};
+use serde_bytes::ByteBuf;
+use serde_cbor::Value;
+use serde_derive::{Deserialize, Serialize};
+use sha2::Sha256;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tracing::{trace, warn};
+
+use crate::proto::ctap1::apdu::{ApduRequest, ApduResponse};
+use crate::proto::ctap2::cbor::{CborRequest, CborResponse};
+use crate::proto::ctap2::{Ctap2CommandCode, Ctap2PinUvAuthProtocolCommand};
+use crate::transport::device::SupportedProtocols;
+use crate::transport::error::{CtapError, Error, TransportError};
+
+pub const PROTOCOL_VERSION: u32 = 1;
+pub const MAX_FRAME_LEN: usize = 1024 * 1024;
+/// Shortest shared secret a TCP server accepts.
+pub const MIN_SECRET_LEN: usize = 16;
+pub const NONCE_LEN: usize = 32;
+
+const U2F_REGISTER: u8 = 0x01;
+const U2F_AUTHENTICATE: u8 = 0x02;
+const U2F_VERSION: u8 = 0x03;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum RemoteTransport {
+    Hid,
+    Ble,
+    Nfc,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RemoteDeviceInfo {
+    pub index: u32,
+    pub name: String,
+    pub transport: RemoteTransport,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum RemoteErrorCode {
+    UnsupportedVersion,
+    UnexpectedMessage,
+    UnknownDevice,
+    NoDeviceSelected,
+    CommandNotAllowed,
+    Timeout,
+    DeviceError,
+    AuthenticationFailed,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum RemoteMessage {
+    Hello {
+        version: u32,
+    },
+    HelloAck {
+        version: u32,
+    },
+    Challenge {
+        nonce: ByteBuf,
+    },
+    Authenticate {
+        mac: ByteBuf,
+    },
+    Authenticated,
+    ListDevices,
+    Devices(Vec<RemoteDeviceInfo>),
+    SelectDevice {
+        index: u32,
+    },
+    DeviceSelected {
+        u2f: bool,
+        fido2: bool,
+    },
+    Cbor {
+        id: u32,
+        command: u8,
+        data: ByteBuf,
+        timeout_ms: u64,
+    },
+    CborResponse {
+        id: u32,
+        status: u8,
+        data: Option<ByteBuf>,
+    },
+    Apdu {
+        id: u32,
+        ins: u8,
+        p1: u8,
+        p2: u8,
+        data: Option<ByteBuf>,
+        response_max_length: Option<u64>,
+        timeout_ms: u64,
+    },
+    ApduResponse {
+        id: u32,
+        data: ByteBuf,
+    },
+    /// A request with the given id failed.
+    RequestFailed {
+        id: u32,
+        error: RemoteErrorCode,
+    },
+    Error(RemoteErrorCode),
+}
+
+impl RemoteMessage {
+    pub fn cbor(id: u32, request: &CborRequest, timeout: Duration) -> Self {
+        Self::Cbor {
+            id,
+            command: request.command as u8,
+            data: ByteBuf::from(request.encoded_data.clone()),
+            timeout_ms: timeout.as_millis() as u64,
+        }
+    }
+
+    pub fn cbor_response(id: u32, response: &CborResponse) -> Self {
+        Self::CborResponse {
+            id,
+            status: response.status_code.into(),
+            data: response.data.clone().map(ByteBuf::from),
+        }
+    }
+
+    pub fn apdu(id: u32, request: &ApduRequest, timeout: Duration) -> Self {
+        Self::Apdu {
+            id,
+            ins: request.ins,
+            p1: request.p1,
+            p2: request.p2,
+            data: request.data.clone().map(ByteBuf::from),
+            response_max_length: request.response_max_length.map(|le| le as u64),
+            timeout_ms: timeout.as_millis() as u64,
+        }
+    }
+
+    pub fn apdu_response(id: u32, response: &ApduResponse) -> Self {
+        Self::ApduResponse {
+            id,
+            data: ByteBuf::from(response.raw()),
+        }
+    }
+
+    /// The id of the request this message answers, if it is a response.
+    pub fn response_id(&self) -> Option<u32> {
+        match self {
+            Self::CborResponse { id, .. }
+            | Self::ApduResponse { id, .. }
+            | Self::RequestFailed { id, .. } => Some(*id),
+            _ => None,
+        }
+    }
+
+    pub fn device_selected(protocols: &SupportedProtocols) -> Self {
+        Self::DeviceSelected {
+            u2f: protocols.u2f,
+            fido2: protocols.fido2,
+        }
+    }
+}
+
+impl From<RemoteErrorCode> for Error {
+    fn from(code: RemoteErrorCode) -> Self {
+        match code {
+            RemoteErrorCode::UnsupportedVersion | RemoteErrorCode::AuthenticationFailed => {
+                Error::Transport(TransportError::NegotiationFailed)
+            }
+            RemoteErrorCode::UnknownDevice => Error::Transport(TransportError::InvalidEndpoint),
+            RemoteErrorCode::CommandNotAllowed => Error::Ctap(CtapError::InvalidCommand),
+            RemoteErrorCode::Timeout => Error::Transport(TransportError::Timeout),
+            RemoteErrorCode::DeviceError => Error::Transport(TransportError::ConnectionLost),
+            RemoteErrorCode::UnexpectedMessage | RemoteErrorCode::NoDeviceSelected => {
+                Error::Transport(TransportError::InvalidFraming)
+            }
+        }
+    }
+}
+
+type HmacSha256 = Hmac<Sha256>;
+
+fn challenge_mac(secret: &[u8], nonce: &[u8]) -> HmacSha256 {
+    let mut mac = HmacSha256::new_from_slice(secret).expect("Any key size is valid");
+    mac.update(nonce);
+    mac
+}
+
+/// Proves knowledge of the shared secret, in answer to the server's challenge.
+pub fn challenge_response(secret: &[u8], nonce: &[u8]) -> Vec<u8> {
+    challenge_mac(secret, nonce)
+        .finalize()
+        .into_bytes()
+        .to_vec()
+}
+
+/// Checks a client's answer to a challenge, in constant time.
+pub fn verify_challenge_response(secret: &[u8], nonce: &[u8], response: &[u8]) -> bool {
+    challenge_mac(secret, nonce).verify_slice(response).is_ok()
+}
+
+/// Which CTAP commands a server is willing to forward to its devices.
+#[derive(Debug, Clone)]
+pub struct CommandAllowlist {
+    pub ctap2: Vec<Ctap2CommandCode>,
+    /// Allowed ClientPin subcommands, if ClientPin itself is allowed.
+    pub client_pin: Vec<Ctap2PinUvAuthProtocolCommand>,
+    /// Allowed U2F instructions (the APDU INS byte).
+    pub ctap1: Vec<u8>,
+}
+
+impl CommandAllowlist {
+    pub fn allow_all() -> Self {
+        Self {
+            ctap2: vec![
+                Ctap2CommandCode::AuthenticatorMakeCredential,
+                Ctap2CommandCode::AuthenticatorGetAssertion,
+                Ctap2CommandCode::AuthenticatorGetInfo,
+                Ctap2CommandCode::AuthenticatorClientPin,
+                Ctap2CommandCode::AuthenticatorGetNextAssertion,
+                Ctap2CommandCode::AuthenticatorSelection,
+            ],
+            client_pin: vec![
+                Ctap2PinUvAuthProtocolCommand::GetPinRetries,
+                Ctap2PinUvAuthProtocolCommand::GetKeyAgreement,
+                Ctap2PinUvAuthProtocolCommand::SetPin,
+                Ctap2PinUvAuthProtocolCommand::ChangePin,
+                Ctap2PinUvAuthProtocolCommand::GetPinToken,
+                Ctap2PinUvAuthProtocolCommand::GetPinUvAuthTokenUsingUvWithPermissions,
+                Ctap2PinUvAuthProtocolCommand::GetUvRetries,
+                Ctap2PinUvAuthProtocolCommand::GetPinUvAuthTokenUsingPinWithPermissions,
+            ],
+            ctap1: vec![U2F_REGISTER, U2F_AUTHENTICATE, U2F_VERSION],
+        }
+    }
+
+    pub fn is_cbor_allowed(&self, command: u8, data: &[u8]) -> bool {
+        self.cbor_command(command, data).is_some()
+    }
+
+    /// The command of an allowed CTAP2 request. ClientPin requests are also checked against the
+    /// allowed subcommands, so their parameters must be well-formed.
+    pub(crate) fn cbor_command(&self, command: u8, data: &[u8]) -> Option<Ctap2CommandCode> {
+        let command = self
+            .ctap2
+            .iter()
+            .copied()
+            .find(|allowed| *allowed as u8 == command)?;
+        if command != Ctap2CommandCode::AuthenticatorClientPin {
+            return Some(command);
+        }
+        let subcommand = client_pin_subcommand(data)?;
+        self.client_pin
+            .iter()
+            .any(|allowed| allowed.clone() as i128 == subcommand)
+            .then_some(command)
+    }
+
+    pub fn is_apdu_allowed(&self, ins: u8) -> bool {
+        self.ctap1.contains(&ins)
+    }
+}
+
+impl Default for CommandAllowlist {
+    /// Only allows assertions and the commands needed to obtain them: ClientPin is limited to
+    /// obtaining tokens, so that the PIN can not be set or changed. Registration has to be enabled
+    /// explicitly.
+    fn default() -> Self {
+        Self {
+            ctap2: vec![
+                Ctap2CommandCode::AuthenticatorGetAssertion,
+                Ctap2CommandCode::AuthenticatorGetNextAssertion,
+                Ctap2CommandCode::AuthenticatorGetInfo,
+                Ctap2CommandCode::AuthenticatorClientPin,
+                Ctap2CommandCode::AuthenticatorSelection,
+            ],
+            client_pin: vec![
+                Ctap2PinUvAuthProtocolCommand::GetPinRetries,
+                Ctap2PinUvAuthProtocolCommand::GetKeyAgreement,
+                Ctap2PinUvAuthProtocolCommand::GetPinToken,
+                Ctap2PinUvAuthProtocolCommand::GetPinUvAuthTokenUsingUvWithPermissions,
+                Ctap2PinUvAuthProtocolCommand::GetUvRetries,
+                Ctap2PinUvAuthProtocolCommand::GetPinUvAuthTokenUsingPinWithPermissions,
+            ],
+            ctap1: vec![U2F_AUTHENTICATE, U2F_VERSION],
+        }
+    }
+}
+
+/// The subCommand (0x02) of ClientPin parameters.
+fn client_pin_subcommand(data: &[u8]) -> Option<i128> {
+    let Ok(Value::Map(parameters)) = serde_cbor::from_slice(data) else {
+        warn!("Rejecting malformed ClientPin parameters");
+        return None;
+    };
+    match parameters.get(&Value::Integer(0x02)) {
+        Some(Value::Integer(subcommand)) => Some(*subcommand),
+        _ => {
+            warn!("Rejecting ClientPin parameters without a subcommand");
+            None
+        }
+    }
+}
+
+pub async fn write_message<S>(stream: &mut S, message: &RemoteMessage) -> Result<(), Error>
+where
+    S: AsyncWrite + Unpin,
+{
+    trace!(?message, "Sending remote message");
+    let encoded = serde_cbor::to_vec(message).unwrap();
+    if encoded.len() > MAX_FRAME_LEN {
+        warn!({ len = encoded.len() }, "Remote message is too large");
+        return Err(Error::Transport(TransportError::InvalidFraming));
+    }
+
+    let mut frame = (encoded.len() as u32).to_be_bytes().to_vec();
+    frame.extend(encoded);
+    stream
+        .write_all(&frame)
+        .await
+        .or(Err(Error::Transport(TransportError::ConnectionLost)))?;
+    stream
+        .flush()
+        .await
+        .or(Err(Error::Transport(TransportError::ConnectionLost)))
+}
+
+/// Reads the next message, returning `None` if the peer closed the connection cleanly.
+pub async fn read_message<S>(stream: &mut S) -> Result<Option<RemoteMessage>, Error>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut len = [0u8; 4];
+    match stream.read_exact(&mut len).await {
+        Ok(_) => {}
+        Err(err) if err.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(_) => return Err(Error::Transport(TransportError::ConnectionLost)),
+    }
+
+    let len = u32::from_be_bytes(len) as usize;
+    if len > MAX_FRAME_LEN {
+        warn!({ len }, "Remote frame exceeds the maximum length");
+        return Err(Error::Transport(TransportError::InvalidFraming));
+    }
+
+    let mut encoded = vec![0u8; len];
+    stream
+        .read_exact(&mut encoded)
+        .await
+        .or(Err(Error::Transport(TransportError::ConnectionLost)))?;
+    let message = serde_cbor::from_slice(&encoded)
+        .or(Err(Error::Transport(TransportError::InvalidFraming)))?;
+    trace!(?message, "Received remote message");
+    Ok(Some(message))
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_bytes::ByteBuf;
+
+    use crate::proto::ctap2::cbor::CborRequest;
+    use crate::proto::ctap2::{
+        Ctap2ClientPinRequest, Ctap2CommandCode, Ctap2PinUvAuthProtocolCommand,
+    };
+    use crate::transport::error::{Error, TransportError};
+    use crate::transport::remote::protocol::{
+        challenge_response, read_message, verify_challenge_response, write_message,
+        CommandAllowlist, RemoteMessage, MAX_FRAME_LEN,
+    };
+
+    #[tokio::test]
+    async fn message_roundtrip() {
+        let (mut client, mut server) = tokio::io::duplex(4096);
+        let message = RemoteMessage::Cbor {
+            id: 1,
+            command: 0x04,
+            data: ByteBuf::from(vec![0xA0]),
+            timeout_ms: 1000,
+        };
+        write_message(&mut client, &message).await.unwrap();
+        write_message(&mut client, &RemoteMessage::ListDevices)
+            .await
+            .unwrap();
+        drop(client);
+
+        assert_eq!(read_message(&mut server).await.unwrap(), Some(message));
+        assert_eq!(
+            read_message(&mut server).await.unwrap(),
+            Some(RemoteMessage::ListDevices)
+        );
+        assert_eq!(read_message(&mut server).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn oversized_frame_rejected() {
+        use tokio::io::AsyncWriteExt;
+
+        let (mut client, mut server) = tokio::io::duplex(64);
+        client
+            .write_all(&((MAX_FRAME_LEN + 1) as u32).to_be_bytes())
+            .await
+            .unwrap();
+        assert_eq!(
+            read_message(&mut server).await,
+            Err(Error::Transport(TransportError::InvalidFraming))
+        );
+    }
+
+    #[test]
+    fn default_allowlist_blocks_registration() {
+        let allowlist = CommandAllowlist::default();
+        assert!(allowlist.is_cbor_allowed(Ctap2CommandCode::AuthenticatorGetAssertion as u8, &[]));
+        assert!(
+            !allowlist.is_cbor_allowed(Ctap2CommandCode::AuthenticatorMakeCredential as u8, &[])
+        );
+        assert!(!allowlist.is_apdu_allowed(0x01));
+        assert!(CommandAllowlist::allow_all().is_apdu_allowed(0x01));
+    }
+
+    #[test]
+    fn default_allowlist_blocks_pin_changes() {
+        let client_pin = Ctap2CommandCode::AuthenticatorClientPin as u8;
+        let request = |subcommand| {
+            let request = Ctap2ClientPinRequest {
+                command: subcommand,
+                ..Ctap2ClientPinRequest::new_get_pin_retries()
+            };
+            CborRequest::from(&request).encoded_data
+        };
+        let allowlist = CommandAllowlist::default();
+        assert!(allowlist.is_cbor_allowed(
+            client_pin,
+            &request(Ctap2PinUvAuthProtocolCommand::GetPinRetries)
+        ));
+        assert!(
+            !allowlist.is_cbor_allowed(client_pin, &request(Ctap2PinUvAuthProtocolCommand::SetPin))
+        );
+        assert!(!allowlist.is_cbor_allowed(
+            client_pin,
+            &request(Ctap2PinUvAuthProtocolCommand::ChangePin)
+        ));
+        assert!(!allowlist.is_cbor_allowed(client_pin, &[]));
+        assert!(CommandAllowlist::allow_all()
+            .is_cbor_allowed(client_pin, &request(Ctap2PinUvAuthProtocolCommand::SetPin)));
+    }
+
+    #[test]
+    fn challenge_response_requires_secret() {
+        let secret = [0x42; 32];
+        let nonce = [0x01; 32];
+        let response = challenge_response(&secret, &nonce);
+        assert!(verify_challenge_response(&secret, &nonce, &response));
+        assert!(!verify_challenge_response(&[0x43; 32], &nonce, &response));
+        assert!(!verify_challenge_response(&secret, &[0x02; 32], &response));
+        assert!(!verify_challenge_response(&secret, &nonce, &[]));
+    }
+}
@@ -0,0 +1,400 @@
+use std::fs::{self, Permissions};
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::path::Path;
+use std::time::Duration;
+
+use rand::rngs::OsRng;
+use rand::RngCore;
+use serde_bytes::ByteBuf;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpListener, ToSocketAddrs, UnixListener};
+use tokio::time::timeout as tokio_timeout;
+use tracing::{debug, info, instrument, warn};
+
+use crate::proto::ctap1::apdu::ApduRequest;
+use crate::proto::ctap2::cbor::CborRequest;
+use crate::secret::SecretBytes;
+use crate::transport::error::{Error, TransportError};
+use crate::transport::{AnyDevice, Channel};
+use crate::Transport;
+
+use super::protocol::{
+    read_message, verify_challenge_response, write_message, CommandAllowlist, RemoteDeviceInfo,
+    RemoteErrorCode, RemoteMessage, RemoteTransport, MIN_SECRET_LEN, NONCE_LEN, PROTOCOL_VERSION,
+};
+
+pub type LocalDevice = AnyDevice;
+
+const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Owns local authenticators and relays CTAP traffic to them on behalf of remote clients.
+///
+/// Connections are served one at a time, as an authenticator can only take part in a single
+/// ceremony at once. Clients which stay silent for longer than the idle timeout are
+/// disconnected, so that they can not keep the others waiting.
+pub struct RemoteServer {
+    devices: Vec<LocalDevice>,
+    allowlist: CommandAllowlist,
+    idle_timeout: Duration,
+}
+
+impl RemoteServer {
+    pub fn new(devices: Vec<LocalDevice>, allowlist: CommandAllowlist) -> Self {
+        Self {
+            devices,
+            allowlist,
+            idle_timeout: DEFAULT_IDLE_TIMEOUT,
+        }
+    }
+
+    /// Sets how long a client may stay silent, which also bounds how long a single request may
+    /// keep a device busy.
+    pub fn with_idle_timeout(mut self, idle_timeout: Duration) -> Self {
+        self.idle_timeout = idle_timeout;
+        self
+    }
+
+    /// Serves clients connecting to a Unix socket at `path`. The socket is only accessible to
+    /// the current user, and connections from other users are rejected.
+    #[instrument(skip_all, fields(path = ?path.as_ref()))]
+    pub async fn serve_unix<P: AsRef<Path>>(&mut self, path: P) -> Result<(), Error> {
+        let listener = UnixListener::bind(&path)
+            .or(Err(Error::Transport(TransportError::TransportUnavailable)))?;
+        fs::set_permissions(&path, Permissions::from_mode(0o600))
+            .or(Err(Error::Transport(TransportError::TransportUnavailable)))?;
+        let owner = fs::metadata(&path)
+            .or(Err(Error::Transport(TransportError::TransportUnavailable)))?
+            .uid();
+        loop {
+            let (stream, _) = listener
+                .accept()
+                .await
+                .or(Err(Error::Transport(TransportError::ConnectionFailed)))?;
+            match stream.peer_cred() {
+                Ok(cred) if cred.uid() == owner => {}
+                cred => {
+                    warn!(?cred, "Rejecting connection from another user");
+                    continue;
+                }
+            }
+            self.serve_connection(stream).await;
+        }
+    }
+
+    /// Serves clients connecting over TCP, which must prove knowledge of `secret`. The traffic
+    /// itself is not encrypted.
+    #[instrument(skip_all)]
+    pub async fn serve_tcp<A: ToSocketAddrs>(
+        &mut self,
+        addr: A,
+        secret: SecretBytes,
+    ) -> Result<(), Error> {
+        if secret.len() < MIN_SECRET_LEN {
+            warn!(
+                min_len = MIN_SECRET_LEN,
+                "Refusing to serve TCP with a short shared secret"
+            );
+            return Err(Error::Transport(TransportError::TransportUnavailable));
+        }
+        let listener = TcpListener::bind(addr)
+            .await
+            .or(Err(Error::Transport(TransportError::TransportUnavailable)))?;
+        loop {
+            let (mut stream, peer) = listener
+                .accept()
+                .await
+                .or(Err(Error::Transport(TransportError::ConnectionFailed)))?;
+            info!(%peer, "Accepted remote connection");
+            match self.session(&mut stream, Some(&secret)).await {
+                Ok(()) => debug!("Remote session ended"),
+                Err(err) => warn!(?err, "Remote session failed"),
+            }
+        }
+    }
+
+    /// Serves a single client over an already-connected, trusted stream.
+    #[instrument(skip_all)]
+    pub async fn serve_connection<S>(&mut self, mut stream: S)
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send,
+    {
+        match self.session(&mut stream, None).await {
+            Ok(()) => debug!("Remote session ended"),
+            Err(err) => warn!(?err, "Remote session failed"),
+        }
+    }
+
+    async fn session<S>(
+        &mut self,
+        stream: &mut S,
+        secret: Option<&SecretBytes>,
+    ) -> Result<(), Error>
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send,
+    {
+        let handshake = async {
+            accept_hello(stream).await?;
+            match secret {
+                Some(secret) => authenticate(stream, secret).await,
+                None => Ok(()),
+            }
+        };
+        tokio_timeout(self.idle_timeout, handshake)
+            .await
+            .or(Err(Error::Transport(TransportError::Timeout)))??;
+
+        loop {
+            let Some(message) = read_idle(stream, self.idle_timeout).await? else {
+                return Ok(());
+            };
+            match message {
+                RemoteMessage::ListDevices => {
+                    let devices = self.device_info();
+                    write_message(stream, &RemoteMessage::Devices(devices)).await?;
+                }
+                RemoteMessage::SelectDevice { index } => {
+                    let Some(device) = self.devices.get_mut(index as usize) else {
+                        warn!({ index }, "Client selected an unknown device");
+                        reply_error(stream, RemoteErrorCode::UnknownDevice).await?;
+                        continue;
+                    };
+                    info!(%device, "Client selected device");
+                    let channel = open(device.channel().await, stream).await?;
+                    return relay(stream, &channel, &self.allowlist, self.idle_timeout).await;
+                }
+                RemoteMessage::Cbor { .. } | RemoteMessage::Apdu { .. } => {
+                    reply_error(stream, RemoteErrorCode::NoDeviceSelected).await?;
+                }
+                other => {
+                    warn!(?other, "Unexpected message before device selection");
+                    reply_error(stream, RemoteErrorCode::UnexpectedMessage).await?;
+                }
+            }
+        }
+    }
+
+    fn device_info(&self) -> Vec<RemoteDeviceInfo> {
+        self.devices
+            .iter()
+            .enumerate()
+            .map(|(index, device)| RemoteDeviceInfo {
+                index: index as u32,
+                name: device.name(),
+                transport: match device.transport() {
+                    Transport::Usb => RemoteTransport::Hid,
+                    Transport::Ble => RemoteTransport::Ble,
+                    Transport::Nfc => RemoteTransport::Nfc,
+                },
+            })
+            .collect()
+    }
+}
+
+pub(crate) async fn accept_hello<S>(stream: &mut S) -> Result<(), Error>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    match read_message(stream).await? {
+        Some(RemoteMessage::Hello { version }) if version >= PROTOCOL_VERSION => {
+            debug!({ version }, "Client hello accepted");
+            write_message(
+                stream,
+                &RemoteMessage::HelloAck {
+                    version: PROTOCOL_VERSION,
+                },
+            )
+            .await
+        }
+        Some(RemoteMessage::Hello { version }) => {
+            warn!({ version }, "Client protocol version is not supported");
+            reply_error(stream, RemoteErrorCode::UnsupportedVersion).await?;
+            Err(Error::Transport(TransportError::NegotiationFailed))
+        }
+        _ => {
+            warn!("Client did not start with a hello");
+            reply_error(stream, RemoteErrorCode::UnexpectedMessage).await?;
+            Err(Error::Transport(TransportError::NegotiationFailed))
+        }
+    }
+}
+
+/// Challenges the client to prove that it knows the shared secret.
+pub(crate) async fn authenticate<S>(stream: &mut S, secret: &SecretBytes) -> Result<(), Error>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut nonce = vec![0u8; NONCE_LEN];
+    OsRng.fill_bytes(&mut nonce);
+    let challenge = RemoteMessage::Challenge {
+        nonce: ByteBuf::from(nonce.clone()),
+    };
+    write_message(stream, &challenge).await?;
+    match read_message(stream).await? {
+        Some(RemoteMessage::Authenticate { mac })
+            if verify_challenge_response(secret, &nonce, &mac) =>
+        {
+            debug!("Client authenticated");
+            write_message(stream, &RemoteMessage::Authenticated).await
+        }
+        _ => {
+            warn!("Client failed to authenticate");
+            reply_error(stream, RemoteErrorCode::AuthenticationFailed).await?;
+            Err(Error::Transport(TransportError::NegotiationFailed))
+        }
+    }
+}
+
+async fn open<S, C>(channel: Result<C, Error>, stream: &mut S) -> Result<C, Error>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+    C: Channel,
+{
+    let channel = match channel {
+        Ok(channel) => channel,
+        Err(err) => {
+            warn!(?err, "Failed to open channel to local device");
+            reply_error(stream, RemoteErrorCode::DeviceError).await?;
+            return Err(err);
+        }
+    };
+    let protocols = channel.supported_protocols().await?;
+    write_message(stream, &RemoteMessage::device_selected(&protocols)).await?;
+    Ok(channel)
+}
+
+/// Reads the next message, giving up on clients which stay silent for too long.
+async fn read_idle<S>(
+    stream: &mut S,
+    idle_timeout: Duration,
+) -> Result<Option<RemoteMessage>, Error>
+where
+    S: AsyncRead + Unpin,
+{
+    tokio_timeout(idle_timeout, read_message(stream))
+        .await
+        .unwrap_or_else(|_| {
+            info!("Client has been idle for too long, disconnecting");
+            Err(Error::Transport(TransportError::Timeout))
+        })
+}
+
+/// Forwards requests to the channel until the client disconnects.
+#[instrument(skip_all, fields(dev = %channel))]
+pub(crate) async fn relay<S, C>(
+    stream: &mut S,
+    channel: &C,
+    allowlist: &CommandAllowlist,
+    idle_timeout: Duration,
+) -> Result<(), Error>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+    C: Channel,
+{
+    loop {
+        let Some(message) = read_idle(stream, idle_timeout).await? else {
+            return Ok(());
+        };
+        let (id, response) = match message {
+            RemoteMessage::Cbor {
+                id,
+                command,
+                data,
+                timeout_ms,
+            } => {
+                let Some(command) = allowlist.cbor_command(command, &data) else {
+                    warn!({ command }, "Rejecting CTAP2 command which is not allowed");
+                    reply_failed(stream, id, RemoteErrorCode::CommandNotAllowed).await?;
+                    continue;
+                };
+                let request = CborRequest {
+                    command,
+                    encoded_data: data.into_vec(),
+                };
+                let timeout = Duration::from_millis(timeout_ms).min(idle_timeout);
+                (id, forward_cbor(channel, id, &request, timeout).await)
+            }
+            RemoteMessage::Apdu {
+                id,
+                ins,
+                p1,
+                p2,
+                data,
+                response_max_length,
+                timeout_ms,
+            } => {
+                if !allowlist.is_apdu_allowed(ins) {
+                    warn!({ ins }, "Rejecting U2F instruction which is not allowed");
+                    reply_failed(stream, id, RemoteErrorCode::CommandNotAllowed).await?;
+                    continue;
+                }
+                let request = ApduRequest::new(
+                    ins,
+                    p1,
+                    p2,
+                    data.as_deref().map(Vec::as_slice),
+                    response_max_length.map(|le| le as usize),
+                );
+                let timeout = Duration::from_millis(timeout_ms).min(idle_timeout);
+                (id, forward_apdu(channel, id, &request, timeout).await)
+            }
+            other => {
+                warn!(?other, "Unexpected message after device selection");
+                reply_error(stream, RemoteErrorCode::UnexpectedMessage).await?;
+                continue;
+            }
+        };
+
+        let response = match response {
+            Ok(response) => response,
+            Err(Error::Transport(TransportError::Timeout)) => {
+                debug!({ id }, "Local device timed out");
+                RemoteMessage::RequestFailed {
+                    id,
+                    error: RemoteErrorCode::Timeout,
+                }
+            }
+            Err(err) => {
+                warn!(?err, "Local device failed, closing remote session");
+                reply_failed(stream, id, RemoteErrorCode::DeviceError).await?;
+                return Err(err);
+            }
+        };
+        write_message(stream, &response).await?;
+    }
+}
+
+async fn forward_cbor<C: Channel>(
+    channel: &C,
+    id: u32,
+    request: &CborRequest,
+    timeout: Duration,
+) -> Result<RemoteMessage, Error> {
+    channel.cbor_send(request, timeout).await?;
+    let response = channel.cbor_recv(timeout).await?;
+    Ok(RemoteMessage::cbor_response(id, &response))
+}
+
+async fn forward_apdu<C: Channel>(
+    channel: &C,
+    id: u32,
+    request: &ApduRequest,
+    timeout: Duration,
+) -> Result<RemoteMessage, Error> {
+    channel.apdu_send(request, timeout).await?;
+    let response = channel.apdu_recv(timeout).await?;
+    Ok(RemoteMessage::apdu_response(id, &response))
+}
+
+async fn reply_error<S>(stream: &mut S, code: RemoteErrorCode) -> Result<(), Error>
+where
+    S: AsyncWrite + Unpin,
+{
+    write_message(stream, &RemoteMessage::Error(code)).await
+}
+
+async fn reply_failed<S>(stream: &mut S, id: u32, error: RemoteErrorCode) -> Result<(), Error>
+where
+    S: AsyncWrite + Unpin,
+{
+    write_message(stream, &RemoteMessage::RequestFailed { id, error }).await
+}
@@ -0,0 +1,185 @@
+//! Recording and replay of channel traffic, so that issues seen with specific authenticators
+//! can be turned into regression tests that run without the hardware.
+//!
+//! A [`RecordingChannel`] wraps any [`crate::transport::Channel`] and captures every request and
+//! response into a [`Transcript`], which can be saved to disk. A [`ReplayChannel`] later serves
+//! the transcript back, panicking as soon as the platform sends a request which differs from the
+//! recorded one.
+
+mod recording;
+mod redact;
+mod replay;
+
+use std::fs::File;
+use std::io::{Error as IOError, ErrorKind as IOErrorKind, Write};
+use std::path::Path;
+
+use serde_bytes::ByteBuf;
+use serde_derive::{Deserialize, Serialize};
+use tracing::{debug, instrument, warn};
+
+use crate::transport::device::SupportedProtocols;
+use crate::transport::error::Error;
+
+pub use recording::{RecordingChannel, RecordingOptions};
+pub use replay::ReplayChannel;
+
+pub const TRANSCRIPT_FORMAT_VERSION: u32 = 1;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum TranscriptEvent {
+    CborRequest {
+        command: u8,
+        data: ByteBuf,
+    },
+    CborResponse {
+        status: u8,
+        data: Option<ByteBuf>,
+    },
+    ApduRequest {
+        ins: u8,
+        p1: u8,
+        p2: u8,
+        data: Option<ByteBuf>,
+        response_max_length: Option<u64>,
+    },
+    ApduResponse {
+        data: ByteBuf,
+    },
+    /// A request which the recorded channel failed to send.
+    SendError(Error),
+    /// A response which the recorded channel failed to receive.
+    RecvError(Error),
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TranscriptEntry {
+    /// Time elapsed since the previous entry, or since the start of the recording.
+    pub elapsed_ms: u64,
+    pub event: TranscriptEvent,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Transcript {
+    pub version: u32,
+    /// Display name of the recorded device.
+    pub device: String,
+    pub protocols: Option<SupportedProtocols>,
+    /// Whether PIN-derived fields were replaced with zeroes while recording.
+    pub redacted: bool,
+    pub entries: Vec<TranscriptEntry>,
+}
+
+impl Transcript {
+    pub fn new(device: &str, redacted: bool) -> Self {
+        Self {
+            version: TRANSCRIPT_FORMAT_VERSION,
+            device: device.to_owned(),
+            protocols: None,
+            redacted,
+            entries: vec![],
+        }
+    }
+
+    pub fn to_vec(&self) -> Vec<u8> {
+        serde_cbor::to_vec(self).unwrap()
+    }
+
+    pub fn from_slice(data: &[u8]) -> Result<Self, IOError> {
+        let transcript: Self = serde_cbor::from_slice(data).or(Err(IOError::new(
+            IOErrorKind::InvalidData,
+            "Invalid transcript",
+        )))?;
+        if transcript.version != TRANSCRIPT_FORMAT_VERSION {
+            warn!(
+                { version = transcript.version },
+                "Unsupported transcript version"
+            );
+            return Err(IOError::new(
+                IOErrorKind::InvalidData,
+                format!("Unsupported transcript version: {}", transcript.version),
+            ));
+        }
+        Ok(transcript)
+    }
+
+    #[instrument(skip_all, fields(path = ?path.as_ref()))]
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), IOError> {
+        let mut file = File::create(path)?;
+        file.write_all(&self.to_vec())?;
+        debug!({ entries = self.entries.len() }, "Saved transcript");
+        Ok(())
+    }
+
+    #[instrument(skip_all, fields(path = ?path.as_ref()))]
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, IOError> {
+        let data = std::fs::read(path)?;
+        let transcript = Self::from_slice(&data)?;
+        debug!({ entries = transcript.entries.len() }, "Loaded transcript");
+        Ok(transcript)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::authenticator::store::CredentialStore;
+    use crate::authenticator::{SoftwareAuthenticator, SoftwareChannel};
+    use crate::ops::webauthn::MakeCredentialRequest;
+    use crate::proto::ctap1::Ctap1;
+    use crate::proto::ctap2::{Ctap2, Ctap2MakeCredentialRequest};
+    use crate::transport::transcript::{
+        RecordingChannel, RecordingOptions, ReplayChannel, Transcript,
+    };
+
+    const TIMEOUT: Duration = Duration::from_secs(1);
+
+    fn software_channel() -> SoftwareChannel {
+        SoftwareChannel::new(SoftwareAuthenticator::new(CredentialStore::temporary()))
+    }
+
+    async fn record() -> Transcript {
+        let mut channel = RecordingChannel::new(software_channel(), RecordingOptions::default());
+        channel.ctap2_get_info().await.unwrap();
+        let request: Ctap2MakeCredentialRequest = (&MakeCredentialRequest::dummy()).into();
+        channel
+            .ctap2_make_credential(&request, TIMEOUT)
+            .await
+            .unwrap();
+        channel.ctap1_version().await.unwrap();
+        channel.transcript()
+    }
+
+    #[tokio::test]
+    async fn transcript_roundtrip() {
+        let transcript = record().await;
+        assert_eq!(transcript.entries.len(), 6);
+        assert_eq!(
+            Transcript::from_slice(&transcript.to_vec()).unwrap(),
+            transcript
+        );
+    }
+
+    #[tokio::test]
+    async fn replay_matching_requests() {
+        let transcript = record().await;
+        let mut channel = ReplayChannel::new(transcript.clone());
+        channel.ctap2_get_info().await.unwrap();
+        let request: Ctap2MakeCredentialRequest = (&MakeCredentialRequest::dummy()).into();
+        channel
+            .ctap2_make_credential(&request, TIMEOUT)
+            .await
+            .unwrap();
+        channel.ctap1_version().await.unwrap();
+        channel.assert_finished();
+    }
+
+    #[tokio::test]
+    #[should_panic(expected = "diverged")]
+    async fn replay_diverging_request() {
+        let transcript = record().await;
+        let mut channel = ReplayChannel::new(transcript);
+        channel.ctap1_version().await.unwrap();
+    }
+}
@@ -0,0 +1,171 @@
+use std::fmt::{Display, Formatter};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+use serde_bytes::ByteBuf;
+use tracing::{debug, trace};
+
+use crate::proto::ctap1::apdu::{ApduRequest, ApduResponse};
+use crate::proto::ctap2::cbor::{CborRequest, CborResponse};
+use crate::transport::channel::{Channel, ChannelStatus};
+use crate::transport::device::SupportedProtocols;
+use crate::transport::error::Error;
+
+use super::redact::{redact_request, redact_response};
+use super::{Transcript, TranscriptEntry, TranscriptEvent};
+
+#[derive(Debug, Clone, Copy)]
+pub struct RecordingOptions {
+    /// Replace PIN-derived fields (pinHashEnc, newPinEnc, pinUvAuthParam and pinUvAuthToken)
+    /// with zeroes, so that transcripts can be shared without leaking PIN material.
+    pub redact_pin: bool,
+}
+
+impl Default for RecordingOptions {
+    fn default() -> Self {
+        Self { redact_pin: true }
+    }
+}
+
+struct RecordingState {
+    transcript: Transcript,
+    last_event: Instant,
+    last_command: Option<u8>,
+}
+
+/// Wraps a channel, capturing all traffic into a [`Transcript`].
+pub struct RecordingChannel<C: Channel> {
+    inner: C,
+    options: RecordingOptions,
+    state: Mutex<RecordingState>,
+}
+
+impl<C: Channel> RecordingChannel<C> {
+    pub fn new(inner: C, options: RecordingOptions) -> Self {
+        let transcript = Transcript::new(&inner.to_string(), options.redact_pin);
+        Self {
+            inner,
+            options,
+            state: Mutex::new(RecordingState {
+                transcript,
+                last_event: Instant::now(),
+                last_command: None,
+            }),
+        }
+    }
+
+    /// Returns a copy of everything recorded so far.
+    pub fn transcript(&self) -> Transcript {
+        self.state.lock().unwrap().transcript.clone()
+    }
+
+    pub fn into_inner(self) -> (C, Transcript) {
+        let transcript = self.state.into_inner().unwrap().transcript;
+        (self.inner, transcript)
+    }
+
+    fn record(&self, event: TranscriptEvent) {
+        let mut state = self.state.lock().unwrap();
+        let now = Instant::now();
+        let elapsed_ms = now.duration_since(state.last_event).as_millis() as u64;
+        state.last_event = now;
+        trace!(elapsed_ms, ?event, "Recording event");
+        state
+            .transcript
+            .entries
+            .push(TranscriptEntry { elapsed_ms, event });
+    }
+
+    fn record_send_result(&self, result: &Result<(), Error>) {
+        if let Err(err) = result {
+            debug!(?err, "Recording send error");
+            self.record(TranscriptEvent::SendError(*err));
+        }
+    }
+}
+
+impl<C: Channel> Display for RecordingChannel<C> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} (recording)", self.inner)
+    }
+}
+
+#[async_trait]
+impl<C: Channel> Channel for RecordingChannel<C> {
+    async fn supported_protocols(&self) -> Result<SupportedProtocols, Error> {
+        let protocols = self.inner.supported_protocols().await?;
+        self.state.lock().unwrap().transcript.protocols = Some(protocols);
+        Ok(protocols)
+    }
+
+    async fn status(&self) -> ChannelStatus {
+        self.inner.status().await
+    }
+
+    async fn close(&self) {
+        self.inner.close().await
+    }
+
+    async fn apdu_send(&self, request: &ApduRequest, timeout: Duration) -> Result<(), Error> {
+        self.record(TranscriptEvent::ApduRequest {
+            ins: request.ins,
+            p1: request.p1,
+            p2: request.p2,
+            data: request.data.clone().map(ByteBuf::from),
+            response_max_length: request.response_max_length.map(|le| le as u64),
+        });
+        let result = self.inner.apdu_send(request, timeout).await;
+        self.record_send_result(&result);
+        result
+    }
+
+    async fn apdu_recv(&self, timeout: Duration) -> Result<ApduResponse, Error> {
+        let result = self.inner.apdu_recv(timeout).await;
+        match &result {
+            Ok(response) => self.record(TranscriptEvent::ApduResponse {
+                data: ByteBuf::from(response.raw()),
+            }),
+            Err(err) => self.record(TranscriptEvent::RecvError(*err)),
+        }
+        result
+    }
+
+    async fn cbor_send(&self, request: &CborRequest, timeout: Duration) -> Result<(), Error> {
+        let command = request.command as u8;
+        let data = if self.options.redact_pin {
+            redact_request(command, &request.encoded_data)
+        } else {
+            request.encoded_data.clone()
+        };
+        self.state.lock().unwrap().last_command = Some(command);
+        self.record(TranscriptEvent::CborRequest {
+            command,
+            data: ByteBuf::from(data),
+        });
+        let result = self.inner.cbor_send(request, timeout).await;
+        self.record_send_result(&result);
+        result
+    }
+
+    async fn cbor_recv(&self, timeout: Duration) -> Result<CborResponse, Error> {
+        let result = self.inner.cbor_recv(timeout).await;
+        match &result {
+            Ok(response) => {
+                let last_command = self.state.lock().unwrap().last_command;
+                let data = match (&response.data, last_command) {
+                    (Some(data), Some(command)) if self.options.redact_pin => {
+                        Some(redact_response(command, data))
+                    }
+                    (data, _) => data.clone(),
+                };
+                self.record(TranscriptEvent::CborResponse {
+                    status: response.status_code.into(),
+                    data: data.map(ByteBuf::from),
+                });
+            }
+            Err(err) => self.record(TranscriptEvent::RecvError(*err)),
+        }
+        result
+    }
+}
@@ -0,0 +1,113 @@
+use std::collections::BTreeMap;
+
+use serde_cbor::Value;
+
+use crate::proto::ctap2::Ctap2CommandCode;
+
+// pinUvAuthParam, newPinEnc and pinHashEnc
+const CLIENT_PIN_SECRET_KEYS: &[i128] = &[0x04, 0x05, 0x06];
+// keyAgreement: the platform's ephemeral public key, which differs on every run
+const CLIENT_PIN_KEY_AGREEMENT: i128 = 0x03;
+// pinUvAuthToken
+const CLIENT_PIN_RESPONSE_SECRET_KEYS: &[i128] = &[0x02];
+const MAKE_CREDENTIAL_PIN_AUTH_PARAM: i128 = 0x08;
+const GET_ASSERTION_PIN_AUTH_PARAM: i128 = 0x06;
+const CREDENTIAL_MANAGEMENT_PIN_AUTH_PARAM: i128 = 0x04;
+
+/// Replaces PIN-derived values in a CBOR request with zeroes of the same length.
+pub(crate) fn redact_request(command: u8, data: &[u8]) -> Vec<u8> {
+    map_fields(data, |map| {
+        for key in secret_request_keys(command) {
+            zero_bytes(map, *key);
+        }
+    })
+}
+
+/// Replaces PIN-derived values in a CBOR response with zeroes of the same length.
+pub(crate) fn redact_response(command: u8, data: &[u8]) -> Vec<u8> {
+    if command != Ctap2CommandCode::AuthenticatorClientPin as u8 {
+        return data.to_vec();
+    }
+    map_fields(data, |map| {
+        for key in CLIENT_PIN_RESPONSE_SECRET_KEYS {
+            zero_bytes(map, *key);
+        }
+    })
+}
+
+/// Normalizes a request for comparison, erasing both PIN-derived values and values which are
+/// randomly generated by the platform on every run.
+pub(crate) fn normalize_request(command: u8, data: &[u8]) -> Vec<u8> {
+    map_fields(data, |map| {
+        for key in secret_request_keys(command) {
+            zero_bytes(map, *key);
+        }
+        if command == Ctap2CommandCode::AuthenticatorClientPin as u8 {
+            if let Some(value) = map.get_mut(&Value::Integer(CLIENT_PIN_KEY_AGREEMENT)) {
+                *value = Value::Null;
+            }
+        }
+    })
+}
+
+fn secret_request_keys(command: u8) -> &'static [i128] {
+    match Ctap2CommandCode::try_from(command) {
+        Ok(Ctap2CommandCode::AuthenticatorClientPin) => CLIENT_PIN_SECRET_KEYS,
+        Ok(Ctap2CommandCode::AuthenticatorMakeCredential) => &[MAKE_CREDENTIAL_PIN_AUTH_PARAM],
+        Ok(Ctap2CommandCode::AuthenticatorGetAssertion) => &[GET_ASSERTION_PIN_AUTH_PARAM],
+        Ok(Ctap2CommandCode::AuthenticatorCredentialManagement) => {
+            &[CREDENTIAL_MANAGEMENT_PIN_AUTH_PARAM]
+        }
+        _ => &[],
+    }
+}
+
+fn map_fields<F>(data: &[u8], f: F) -> Vec<u8>
+where
+    F: FnOnce(&mut BTreeMap<Value, Value>),
+{
+    // Anything that is not a CBOR map is left untouched, and compared byte by byte.
+    let Ok(Value::Map(mut map)) = serde_cbor::from_slice(data) else {
+        return data.to_vec();
+    };
+    f(&mut map);
+    serde_cbor::to_vec(&Value::Map(map)).unwrap()
+}
+
+fn zero_bytes(map: &mut BTreeMap<Value, Value>, key: i128) {
+    if let Some(Value::Bytes(bytes)) = map.get_mut(&Value::Integer(key)) {
+        bytes.iter_mut().for_each(|byte| *byte = 0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use serde_cbor::Value;
+
+    use super::{normalize_request, redact_request};
+
+    fn client_pin_request(pin_hash_enc: u8, key_agreement: u8) -> Vec<u8> {
+        let mut map = BTreeMap::new();
+        map.insert(Value::Integer(0x01), Value::Integer(2));
+        map.insert(Value::Integer(0x02), Value::Integer(5));
+        map.insert(Value::Integer(0x03), Value::Bytes(vec![key_agreement; 4]));
+        map.insert(Value::Integer(0x06), Value::Bytes(vec![pin_hash_enc; 16]));
+        serde_cbor::to_vec(&Value::Map(map)).unwrap()
+    }
+
+    #[test]
+    fn pin_hash_is_redacted() {
+        let redacted = redact_request(0x06, &client_pin_request(0xAA, 0x01));
+        assert_eq!(redacted, client_pin_request(0x00, 0x01));
+    }
+
+    #[test]
+    fn key_agreement_is_ignored_for_comparison() {
+        assert_eq!(
+            normalize_request(0x06, &client_pin_request(0xAA, 0x01)),
+            normalize_request(0x06, &client_pin_request(0xBB, 0x02))
+        );
+    }
+}
@@ -0,0 +1,200 @@
+use std::fmt::{Display, Formatter};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use serde_bytes::ByteBuf;
+use tokio::time::sleep;
+use tracing::{debug, error, trace};
+
+use crate::proto::ctap1::apdu::{ApduRequest, ApduResponse};
+use crate::proto::ctap2::cbor::{CborRequest, CborResponse};
+use crate::proto::CtapError;
+use crate::transport::channel::{Channel, ChannelStatus};
+use crate::transport::device::SupportedProtocols;
+use crate::transport::error::{Error, TransportError};
+
+use super::redact::normalize_request;
+use super::{Transcript, TranscriptEntry, TranscriptEvent};
+
+/// Serves a recorded [`Transcript`] back to the platform.
+///
+/// Every request is checked against the next recorded one, ignoring PIN-derived fields and
+/// the platform's ephemeral key agreement key. Any divergence is a bug in the test or in the
+/// platform, so it panics rather than returning an error which could be silently handled.
+pub struct ReplayChannel {
+    transcript: Transcript,
+    position: Mutex<usize>,
+    honour_timing: bool,
+}
+
+impl ReplayChannel {
+    pub fn new(transcript: Transcript) -> Self {
+        Self {
+            transcript,
+            position: Mutex::new(0),
+            honour_timing: false,
+        }
+    }
+
+    /// Sleeps before each response for as long as the recorded device took to respond.
+    pub fn with_timing(mut self, honour_timing: bool) -> Self {
+        self.honour_timing = honour_timing;
+        self
+    }
+
+    /// Panics if part of the transcript was not replayed.
+    pub fn assert_finished(&self) {
+        let position = *self.position.lock().unwrap();
+        assert_eq!(
+            position,
+            self.transcript.entries.len(),
+            "Replay finished early: {} of {} transcript entries were not replayed",
+            self.transcript.entries.len() - position,
+            self.transcript.entries.len()
+        );
+    }
+
+    fn next(&self) -> (usize, TranscriptEntry) {
+        let mut position = self.position.lock().unwrap();
+        let Some(entry) = self.transcript.entries.get(*position) else {
+            error!("Platform sent more requests than were recorded");
+            panic!(
+                "Replay diverged: transcript exhausted after {} entries",
+                *position
+            );
+        };
+        *position += 1;
+        trace!(?entry, "Replaying transcript entry");
+        (*position - 1, entry.clone())
+    }
+
+    fn diverged(&self, index: usize, expected: &TranscriptEvent, actual: &TranscriptEvent) -> ! {
+        error!(
+            index,
+            ?expected,
+            ?actual,
+            "Replayed request differs from recording"
+        );
+        panic!(
+            "Replay diverged at entry {}:\n  recorded: {:?}\n  received: {:?}",
+            index, expected, actual
+        );
+    }
+
+    /// Consumes the recorded outcome of a send operation, if it failed.
+    fn send_result(&self) -> Result<(), Error> {
+        let position = *self.position.lock().unwrap();
+        match self.transcript.entries.get(position) {
+            Some(TranscriptEntry {
+                event: TranscriptEvent::SendError(err),
+                ..
+            }) => {
+                self.next();
+                Err(*err)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    async fn next_response(&self) -> (usize, TranscriptEvent) {
+        let (index, entry) = self.next();
+        if self.honour_timing {
+            sleep(Duration::from_millis(entry.elapsed_ms)).await;
+        }
+        (index, entry.event)
+    }
+}
+
+impl Display for ReplayChannel {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} (replay)", self.transcript.device)
+    }
+}
+
+#[async_trait]
+impl Channel for ReplayChannel {
+    async fn supported_protocols(&self) -> Result<SupportedProtocols, Error> {
+        self.transcript
+            .protocols
+            .ok_or(Error::Transport(TransportError::NegotiationFailed))
+    }
+
+    async fn status(&self) -> ChannelStatus {
+        ChannelStatus::Ready
+    }
+
+    async fn close(&self) {}
+
+    async fn apdu_send(&self, request: &ApduRequest, _timeout: Duration) -> Result<(), Error> {
+        let actual = TranscriptEvent::ApduRequest {
+            ins: request.ins,
+            p1: request.p1,
+            p2: request.p2,
+            data: request.data.clone().map(ByteBuf::from),
+            response_max_length: request.response_max_length.map(|le| le as u64),
+        };
+        let (index, entry) = self.next();
+        if entry.event != actual {
+            self.diverged(index, &entry.event, &actual);
+        }
+        debug!({ index }, "Replayed APDU request");
+        self.send_result()
+    }
+
+    async fn apdu_recv(&self, _timeout: Duration) -> Result<ApduResponse, Error> {
+        match self.next_response().await {
+            (_, TranscriptEvent::ApduResponse { data }) => ApduResponse::try_from(&data.into_vec())
+                .or(Err(Error::Transport(TransportError::InvalidFraming))),
+            (_, TranscriptEvent::RecvError(err)) => Err(err),
+            (index, other) => panic!(
+                "Replay diverged at entry {}: expected an APDU response, found {:?}",
+                index, other
+            ),
+        }
+    }
+
+    async fn cbor_send(&self, request: &CborRequest, _timeout: Duration) -> Result<(), Error> {
+        let command = request.command as u8;
+        let (index, entry) = self.next();
+        let TranscriptEvent::CborRequest {
+            command: recorded_command,
+            data: recorded_data,
+        } = &entry.event
+        else {
+            let actual = TranscriptEvent::CborRequest {
+                command,
+                data: ByteBuf::from(request.encoded_data.clone()),
+            };
+            self.diverged(index, &entry.event, &actual);
+        };
+
+        if *recorded_command != command
+            || normalize_request(command, recorded_data)
+                != normalize_request(command, &request.encoded_data)
+        {
+            let actual = TranscriptEvent::CborRequest {
+                command,
+                data: ByteBuf::from(request.encoded_data.clone()),
+            };
+            self.diverged(index, &entry.event, &actual);
+        }
+        debug!({ index }, "Replayed CBOR request");
+        self.send_result()
+    }
+
+    async fn cbor_recv(&self, _timeout: Duration) -> Result<CborResponse, Error> {
+        match self.next_response().await {
+            (_, TranscriptEvent::CborResponse { status, data }) => Ok(CborResponse {
+                status_code: CtapError::try_from(status)
+                    .or(Err(Error::Transport(TransportError::InvalidFraming)))?,
+                data: data.map(|data| data.into_vec()),
+            }),
+            (_, TranscriptEvent::RecvError(err)) => Err(err),
+            (index, other) => panic!(
+                "Replay diverged at entry {}: expected a CBOR response, found {:?}",
+                index, other
+            ),
+        }
+    }
+}
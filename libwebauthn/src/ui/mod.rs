@@ -0,0 +1,88 @@
+//! User interface for FIDO2 and FIDO U2F ceremonies, for applications running ceremonies with
+//! this library directly rather than through the credentials portal.
+
+pub mod notification;
+pub mod strings;
+
+use std::time::Duration;
+
+use async_trait::async_trait;
+
+use crate::backend::{AccountDescription, DeviceDescription, Operation};
+use crate::pin::{Pin, PinRequest};
+use crate::Transport;
+
+pub use notification::NotificationPortalUI;
+
+/// The relying party, as presented to the user.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RelyingPartyInfo {
+    pub id: String,
+    pub name: Option<String>,
+}
+
+/// The account which a credential is created for, as presented to the user.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UserInfo {
+    pub name: Option<String>,
+    pub display_name: Option<String>,
+}
+
+/// The ceremony which the user interface is shown for.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CeremonyInfo {
+    pub operation: Operation,
+    /// Application running the ceremony, empty if unknown.
+    pub app_id: String,
+    pub relying_party: RelyingPartyInfo,
+    /// Only known when creating a credential.
+    pub user: Option<UserInfo>,
+    pub timeout: Duration,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DeviceChoice {
+    /// The `id` of one of the authenticators found.
+    Device(String),
+    /// A transport to look for authenticators on.
+    Transport(Transport),
+}
+
+/// What the user needs to do on the authenticator for the ceremony to proceed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UserAction {
+    Touch,
+    Fingerprint,
+}
+
+#[async_trait]
+pub trait UI: Send + Sync {
+    /// Presents the relying party and user, returning whether the user wants to proceed.
+    async fn present(&self, ceremony: &CeremonyInfo) -> bool;
+
+    /// Asks which of the authenticators found to use, or which transport to look for one on.
+    async fn choose_device(
+        &self,
+        ceremony: &CeremonyInfo,
+        devices: &[DeviceDescription],
+        transports: &[Transport],
+    ) -> Option<DeviceChoice>;
+
+    /// Collects a PIN. The request carries the authenticator's PIN policy, and whether previous
+    /// attempts failed.
+    async fn enter_pin(&self, ceremony: &CeremonyInfo, request: &PinRequest) -> Option<Pin>;
+
+    /// Asks the user to touch the authenticator or scan their fingerprint, for as long as the
+    /// returned future is not dropped. The future only completes if the user cancels.
+    async fn prompt_user_action(&self, ceremony: &CeremonyInfo, action: UserAction);
+
+    /// Asks which of the discoverable credentials to use, returning the index of the chosen one.
+    async fn choose_account(
+        &self,
+        ceremony: &CeremonyInfo,
+        accounts: &[AccountDescription],
+    ) -> Option<usize>;
+
+    /// Tells the user that the ceremony failed.
+    async fn show_error(&self, ceremony: &CeremonyInfo, message: &str);
+}
@@ -0,0 +1,418 @@
+//! A user interface made of desktop notifications, through `org.freedesktop.portal.Notification`.
+//!
+//! Notifications can only offer a few buttons, and can not collect text: PINs are left to a
+//! separate PIN provider, and longer lists of authenticators or accounts are offered a page at a
+//! time.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use dbus::arg::{PropMap, RefArg};
+use dbus::channel::Sender;
+use dbus::message::MatchRule;
+use dbus::nonblock::{MethodReply, MsgMatch, Proxy, SyncConnection};
+use dbus::Message;
+use futures::channel::mpsc::UnboundedReceiver;
+use futures::StreamExt;
+use tokio::time::{timeout_at, Instant};
+use tracing::{debug, instrument, warn};
+use uuid::Uuid;
+
+use super::strings::{Strings, Text};
+use super::{CeremonyInfo, DeviceChoice, UserAction, UI};
+use crate::backend::{dict, transport_name, AccountDescription, DeviceDescription};
+use crate::pin::{Pin, PinProvider, PinRequest};
+use crate::Transport;
+
+const PORTAL_BUS_NAME: &str = "org.freedesktop.portal.Desktop";
+const PORTAL_PATH: &str = "/org/freedesktop/portal/desktop";
+const NOTIFICATION_INTERFACE: &str = "org.freedesktop.portal.Notification";
+const DBUS_CALL_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Notification servers show few buttons, one of which is always Cancel.
+const MAX_CHOICES: usize = 2;
+
+const CONTINUE_ACTION: &str = "continue";
+const MORE_ACTION: &str = "more";
+const CANCEL_ACTION: &str = "cancel";
+const ICON: &str = "dialog-password"; // https://developer.gnome.org/icon-naming-spec/
+
+pub struct NotificationPortalUI {
+    connection: Arc<SyncConnection>,
+    strings: Strings,
+    pin_provider: Box<dyn PinProvider>,
+}
+
+impl NotificationPortalUI {
+    /// Shows notifications in the language of the user's locale, and asks for PINs through the
+    /// given provider.
+    pub fn new(connection: Arc<SyncConnection>, pin_provider: Box<dyn PinProvider>) -> Self {
+        Self::with_strings(connection, Strings::from_env(), pin_provider)
+    }
+
+    pub fn with_strings(
+        connection: Arc<SyncConnection>,
+        strings: Strings,
+        pin_provider: Box<dyn PinProvider>,
+    ) -> Self {
+        Self {
+            connection,
+            strings,
+            pin_provider,
+        }
+    }
+
+    fn proxy(&self) -> Proxy<'_, &SyncConnection> {
+        Proxy::new(
+            PORTAL_BUS_NAME,
+            PORTAL_PATH,
+            DBUS_CALL_TIMEOUT,
+            self.connection.as_ref(),
+        )
+    }
+
+    fn add_notification(
+        &self,
+        id: &str,
+        title: &str,
+        body: &str,
+        buttons: &[(String, String)],
+    ) -> MethodReply<()> {
+        let buttons: Vec<PropMap> = buttons
+            .iter()
+            .map(|(action, label)| {
+                dict(vec![
+                    ("action", Box::new(action.clone())),
+                    ("label", Box::new(label.clone())),
+                ])
+            })
+            .collect();
+        let mut options: Vec<(&str, Box<dyn RefArg>)> = vec![
+            ("title", Box::new(title.to_owned())),
+            ("body", Box::new(body.to_owned())),
+            ("priority", Box::new(String::from("urgent"))),
+            ("icon", Box::new(String::from(ICON))),
+        ];
+        if !buttons.is_empty() {
+            options.push(("default-action", Box::new(String::from(CANCEL_ACTION))));
+            options.push(("buttons", Box::new(buttons)));
+        }
+        self.proxy().method_call(
+            NOTIFICATION_INTERFACE,
+            "AddNotification",
+            (id, dict(options)),
+        )
+    }
+
+    /// Shows a notification, followed by a Cancel button.
+    async fn show(
+        &self,
+        title: &str,
+        body: &str,
+        mut buttons: Vec<(String, String)>,
+    ) -> Result<Notification<'_>, dbus::Error> {
+        buttons.push((
+            CANCEL_ACTION.to_owned(),
+            self.strings.get(Text::Cancel).to_owned(),
+        ));
+        let (actions_match, actions) = self
+            .connection
+            .add_match(MatchRule::new_signal(
+                NOTIFICATION_INTERFACE,
+                "ActionInvoked",
+            ))
+            .await?
+            .msg_stream();
+        let id = Uuid::new_v4().to_hyphenated().to_string();
+        let notification = Notification {
+            ui: self,
+            id,
+            actions_match: Some(actions_match),
+            actions,
+        };
+        self.add_notification(&notification.id, title, body, &buttons)
+            .await?;
+        debug!(id = %notification.id, "Showing notification");
+        Ok(notification)
+    }
+
+    /// Shows notifications offering the given choices, a page at a time, and waits for one to be
+    /// made.
+    async fn choose(
+        &self,
+        ceremony: &CeremonyInfo,
+        title: &str,
+        choices: Vec<(String, String)>,
+    ) -> Option<String> {
+        let body = self.ceremony_body(ceremony);
+        let deadline = Instant::now() + ceremony.timeout;
+        let mut pages = pages(choices, self.strings.get(Text::More))
+            .into_iter()
+            .cycle();
+        loop {
+            let page = pages.next()?;
+            let mut notification = match self.show(title, &body, page).await {
+                Ok(notification) => notification,
+                Err(err) => {
+                    warn!(%err, "Failed to show notification");
+                    return None;
+                }
+            };
+            match timeout_at(deadline, notification.action()).await {
+                Ok(Some(action)) if action == MORE_ACTION => continue,
+                Ok(Some(action)) if action != CANCEL_ACTION => return Some(action),
+                _ => return None,
+            }
+        }
+    }
+
+    fn ceremony_title(&self, ceremony: &CeremonyInfo) -> String {
+        let relying_party = &ceremony.relying_party;
+        let rp = relying_party.name.as_deref().unwrap_or(&relying_party.id);
+        self.strings
+            .format(Text::CeremonyTitle(ceremony.operation), &[("rp", rp)])
+    }
+
+    fn ceremony_body(&self, ceremony: &CeremonyInfo) -> String {
+        let app = if ceremony.app_id.is_empty() {
+            self.strings.get(Text::UnknownApp)
+        } else {
+            &ceremony.app_id
+        };
+        let rp = &ceremony.relying_party.id;
+        let user = ceremony
+            .user
+            .as_ref()
+            .and_then(|user| user.display_name.as_deref().or(user.name.as_deref()));
+        match user {
+            Some(user) => self.strings.format(
+                Text::CeremonyBodyForUser,
+                &[("app", app), ("rp", rp), ("user", user)],
+            ),
+            None => self
+                .strings
+                .format(Text::CeremonyBody, &[("app", app), ("rp", rp)]),
+        }
+    }
+}
+
+#[async_trait]
+impl UI for NotificationPortalUI {
+    #[instrument(skip_all, fields(rp = %ceremony.relying_party.id))]
+    async fn present(&self, ceremony: &CeremonyInfo) -> bool {
+        let continue_button = (
+            CONTINUE_ACTION.to_owned(),
+            self.strings.get(Text::Continue).to_owned(),
+        );
+        let title = self.ceremony_title(ceremony);
+        self.choose(ceremony, &title, vec![continue_button])
+            .await
+            .is_some()
+    }
+
+    #[instrument(skip_all)]
+    async fn choose_device(
+        &self,
+        ceremony: &CeremonyInfo,
+        devices: &[DeviceDescription],
+        transports: &[Transport],
+    ) -> Option<DeviceChoice> {
+        if let [device] = devices {
+            return Some(DeviceChoice::Device(device.id.clone()));
+        }
+        let choices: Vec<_> = devices
+            .iter()
+            .enumerate()
+            .map(|(index, device)| (format!("device-{}", index), device.name.clone()))
+            .chain(transports.iter().map(|transport| {
+                let name = transport_name(*transport);
+                (format!("transport-{}", name), name.to_uppercase())
+            }))
+            .collect();
+        let title = self.strings.get(Text::ChooseDevice);
+        let action = self.choose(ceremony, title, choices).await?;
+        if let Some(index) = action.strip_prefix("device-") {
+            let device = devices.get(index.parse::<usize>().ok()?)?;
+            return Some(DeviceChoice::Device(device.id.clone()));
+        }
+        let transport = action.strip_prefix("transport-")?;
+        transports
+            .iter()
+            .find(|candidate| transport_name(**candidate) == transport)
+            .map(|transport| DeviceChoice::Transport(*transport))
+    }
+
+    #[instrument(skip_all, fields(device = %request.device))]
+    async fn enter_pin(&self, _ceremony: &CeremonyInfo, request: &PinRequest) -> Option<Pin> {
+        self.pin_provider.provide_pin(request).await
+    }
+
+    #[instrument(skip_all, fields(?action))]
+    async fn prompt_user_action(&self, ceremony: &CeremonyInfo, action: UserAction) {
+        let title = match action {
+            UserAction::Touch => self.strings.get(Text::Touch),
+            UserAction::Fingerprint => self.strings.get(Text::Fingerprint),
+        };
+        let seconds = ceremony.timeout.as_secs().to_string();
+        let body = format!(
+            "{}\n\n{}",
+            self.ceremony_body(ceremony),
+            self.strings
+                .format(Text::UserActionBody, &[("seconds", &seconds)])
+        );
+        let mut notification = match self.show(title, &body, vec![]).await {
+            Ok(notification) => notification,
+            Err(err) => {
+                warn!(%err, "Failed to show notification");
+                return std::future::pending().await;
+            }
+        };
+        loop {
+            match notification.action().await {
+                Some(action) if action == CANCEL_ACTION => {
+                    debug!("User action prompt cancelled");
+                    return;
+                }
+                Some(_) => continue,
+                // The prompt can no longer be cancelled, but stays up until dropped.
+                None => return std::future::pending().await,
+            }
+        }
+    }
+
+    #[instrument(skip_all)]
+    async fn choose_account(
+        &self,
+        ceremony: &CeremonyInfo,
+        accounts: &[AccountDescription],
+    ) -> Option<usize> {
+        let choices: Vec<_> = accounts
+            .iter()
+            .enumerate()
+            .map(|(index, account)| {
+                let label = account
+                    .display_name
+                    .clone()
+                    .or_else(|| account.name.clone())
+                    .unwrap_or_else(|| hex::encode(&account.id));
+                (format!("account-{}", index), label)
+            })
+            .collect();
+        let title = self.strings.get(Text::ChooseAccount);
+        let action = self.choose(ceremony, title, choices).await?;
+        action.strip_prefix("account-")?.parse().ok()
+    }
+
+    #[instrument(skip_all)]
+    async fn show_error(&self, _ceremony: &CeremonyInfo, message: &str) {
+        let id = Uuid::new_v4().to_hyphenated().to_string();
+        let title = self.strings.get(Text::Error);
+        // Left for the user to dismiss.
+        if let Err(err) = self.add_notification(&id, title, message, &[]).await {
+            warn!(%err, "Failed to show notification");
+        }
+    }
+}
+
+/// A notification, removed once dropped.
+struct Notification<'a> {
+    ui: &'a NotificationPortalUI,
+    id: String,
+    actions_match: Option<MsgMatch>,
+    actions: UnboundedReceiver<Message>,
+}
+
+impl Notification<'_> {
+    /// Waits for the user to click one of the notification's buttons.
+    async fn action(&mut self) -> Option<String> {
+        while let Some(message) = self.actions.next().await {
+            let Ok((id, action)) = message.read2::<String, String>() else {
+                continue;
+            };
+            if id == self.id {
+                debug!(%id, %action, "Notification action invoked");
+                return Some(action);
+            }
+        }
+        None
+    }
+}
+
+impl Drop for Notification<'_> {
+    fn drop(&mut self) {
+        let connection = self.ui.connection.clone();
+        if let Some(actions_match) = self.actions_match.take() {
+            let token = actions_match.token();
+            tokio::spawn(async move {
+                if let Err(err) = connection.remove_match(token).await {
+                    warn!(%err, "Failed to remove notification action match");
+                }
+            });
+        }
+        let Ok(mut remove) = Message::new_method_call(
+            PORTAL_BUS_NAME,
+            PORTAL_PATH,
+            NOTIFICATION_INTERFACE,
+            "RemoveNotification",
+        ) else {
+            return;
+        };
+        remove.set_no_reply(true);
+        if self.ui.connection.send(remove.append1(&self.id)).is_err() {
+            warn!(id = %self.id, "Failed to remove notification");
+        }
+        debug!(id = %self.id, "Removed notification");
+    }
+}
+
+/// Splits the choices into pages fitting in a notification. When they do not all fit, each page
+/// ends with a button showing the next one, the last page leading back to the first.
+fn pages(choices: Vec<(String, String)>, more: &str) -> Vec<Vec<(String, String)>> {
+    if choices.len() <= MAX_CHOICES {
+        return vec![choices];
+    }
+    choices
+        .chunks(MAX_CHOICES - 1)
+        .map(|chunk| {
+            let mut page = chunk.to_vec();
+            page.push((MORE_ACTION.to_owned(), more.to_owned()));
+            page
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{pages, MAX_CHOICES, MORE_ACTION};
+
+    fn choices(count: usize) -> Vec<(String, String)> {
+        (0..count)
+            .map(|index| (format!("choice-{}", index), format!("Choice {}", index)))
+            .collect()
+    }
+
+    #[test]
+    fn choices_fitting_in_one_page() {
+        assert_eq!(
+            pages(choices(MAX_CHOICES), "More"),
+            vec![choices(MAX_CHOICES)]
+        );
+        assert_eq!(pages(vec![], "More"), vec![Vec::<(String, String)>::new()]);
+    }
+
+    #[test]
+    fn choices_paged() {
+        let count = MAX_CHOICES * 3;
+        let pages = pages(choices(count), "More");
+        assert!(pages.iter().all(|page| page.len() <= MAX_CHOICES));
+        assert!(pages
+            .iter()
+            .all(|page| page.last().unwrap().0 == MORE_ACTION));
+        let offered: Vec<_> = pages
+            .into_iter()
+            .flat_map(|page| page.into_iter().filter(|(action, _)| action != MORE_ACTION))
+            .collect();
+        assert_eq!(offered, choices(count));
+    }
+}
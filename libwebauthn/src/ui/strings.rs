@@ -0,0 +1,222 @@
+//! Strings shown to the user, in the language of their locale.
+
+use std::env;
+
+use crate::backend::Operation;
+
+/// Languages which strings are translated to. English is used for any other language.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Language {
+    English,
+    French,
+    German,
+    Italian,
+}
+
+impl Language {
+    /// Language of a POSIX locale name, such as `it_IT.UTF-8`.
+    pub fn from_locale(locale: &str) -> Option<Self> {
+        let language = locale.split(['_', '.', '@']).next()?;
+        match language {
+            "en" | "C" | "POSIX" => Some(Language::English),
+            "fr" => Some(Language::French),
+            "de" => Some(Language::German),
+            "it" => Some(Language::Italian),
+            _ => None,
+        }
+    }
+
+    /// The user's preferred language, following gettext's precedence of locale variables.
+    pub fn from_env() -> Self {
+        let language = env::var("LANGUAGE").unwrap_or_default();
+        let locales = language.split(':').map(str::to_owned).chain(
+            ["LC_ALL", "LC_MESSAGES", "LANG"]
+                .iter()
+                .filter_map(|variable| env::var(variable).ok()),
+        );
+        locales
+            .filter(|locale| !locale.is_empty())
+            .find_map(|locale| Language::from_locale(&locale))
+            .unwrap_or(Language::English)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Text {
+    /// Title presenting a ceremony, with the `{rp}` placeholder.
+    CeremonyTitle(Operation),
+    /// Body presenting a ceremony, with the `{app}` and `{rp}` placeholders.
+    CeremonyBody,
+    /// Body presenting a ceremony for a given account, with the `{app}`, `{rp}` and `{user}`
+    /// placeholders.
+    CeremonyBodyForUser,
+    ChooseDevice,
+    ChooseAccount,
+    Touch,
+    Fingerprint,
+    /// Body of touch and fingerprint prompts, with the `{seconds}` placeholder.
+    UserActionBody,
+    Error,
+    UnknownApp,
+    Continue,
+    /// Button showing the next page of choices.
+    More,
+    Cancel,
+}
+
+/// Strings in a given language.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Strings {
+    language: Language,
+}
+
+impl Strings {
+    pub fn new(language: Language) -> Self {
+        Self { language }
+    }
+
+    pub fn from_env() -> Self {
+        Self::new(Language::from_env())
+    }
+
+    pub fn get(&self, text: Text) -> &'static str {
+        translate(self.language, text)
+    }
+
+    /// The text, with each `{name}` placeholder replaced by its value.
+    pub fn format(&self, text: Text, values: &[(&str, &str)]) -> String {
+        values
+            .iter()
+            .fold(self.get(text).to_owned(), |string, (name, value)| {
+                string.replace(&format!("{{{}}}", name), value)
+            })
+    }
+}
+
+fn translate(language: Language, text: Text) -> &'static str {
+    use Language::*;
+    use Operation::*;
+    use Text::*;
+
+    match (text, language) {
+        (CeremonyTitle(Create), English) => "Create a credential for {rp}",
+        (CeremonyTitle(Create), French) => "Créer un identifiant pour {rp}",
+        (CeremonyTitle(Create), German) => "Anmeldedaten für {rp} erstellen",
+        (CeremonyTitle(Create), Italian) => "Crea una credenziale per {rp}",
+        (CeremonyTitle(Get), English) => "Sign in to {rp}",
+        (CeremonyTitle(Get), French) => "Se connecter à {rp}",
+        (CeremonyTitle(Get), German) => "Bei {rp} anmelden",
+        (CeremonyTitle(Get), Italian) => "Accedi a {rp}",
+        (CeremonyTitle(Register), English) => "Register your security key with {rp}",
+        (CeremonyTitle(Register), French) => "Enregistrer votre clé de sécurité auprès de {rp}",
+        (CeremonyTitle(Register), German) => "Sicherheitsschlüssel bei {rp} registrieren",
+        (CeremonyTitle(Register), Italian) => "Registra la tua chiave di sicurezza su {rp}",
+        (CeremonyTitle(Sign), English) => "Verify your identity with {rp}",
+        (CeremonyTitle(Sign), French) => "Vérifier votre identité auprès de {rp}",
+        (CeremonyTitle(Sign), German) => "Identität bei {rp} bestätigen",
+        (CeremonyTitle(Sign), Italian) => "Verifica la tua identità su {rp}",
+
+        (CeremonyBody, English) => "{app} would like to use your security key for {rp}.",
+        (CeremonyBody, French) => "{app} souhaite utiliser votre clé de sécurité pour {rp}.",
+        (CeremonyBody, German) => "{app} möchte Ihren Sicherheitsschlüssel für {rp} verwenden.",
+        (CeremonyBody, Italian) => "{app} vuole usare la tua chiave di sicurezza per {rp}.",
+        (CeremonyBodyForUser, English) => {
+            "{app} would like to use your security key for {rp}, as {user}."
+        }
+        (CeremonyBodyForUser, French) => {
+            "{app} souhaite utiliser votre clé de sécurité pour {rp}, en tant que {user}."
+        }
+        (CeremonyBodyForUser, German) => {
+            "{app} möchte Ihren Sicherheitsschlüssel für {rp} als {user} verwenden."
+        }
+        (CeremonyBodyForUser, Italian) => {
+            "{app} vuole usare la tua chiave di sicurezza per {rp}, come {user}."
+        }
+
+        (ChooseDevice, English) => "Choose a security key",
+        (ChooseDevice, French) => "Choisissez une clé de sécurité",
+        (ChooseDevice, German) => "Sicherheitsschlüssel auswählen",
+        (ChooseDevice, Italian) => "Scegli una chiave di sicurezza",
+        (ChooseAccount, English) => "Choose an account",
+        (ChooseAccount, French) => "Choisissez un compte",
+        (ChooseAccount, German) => "Konto auswählen",
+        (ChooseAccount, Italian) => "Scegli un account",
+
+        (Touch, English) => "Touch your security key",
+        (Touch, French) => "Touchez votre clé de sécurité",
+        (Touch, German) => "Berühren Sie Ihren Sicherheitsschlüssel",
+        (Touch, Italian) => "Tocca la tua chiave di sicurezza",
+        (Fingerprint, English) => "Scan your fingerprint on your security key",
+        (Fingerprint, French) => "Posez votre doigt sur votre clé de sécurité",
+        (Fingerprint, German) => "Scannen Sie Ihren Fingerabdruck am Sicherheitsschlüssel",
+        (Fingerprint, Italian) => "Appoggia il dito sulla tua chiave di sicurezza",
+        (UserActionBody, English) => "Do so within {seconds} seconds, or click Cancel.",
+        (UserActionBody, French) => "Dans les {seconds} secondes, ou cliquez sur Annuler.",
+        (UserActionBody, German) => {
+            "Innerhalb von {seconds} Sekunden, oder klicken Sie auf Abbrechen."
+        }
+        (UserActionBody, Italian) => "Entro {seconds} secondi, oppure fai clic su Annulla.",
+
+        (Error, English) => "Your security key could not be used",
+        (Error, French) => "Votre clé de sécurité n'a pas pu être utilisée",
+        (Error, German) => "Ihr Sicherheitsschlüssel konnte nicht verwendet werden",
+        (Error, Italian) => "Non è stato possibile usare la tua chiave di sicurezza",
+        (UnknownApp, English) => "An application",
+        (UnknownApp, French) => "Une application",
+        (UnknownApp, German) => "Eine Anwendung",
+        (UnknownApp, Italian) => "Un'applicazione",
+        (Continue, English) => "Continue",
+        (Continue, French) => "Continuer",
+        (Continue, German) => "Weiter",
+        (Continue, Italian) => "Continua",
+        (More, English) => "More…",
+        (More, French) => "Plus…",
+        (More, German) => "Mehr…",
+        (More, Italian) => "Altro…",
+        (Cancel, English) => "Cancel",
+        (Cancel, French) => "Annuler",
+        (Cancel, German) => "Abbrechen",
+        (Cancel, Italian) => "Annulla",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::backend::Operation;
+    use crate::ui::strings::{Language, Strings, Text};
+
+    #[test]
+    fn language_from_locale() {
+        assert_eq!(
+            Language::from_locale("it_IT.UTF-8"),
+            Some(Language::Italian)
+        );
+        assert_eq!(Language::from_locale("de"), Some(Language::German));
+        assert_eq!(Language::from_locale("fr_CA@euro"), Some(Language::French));
+        assert_eq!(Language::from_locale("C.UTF-8"), Some(Language::English));
+        assert_eq!(Language::from_locale("ja_JP.UTF-8"), None);
+    }
+
+    #[test]
+    fn format_placeholders() {
+        let strings = Strings::new(Language::Italian);
+        assert_eq!(
+            strings.format(
+                Text::CeremonyTitle(Operation::Get),
+                &[("rp", "example.org")]
+            ),
+            "Accedi a example.org"
+        );
+        assert_eq!(
+            Strings::new(Language::English).format(
+                Text::CeremonyBodyForUser,
+                &[
+                    ("app", "org.example.App"),
+                    ("rp", "example.org"),
+                    ("user", "mario.rossi")
+                ]
+            ),
+            "org.example.App would like to use your security key for example.org, as mario.rossi."
+        );
+    }
+}
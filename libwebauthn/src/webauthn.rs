@@ -1,23 +1,22 @@
-use std::time::Duration;
-
 use async_trait::async_trait;
-use cosey::PublicKey;
+use sha2::{Digest, Sha256};
 use tracing::{debug, error, info, instrument, trace, warn};
 
+use crate::account::{self, AccountSelector};
 use crate::fido::FidoProtocol;
 use crate::ops::u2f::{RegisterRequest, SignRequest, UpgradableResponse};
 use crate::ops::webauthn::{
-    DowngradableRequest, GetAssertionRequest, GetAssertionResponse, UserVerificationRequirement,
-};
-use crate::ops::webauthn::{MakeCredentialRequest, MakeCredentialResponse};
-use crate::pin::{
-    pin_hash, PinProvider, PinUvAuthProtocol, PinUvAuthProtocolOne, PinUvAuthProtocolTwo,
+    CredentialPropertiesOutput, MakeCredentialRequest, MakeCredentialResponse, ProbeResponse,
+    ResidentKeyRequirement,
 };
-use crate::proto::ctap1::Ctap1;
+use crate::ops::webauthn::{DowngradableRequest, GetAssertionRequest, GetAssertionResponse};
+use crate::pin::PinProvider;
+use crate::proto::ctap1::{Ctap1, Ctap1SignRequest};
 use crate::proto::ctap2::{
-    Ctap2, Ctap2ClientPinRequest, Ctap2GetAssertionRequest, Ctap2GetInfoResponse,
-    Ctap2MakeCredentialRequest, Ctap2UserVerifiableRequest, Ctap2UserVerificationOperation,
+    Ctap2, Ctap2GetAssertionOptions, Ctap2GetAssertionRequest, Ctap2GetAssertionResponse,
+    Ctap2MakeCredentialRequest,
 };
+use crate::session::AuthenticatorSession;
 use crate::transport::Channel;
 
 pub use crate::transport::error::{CtapError, Error, TransportError};
@@ -34,6 +33,18 @@ pub trait WebAuthn {
         op: &GetAssertionRequest,
         pin_provider: &Box<dyn PinProvider>,
     ) -> Result<GetAssertionResponse, Error>;
+    /// Runs GetAssertion, and asks which account to sign in with if the authenticator returned
+    /// several assertions, unless the user already chose one on the authenticator.
+    async fn webauthn_get_assertion_for_account(
+        &mut self,
+        op: &GetAssertionRequest,
+        pin_provider: &Box<dyn PinProvider>,
+        account_selector: &Box<dyn AccountSelector>,
+    ) -> Result<Ctap2GetAssertionResponse, Error>;
+    /// Finds which credentials of the request's allowList the authenticator holds, without
+    /// requiring user presence, so that the ceremony can be sent only to authenticators which can
+    /// answer it.
+    async fn webauthn_probe(&mut self, op: &GetAssertionRequest) -> Result<ProbeResponse, Error>;
     async fn _webauthn_make_credential_fido2(
         &mut self,
         op: &MakeCredentialRequest,
@@ -54,26 +65,125 @@ pub trait WebAuthn {
         op: &GetAssertionRequest,
     ) -> Result<GetAssertionResponse, Error>;
 
+    async fn _webauthn_probe_fido2(
+        &mut self,
+        op: &GetAssertionRequest,
+    ) -> Result<ProbeResponse, Error>;
+    async fn _webauthn_probe_u2f(
+        &mut self,
+        op: &GetAssertionRequest,
+    ) -> Result<ProbeResponse, Error>;
+
     async fn _negotiate_protocol(&mut self, allow_u2f: bool) -> Result<FidoProtocol, Error>;
 }
 
-async fn select_uv_proto(
-    get_info_response: &Ctap2GetInfoResponse,
-) -> Result<Box<dyn PinUvAuthProtocol>, Error> {
-    for &protocol in get_info_response.pin_auth_protos.iter().flatten() {
-        match protocol {
-            1 => return Ok(Box::new(PinUvAuthProtocolOne::new())),
-            2 => return Ok(Box::new(PinUvAuthProtocolTwo::new())),
-            _ => (),
-        };
+/// Each operation on a bare channel runs in its own session, use an [`AuthenticatorSession`] to
+/// share state across operations.
+#[async_trait]
+impl<C> WebAuthn for C
+where
+    C: Channel,
+{
+    async fn webauthn_make_credential(
+        &mut self,
+        op: &MakeCredentialRequest,
+        pin_provider: &Box<dyn PinProvider>,
+    ) -> Result<MakeCredentialResponse, Error> {
+        AuthenticatorSession::new(self)
+            .webauthn_make_credential(op, pin_provider)
+            .await
     }
 
-    error!(?get_info_response.pin_auth_protos, "No supported PIN/UV auth protocols found");
-    return Err(Error::Ctap(CtapError::Other));
+    async fn webauthn_get_assertion(
+        &mut self,
+        op: &GetAssertionRequest,
+        pin_provider: &Box<dyn PinProvider>,
+    ) -> Result<GetAssertionResponse, Error> {
+        AuthenticatorSession::new(self)
+            .webauthn_get_assertion(op, pin_provider)
+            .await
+    }
+
+    async fn webauthn_get_assertion_for_account(
+        &mut self,
+        op: &GetAssertionRequest,
+        pin_provider: &Box<dyn PinProvider>,
+        account_selector: &Box<dyn AccountSelector>,
+    ) -> Result<Ctap2GetAssertionResponse, Error> {
+        AuthenticatorSession::new(self)
+            .webauthn_get_assertion_for_account(op, pin_provider, account_selector)
+            .await
+    }
+
+    async fn webauthn_probe(&mut self, op: &GetAssertionRequest) -> Result<ProbeResponse, Error> {
+        AuthenticatorSession::new(self).webauthn_probe(op).await
+    }
+
+    async fn _webauthn_make_credential_fido2(
+        &mut self,
+        op: &MakeCredentialRequest,
+        pin_provider: &Box<dyn PinProvider>,
+    ) -> Result<MakeCredentialResponse, Error> {
+        AuthenticatorSession::new(self)
+            ._webauthn_make_credential_fido2(op, pin_provider)
+            .await
+    }
+
+    async fn _webauthn_make_credential_u2f(
+        &mut self,
+        op: &MakeCredentialRequest,
+    ) -> Result<MakeCredentialResponse, Error> {
+        AuthenticatorSession::new(self)
+            ._webauthn_make_credential_u2f(op)
+            .await
+    }
+
+    async fn _webauthn_get_assertion_fido2(
+        &mut self,
+        op: &GetAssertionRequest,
+        pin_provider: &Box<dyn PinProvider>,
+    ) -> Result<GetAssertionResponse, Error> {
+        AuthenticatorSession::new(self)
+            ._webauthn_get_assertion_fido2(op, pin_provider)
+            .await
+    }
+
+    async fn _webauthn_get_assertion_u2f(
+        &mut self,
+        op: &GetAssertionRequest,
+    ) -> Result<GetAssertionResponse, Error> {
+        AuthenticatorSession::new(self)
+            ._webauthn_get_assertion_u2f(op)
+            .await
+    }
+
+    async fn _webauthn_probe_fido2(
+        &mut self,
+        op: &GetAssertionRequest,
+    ) -> Result<ProbeResponse, Error> {
+        AuthenticatorSession::new(self)
+            ._webauthn_probe_fido2(op)
+            .await
+    }
+
+    async fn _webauthn_probe_u2f(
+        &mut self,
+        op: &GetAssertionRequest,
+    ) -> Result<ProbeResponse, Error> {
+        AuthenticatorSession::new(self)
+            ._webauthn_probe_u2f(op)
+            .await
+    }
+
+    async fn _negotiate_protocol(&mut self, allow_u2f: bool) -> Result<FidoProtocol, Error> {
+        AuthenticatorSession::new(self)
+            ._negotiate_protocol(allow_u2f)
+            .await
+    }
 }
 
 #[async_trait]
-impl<C> WebAuthn for C
+impl<C> WebAuthn for AuthenticatorSession<C>
 where
     C: Channel,
 {
@@ -96,16 +206,71 @@ where
         op: &MakeCredentialRequest,
         pin_provider: &Box<dyn PinProvider>,
     ) -> Result<MakeCredentialResponse, Error> {
-        let mut ctap2_request: Ctap2MakeCredentialRequest = op.into();
-        user_verification(
-            self,
-            op.user_verification,
-            &mut ctap2_request,
-            pin_provider,
-            op.timeout,
-        )
-        .await?;
-        self.ctap2_make_credential(&ctap2_request, op.timeout).await
+        let exclude = match &op.exclude {
+            Some(exclude) if !exclude.is_empty() => {
+                let request: Ctap2MakeCredentialRequest = op.into();
+                let exclude = self
+                    .fit_credentials(
+                        &request,
+                        exclude,
+                        op.user_verification,
+                        pin_provider,
+                        op.timeout,
+                    )
+                    .await?;
+                match exclude.is_empty() {
+                    true => None,
+                    false => Some(exclude),
+                }
+            }
+            _ => None,
+        };
+        let mut discoverable = match op.resident_key {
+            ResidentKeyRequirement::Required => true,
+            ResidentKeyRequirement::Discouraged => false,
+            ResidentKeyRequirement::Preferred => {
+                let discoverable = self.get_info().await?.can_store_discoverable_credential();
+                if !discoverable {
+                    info!("Discoverable credentials unavailable, creating a non-discoverable one");
+                }
+                discoverable
+            }
+        };
+        loop {
+            let mut ctap2_request: Ctap2MakeCredentialRequest = op.into();
+            ctap2_request.exclude = exclude.clone();
+            if let Some(options) = &mut ctap2_request.options {
+                options.require_resident_key = if discoverable { Some(true) } else { None };
+            }
+            self.user_verification(
+                op.user_verification,
+                &mut ctap2_request,
+                pin_provider,
+                op.timeout,
+            )
+            .await?;
+            match self
+                .channel
+                .ctap2_make_credential(&ctap2_request, op.timeout)
+                .await
+            {
+                Err(err) if self.token_rejected(&err) => continue,
+                Err(Error::Ctap(CtapError::KeyStoreFull))
+                    if discoverable && op.resident_key == ResidentKeyRequirement::Preferred =>
+                {
+                    info!("No space left for discoverable credentials, creating a non-discoverable one");
+                    discoverable = false;
+                }
+                result => {
+                    return result.map(|attestation_object| MakeCredentialResponse {
+                        attestation_object,
+                        cred_props: CredentialPropertiesOutput {
+                            rk: Some(discoverable),
+                        },
+                    })
+                }
+            }
+        }
     }
 
     async fn _webauthn_make_credential_u2f(
@@ -113,7 +278,8 @@ where
         op: &MakeCredentialRequest,
     ) -> Result<MakeCredentialResponse, Error> {
         let register_request: RegisterRequest = op.try_downgrade()?;
-        self.ctap1_register(&register_request)
+        self.channel
+            .ctap1_register(&register_request)
             .await?
             .try_upgrade(op)
     }
@@ -132,27 +298,109 @@ where
         }
     }
 
+    #[instrument(skip_all, fields(dev = %self))]
+    async fn webauthn_get_assertion_for_account(
+        &mut self,
+        op: &GetAssertionRequest,
+        pin_provider: &Box<dyn PinProvider>,
+        account_selector: &Box<dyn AccountSelector>,
+    ) -> Result<Ctap2GetAssertionResponse, Error> {
+        let mut assertions = self
+            .webauthn_get_assertion(op, pin_provider)
+            .await?
+            .assertions;
+        if let Some(index) = assertions
+            .iter()
+            .position(|assertion| assertion.user_selected == Some(true))
+        {
+            debug!("Account was chosen on the authenticator");
+            return Ok(assertions.swap_remove(index));
+        }
+        match assertions.len() {
+            0 => {
+                warn!("Authenticator returned no assertions");
+                return Err(Error::Ctap(CtapError::NoCredentials));
+            }
+            1 => return Ok(assertions.remove(0)),
+            _ => (),
+        }
+
+        let mut accounts = account::accounts(&assertions);
+        if account_selector.look_up_users() && account::has_unnamed(&accounts) {
+            match self
+                .enumerate_credentials(&op.relying_party_id, pin_provider, op.timeout)
+                .await
+            {
+                Ok(credentials) => account::fill_in_users(&mut accounts, &assertions, &credentials),
+                Err(err) => warn!(%err, "Failed to look up accounts, showing them as returned"),
+            }
+        }
+        match account_selector.select_account(&accounts).await {
+            Some(index) if index < assertions.len() => Ok(assertions.swap_remove(index)),
+            _ => {
+                info!("User cancelled operation: no account chosen");
+                Err(Error::Ctap(CtapError::OperationDenied))
+            }
+        }
+    }
+
     async fn _webauthn_get_assertion_fido2(
         &mut self,
         op: &GetAssertionRequest,
         pin_provider: &Box<dyn PinProvider>,
     ) -> Result<GetAssertionResponse, Error> {
-        let mut ctap2_request: Ctap2GetAssertionRequest = op.into();
-        user_verification(
-            self,
-            op.user_verification,
-            &mut ctap2_request,
-            pin_provider,
-            op.timeout,
-        )
-        .await?;
-
-        let response = self.ctap2_get_assertion(&ctap2_request, op.timeout).await?;
-        let count = response.credentials_count.unwrap_or(1);
+        let allow = match op.allow.is_empty() {
+            true => vec![],
+            false => {
+                let request: Ctap2GetAssertionRequest = op.into();
+                let allow = self
+                    .fit_credentials(
+                        &request,
+                        &op.allow,
+                        op.user_verification,
+                        pin_provider,
+                        op.timeout,
+                    )
+                    .await?;
+                if allow.is_empty() {
+                    info!("None of the credentials in the allowList are on the authenticator");
+                    return Err(Error::Ctap(CtapError::NoCredentials));
+                }
+                allow
+            }
+        };
+        let mut response = loop {
+            let mut ctap2_request: Ctap2GetAssertionRequest = op.into();
+            ctap2_request.allow = allow.clone();
+            self.user_verification(
+                op.user_verification,
+                &mut ctap2_request,
+                pin_provider,
+                op.timeout,
+            )
+            .await?;
+            match self
+                .channel
+                .ctap2_get_assertion(&ctap2_request, op.timeout)
+                .await
+            {
+                Err(err) if self.token_rejected(&err) => continue,
+                result => break result?,
+            }
+        };
+        // The credential may be omitted when the allowList held a single one.
+        if allow.len() == 1 && response.credential_id.is_none() {
+            response.credential_id = allow.first().cloned();
+        }
+        // Authenticators with a display let the user choose, and only return that assertion.
+        let count = match response.user_selected {
+            Some(true) => 1,
+            _ => response.credentials_count.unwrap_or(1),
+        };
         let mut assertions = vec![response];
         for i in 1..count {
             debug!({ i }, "Fetching additional credential");
-            assertions.push(self.ctap2_get_next_assertion(op.timeout).await?);
+            assertions.push(self.channel.ctap2_get_next_assertion(op.timeout).await?);
         }
         Ok(assertions.as_slice().into())
     }
@@ -164,7 +412,7 @@ where
         let sign_requests: Vec<SignRequest> = op.try_downgrade()?;
 
         for sign_request in sign_requests {
-            match self.ctap1_sign(&sign_request).await {
+            match self.channel.ctap1_sign(&sign_request).await {
                 Ok(response) => {
                     debug!("Found successful candidate in allowList");
                     return response.try_upgrade(&sign_request);
@@ -185,9 +433,86 @@ where
         Err(Error::Ctap(CtapError::NoCredentials))
     }
 
+    #[instrument(skip_all, fields(dev = %self))]
+    async fn webauthn_probe(&mut self, op: &GetAssertionRequest) -> Result<ProbeResponse, Error> {
+        trace!(?op, "WebAuthn probe request");
+        // Probing never requires user verification, so U2F authenticators can always answer.
+        let allow_u2f = !op.allow.is_empty();
+        let response = match self._negotiate_protocol(allow_u2f).await? {
+            FidoProtocol::FIDO2 => self._webauthn_probe_fido2(op).await?,
+            FidoProtocol::U2F => self._webauthn_probe_u2f(op).await?,
+        };
+        debug!(matches = response.credentials.len(), "Probe complete");
+        Ok(response)
+    }
+
+    async fn _webauthn_probe_fido2(
+        &mut self,
+        op: &GetAssertionRequest,
+    ) -> Result<ProbeResponse, Error> {
+        if !op.allow.is_empty() {
+            let credentials = self
+                .preflight_credentials(&op.relying_party_id, &op.hash, &op.allow, None, op.timeout)
+                .await?;
+            return Ok(ProbeResponse {
+                credentials,
+                discoverable: false,
+            });
+        }
+
+        // An empty allowList is probed once, for discoverable credentials.
+        let mut request: Ctap2GetAssertionRequest = op.into();
+        request.extensions_cbor = None;
+        request.options = Some(Ctap2GetAssertionOptions {
+            require_user_presence: false,
+            require_user_verification: false,
+        });
+        let discoverable = match self.channel.ctap2_get_assertion(&request, op.timeout).await {
+            Ok(_) => true,
+            Err(Error::Ctap(CtapError::NoCredentials)) => false,
+            Err(err) => return Err(err),
+        };
+        Ok(ProbeResponse {
+            credentials: vec![],
+            discoverable,
+        })
+    }
+
+    async fn _webauthn_probe_u2f(
+        &mut self,
+        op: &GetAssertionRequest,
+    ) -> Result<ProbeResponse, Error> {
+        let mut hasher = Sha256::default();
+        hasher.update(op.relying_party_id.as_bytes());
+        let rp_id_hash = hasher.finalize().to_vec();
+
+        let mut response = ProbeResponse::default();
+        for credential in &op.allow {
+            // Key handle lengths are encoded in a single byte, so longer ones can not be U2F's.
+            if credential.id.len() > u8::MAX as usize {
+                debug!(
+                    length = credential.id.len(),
+                    "Skipping credential too long for U2F"
+                );
+                continue;
+            }
+            let request = Ctap1SignRequest::new_check_only(&rp_id_hash, &credential.id, op.timeout);
+            match self.channel.ctap1_check_only(&request).await {
+                Ok(true) => response.credentials.push(credential.clone()),
+                Ok(false) => (),
+                // The authenticator rejected this key handle, which it therefore does not hold.
+                Err(Error::Ctap(error)) => {
+                    debug!(?error, "Check-only request failed, credential not held");
+                }
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(response)
+    }
+
     #[instrument(skip_all)]
     async fn _negotiate_protocol(&mut self, allow_u2f: bool) -> Result<FidoProtocol, Error> {
-        let supported = self.supported_protocols().await?;
+        let supported = self.channel.supported_protocols().await?;
         if !supported.u2f && !supported.fido2 {
             return Err(Error::Transport(TransportError::NegotiationFailed));
         }
@@ -200,7 +525,7 @@ where
             FidoProtocol::FIDO2
         } else {
             // Ensure CTAP1 version is reported correctly.
-            self.ctap1_version().await?;
+            self.channel.ctap1_version().await?;
             FidoProtocol::U2F
         };
 
@@ -213,147 +538,235 @@ where
     }
 }
 
-#[instrument(skip_all)]
-async fn user_verification<R, C>(
-    channel: &mut C,
-    user_verification: UserVerificationRequirement,
-    ctap2_request: &mut R,
-    pin_provider: &Box<dyn PinProvider>,
-    timeout: Duration,
-) -> Result<(), Error>
-where
-    C: Channel,
-    R: Ctap2UserVerifiableRequest,
-{
-    let get_info_response = channel.ctap2_get_info().await?;
+#[cfg(test)]
+mod tests {
+    use std::convert::TryFrom;
 
-    let rp_uv_preferred = user_verification.is_preferred();
-    let dev_uv_protected = get_info_response.is_uv_protected();
-    let uv = rp_uv_preferred || dev_uv_protected;
-    debug!(%rp_uv_preferred, %dev_uv_protected, %uv, "Checking if user verification is required");
+    use serde_bytes::ByteBuf;
 
-    if !uv {
-        debug!("User verification not requested by either RP nor authenticator. Ignoring.");
-        return Ok(());
+    use crate::authenticator::store::CredentialStore;
+    use crate::authenticator::{SoftwareAuthenticator, SoftwareChannel};
+    use crate::ops::webauthn::{
+        GetAssertionRequest, MakeCredentialRequest, ResidentKeyRequirement,
+        UserVerificationRequirement,
+    };
+    use crate::pin::{PinProvider, StaticPinProvider};
+    use crate::proto::ctap1::apdu::ApduResponseStatus;
+    use crate::proto::ctap2::{Ctap2PublicKeyCredentialDescriptor, Ctap2PublicKeyCredentialType};
+    use crate::session::AuthenticatorSession;
+    use crate::transport::fault::{
+        Fault, FaultInjectingChannel, FaultSchedule, FaultTarget, FaultTrigger,
+    };
+    use crate::webauthn::{CtapError, Error, WebAuthn};
+
+    async fn session_with_credential() -> (
+        AuthenticatorSession<SoftwareChannel>,
+        Ctap2PublicKeyCredentialDescriptor,
+    ) {
+        let channel =
+            SoftwareChannel::new(SoftwareAuthenticator::new(CredentialStore::temporary()));
+        let mut session = AuthenticatorSession::new(channel);
+        let pin_provider: Box<dyn PinProvider> = Box::new(StaticPinProvider::new("1234"));
+        let response = session
+            .webauthn_make_credential(&MakeCredentialRequest::dummy(), &pin_provider)
+            .await
+            .unwrap();
+        let credential =
+            Ctap2PublicKeyCredentialDescriptor::try_from(&response.attestation_object).unwrap();
+        (session, credential)
     }
 
-    if !dev_uv_protected && user_verification.is_required() {
-        error!(
-            "Request requires user verification, but device user verification is not available."
-        );
-        return Err(Error::Ctap(CtapError::PINNotSet));
-    };
+    fn unknown_credential() -> Ctap2PublicKeyCredentialDescriptor {
+        unknown_credentials(1).remove(0)
+    }
 
-    if !dev_uv_protected && user_verification.is_preferred() {
-        warn!("User verification is preferred, but not device user verification is not available. Ignoring.");
-        return Ok(());
+    fn unknown_credentials(count: u8) -> Vec<Ctap2PublicKeyCredentialDescriptor> {
+        (0..count)
+            .map(|i| Ctap2PublicKeyCredentialDescriptor {
+                r#type: Ctap2PublicKeyCredentialType::PublicKey,
+                id: ByteBuf::from(vec![0x42 + i; 32]),
+                transports: None,
+            })
+            .collect()
     }
 
-    let uv_operation = get_info_response.uv_operation();
-    if let Ctap2UserVerificationOperation::None = uv_operation {
-        debug!("No client operation. Setting deprecated request options.uv flag to true.");
-        ctap2_request.ensure_uv_set();
-        return Ok(());
+    #[tokio::test]
+    async fn probe_finds_credential() {
+        let (mut session, credential) = session_with_credential().await;
+        let mut request = GetAssertionRequest::dummy();
+        request.allow = vec![unknown_credential(), credential.clone()];
+
+        let response = session.webauthn_probe(&request).await.unwrap();
+        assert!(response.has_match());
+        assert_eq!(response.credentials.len(), 1);
+        assert_eq!(response.credentials[0].id, credential.id);
+
+        request.allow = vec![unknown_credential()];
+        let response = session.webauthn_probe(&request).await.unwrap();
+        assert!(!response.has_match());
     }
 
-    // For operations that include a PIN, we want to fetch one before obtaining a shared secret.
-    // This prevents the shared secret from expiring whilst we wait for the user to enter a PIN.
-    let pin = match uv_operation {
-        Ctap2UserVerificationOperation::None => unreachable!(),
-        Ctap2UserVerificationOperation::GetPinToken
-        | Ctap2UserVerificationOperation::GetPinUvAuthTokenUsingPinWithPermissions => {
-            Some(obtain_pin(channel, pin_provider, timeout).await?)
-        }
-        Ctap2UserVerificationOperation::GetPinUvAuthTokenUsingUvWithPermissions => {
-            None // TODO probably?
-        }
-    };
+    #[tokio::test]
+    async fn probe_u2f_check_only() {
+        let (mut session, credential) = session_with_credential().await;
+        let mut request = GetAssertionRequest::dummy();
+        request.allow = vec![credential.clone(), unknown_credential()];
 
-    // In preparation for obtaining pinUvAuthToken, the platform:
-    // * Obtains a shared secret.
-    let uv_proto = select_uv_proto(&get_info_response).await?;
-    let (public_key, shared_secret) = obtain_shared_secret(channel, &uv_proto, timeout).await?;
-
-    // Then the platform obtains a pinUvAuthToken from the authenticator, with the mc (and likely also with the ga)
-    // permission (see "pre-flight", mentioned above), using the selected operation.
-    let token_request = match uv_operation {
-        Ctap2UserVerificationOperation::None => unreachable!(),
-        Ctap2UserVerificationOperation::GetPinToken => Ctap2ClientPinRequest::new_get_pin_token(
-            uv_proto.version(),
-            public_key,
-            &uv_proto.encrypt(&shared_secret, &pin_hash(&pin.unwrap()))?,
-        ),
-        Ctap2UserVerificationOperation::GetPinUvAuthTokenUsingPinWithPermissions => {
-            Ctap2ClientPinRequest::new_get_pin_token_with_perm(
-                uv_proto.version(),
-                public_key,
-                &uv_proto.encrypt(&shared_secret, &pin_hash(&pin.unwrap()))?,
-                ctap2_request.permissions(),
-                ctap2_request.permissions_rpid(),
-            )
-        }
-        Ctap2UserVerificationOperation::GetPinUvAuthTokenUsingUvWithPermissions => {
-            Ctap2ClientPinRequest::new_get_uv_token_with_perm(
-                uv_proto.version(),
-                public_key,
-                ctap2_request.permissions(),
-                ctap2_request.permissions_rpid(),
-            )
-        }
-    };
+        let response = session._webauthn_probe_u2f(&request).await.unwrap();
+        assert_eq!(response.credentials.len(), 1);
+        assert_eq!(response.credentials[0].id, credential.id);
+    }
 
-    let token_response = channel.ctap2_client_pin(&token_request, timeout).await?;
-    let Some(encrypted_pin_uv_auth_token) = token_response.pin_uv_auth_token else {
-        error!("Client PIN response did not include a PIN UV auth token");
-        return Err(Error::Ctap(CtapError::Other));
-    };
+    #[tokio::test]
+    async fn probe_u2f_skips_rejected_credentials() {
+        let schedule = FaultSchedule::new(0)
+            .with(
+                FaultTarget::ApduRecv,
+                FaultTrigger::Nth(0),
+                Fault::ApduStatus(ApduResponseStatus::InvalidRequestLength),
+            )
+            .unwrap();
+        let authenticator = SoftwareAuthenticator::new(CredentialStore::temporary());
+        let channel = FaultInjectingChannel::new(SoftwareChannel::new(authenticator), schedule);
+        let mut session = AuthenticatorSession::new(channel);
+        let pin_provider: Box<dyn PinProvider> = Box::new(StaticPinProvider::new("1234"));
+        let response = session
+            .webauthn_make_credential(&MakeCredentialRequest::dummy(), &pin_provider)
+            .await
+            .unwrap();
+        let credential =
+            Ctap2PublicKeyCredentialDescriptor::try_from(&response.attestation_object).unwrap();
+
+        // Too long for U2F, and then rejected with a wrong length status.
+        let mut request = GetAssertionRequest::dummy();
+        request.allow = unknown_credentials(2);
+        request.allow[0].id = ByteBuf::from(vec![0x42; 256]);
+        request.allow.push(credential.clone());
+
+        let response = session._webauthn_probe_u2f(&request).await.unwrap();
+        assert_eq!(response.credentials.len(), 1);
+        assert_eq!(response.credentials[0].id, credential.id);
+        assert_eq!(session.channel().injected().len(), 1);
+    }
 
-    // If successful, the platform creates the pinUvAuthParam parameter by calling
-    // authenticate(pinUvAuthToken, clientDataHash), and goes to Step 1.1.1.
-    let uv_auth_token = uv_proto.decrypt(&shared_secret, &encrypted_pin_uv_auth_token)?;
-    let uv_auth_param =
-        uv_proto.authenticate(uv_auth_token.as_slice(), ctap2_request.client_data_hash());
+    #[tokio::test]
+    async fn long_allow_list_batched() {
+        let (mut session, credential) = session_with_credential().await;
+        let pin_provider: Box<dyn PinProvider> = Box::new(StaticPinProvider::new("1234"));
+        let mut request = GetAssertionRequest::dummy();
+        request.user_verification = UserVerificationRequirement::Discouraged;
+        // Longer than the authenticator's maxCredentialCountInList, and a credential ID longer
+        // than its maxCredentialIdLength.
+        request.allow = unknown_credentials(6);
+        request.allow[0].id = ByteBuf::from(vec![0x42; 256]);
+        request.allow.insert(3, credential.clone());
+
+        let response = session
+            .webauthn_get_assertion(&request, &pin_provider)
+            .await
+            .unwrap();
+        let assertion = &response.assertions[0];
+        assert_eq!(assertion.credential_id.as_ref().unwrap().id, credential.id);
+
+        request.allow.remove(3);
+        let result = session
+            .webauthn_get_assertion(&request, &pin_provider)
+            .await;
+        assert!(matches!(result, Err(Error::Ctap(CtapError::NoCredentials))));
+    }
 
-    // Sets the pinUvAuthProtocol parameter to the value as selected when it obtained the shared secret.
-    ctap2_request.set_uv_auth(uv_proto.version(), uv_auth_param.as_slice());
+    #[tokio::test]
+    async fn omitted_credential_filled_in() {
+        let authenticator = SoftwareAuthenticator::new(CredentialStore::temporary())
+            .with_single_credential_omitted();
+        let mut session = AuthenticatorSession::new(SoftwareChannel::new(authenticator));
+        let pin_provider: Box<dyn PinProvider> = Box::new(StaticPinProvider::new("1234"));
+        let response = session
+            .webauthn_make_credential(&MakeCredentialRequest::dummy(), &pin_provider)
+            .await
+            .unwrap();
+        let credential =
+            Ctap2PublicKeyCredentialDescriptor::try_from(&response.attestation_object).unwrap();
+
+        // The pre-flight narrows the allowList down to the single credential held.
+        let mut request = GetAssertionRequest::dummy();
+        request.user_verification = UserVerificationRequirement::Discouraged;
+        request.allow = unknown_credentials(4);
+        request.allow.push(credential.clone());
+        let response = session
+            .webauthn_get_assertion(&request, &pin_provider)
+            .await
+            .unwrap();
+        let assertion = &response.assertions[0];
+        assert_eq!(assertion.credential_id.as_ref().unwrap().id, credential.id);
+    }
 
-    Ok(())
-}
+    #[tokio::test]
+    async fn long_exclude_list_batched() {
+        let (mut session, credential) = session_with_credential().await;
+        let pin_provider: Box<dyn PinProvider> = Box::new(StaticPinProvider::new("1234"));
+        let mut request = MakeCredentialRequest::dummy();
+        request.exclude = Some(unknown_credentials(6));
+        session
+            .webauthn_make_credential(&request, &pin_provider)
+            .await
+            .unwrap();
+
+        request.exclude.as_mut().unwrap().push(credential);
+        let result = session
+            .webauthn_make_credential(&request, &pin_provider)
+            .await;
+        assert!(matches!(
+            result,
+            Err(Error::Ctap(CtapError::CredentialExcluded))
+        ));
+    }
 
-async fn obtain_shared_secret<C>(
-    channel: &mut C,
-    pin_proto: &Box<dyn PinUvAuthProtocol>,
-    timeout: Duration,
-) -> Result<(PublicKey, Vec<u8>), Error>
-where
-    C: Channel,
-{
-    let client_pin_request = Ctap2ClientPinRequest::new_get_key_agreement(pin_proto.version());
-    let client_pin_response = channel
-        .ctap2_client_pin(&client_pin_request, timeout)
-        .await?;
-    let Some(public_key) = client_pin_response.key_agreement else {
-        error!("Missing public key from Client PIN response");
-        return Err(Error::Ctap(CtapError::Other));
-    };
-    pin_proto.encapsulate(&public_key)
-}
+    #[tokio::test]
+    async fn preferred_resident_key_reported() {
+        let (mut session, _) = session_with_credential().await;
+        let pin_provider: Box<dyn PinProvider> = Box::new(StaticPinProvider::new("1234"));
+        let mut request = MakeCredentialRequest::dummy();
+        request.resident_key = ResidentKeyRequirement::Preferred;
+        let response = session
+            .webauthn_make_credential(&request, &pin_provider)
+            .await
+            .unwrap();
+        assert_eq!(response.cred_props.rk, Some(true));
+
+        let mut request = GetAssertionRequest::dummy();
+        request.user_verification = UserVerificationRequirement::Discouraged;
+        let response = session
+            .webauthn_get_assertion(&request, &pin_provider)
+            .await
+            .unwrap();
+        assert_eq!(response.assertions.len(), 1);
+
+        let response = session
+            .webauthn_make_credential(&MakeCredentialRequest::dummy(), &pin_provider)
+            .await
+            .unwrap();
+        assert_eq!(response.cred_props.rk, Some(false));
+    }
 
-async fn obtain_pin<C>(
-    channel: &mut C,
-    pin_provider: &Box<dyn PinProvider>,
-    timeout: Duration,
-) -> Result<Vec<u8>, Error>
-where
-    C: Channel,
-{
-    let attempts_left = channel
-        .ctap2_client_pin(&Ctap2ClientPinRequest::new_get_pin_retries(), timeout)
-        .await?
-        .pin_retries;
-    let Some(raw_pin) = pin_provider.provide_pin(attempts_left).await else {
-        info!("User cancelled operation: no PIN provided");
-        return Err(Error::Ctap(CtapError::PINRequired));
-    };
-    Ok(raw_pin.as_bytes().to_owned())
+    #[tokio::test]
+    async fn preferred_resident_key_falls_back_when_store_full() {
+        let authenticator =
+            SoftwareAuthenticator::new(CredentialStore::temporary()).with_discoverable_capacity(0);
+        let mut session = AuthenticatorSession::new(SoftwareChannel::new(authenticator));
+        let pin_provider: Box<dyn PinProvider> = Box::new(StaticPinProvider::new("1234"));
+        let mut request = MakeCredentialRequest::dummy();
+        request.resident_key = ResidentKeyRequirement::Preferred;
+        let response = session
+            .webauthn_make_credential(&request, &pin_provider)
+            .await
+            .unwrap();
+        assert_eq!(response.cred_props.rk, Some(false));
+
+        request.resident_key = ResidentKeyRequirement::Required;
+        let result = session
+            .webauthn_make_credential(&request, &pin_provider)
+            .await;
+        assert!(matches!(result, Err(Error::Ctap(CtapError::KeyStoreFull))));
+    }
 }
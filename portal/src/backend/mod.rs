@@ -0,0 +1,377 @@
+//! Serves a [`Backend`] on D-Bus as `org.freedesktop.impl.portal.Credentials`, for the portal to
+//! call whenever the user needs to be asked something. Desktop-specific backends implement the
+//! trait, and are exported by [`BackendService`].
+
+pub mod terminal;
+
+use std::future::Future;
+use std::sync::{Arc, Mutex, Weak};
+
+use dbus::arg::PropMap;
+use dbus::channel::{MatchingReceiver, Token};
+use dbus::message::MatchRule;
+use dbus::nonblock::SyncConnection;
+use dbus::{MethodErr, Path};
+use dbus_crossroads::{Context, Crossroads, IfaceBuilder, IfaceToken};
+use libwebauthn::backend::{
+    pin_request_from_dict, AccountDescription, Backend, DeviceDescription, Interaction,
+    BACKEND_INTERFACE, BACKEND_PATH, BACKEND_REQUEST_INTERFACE,
+};
+use tokio::sync::oneshot;
+use tracing::{debug, info, warn};
+
+use crate::request::{caller, Outcome, Results, REQUEST_PATH_PREFIX};
+
+/// Well-known name of the terminal backend, which the portal calls unless configured otherwise.
+pub const TERMINAL_BACKEND_BUS_NAME: &str = "org.freedesktop.impl.portal.desktop.terminal";
+
+type BackendReply = (u32, PropMap);
+
+/// A backend, exported on the connection until dropped.
+pub struct BackendService {
+    connection: Arc<SyncConnection>,
+    token: Token,
+}
+
+impl BackendService {
+    pub fn export(connection: Arc<SyncConnection>, backend: Arc<dyn Backend>) -> Self {
+        let crossroads = Arc::new(Mutex::new(Crossroads::new()));
+        {
+            let mut cr = crossroads.lock().unwrap();
+            cr.set_async_support(Some((
+                connection.clone(),
+                Box::new(|future| {
+                    tokio::spawn(future);
+                }),
+            )));
+            let interactions = Interactions::register(&crossroads, &mut cr);
+            let iface = register(&mut cr, interactions, backend);
+            cr.insert(BACKEND_PATH, &[iface], ());
+        }
+
+        let token = connection.start_receive(
+            MatchRule::new_method_call(),
+            Box::new(move |message, connection| {
+                if crossroads
+                    .lock()
+                    .unwrap()
+                    .handle_message(message, connection)
+                    .is_err()
+                {
+                    warn!("Failed to handle backend method call");
+                }
+                true
+            }),
+        );
+        debug!("Exported portal backend");
+        Self { connection, token }
+    }
+
+    /// Requests the given well-known name on the bus, for the portal to find the backend.
+    pub async fn request_name(&self, name: &str) -> Result<(), dbus::Error> {
+        self.connection
+            .request_name(name, false, true, false)
+            .await?;
+        Ok(())
+    }
+}
+
+impl Drop for BackendService {
+    fn drop(&mut self) {
+        self.connection.stop_receive(self.token);
+    }
+}
+
+/// An interaction in progress, owned by the portal which started it. Removing it from the object
+/// tree ends the interaction.
+struct PendingInteraction {
+    caller: String,
+    _closed: oneshot::Sender<()>,
+}
+
+/// Exports an `org.freedesktop.impl.portal.Request` for every interaction in progress.
+#[derive(Clone)]
+struct Interactions {
+    crossroads: Weak<Mutex<Crossroads>>,
+    iface: IfaceToken<PendingInteraction>,
+}
+
+impl Interactions {
+    fn register(crossroads: &Arc<Mutex<Crossroads>>, cr: &mut Crossroads) -> Self {
+        let iface = cr.register(
+            BACKEND_REQUEST_INTERFACE,
+            |b: &mut IfaceBuilder<PendingInteraction>| {
+                b.method_with_cr("Close", (), (), |ctx, cr, _: ()| {
+                    let path = ctx.path().clone();
+                    let sender = caller(ctx)?;
+                    let interaction: &mut PendingInteraction = cr
+                        .data_mut(&path)
+                        .ok_or_else(|| MethodErr::no_path(&path))?;
+                    if sender != interaction.caller {
+                        warn!(%path, ?sender, "Rejecting request to close another caller's interaction");
+                        return Err(MethodErr::failed(&"Not the owner of this request"));
+                    }
+                    cr.remove::<PendingInteraction>(&path);
+                    info!(%path, "Interaction closed by the portal");
+                    Ok(())
+                });
+            },
+        );
+        Self {
+            crossroads: Arc::downgrade(crossroads),
+            iface,
+        }
+    }
+
+    /// Exports a request at the interaction's handle, and runs the interaction until it is over
+    /// or the request is closed. Fails if the handle is not a request path, or is in use.
+    fn run<C, F>(
+        &self,
+        ctx: &Context,
+        cr: &mut Crossroads,
+        interaction: Interaction,
+        run: C,
+    ) -> impl Future<Output = Result<BackendReply, MethodErr>> + Send + 'static
+    where
+        C: FnOnce(Interaction) -> F,
+        F: Future<Output = Outcome> + Send + 'static,
+    {
+        let handle = interaction.handle.clone();
+        let started = self.start(ctx, cr, &handle);
+        let future = run(interaction);
+
+        let crossroads = self.crossroads.clone();
+        async move {
+            let closed = started?;
+            let outcome = tokio::select! {
+                outcome = future => outcome,
+                _ = closed => Outcome::Cancelled,
+            };
+            if let Some(crossroads) = crossroads.upgrade() {
+                crossroads
+                    .lock()
+                    .unwrap()
+                    .remove::<PendingInteraction>(&handle);
+            }
+            debug!(%handle, code = ?outcome.code(), "Interaction over");
+            Ok(outcome.into_response())
+        }
+    }
+
+    fn start(
+        &self,
+        ctx: &Context,
+        cr: &mut Crossroads,
+        handle: &Path<'static>,
+    ) -> Result<oneshot::Receiver<()>, MethodErr> {
+        if !is_request_handle(handle) {
+            warn!(%handle, "Rejecting interaction outside the request namespace");
+            return Err(MethodErr::invalid_arg(&"handle"));
+        }
+        if cr.has_interface(handle, self.iface) {
+            warn!(%handle, "Rejecting interaction with a handle already in use");
+            return Err(MethodErr::failed(&"Handle already in use"));
+        }
+
+        let (closed_sender, closed) = oneshot::channel();
+        cr.insert(
+            handle.clone(),
+            &[self.iface],
+            PendingInteraction {
+                caller: caller(ctx).unwrap_or_default(),
+                _closed: closed_sender,
+            },
+        );
+        debug!(%handle, "Interaction started");
+        Ok(closed)
+    }
+}
+
+fn is_request_handle(handle: &Path) -> bool {
+    handle
+        .strip_prefix(REQUEST_PATH_PREFIX)
+        .and_then(|rest| rest.strip_prefix('/'))
+        .is_some_and(|rest| !rest.is_empty())
+}
+
+type ChooseDeviceArgs = (Path<'static>, String, String, Vec<PropMap>, PropMap);
+type EnterPinArgs = (Path<'static>, String, String, PropMap);
+type ConfirmRelyingPartyArgs = (
+    Path<'static>,
+    String,
+    String,
+    String,
+    String,
+    String,
+    PropMap,
+);
+type ChooseAccountArgs = (Path<'static>, String, String, Vec<PropMap>, PropMap);
+type ShowErrorArgs = (Path<'static>, String, String, String, PropMap);
+
+fn register(
+    cr: &mut Crossroads,
+    interactions: Interactions,
+    backend: Arc<dyn Backend>,
+) -> IfaceToken<()> {
+    cr.register(BACKEND_INTERFACE, |b: &mut IfaceBuilder<()>| {
+        let (i, be) = (interactions.clone(), backend.clone());
+        b.method_with_cr_async(
+            "ChooseDevice",
+            ("handle", "app_id", "parent_window", "devices", "options"),
+            ("response", "results"),
+            move |mut ctx, cr, args: ChooseDeviceArgs| {
+                let (handle, app_id, parent_window, devices, _) = args;
+                let interaction = Interaction {
+                    handle,
+                    app_id,
+                    parent_window,
+                };
+                let devices: Vec<_> = devices
+                    .iter()
+                    .filter_map(DeviceDescription::from_dict)
+                    .collect();
+                let backend = be.clone();
+                let reply = i.run(&ctx, cr, interaction, |interaction| async move {
+                    match backend.choose_device(&interaction, &devices).await {
+                        Some(id) => Outcome::Success(Results::from([("device", id.into())])),
+                        None => Outcome::Cancelled,
+                    }
+                });
+                async move { ctx.reply(reply.await) }
+            },
+        );
+        let (i, be) = (interactions.clone(), backend.clone());
+        b.method_with_cr_async(
+            "EnterPin",
+            ("handle", "app_id", "parent_window", "options"),
+            ("response", "results"),
+            move |mut ctx, cr, args: EnterPinArgs| {
+                let (handle, app_id, parent_window, options) = args;
+                let interaction = Interaction {
+                    handle,
+                    app_id,
+                    parent_window,
+                };
+                let request = pin_request_from_dict(&options);
+                let backend = be.clone();
+                let reply = i.run(&ctx, cr, interaction, |interaction| async move {
+                    let Some(request) = request else {
+                        return Outcome::Failed(String::from("Invalid PIN request"));
+                    };
+                    match backend.enter_pin(&interaction, &request).await {
+                        Some(pin) => Outcome::Success(Results::from([("pin", pin.into())])),
+                        None => Outcome::Cancelled,
+                    }
+                });
+                async move { ctx.reply(reply.await) }
+            },
+        );
+        let (i, be) = (interactions.clone(), backend.clone());
+        b.method_with_cr_async(
+            "ConfirmRelyingParty",
+            (
+                "handle",
+                "app_id",
+                "parent_window",
+                "operation",
+                "relying_party_id",
+                "origin",
+                "options",
+            ),
+            ("response", "results"),
+            move |mut ctx, cr, args: ConfirmRelyingPartyArgs| {
+                let (handle, app_id, parent_window, operation, relying_party_id, origin, _) = args;
+                let interaction = Interaction {
+                    handle,
+                    app_id,
+                    parent_window,
+                };
+                let backend = be.clone();
+                let reply = i.run(&ctx, cr, interaction, |interaction| async move {
+                    let Ok(operation) = operation.parse() else {
+                        return Outcome::Failed(String::from("Invalid operation"));
+                    };
+                    if backend
+                        .confirm_relying_party(&interaction, operation, &relying_party_id, &origin)
+                        .await
+                    {
+                        Outcome::Success(Results::new())
+                    } else {
+                        Outcome::Cancelled
+                    }
+                });
+                async move { ctx.reply(reply.await) }
+            },
+        );
+        let (i, be) = (interactions.clone(), backend.clone());
+        b.method_with_cr_async(
+            "ChooseAccount",
+            ("handle", "app_id", "parent_window", "accounts", "options"),
+            ("response", "results"),
+            move |mut ctx, cr, args: ChooseAccountArgs| {
+                let (handle, app_id, parent_window, accounts, _) = args;
+                let interaction = Interaction {
+                    handle,
+                    app_id,
+                    parent_window,
+                };
+                let accounts: Option<Vec<_>> =
+                    accounts.iter().map(AccountDescription::from_dict).collect();
+                let backend = be.clone();
+                let reply = i.run(&ctx, cr, interaction, |interaction| async move {
+                    let Some(accounts) = accounts else {
+                        return Outcome::Failed(String::from("Invalid accounts"));
+                    };
+                    match backend.choose_account(&interaction, &accounts).await {
+                        Some(index) => {
+                            Outcome::Success(Results::from([("account", (index as u32).into())]))
+                        }
+                        None => Outcome::Cancelled,
+                    }
+                });
+                async move { ctx.reply(reply.await) }
+            },
+        );
+        let (i, be) = (interactions.clone(), backend.clone());
+        b.method_with_cr_async(
+            "ShowError",
+            ("handle", "app_id", "parent_window", "message", "options"),
+            ("response", "results"),
+            move |mut ctx, cr, args: ShowErrorArgs| {
+                let (handle, app_id, parent_window, message, _) = args;
+                let interaction = Interaction {
+                    handle,
+                    app_id,
+                    parent_window,
+                };
+                let backend = be.clone();
+                let reply = i.run(&ctx, cr, interaction, |interaction| async move {
+                    backend.show_error(&interaction, &message).await;
+                    Outcome::Success(Results::new())
+                });
+                async move { ctx.reply(reply.await) }
+            },
+        );
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use dbus::Path;
+    use libwebauthn::backend::BACKEND_PATH;
+
+    use super::is_request_handle;
+    use crate::request::request_path;
+
+    #[test]
+    fn request_handles() {
+        assert!(is_request_handle(&request_path(":1.42", "token")));
+        assert!(!is_request_handle(&Path::from(BACKEND_PATH)));
+        assert!(!is_request_handle(&Path::from(
+            "/org/freedesktop/portal/desktop/request"
+        )));
+        assert!(!is_request_handle(&Path::from(
+            "/org/freedesktop/portal/desktop/requests/1_42/token"
+        )));
+    }
+}
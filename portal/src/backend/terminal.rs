@@ -0,0 +1,188 @@
+//! Reference backend, asking the user on the terminal it runs in. An empty answer cancels.
+
+use std::io::{self, Write};
+
+use async_trait::async_trait;
+use libwebauthn::backend::{
+    AccountDescription, Backend, DeviceDescription, Interaction, Operation,
+};
+use libwebauthn::pin::{Pin, PinPurpose, PinRequest};
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, BufReader, Lines, Stdin};
+use tokio::sync::Mutex;
+use tracing::warn;
+
+pub struct TerminalBackend {
+    // Held for the whole interaction, so that concurrent requests are asked one at a time.
+    input: Mutex<Lines<BufReader<Stdin>>>,
+}
+
+impl Default for TerminalBackend {
+    fn default() -> Self {
+        Self {
+            input: Mutex::new(BufReader::new(tokio::io::stdin()).lines()),
+        }
+    }
+}
+
+impl TerminalBackend {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+/// Prints the prompt and reads the answer, `None` if empty or if input is over.
+async fn ask<R>(input: &mut Lines<R>, prompt: &str) -> Option<String>
+where
+    R: AsyncBufRead + Unpin,
+{
+    print!("{}", prompt);
+    io::stdout().flush().unwrap();
+    match input.next_line().await {
+        Ok(Some(line)) if !line.trim().is_empty() => Some(line.trim().to_owned()),
+        Ok(_) => None,
+        Err(err) => {
+            warn!(%err, "Failed to read from the terminal");
+            None
+        }
+    }
+}
+
+/// Asks for an index in `0..count`, until a valid one is entered. `None` if there is nothing
+/// to choose from.
+async fn ask_index<R>(input: &mut Lines<R>, prompt: &str, count: usize) -> Option<usize>
+where
+    R: AsyncBufRead + Unpin,
+{
+    if count == 0 {
+        return None;
+    }
+    loop {
+        match ask(input, prompt).await?.parse::<usize>() {
+            Ok(index) if index < count => return Some(index),
+            _ => println!("Please enter a number between 0 and {}.", count - 1),
+        }
+    }
+}
+
+fn describe(interaction: &Interaction) -> &str {
+    if interaction.app_id.is_empty() {
+        "An application"
+    } else {
+        &interaction.app_id
+    }
+}
+
+#[async_trait]
+impl Backend for TerminalBackend {
+    async fn choose_device(
+        &self,
+        interaction: &Interaction,
+        devices: &[DeviceDescription],
+    ) -> Option<String> {
+        let mut input = self.input.lock().await;
+        println!("{} needs a security key:", describe(interaction));
+        for (index, device) in devices.iter().enumerate() {
+            println!("  {}: {} ({:?})", index, device.name, device.transport);
+        }
+        let index = ask_index(&mut input, "Security key to use: ", devices.len()).await?;
+        Some(devices[index].id.clone())
+    }
+
+    async fn enter_pin(&self, _interaction: &Interaction, request: &PinRequest) -> Option<Pin> {
+        let mut input = self.input.lock().await;
+        if request.power_cycle_required {
+            println!("PIN: Too many failed attempts, please unplug and replug your authenticator.");
+        }
+        if request.previous_attempt_failed {
+            println!("PIN: The PIN was rejected.");
+        }
+        if let Some(attempts_left) = request.attempts_left {
+            println!("PIN: {} attempts left.", attempts_left);
+        }
+        let prompt = match request.purpose {
+            PinPurpose::Entry => "Please enter the PIN for",
+            PinPurpose::Set | PinPurpose::Change => "Please choose a new PIN for",
+        };
+        let prompt = format!(
+            "PIN: {} {} (at least {} characters): ",
+            prompt, request.device, request.min_length
+        );
+        match ask(&mut input, &prompt).await {
+            Some(pin) => Some(Pin::new(pin)),
+            None => {
+                println!("PIN: No PIN provided, cancelling operation.");
+                None
+            }
+        }
+    }
+
+    async fn confirm_relying_party(
+        &self,
+        interaction: &Interaction,
+        operation: Operation,
+        relying_party_id: &str,
+        origin: &str,
+    ) -> bool {
+        let mut input = self.input.lock().await;
+        let action = match operation {
+            Operation::Create | Operation::Register => "create a credential",
+            Operation::Get | Operation::Sign => "sign in",
+        };
+        let prompt = format!(
+            "{} wants to {} for {} (origin {}). Allow? [y/N] ",
+            describe(interaction),
+            action,
+            relying_party_id,
+            origin
+        );
+        matches!(
+            ask(&mut input, &prompt).await.as_deref(),
+            Some("y" | "Y" | "yes")
+        )
+    }
+
+    async fn choose_account(
+        &self,
+        _interaction: &Interaction,
+        accounts: &[AccountDescription],
+    ) -> Option<usize> {
+        let mut input = self.input.lock().await;
+        println!("Several accounts are available:");
+        for (index, account) in accounts.iter().enumerate() {
+            let name = account.name.as_deref().unwrap_or("(unnamed)");
+            match &account.display_name {
+                Some(display_name) => println!("  {}: {} ({})", index, display_name, name),
+                None => println!("  {}: {}", index, name),
+            }
+        }
+        ask_index(&mut input, "Account to sign in with: ", accounts.len()).await
+    }
+
+    async fn show_error(&self, interaction: &Interaction, message: &str) {
+        let _input = self.input.lock().await;
+        println!(
+            "{} could not use your security key: {}",
+            describe(interaction),
+            message
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncBufReadExt, BufReader};
+
+    use super::ask_index;
+
+    #[tokio::test]
+    async fn ask_index_retries_until_valid() {
+        let mut input = BufReader::new(&b"x\n7\n1\n"[..]).lines();
+        assert_eq!(ask_index(&mut input, "", 2).await, Some(1));
+    }
+
+    #[tokio::test]
+    async fn ask_index_empty_list() {
+        let mut input = BufReader::new(&b"0\n"[..]).lines();
+        assert_eq!(ask_index(&mut input, "", 0).await, None);
+    }
+}
@@ -0,0 +1,32 @@
+//! Runs the terminal backend, for the portal to ask the user on this terminal.
+
+use std::error::Error;
+use std::sync::Arc;
+
+use portal::backend::terminal::TerminalBackend;
+use portal::backend::{BackendService, TERMINAL_BACKEND_BUS_NAME};
+use tracing::{error, info};
+use tracing_subscriber::{self, EnvFilter};
+
+fn setup_logging() {
+    tracing_subscriber::fmt()
+        .with_env_filter(EnvFilter::from_default_env())
+        .without_time()
+        .init();
+}
+
+#[tokio::main]
+pub async fn main() -> Result<(), Box<dyn Error>> {
+    setup_logging();
+
+    let (resource, connection) = dbus_tokio::connection::new_session_sync()?;
+    let dispatcher = tokio::spawn(resource);
+
+    let service = BackendService::export(connection, Arc::new(TerminalBackend::new()));
+    service.request_name(TERMINAL_BACKEND_BUS_NAME).await?;
+    info!("Terminal backend is running");
+
+    let err = dispatcher.await?;
+    error!(%err, "Lost connection to D-Bus");
+    Err(err.into())
+}
@@ -0,0 +1,220 @@
+//! Identification of the application behind a D-Bus caller, for any service exporting the portal
+//! interfaces to check what the caller may do.
+//!
+//! The caller's process is found through the bus, preferably as a pidfd so that the process can
+//! not be swapped for another one reusing its PID. Flatpak apps are identified by the
+//! `.flatpak-info` file at the root of their sandbox, and Snap apps by their cgroup. Unsandboxed
+//! callers can not be told apart reliably, and are left without an app ID.
+
+use std::fmt;
+use std::fs::{self, File};
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::time::Duration;
+
+use dbus::arg::{prop_cast, PropMap};
+use dbus::nonblock::{Proxy, SyncConnection};
+use dbus::Path;
+use libwebauthn::backend::Interaction;
+use tracing::{debug, instrument, warn};
+
+const DBUS_BUS_NAME: &str = "org.freedesktop.DBus";
+const DBUS_PATH: &str = "/org/freedesktop/DBus";
+const DBUS_INTERFACE: &str = "org.freedesktop.DBus";
+const DBUS_CALL_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Snap apps run in cgroups named snap.<snap name>.<app name>.
+const SNAP_CGROUP_PREFIX: &str = "snap.";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Sandbox {
+    Unsandboxed,
+    Flatpak,
+    Snap,
+}
+
+/// The application behind a portal method call.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Caller {
+    pub sandbox: Sandbox,
+    /// Flatpak app ID, or `snap.` followed by the snap name. Empty for unsandboxed callers.
+    pub app_id: String,
+}
+
+impl Caller {
+    pub fn unsandboxed() -> Self {
+        Self {
+            sandbox: Sandbox::Unsandboxed,
+            app_id: String::new(),
+        }
+    }
+
+    /// An interaction with the user on behalf of this caller, for the request with the given
+    /// handle.
+    pub fn interaction(&self, handle: Path<'static>) -> Interaction {
+        let mut interaction = Interaction::new(handle);
+        interaction.app_id = self.app_id.clone();
+        interaction
+    }
+}
+
+impl fmt::Display for Caller {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.sandbox {
+            Sandbox::Unsandboxed => write!(f, "unsandboxed caller"),
+            Sandbox::Flatpak => write!(f, "Flatpak app {}", self.app_id),
+            Sandbox::Snap => write!(f, "Snap app {}", self.app_id),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum IdentifyError {
+    /// The bus did not tell which process the caller is.
+    Bus(dbus::Error),
+    /// The caller's process could not be inspected.
+    Process(io::Error),
+    /// The caller's process exited, or its sandbox metadata is invalid.
+    Invalid,
+}
+
+impl fmt::Display for IdentifyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            IdentifyError::Bus(err) => write!(f, "Unable to get the caller's credentials: {}", err),
+            IdentifyError::Process(err) => write!(f, "Unable to inspect the caller: {}", err),
+            IdentifyError::Invalid => write!(f, "Invalid caller process"),
+        }
+    }
+}
+
+impl std::error::Error for IdentifyError {}
+
+impl From<dbus::Error> for IdentifyError {
+    fn from(err: dbus::Error) -> Self {
+        IdentifyError::Bus(err)
+    }
+}
+
+impl From<io::Error> for IdentifyError {
+    fn from(err: io::Error) -> Self {
+        IdentifyError::Process(err)
+    }
+}
+
+/// Identifies the application behind the given unique bus name.
+#[instrument(skip_all, fields(%sender))]
+pub async fn identify(connection: &SyncConnection, sender: &str) -> Result<Caller, IdentifyError> {
+    let proxy = Proxy::new(DBUS_BUS_NAME, DBUS_PATH, DBUS_CALL_TIMEOUT, connection);
+    let (credentials,): (PropMap,) = proxy
+        .method_call(DBUS_INTERFACE, "GetConnectionCredentials", (sender,))
+        .await?;
+
+    let Some(pidfd) = prop_cast::<File>(&credentials, "ProcessFD") else {
+        // Older buses only provide the PID, which may be reused if the caller exits.
+        warn!("Bus does not provide a pidfd, falling back to the caller's PID");
+        let Some(&pid) = prop_cast::<u32>(&credentials, "ProcessID") else {
+            return Err(IdentifyError::Invalid);
+        };
+        return identify_process(pid);
+    };
+    let pid = pidfd_pid(pidfd)?;
+    let caller = identify_process(pid)?;
+    // The process inspected above is the caller only if it is still running.
+    if pidfd_pid(pidfd)? != pid {
+        return Err(IdentifyError::Invalid);
+    }
+    Ok(caller)
+}
+
+/// PID of the process which the pidfd refers to.
+fn pidfd_pid(pidfd: &File) -> Result<u32, IdentifyError> {
+    let fdinfo = fs::read_to_string(format!("/proc/self/fdinfo/{}", pidfd.as_raw_fd()))?;
+    let pid = fdinfo
+        .lines()
+        .find_map(|line| line.strip_prefix("Pid:"))
+        .and_then(|pid| pid.trim().parse::<i64>().ok());
+    match pid {
+        Some(pid) if pid > 0 => Ok(pid as u32),
+        // The process exited.
+        _ => Err(IdentifyError::Invalid),
+    }
+}
+
+fn identify_process(pid: u32) -> Result<Caller, IdentifyError> {
+    match fs::read_to_string(format!("/proc/{}/root/.flatpak-info", pid)) {
+        Ok(flatpak_info) => {
+            let app_id = flatpak_app_id(&flatpak_info).ok_or(IdentifyError::Invalid)?;
+            debug!(%app_id, "Caller is a Flatpak app");
+            return Ok(Caller {
+                sandbox: Sandbox::Flatpak,
+                app_id,
+            });
+        }
+        Err(err) if err.kind() == io::ErrorKind::NotFound => (),
+        Err(err) => return Err(err.into()),
+    }
+
+    let cgroup = fs::read_to_string(format!("/proc/{}/cgroup", pid))?;
+    if let Some(snap) = snap_name(&cgroup) {
+        debug!(%snap, "Caller is a Snap app");
+        return Ok(Caller {
+            sandbox: Sandbox::Snap,
+            app_id: format!("{}{}", SNAP_CGROUP_PREFIX, snap),
+        });
+    }
+    Ok(Caller::unsandboxed())
+}
+
+/// The `name` key of the `[Application]` group of a `.flatpak-info` keyfile.
+fn flatpak_app_id(flatpak_info: &str) -> Option<String> {
+    let mut in_application = false;
+    for line in flatpak_info.lines().map(str::trim) {
+        if line.starts_with('[') {
+            in_application = line == "[Application]";
+        } else if in_application {
+            if let Some((key, value)) = line.split_once('=') {
+                if key.trim() == "name" && !value.trim().is_empty() {
+                    return Some(value.trim().to_owned());
+                }
+            }
+        }
+    }
+    None
+}
+
+/// Name of the snap whose cgroup the process runs in, from `/proc/<pid>/cgroup`.
+fn snap_name(cgroup: &str) -> Option<&str> {
+    cgroup
+        .lines()
+        // hierarchy-ID:controller-list:cgroup-path
+        .filter_map(|line| line.splitn(3, ':').nth(2))
+        .filter_map(|path| path.rsplit('/').next())
+        .filter_map(|group| group.strip_prefix(SNAP_CGROUP_PREFIX))
+        .filter_map(|group| group.split('.').next())
+        .find(|snap| !snap.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::caller::{flatpak_app_id, snap_name};
+
+    #[test]
+    fn flatpak_app_id_from_info() {
+        let flatpak_info = "[Application]\nname=org.mozilla.firefox\nruntime=runtime/org.freedesktop.Platform/x86_64/22.08\n\n[Instance]\ninstance-id=1234\n";
+        assert_eq!(
+            flatpak_app_id(flatpak_info).as_deref(),
+            Some("org.mozilla.firefox")
+        );
+        assert_eq!(flatpak_app_id("[Instance]\nname=org.example.App\n"), None);
+        assert_eq!(flatpak_app_id("[Application]\nname=\n"), None);
+    }
+
+    #[test]
+    fn snap_name_from_cgroup() {
+        let cgroup = "0::/user.slice/user-1000.slice/user@1000.service/app.slice/snap.firefox.firefox-6a1c.scope\n";
+        assert_eq!(snap_name(cgroup), Some("firefox"));
+        let cgroup = "12:pids:/user.slice\n0::/user.slice/user-1000.slice/session-2.scope\n";
+        assert_eq!(snap_name(cgroup), None);
+    }
+}
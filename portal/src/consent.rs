@@ -0,0 +1,499 @@
+//! Per-application consent, remembered in `org.freedesktop.impl.portal.PermissionStore`.
+//!
+//! The user is asked at least once before a sandboxed app uses a relying party, and their answer
+//! is remembered for a while. Decisions are stored in the `webauthn` table, with one entry per
+//! relying party ID, holding each app's decision and its expiry, in seconds since the Unix epoch.
+//!
+//! The `org.freedesktop.portal.CredentialsConsent` interface lists and revokes remembered
+//! decisions. Sandboxed callers only see and revoke their own.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use dbus::arg::{PropMap, RefArg, Variant};
+use dbus::nonblock::{Proxy, SyncConnection};
+use dbus::MethodErr;
+use dbus_crossroads::{Crossroads, IfaceBuilder, IfaceToken};
+use tracing::{debug, info, instrument, warn};
+
+use crate::caller::{self, Caller, Sandbox};
+use crate::request;
+use crate::service::Context;
+
+pub const CONSENT_INTERFACE: &str = "org.freedesktop.portal.CredentialsConsent";
+
+pub const PERMISSION_STORE_BUS_NAME: &str = "org.freedesktop.impl.portal.PermissionStore";
+pub const PERMISSION_STORE_PATH: &str = "/org/freedesktop/impl/portal/PermissionStore";
+pub const PERMISSION_STORE_INTERFACE: &str = "org.freedesktop.impl.portal.PermissionStore";
+pub const CONSENT_TABLE: &str = "webauthn";
+
+const PERMISSION_STORE_TIMEOUT: Duration = Duration::from_secs(5);
+pub const NOT_FOUND_ERROR: &str = "org.freedesktop.portal.Error.NotFound";
+
+// Grants are remembered for longer than denials, which the user is more likely to revisit.
+const GRANT_LIFETIME: Duration = Duration::from_secs(90 * 24 * 60 * 60);
+const DENIAL_LIFETIME: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+const GRANTED: &str = "yes";
+const DENIED: &str = "no";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Decision {
+    Granted,
+    Denied,
+}
+
+/// The user's decision about an app using a relying party.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Grant {
+    pub app_id: String,
+    pub relying_party_id: String,
+    pub decision: Decision,
+    pub expires: SystemTime,
+}
+
+impl Grant {
+    pub fn new(app_id: &str, relying_party_id: &str, decision: Decision) -> Self {
+        let lifetime = match decision {
+            Decision::Granted => GRANT_LIFETIME,
+            Decision::Denied => DENIAL_LIFETIME,
+        };
+        Self {
+            app_id: app_id.to_owned(),
+            relying_party_id: relying_party_id.to_owned(),
+            decision,
+            expires: SystemTime::now() + lifetime,
+        }
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.expires <= SystemTime::now()
+    }
+
+    /// Permissions stored for the app: the decision, followed by its expiry.
+    fn to_permissions(&self) -> Vec<String> {
+        let decision = match self.decision {
+            Decision::Granted => GRANTED,
+            Decision::Denied => DENIED,
+        };
+        let expires = self
+            .expires
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        vec![decision.to_owned(), expires.to_string()]
+    }
+
+    fn from_permissions(
+        app_id: &str,
+        relying_party_id: &str,
+        permissions: &[String],
+    ) -> Option<Self> {
+        let [decision, expires] = permissions else {
+            return None;
+        };
+        let decision = match decision.as_str() {
+            GRANTED => Decision::Granted,
+            DENIED => Decision::Denied,
+            _ => return None,
+        };
+        let expires = UNIX_EPOCH + Duration::from_secs(expires.parse().ok()?);
+        Some(Self {
+            app_id: app_id.to_owned(),
+            relying_party_id: relying_party_id.to_owned(),
+            decision,
+            expires,
+        })
+    }
+
+    pub fn to_dict(&self) -> PropMap {
+        let expires = self
+            .expires
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let entries: Vec<(&str, Box<dyn RefArg>)> = vec![
+            ("app_id", Box::new(self.app_id.clone())),
+            ("relying_party_id", Box::new(self.relying_party_id.clone())),
+            ("granted", Box::new(self.decision == Decision::Granted)),
+            ("expires", Box::new(expires)),
+        ];
+        entries
+            .into_iter()
+            .map(|(key, value)| (key.to_owned(), Variant(value)))
+            .collect()
+    }
+}
+
+/// Remembers decisions in the permission store.
+#[derive(Clone)]
+pub struct ConsentStore {
+    connection: Arc<SyncConnection>,
+    destination: String,
+}
+
+impl ConsentStore {
+    pub fn new(connection: Arc<SyncConnection>, destination: &str) -> Self {
+        Self {
+            connection,
+            destination: destination.to_owned(),
+        }
+    }
+
+    fn proxy(&self) -> Proxy<'_, &SyncConnection> {
+        Proxy::new(
+            self.destination.as_str(),
+            PERMISSION_STORE_PATH,
+            PERMISSION_STORE_TIMEOUT,
+            self.connection.as_ref(),
+        )
+    }
+
+    /// Decisions about the relying party, by app ID, expired ones included.
+    async fn entry(&self, relying_party_id: &str) -> Result<Vec<Grant>, dbus::Error> {
+        let result: Result<(HashMap<String, Vec<String>>,), _> = self
+            .proxy()
+            .method_call(
+                PERMISSION_STORE_INTERFACE,
+                "Lookup",
+                (CONSENT_TABLE, relying_party_id),
+            )
+            .await;
+        let permissions = match result {
+            Ok((permissions,)) => permissions,
+            Err(err) if err.name() == Some(NOT_FOUND_ERROR) => return Ok(vec![]),
+            Err(err) => return Err(err),
+        };
+        Ok(permissions
+            .iter()
+            .filter_map(|(app_id, permissions)| {
+                Grant::from_permissions(app_id, relying_party_id, permissions)
+            })
+            .collect())
+    }
+
+    /// The remembered decision about the app using the relying party, unless expired.
+    #[instrument(skip(self))]
+    pub async fn lookup(
+        &self,
+        app_id: &str,
+        relying_party_id: &str,
+    ) -> Result<Option<Decision>, dbus::Error> {
+        let grant = self
+            .entry(relying_party_id)
+            .await?
+            .into_iter()
+            .find(|grant| grant.app_id == app_id && !grant.is_expired());
+        Ok(grant.map(|grant| grant.decision))
+    }
+
+    #[instrument(skip_all, fields(app_id = %grant.app_id, rp = %grant.relying_party_id))]
+    pub async fn record(&self, grant: &Grant) -> Result<(), dbus::Error> {
+        let () = self
+            .proxy()
+            .method_call(
+                PERMISSION_STORE_INTERFACE,
+                "SetPermission",
+                (
+                    CONSENT_TABLE,
+                    true,
+                    grant.relying_party_id.as_str(),
+                    grant.app_id.as_str(),
+                    grant.to_permissions(),
+                ),
+            )
+            .await?;
+        debug!(decision = ?grant.decision, "Recorded consent");
+        Ok(())
+    }
+
+    /// Decisions which have not expired, of the given app only if any.
+    pub async fn list(&self, app_id: Option<&str>) -> Result<Vec<Grant>, dbus::Error> {
+        let (relying_party_ids,): (Vec<String>,) = self
+            .proxy()
+            .method_call(PERMISSION_STORE_INTERFACE, "List", (CONSENT_TABLE,))
+            .await?;
+        let mut grants = vec![];
+        for relying_party_id in relying_party_ids {
+            grants.extend(
+                self.entry(&relying_party_id)
+                    .await?
+                    .into_iter()
+                    .filter(|grant| !grant.is_expired())
+                    .filter(|grant| app_id.map_or(true, |app_id| grant.app_id == app_id)),
+            );
+        }
+        Ok(grants)
+    }
+
+    #[instrument(skip(self))]
+    pub async fn revoke(&self, app_id: &str, relying_party_id: &str) -> Result<(), dbus::Error> {
+        let () = self
+            .proxy()
+            .method_call(
+                PERMISSION_STORE_INTERFACE,
+                "DeletePermission",
+                (CONSENT_TABLE, relying_party_id, app_id),
+            )
+            .await?;
+        info!("Revoked consent");
+        Ok(())
+    }
+}
+
+/// Which grants the caller may see and revoke: its own if sandboxed, all otherwise.
+fn visible_app_id(caller: &Caller) -> Option<&str> {
+    match caller.sandbox {
+        Sandbox::Unsandboxed => None,
+        Sandbox::Flatpak | Sandbox::Snap => Some(&caller.app_id),
+    }
+}
+
+pub fn register(
+    cr: &mut Crossroads,
+    connection: Arc<SyncConnection>,
+    context: Arc<Context>,
+) -> IfaceToken<()> {
+    cr.register(CONSENT_INTERFACE, |b: &mut IfaceBuilder<()>| {
+        let (conn, c) = (connection.clone(), context.clone());
+        b.method_with_cr_async(
+            "ListGrants",
+            ("options",),
+            ("grants",),
+            move |mut ctx, _cr, _: (PropMap,)| {
+                let (conn, c) = (conn.clone(), c.clone());
+                let sender = request::caller(&ctx);
+                async move {
+                    let grants = list_grants(&conn, &c, sender).await;
+                    ctx.reply(
+                        grants
+                            .map(|grants| (grants.iter().map(Grant::to_dict).collect::<Vec<_>>(),)),
+                    )
+                }
+            },
+        );
+        let (conn, c) = (connection.clone(), context.clone());
+        b.method_with_cr_async(
+            "RevokeGrant",
+            ("app_id", "relying_party_id"),
+            (),
+            move |mut ctx, _cr, (app_id, relying_party_id): (String, String)| {
+                let (conn, c) = (conn.clone(), c.clone());
+                let sender = request::caller(&ctx);
+                async move {
+                    let result = revoke_grant(&conn, &c, sender, &app_id, &relying_party_id).await;
+                    ctx.reply(result)
+                }
+            },
+        );
+    })
+}
+
+async fn list_grants(
+    connection: &SyncConnection,
+    context: &Context,
+    sender: Result<String, MethodErr>,
+) -> Result<Vec<Grant>, MethodErr> {
+    let caller = identify(connection, &sender?).await?;
+    context
+        .consent
+        .list(visible_app_id(&caller))
+        .await
+        .map_err(|err| {
+            warn!(%err, "Failed to list grants");
+            MethodErr::failed(&"Permission store unavailable")
+        })
+}
+
+async fn revoke_grant(
+    connection: &SyncConnection,
+    context: &Context,
+    sender: Result<String, MethodErr>,
+    app_id: &str,
+    relying_party_id: &str,
+) -> Result<(), MethodErr> {
+    let caller = identify(connection, &sender?).await?;
+    if visible_app_id(&caller).map_or(false, |own| own != app_id) {
+        warn!(%caller, %app_id, "Rejecting revocation of another app's grant");
+        return Err(MethodErr::failed(
+            &"Not allowed to revoke grants of other apps",
+        ));
+    }
+    context
+        .consent
+        .revoke(app_id, relying_party_id)
+        .await
+        .map_err(|err| {
+            warn!(%err, "Failed to revoke grant");
+            MethodErr::failed(&"Permission store unavailable")
+        })
+}
+
+async fn identify(connection: &SyncConnection, sender: &str) -> Result<Caller, MethodErr> {
+    caller::identify(connection, sender).await.map_err(|err| {
+        warn!(%err, "Failed to identify the caller");
+        MethodErr::failed(&"Unable to identify the caller")
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, UNIX_EPOCH};
+
+    #[cfg(feature = "dbus-daemon-tests")]
+    use dbus::arg::{prop_cast, PropMap};
+
+    #[cfg(feature = "dbus-daemon-tests")]
+    use crate::consent::{ConsentStore, CONSENT_INTERFACE, PERMISSION_STORE_BUS_NAME};
+    use crate::consent::{Decision, Grant};
+    #[cfg(feature = "dbus-daemon-tests")]
+    use crate::testing::{PendingDeviceSelector, PermissionStoreStandIn, PrivateBus, TestPortal};
+
+    #[test]
+    fn grant_permissions_round_trip() {
+        let grant = Grant {
+            app_id: String::from("org.example.App"),
+            relying_party_id: String::from("example.org"),
+            decision: Decision::Granted,
+            expires: UNIX_EPOCH + Duration::from_secs(1_700_000_000),
+        };
+        let permissions = grant.to_permissions();
+        assert_eq!(permissions, vec!["yes", "1700000000"]);
+        assert_eq!(
+            Grant::from_permissions("org.example.App", "example.org", &permissions),
+            Some(grant)
+        );
+        assert_eq!(
+            Grant::from_permissions("org.example.App", "example.org", &[String::from("yes")]),
+            None
+        );
+    }
+
+    #[test]
+    fn grants_expire() {
+        let grant = Grant::new("org.example.App", "example.org", Decision::Denied);
+        assert!(!grant.is_expired());
+        let expired = Grant {
+            expires: UNIX_EPOCH,
+            ..grant
+        };
+        assert!(expired.is_expired());
+    }
+
+    #[cfg(feature = "dbus-daemon-tests")]
+    #[tokio::test]
+    async fn consent_remembered() {
+        let bus = PrivateBus::start();
+        let _permission_store = PermissionStoreStandIn::start(bus.connect()).await;
+        let store = ConsentStore::new(bus.connect(), PERMISSION_STORE_BUS_NAME);
+
+        assert_eq!(
+            store
+                .lookup("org.example.App", "example.org")
+                .await
+                .unwrap(),
+            None
+        );
+        store
+            .record(&Grant::new(
+                "org.example.App",
+                "example.org",
+                Decision::Granted,
+            ))
+            .await
+            .unwrap();
+        store
+            .record(&Grant::new(
+                "org.example.Other",
+                "example.org",
+                Decision::Denied,
+            ))
+            .await
+            .unwrap();
+        assert_eq!(
+            store
+                .lookup("org.example.App", "example.org")
+                .await
+                .unwrap(),
+            Some(Decision::Granted)
+        );
+        assert_eq!(
+            store
+                .lookup("org.example.Other", "example.org")
+                .await
+                .unwrap(),
+            Some(Decision::Denied)
+        );
+        assert_eq!(store.list(Some("org.example.App")).await.unwrap().len(), 1);
+        assert_eq!(store.list(None).await.unwrap().len(), 2);
+
+        store
+            .revoke("org.example.App", "example.org")
+            .await
+            .unwrap();
+        assert_eq!(
+            store
+                .lookup("org.example.App", "example.org")
+                .await
+                .unwrap(),
+            None
+        );
+
+        let expired = Grant {
+            expires: UNIX_EPOCH,
+            ..Grant::new("org.example.App", "example.com", Decision::Granted)
+        };
+        store.record(&expired).await.unwrap();
+        assert_eq!(
+            store
+                .lookup("org.example.App", "example.com")
+                .await
+                .unwrap(),
+            None
+        );
+    }
+
+    #[cfg(feature = "dbus-daemon-tests")]
+    #[tokio::test]
+    async fn grants_listed_and_revoked_over_dbus() {
+        let portal = TestPortal::start(Box::new(PendingDeviceSelector)).await;
+        portal
+            .consent
+            .record(&Grant::new(
+                "org.example.App",
+                "example.org",
+                Decision::Granted,
+            ))
+            .await
+            .unwrap();
+
+        let (grants,): (Vec<PropMap>,) = portal
+            .proxy()
+            .method_call(CONSENT_INTERFACE, "ListGrants", (PropMap::new(),))
+            .await
+            .unwrap();
+        assert_eq!(grants.len(), 1);
+        assert_eq!(
+            prop_cast::<String>(&grants[0], "app_id").map(String::as_str),
+            Some("org.example.App")
+        );
+        assert_eq!(prop_cast::<bool>(&grants[0], "granted"), Some(&true));
+
+        let () = portal
+            .proxy()
+            .method_call(
+                CONSENT_INTERFACE,
+                "RevokeGrant",
+                ("org.example.App", "example.org"),
+            )
+            .await
+            .unwrap();
+        let (grants,): (Vec<PropMap>,) = portal
+            .proxy()
+            .method_call(CONSENT_INTERFACE, "ListGrants", (PropMap::new(),))
+            .await
+            .unwrap();
+        assert!(grants.is_empty());
+    }
+}
@@ -0,0 +1,75 @@
+//! Selection of the authenticator which a ceremony runs on.
+
+use async_trait::async_trait;
+use libwebauthn::backend::{Backend, DeviceDescription, Interaction};
+use libwebauthn::transport::{Channel, DeviceManager};
+use libwebauthn::webauthn::{CtapError, Error, TransportError};
+use tracing::{info, instrument, warn};
+
+#[async_trait]
+pub trait DeviceSelector: Send + Sync {
+    /// Opens a channel to the authenticator which the ceremony should run on, asking the user
+    /// through the backend if needed.
+    async fn select(
+        &self,
+        backend: &dyn Backend,
+        interaction: &Interaction,
+    ) -> Result<Box<dyn Channel>, Error>;
+}
+
+/// Selects among the authenticators found on every available transport, letting the user choose
+/// if there are several.
+#[derive(Default)]
+pub struct UserDeviceSelector {
+    manager: DeviceManager,
+}
+
+impl UserDeviceSelector {
+    pub fn new(manager: DeviceManager) -> Self {
+        Self { manager }
+    }
+}
+
+#[async_trait]
+impl DeviceSelector for UserDeviceSelector {
+    #[instrument(skip_all)]
+    async fn select(
+        &self,
+        backend: &dyn Backend,
+        interaction: &Interaction,
+    ) -> Result<Box<dyn Channel>, Error> {
+        let mut devices = self.manager.list_devices().await;
+        let index = match devices.len() {
+            0 => {
+                warn!("No authenticators found");
+                return Err(Error::Transport(TransportError::TransportUnavailable));
+            }
+            1 => 0,
+            _ => {
+                let descriptions: Vec<_> = devices
+                    .iter()
+                    .enumerate()
+                    .map(|(index, device)| DeviceDescription {
+                        id: index.to_string(),
+                        name: device.name(),
+                        transport: device.transport(),
+                    })
+                    .collect();
+                let Some(id) = backend.choose_device(interaction, &descriptions).await else {
+                    info!("No authenticator chosen");
+                    return Err(Error::Ctap(CtapError::OperationDenied));
+                };
+                match id.parse::<usize>() {
+                    Ok(index) if index < devices.len() => index,
+                    _ => {
+                        warn!(%id, "Backend chose an authenticator which does not exist");
+                        return Err(Error::Transport(TransportError::TransportUnavailable));
+                    }
+                }
+            }
+        };
+        let mut device = devices.swap_remove(index);
+        info!(%device, "Selected authenticator");
+        device.channel().await
+    }
+}
@@ -0,0 +1,19 @@
+//! Reference implementation of the credentials portal: a D-Bus service exposing FIDO2 and FIDO U2F
+//! platform APIs to desktop applications, including sandboxed ones.
+
+#![feature(let_else)]
+
+pub mod backend;
+pub mod caller;
+pub mod consent;
+pub mod devices;
+pub mod policy;
+pub mod request;
+pub mod service;
+pub mod u2f;
+pub mod webauthn;
+
+#[cfg(all(test, feature = "dbus-daemon-tests"))]
+mod testing;
+
+pub use service::{Context, Service};
@@ -0,0 +1,50 @@
+use std::env;
+use std::error::Error;
+use std::path::Path;
+use std::sync::Arc;
+
+use libwebauthn::backend::BackendClient;
+use portal::backend::TERMINAL_BACKEND_BUS_NAME;
+use portal::consent::{ConsentStore, PERMISSION_STORE_BUS_NAME};
+use portal::devices::UserDeviceSelector;
+use portal::policy::{OriginPolicy, ASSOCIATIONS_PATH};
+use portal::{Context, Service};
+use tracing::{error, info};
+use tracing_subscriber::{self, EnvFilter};
+
+/// Bus name of the backend asking the user, overriding the terminal backend.
+const BACKEND_ENV_VAR: &str = "XDG_CREDENTIALS_PORTAL_BACKEND";
+
+fn setup_logging() {
+    tracing_subscriber::fmt()
+        .with_env_filter(EnvFilter::from_default_env())
+        .without_time()
+        .init();
+}
+
+#[tokio::main]
+pub async fn main() -> Result<(), Box<dyn Error>> {
+    setup_logging();
+
+    let (resource, connection) = dbus_tokio::connection::new_session_sync()?;
+    let dispatcher = tokio::spawn(resource);
+
+    let backend_name =
+        env::var(BACKEND_ENV_VAR).unwrap_or_else(|_| TERMINAL_BACKEND_BUS_NAME.to_owned());
+    info!(%backend_name, "Using portal backend");
+    let backend = BackendClient::new(connection.clone(), &backend_name);
+    let policy = OriginPolicy::load(Path::new(ASSOCIATIONS_PATH))?;
+    let context = Context::new(
+        Box::new(UserDeviceSelector::default()),
+        Arc::new(backend),
+        policy,
+        ConsentStore::new(connection.clone(), PERMISSION_STORE_BUS_NAME),
+    );
+    let service = Service::export(connection, context);
+    service.request_name().await?;
+    info!("Credentials portal is running");
+
+    let err = dispatcher.await?;
+    error!(%err, "Lost connection to D-Bus");
+    Err(err.into())
+}
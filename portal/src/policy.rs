@@ -0,0 +1,260 @@
+//! Which relying parties a caller may act on behalf of.
+//!
+//! Browsers assert the origin of the web page they run ceremonies for, and are trusted to do so
+//! only if listed as such. Any other sandboxed app may only use the relying parties associated
+//! with its app ID, so that it can not sign in to arbitrary websites with the user's
+//! authenticators. Unsandboxed callers can open authenticators directly, so restricting them would
+//! not protect anything: they are trusted as browsers.
+//!
+//! Associations are read from a local JSON file, e.g.:
+//!
+//! ```json
+//! {
+//!     "browsers": ["org.mozilla.firefox", "snap.chromium"],
+//!     "apps": { "org.example.App": ["example.org"] }
+//! }
+//! ```
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use serde_derive::Deserialize;
+use tracing::{debug, warn};
+
+use crate::caller::{Caller, Sandbox};
+
+pub const ASSOCIATIONS_PATH: &str = "/etc/xdg-credentials-portal/associations.json";
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+pub struct Associations {
+    /// App IDs of browsers, which may assert any web origin.
+    #[serde(default)]
+    pub browsers: Vec<String>,
+    /// Relying party IDs which each app may use.
+    #[serde(default)]
+    pub apps: BTreeMap<String, Vec<String>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PolicyError {
+    /// The origin is not a secure web origin.
+    InvalidOrigin,
+    /// The relying party ID is not the origin's host, nor one of its parent domains.
+    RelyingPartyMismatch,
+    /// The caller is not associated with the relying party.
+    NotAssociated,
+}
+
+impl fmt::Display for PolicyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PolicyError::InvalidOrigin => write!(f, "Invalid origin"),
+            PolicyError::RelyingPartyMismatch => {
+                write!(f, "Relying party does not match the origin")
+            }
+            PolicyError::NotAssociated => {
+                write!(f, "Application is not associated with the relying party")
+            }
+        }
+    }
+}
+
+impl std::error::Error for PolicyError {}
+
+#[derive(Debug, Clone, Default)]
+pub struct OriginPolicy {
+    associations: Associations,
+}
+
+impl OriginPolicy {
+    pub fn new(associations: Associations) -> Self {
+        Self { associations }
+    }
+
+    /// Reads associations from the given file. Without the file, only unsandboxed callers are
+    /// allowed.
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let associations = match fs::read(path) {
+            Ok(associations) => serde_json::from_slice(&associations)
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                warn!(
+                    ?path,
+                    "No associations file, sandboxed apps will be rejected"
+                );
+                Associations::default()
+            }
+            Err(err) => return Err(err),
+        };
+        Ok(Self::new(associations))
+    }
+
+    /// Checks that the caller may use the relying party, from the given origin.
+    pub fn check(
+        &self,
+        caller: &Caller,
+        relying_party_id: &str,
+        origin: &str,
+    ) -> Result<(), PolicyError> {
+        let host = origin_host(origin).ok_or(PolicyError::InvalidOrigin)?;
+        if !is_same_or_parent_domain(relying_party_id, host) {
+            return Err(PolicyError::RelyingPartyMismatch);
+        }
+        if caller.sandbox == Sandbox::Unsandboxed
+            || self.associations.browsers.contains(&caller.app_id)
+        {
+            return Ok(());
+        }
+        let associated = self
+            .associations
+            .apps
+            .get(&caller.app_id)
+            .map_or(false, |ids| {
+                ids.iter()
+                    .any(|id| id.eq_ignore_ascii_case(relying_party_id))
+            });
+        if !associated {
+            return Err(PolicyError::NotAssociated);
+        }
+        debug!(%caller, %relying_party_id, "Caller is associated with the relying party");
+        Ok(())
+    }
+}
+
+/// Host of a secure origin, `scheme://host[:port]`: HTTPS, or HTTP on localhost.
+pub fn origin_host(origin: &str) -> Option<&str> {
+    let (scheme, authority) = origin.split_once("://")?;
+    // IPv6 literals can not be relying party IDs.
+    if authority.contains(['/', '?', '#', '@', '[']) {
+        return None;
+    }
+    let host = authority
+        .rsplit_once(':')
+        .map_or(authority, |(host, _port)| host);
+    let secure = scheme == "https" || (scheme == "http" && host == "localhost");
+    Some(host).filter(|host| secure && !host.is_empty())
+}
+
+// Without the public suffix list, suffixes such as "co.uk" can not be told apart from
+// registrable domains. Single-label relying party IDs other than localhost are rejected though.
+fn is_same_or_parent_domain(relying_party_id: &str, host: &str) -> bool {
+    if relying_party_id.is_empty()
+        || (!relying_party_id.contains('.') && relying_party_id != "localhost")
+    {
+        return false;
+    }
+    let (relying_party_id, host) = (
+        relying_party_id.to_ascii_lowercase(),
+        host.to_ascii_lowercase(),
+    );
+    host == relying_party_id || host.ends_with(&format!(".{}", relying_party_id))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use crate::caller::{Caller, Sandbox};
+    use crate::policy::{origin_host, Associations, OriginPolicy, PolicyError};
+
+    fn app(app_id: &str) -> Caller {
+        Caller {
+            sandbox: Sandbox::Flatpak,
+            app_id: app_id.to_owned(),
+        }
+    }
+
+    fn policy() -> OriginPolicy {
+        OriginPolicy::new(Associations {
+            browsers: vec![String::from("org.mozilla.firefox")],
+            apps: BTreeMap::from([(
+                String::from("org.example.App"),
+                vec![String::from("example.org")],
+            )]),
+        })
+    }
+
+    #[test]
+    fn origin_host_of_secure_origins() {
+        assert_eq!(origin_host("https://example.org"), Some("example.org"));
+        assert_eq!(
+            origin_host("https://login.example.org:8443"),
+            Some("login.example.org")
+        );
+        assert_eq!(origin_host("http://localhost:8080"), Some("localhost"));
+        assert_eq!(origin_host("http://example.org"), None);
+        assert_eq!(origin_host("https://example.org/path"), None);
+        assert_eq!(origin_host("https://user@example.org"), None);
+        assert_eq!(origin_host("example.org"), None);
+    }
+
+    #[test]
+    fn browsers_assert_any_origin() {
+        let policy = policy();
+        let browser = app("org.mozilla.firefox");
+        assert_eq!(
+            policy.check(&browser, "example.com", "https://example.com"),
+            Ok(())
+        );
+        assert_eq!(
+            policy.check(&browser, "example.org", "https://login.example.org"),
+            Ok(())
+        );
+        assert_eq!(
+            policy.check(&browser, "example.org", "https://example.com"),
+            Err(PolicyError::RelyingPartyMismatch)
+        );
+        assert_eq!(
+            policy.check(&browser, "com", "https://example.com"),
+            Err(PolicyError::RelyingPartyMismatch)
+        );
+        assert_eq!(
+            policy.check(&Caller::unsandboxed(), "example.com", "https://example.com"),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn apps_limited_to_associated_relying_parties() {
+        let policy = policy();
+        let caller = app("org.example.App");
+        assert_eq!(
+            policy.check(&caller, "example.org", "https://example.org"),
+            Ok(())
+        );
+        assert_eq!(
+            policy.check(&caller, "example.com", "https://example.com"),
+            Err(PolicyError::NotAssociated)
+        );
+        assert_eq!(
+            policy.check(
+                &app("org.example.Other"),
+                "example.org",
+                "https://example.org"
+            ),
+            Err(PolicyError::NotAssociated)
+        );
+        assert_eq!(
+            policy.check(&caller, "example.org", "https://evil.example"),
+            Err(PolicyError::RelyingPartyMismatch)
+        );
+    }
+
+    #[test]
+    fn associations_from_json() {
+        let associations: Associations = serde_json::from_str(
+            r#"{"browsers": ["snap.chromium"], "apps": {"org.example.App": ["example.org"]}}"#,
+        )
+        .unwrap();
+        assert_eq!(associations.browsers, vec![String::from("snap.chromium")]);
+        assert_eq!(
+            associations.apps["org.example.App"],
+            vec![String::from("example.org")]
+        );
+        let associations: Associations = serde_json::from_str("{}").unwrap();
+        assert_eq!(associations, Associations::default());
+    }
+}
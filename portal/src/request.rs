@@ -0,0 +1,352 @@
+//! `org.freedesktop.portal.Request` objects, one per portal method call, which stay exported for
+//! as long as the ceremony runs and report its outcome through their `Response` signal.
+
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::sync::{Arc, Mutex, Weak};
+
+use std::any::Any;
+
+use dbus::arg::{Arg, ArgType, IterAppend, PropMap, RefArg, Variant};
+use dbus::channel::Sender;
+use dbus::nonblock::SyncConnection;
+use dbus::{Message, MethodErr, Path, Signature};
+use dbus_crossroads::{Context, Crossroads, IfaceBuilder, IfaceToken};
+use libwebauthn::secret::SecretString;
+use libwebauthn::webauthn::{CtapError, Error};
+use tokio::task::JoinHandle;
+use tracing::{debug, info, instrument, warn};
+use uuid::Uuid;
+
+use crate::caller::{self, Caller};
+
+pub const REQUEST_INTERFACE: &str = "org.freedesktop.portal.Request";
+pub const REQUEST_PATH_PREFIX: &str = "/org/freedesktop/portal/desktop/request";
+
+/// Numeric response carried by the `Response` signal.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u32)]
+pub enum ResponseCode {
+    Success = 0,
+    Cancelled = 1,
+    Other = 2,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ResultValue {
+    Bytes(Vec<u8>),
+    String(String),
+    U32(u32),
+    Bool(bool),
+    /// A string such as a PIN, zeroed once the reply carrying it has been sent.
+    Secret(SecretString),
+}
+
+impl From<Vec<u8>> for ResultValue {
+    fn from(bytes: Vec<u8>) -> Self {
+        ResultValue::Bytes(bytes)
+    }
+}
+
+impl From<String> for ResultValue {
+    fn from(string: String) -> Self {
+        ResultValue::String(string)
+    }
+}
+
+impl From<&str> for ResultValue {
+    fn from(string: &str) -> Self {
+        ResultValue::String(string.to_owned())
+    }
+}
+
+impl From<SecretString> for ResultValue {
+    fn from(secret: SecretString) -> Self {
+        ResultValue::Secret(secret)
+    }
+}
+
+impl From<u32> for ResultValue {
+    fn from(value: u32) -> Self {
+        ResultValue::U32(value)
+    }
+}
+
+impl From<bool> for ResultValue {
+    fn from(value: bool) -> Self {
+        ResultValue::Bool(value)
+    }
+}
+
+/// Results of a request, converted to a vardict once the request is over. D-Bus variants can not
+/// be sent across tasks, hence this intermediate representation.
+pub type Results = BTreeMap<&'static str, ResultValue>;
+
+/// How a ceremony ended.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Outcome {
+    Success(Results),
+    /// The user cancelled the interaction.
+    Cancelled,
+    /// The ceremony failed, with a description of the error.
+    Failed(String),
+}
+
+impl Outcome {
+    pub fn code(&self) -> ResponseCode {
+        match self {
+            Outcome::Success(_) => ResponseCode::Success,
+            Outcome::Cancelled => ResponseCode::Cancelled,
+            Outcome::Failed(_) => ResponseCode::Other,
+        }
+    }
+
+    /// Arguments of the `Response` signal, or of a backend method reply.
+    pub fn into_response(self) -> (u32, PropMap) {
+        let code = self.code() as u32;
+        let results = match self {
+            Outcome::Success(results) => results,
+            Outcome::Cancelled => Results::new(),
+            Outcome::Failed(error) => Results::from([("error", ResultValue::String(error))]),
+        };
+        let results = results
+            .into_iter()
+            .map(|(key, value)| {
+                let value: Box<dyn RefArg> = match value {
+                    ResultValue::Bytes(bytes) => Box::new(bytes),
+                    ResultValue::String(string) => Box::new(string),
+                    ResultValue::U32(value) => Box::new(value),
+                    ResultValue::Bool(value) => Box::new(value),
+                    ResultValue::Secret(secret) => Box::new(SecretArg(secret)),
+                };
+                (key.to_owned(), Variant(value))
+            })
+            .collect();
+        (code, results)
+    }
+}
+
+/// A secret string appended to messages as a plain D-Bus string, without leaving an unzeroed copy
+/// behind as a `String` argument would.
+#[derive(Debug)]
+struct SecretArg(SecretString);
+
+impl RefArg for SecretArg {
+    fn arg_type(&self) -> ArgType {
+        ArgType::String
+    }
+
+    fn signature(&self) -> Signature<'static> {
+        <String as Arg>::signature()
+    }
+
+    fn append(&self, i: &mut IterAppend) {
+        i.append(&*self.0)
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+
+    fn as_str(&self) -> Option<&str> {
+        Some(&self.0)
+    }
+
+    fn box_clone(&self) -> Box<dyn RefArg + 'static> {
+        Box::new(SecretArg(self.0.clone()))
+    }
+}
+
+impl From<Error> for Outcome {
+    fn from(err: Error) -> Self {
+        match err {
+            // The user declined, or cancelled PIN entry.
+            Error::Ctap(CtapError::OperationDenied)
+            | Error::Ctap(CtapError::KeepAliveCancel)
+            | Error::Ctap(CtapError::PINRequired) => Outcome::Cancelled,
+            err => Outcome::Failed(err.to_string()),
+        }
+    }
+}
+
+/// An exported request, owned by the caller which made the portal method call.
+pub struct Request {
+    sender: String,
+    task: JoinHandle<()>,
+}
+
+/// Exports requests on the portal's connection, and removes them once they are over.
+#[derive(Clone)]
+pub struct Requests {
+    connection: Arc<SyncConnection>,
+    crossroads: Weak<Mutex<Crossroads>>,
+    iface: IfaceToken<Request>,
+}
+
+impl Requests {
+    /// Registers the Request interface, for requests to be exported on the given object tree.
+    pub fn register(
+        connection: Arc<SyncConnection>,
+        crossroads: &Arc<Mutex<Crossroads>>,
+        cr: &mut Crossroads,
+    ) -> Self {
+        let iface = cr.register(REQUEST_INTERFACE, |b: &mut IfaceBuilder<Request>| {
+            b.method_with_cr("Close", (), (), |ctx, cr, _: ()| {
+                let path = ctx.path().clone();
+                let sender = caller(ctx)?;
+                let request: &mut Request = cr
+                    .data_mut(&path)
+                    .ok_or_else(|| MethodErr::no_path(&path))?;
+                if sender != request.sender {
+                    warn!(%path, ?sender, "Rejecting request to close another caller's request");
+                    return Err(MethodErr::failed(&"Not the owner of this request"));
+                }
+                request.task.abort();
+                cr.remove::<Request>(&path);
+                info!(%path, "Request closed by the caller");
+                Ok(())
+            });
+            b.signal::<(u32, PropMap), _>("Response", ("response", "results"));
+        });
+        Self {
+            connection,
+            crossroads: Arc::downgrade(crossroads),
+            iface,
+        }
+    }
+
+    /// Exports a request for the caller, and runs the ceremony in the background. Its outcome is
+    /// sent to the caller through the `Response` signal, after which the request is removed.
+    ///
+    /// The ceremony is given the request's handle and the identified caller, and does not run if
+    /// the caller can not be identified. Closing the request cancels the ceremony, and no
+    /// `Response` is sent.
+    #[instrument(skip_all, fields(%sender))]
+    pub fn spawn<C, F>(&self, cr: &mut Crossroads, sender: &str, ceremony: C) -> Path<'static>
+    where
+        C: FnOnce(Path<'static>, Caller) -> F + Send + 'static,
+        F: Future<Output = Outcome> + Send + 'static,
+    {
+        let token = format!("libwebauthn{}", Uuid::new_v4().to_simple());
+        let path = request_path(sender, &token);
+
+        let requests = self.clone();
+        let task_path = path.clone();
+        let destination = sender.to_owned();
+        // The ceremony can not respond before the request is inserted below, as removing it
+        // requires the object tree, which is locked whilst the method call is handled.
+        let task = tokio::spawn(async move {
+            let outcome = match caller::identify(&requests.connection, &destination).await {
+                Ok(caller) => {
+                    info!(%caller, "Identified caller");
+                    ceremony(task_path.clone(), caller).await
+                }
+                Err(err) => {
+                    warn!(%err, "Failed to identify the caller");
+                    Outcome::Failed(String::from("Unable to identify the caller"))
+                }
+            };
+            requests.respond(&task_path, &destination, outcome);
+        });
+        cr.insert(
+            path.clone(),
+            &[self.iface],
+            Request {
+                sender: sender.to_owned(),
+                task,
+            },
+        );
+        debug!(%path, "Exported request");
+        path
+    }
+
+    fn respond(&self, path: &Path<'static>, destination: &str, outcome: Outcome) {
+        // Waits for the method call which exported the request to be replied to, so that callers
+        // learn about the request before its response.
+        if let Some(crossroads) = self.crossroads.upgrade() {
+            crossroads.lock().unwrap().remove::<Request>(path);
+        }
+
+        info!(%path, code = ?outcome.code(), "Request completed");
+        let (code, results) = outcome.into_response();
+        let mut signal = Message::signal(path, &REQUEST_INTERFACE.into(), &"Response".into())
+            .append2(code, results);
+        // Results are only meant for the caller.
+        signal.set_destination(Some(destination.to_owned().into()));
+        if self.connection.send(signal).is_err() {
+            warn!(%path, "Failed to send response");
+        }
+    }
+}
+
+/// Unique name of the caller of a portal method.
+pub fn caller(ctx: &Context) -> Result<String, MethodErr> {
+    ctx.message()
+        .sender()
+        .map(|sender| sender.to_string())
+        .ok_or_else(|| MethodErr::failed(&"Unknown caller"))
+}
+
+/// Path of a request, following the `/org/freedesktop/portal/desktop/request/SENDER/TOKEN`
+/// convention: SENDER is the caller's unique name, with the initial ':' removed and all '.'
+/// replaced by '_'.
+pub fn request_path(sender: &str, token: &str) -> Path<'static> {
+    let sender = sender.trim_start_matches(':').replace('.', "_");
+    Path::from(format!("{}/{}/{}", REQUEST_PATH_PREFIX, sender, token))
+}
+
+#[cfg(test)]
+mod tests {
+    use dbus::arg::PropMap;
+    use dbus::Message;
+    use libwebauthn::secret::SecretString;
+    use libwebauthn::webauthn::{CtapError, Error, TransportError};
+
+    use crate::request::{request_path, Outcome, ResponseCode, Results};
+
+    #[test]
+    fn request_path_from_sender() {
+        assert_eq!(
+            &*request_path(":1.42", "token"),
+            "/org/freedesktop/portal/desktop/request/1_42/token"
+        );
+    }
+
+    #[test]
+    fn outcome_from_error() {
+        assert_eq!(
+            Outcome::from(Error::Ctap(CtapError::OperationDenied)).code(),
+            ResponseCode::Cancelled
+        );
+        assert_eq!(
+            Outcome::from(Error::Ctap(CtapError::PINRequired)).code(),
+            ResponseCode::Cancelled
+        );
+        assert_eq!(
+            Outcome::from(Error::Transport(TransportError::ConnectionLost)).code(),
+            ResponseCode::Other
+        );
+    }
+
+    #[test]
+    fn secret_result_sent_as_string() {
+        let pin = SecretString::from("1234");
+        let (code, results) =
+            Outcome::Success(Results::from([("pin", pin.into())])).into_response();
+        assert_eq!(code, ResponseCode::Success as u32);
+        assert_eq!(
+            format!("{:?}", results["pin"]),
+            "Variant(SecretArg(SecretString(<redacted>)))"
+        );
+
+        let message = Message::new_signal("/test", "org.example.Test", "Response")
+            .unwrap()
+            .append1(results);
+        let results: PropMap = message.read1().unwrap();
+        assert_eq!(results["pin"].0.as_str(), Some("1234"));
+    }
+}
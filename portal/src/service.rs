@@ -0,0 +1,207 @@
+//! The portal service, exporting the portal interfaces on a D-Bus connection.
+
+use std::sync::{Arc, Mutex};
+
+use dbus::channel::{MatchingReceiver, Token};
+use dbus::message::MatchRule;
+use dbus::nonblock::SyncConnection;
+use dbus_crossroads::Crossroads;
+use libwebauthn::account::AccountSelector;
+use libwebauthn::backend::{
+    Backend, BackendAccountSelector, BackendPinProvider, Interaction, Operation,
+};
+use libwebauthn::pin::PinProvider;
+use libwebauthn::transport::Channel;
+use libwebauthn::webauthn::Error;
+use tracing::{debug, info, warn};
+
+use crate::caller::{Caller, Sandbox};
+use crate::consent::{self, ConsentStore, Decision, Grant};
+use crate::devices::DeviceSelector;
+use crate::policy::OriginPolicy;
+use crate::request::{Outcome, Requests};
+use crate::u2f;
+use crate::webauthn;
+
+pub const PORTAL_BUS_NAME: &str = "org.freedesktop.portal.Credentials";
+pub const PORTAL_PATH: &str = "/org/freedesktop/portal/desktop";
+
+/// Everything needed to carry out ceremonies, shared by all portal interfaces.
+pub struct Context {
+    pub devices: Box<dyn DeviceSelector>,
+    /// Interacts with the user, see `org.freedesktop.impl.portal.Credentials`.
+    pub backend: Arc<dyn Backend>,
+    pub policy: OriginPolicy,
+    pub consent: ConsentStore,
+}
+
+impl Context {
+    pub fn new(
+        devices: Box<dyn DeviceSelector>,
+        backend: Arc<dyn Backend>,
+        policy: OriginPolicy,
+        consent: ConsentStore,
+    ) -> Self {
+        Self {
+            devices,
+            backend,
+            policy,
+            consent,
+        }
+    }
+
+    /// Checks that the caller may use the relying party and asks the user to confirm it, then
+    /// opens a channel to the authenticator which the ceremony runs on.
+    pub async fn begin(
+        &self,
+        caller: &Caller,
+        interaction: &Interaction,
+        operation: Operation,
+        relying_party_id: &str,
+        origin: &str,
+    ) -> Result<Box<dyn Channel>, Outcome> {
+        if let Err(err) = self.policy.check(caller, relying_party_id, origin) {
+            warn!(%caller, %relying_party_id, %origin, %err, "Rejecting request");
+            return Err(Outcome::Failed(err.to_string()));
+        }
+        if !self
+            .confirm(caller, interaction, operation, relying_party_id, origin)
+            .await
+        {
+            info!("User did not confirm the relying party");
+            return Err(Outcome::Cancelled);
+        }
+        match self
+            .devices
+            .select(self.backend.as_ref(), interaction)
+            .await
+        {
+            Ok(channel) => Ok(channel),
+            Err(err) => Err(self.fail(interaction, err).await),
+        }
+    }
+
+    /// Whether the user consents to the caller using the relying party. Decisions about sandboxed
+    /// apps are remembered, and the user is only asked again once they expire.
+    async fn confirm(
+        &self,
+        caller: &Caller,
+        interaction: &Interaction,
+        operation: Operation,
+        relying_party_id: &str,
+        origin: &str,
+    ) -> bool {
+        let sandboxed = caller.sandbox != Sandbox::Unsandboxed;
+        if sandboxed {
+            match self.consent.lookup(&caller.app_id, relying_party_id).await {
+                Ok(Some(decision)) => {
+                    debug!(?decision, "Using remembered consent");
+                    return decision == Decision::Granted;
+                }
+                Ok(None) => (),
+                // Without the permission store, the user is asked every time.
+                Err(err) => warn!(%err, "Failed to look up consent"),
+            }
+        }
+
+        let granted = self
+            .backend
+            .confirm_relying_party(interaction, operation, relying_party_id, origin)
+            .await;
+        if sandboxed {
+            let decision = if granted {
+                Decision::Granted
+            } else {
+                Decision::Denied
+            };
+            let grant = Grant::new(&caller.app_id, relying_party_id, decision);
+            if let Err(err) = self.consent.record(&grant).await {
+                warn!(%err, "Failed to record consent");
+            }
+        }
+        granted
+    }
+
+    /// Asks for PINs on behalf of the given request.
+    pub fn pin_provider(&self, interaction: &Interaction) -> Box<dyn PinProvider> {
+        Box::new(BackendPinProvider::new(
+            self.backend.clone(),
+            interaction.clone(),
+        ))
+    }
+
+    /// Asks which account to sign in with on behalf of the given request.
+    pub fn account_selector(&self, interaction: &Interaction) -> Box<dyn AccountSelector> {
+        Box::new(BackendAccountSelector::new(
+            self.backend.clone(),
+            interaction.clone(),
+        ))
+    }
+
+    /// Tells the user about a failed ceremony, unless they cancelled it.
+    pub async fn fail(&self, interaction: &Interaction, err: Error) -> Outcome {
+        let outcome = Outcome::from(err);
+        if let Outcome::Failed(message) = &outcome {
+            self.backend.show_error(interaction, message).await;
+        }
+        outcome
+    }
+}
+
+/// The portal interfaces and their requests, exported on the connection until dropped.
+pub struct Service {
+    connection: Arc<SyncConnection>,
+    token: Token,
+}
+
+impl Service {
+    pub fn export(connection: Arc<SyncConnection>, context: Context) -> Self {
+        let context = Arc::new(context);
+        let crossroads = Arc::new(Mutex::new(Crossroads::new()));
+        {
+            let mut cr = crossroads.lock().unwrap();
+            cr.set_async_support(Some((
+                connection.clone(),
+                Box::new(|future| {
+                    tokio::spawn(future);
+                }),
+            )));
+            let requests = Requests::register(connection.clone(), &crossroads, &mut cr);
+            let webauthn = webauthn::register(&mut cr, requests.clone(), context.clone());
+            let u2f = u2f::register(&mut cr, requests, context.clone());
+            let consent = consent::register(&mut cr, connection.clone(), context);
+            cr.insert(PORTAL_PATH, &[webauthn, u2f, consent], ());
+        }
+
+        let token = connection.start_receive(
+            MatchRule::new_method_call(),
+            Box::new(move |message, connection| {
+                if crossroads
+                    .lock()
+                    .unwrap()
+                    .handle_message(message, connection)
+                    .is_err()
+                {
+                    warn!("Failed to handle portal method call");
+                }
+                true
+            }),
+        );
+        debug!("Exported portal interfaces");
+        Self { connection, token }
+    }
+
+    /// Requests the well-known name of the portal on the bus.
+    pub async fn request_name(&self) -> Result<(), dbus::Error> {
+        self.connection
+            .request_name(PORTAL_BUS_NAME, false, true, false)
+            .await?;
+        Ok(())
+    }
+}
+
+impl Drop for Service {
+    fn drop(&mut self) {
+        self.connection.stop_receive(self.token);
+    }
+}
@@ -0,0 +1,385 @@
+//! The portal service, served on a private D-Bus daemon and backed by a software authenticator
+//! and a scripted UI backend, along with a client to call it.
+//!
+//! Requires `dbus-daemon` to be installed, hence the `dbus-daemon-tests` feature.
+
+use std::collections::{BTreeMap, HashMap};
+use std::io::{BufRead, BufReader};
+use std::path::PathBuf;
+use std::process::{Child, Command, Stdio};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use async_trait::async_trait;
+use dbus::arg::{PropMap, Variant};
+use dbus::channel::{Channel as DBusChannel, MatchingReceiver, Token};
+use dbus::message::MatchRule;
+use dbus::nonblock::{MsgMatch, Proxy, SyncConnection};
+use dbus::{Message, MethodErr, Path};
+use dbus_crossroads::{Crossroads, IfaceBuilder};
+use futures::channel::mpsc::UnboundedReceiver;
+use futures::StreamExt;
+use libwebauthn::authenticator::store::CredentialStore;
+use libwebauthn::authenticator::{SoftwareAuthenticator, SoftwareChannel};
+use libwebauthn::backend::{
+    AccountDescription, Backend, BackendClient, DeviceDescription, Interaction, Operation,
+};
+use libwebauthn::pin::{Pin, PinRequest};
+use libwebauthn::transport::Channel;
+use libwebauthn::webauthn::Error;
+use tokio::time::timeout;
+use uuid::Uuid;
+
+use crate::backend::BackendService;
+use crate::consent::{
+    ConsentStore, NOT_FOUND_ERROR, PERMISSION_STORE_BUS_NAME, PERMISSION_STORE_INTERFACE,
+    PERMISSION_STORE_PATH,
+};
+use crate::devices::DeviceSelector;
+use crate::policy::OriginPolicy;
+use crate::request::REQUEST_INTERFACE;
+use crate::service::{Context, Service, PORTAL_BUS_NAME, PORTAL_PATH};
+
+const CALL_TIMEOUT: Duration = Duration::from_secs(5);
+const TEST_BACKEND_BUS_NAME: &str = "org.freedesktop.impl.portal.desktop.test";
+
+/// A `dbus-daemon` instance, killed on drop.
+pub struct PrivateBus {
+    daemon: Child,
+    address: String,
+}
+
+impl PrivateBus {
+    pub fn start() -> Self {
+        let mut daemon = Command::new("dbus-daemon")
+            .args(["--session", "--nofork", "--print-address"])
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()
+            .expect("dbus-daemon is required to run portal tests");
+        let mut address = String::new();
+        BufReader::new(daemon.stdout.take().unwrap())
+            .read_line(&mut address)
+            .unwrap();
+        Self {
+            daemon,
+            address: address.trim().to_owned(),
+        }
+    }
+
+    /// Opens a connection to the bus, dispatched in the background.
+    pub fn connect(&self) -> Arc<SyncConnection> {
+        let mut channel = DBusChannel::open_private(&self.address).unwrap();
+        channel.register().unwrap();
+        let (resource, connection) = dbus_tokio::connection::from_channel(channel).unwrap();
+        tokio::spawn(resource);
+        connection
+    }
+}
+
+impl Drop for PrivateBus {
+    fn drop(&mut self) {
+        let _ = self.daemon.kill();
+    }
+}
+
+// Permissions of each app, by table and entry ID.
+type PermissionTables = BTreeMap<(String, String), HashMap<String, Vec<String>>>;
+
+/// Stand-in for `org.freedesktop.impl.portal.PermissionStore`, keeping permissions in memory.
+pub struct PermissionStoreStandIn {
+    connection: Arc<SyncConnection>,
+    token: Token,
+}
+
+impl PermissionStoreStandIn {
+    pub async fn start(connection: Arc<SyncConnection>) -> Self {
+        let mut cr = Crossroads::new();
+        let iface = cr.register(
+            PERMISSION_STORE_INTERFACE,
+            |b: &mut IfaceBuilder<PermissionTables>| {
+                b.method(
+                    "Lookup",
+                    ("table", "id"),
+                    ("permissions", "data"),
+                    |_, tables, (table, id): (String, String)| match tables.get(&(table, id)) {
+                        Some(permissions) => Ok((permissions.clone(), Variant(0u8))),
+                        None => Err(MethodErr::from((NOT_FOUND_ERROR, "No such entry"))),
+                    },
+                );
+                b.method(
+                    "SetPermission",
+                    ("table", "create", "id", "app", "permissions"),
+                    (),
+                    |_, tables, args: (String, bool, String, String, Vec<String>)| {
+                        let (table, _create, id, app, permissions) = args;
+                        tables
+                            .entry((table, id))
+                            .or_default()
+                            .insert(app, permissions);
+                        Ok(())
+                    },
+                );
+                b.method(
+                    "DeletePermission",
+                    ("table", "id", "app"),
+                    (),
+                    |_, tables, (table, id, app): (String, String, String)| {
+                        let Some(permissions) = tables.get_mut(&(table, id)) else {
+                            return Err(MethodErr::from((NOT_FOUND_ERROR, "No such entry")));
+                        };
+                        permissions.remove(&app);
+                        Ok(())
+                    },
+                );
+                b.method(
+                    "List",
+                    ("table",),
+                    ("ids",),
+                    |_, tables, (table,): (String,)| {
+                        let ids: Vec<String> = tables
+                            .keys()
+                            .filter(|(entry_table, _)| *entry_table == table)
+                            .map(|(_, id)| id.clone())
+                            .collect();
+                        Ok((ids,))
+                    },
+                );
+            },
+        );
+        cr.insert(PERMISSION_STORE_PATH, &[iface], PermissionTables::new());
+
+        let token = connection.start_receive(
+            MatchRule::new_method_call(),
+            Box::new(move |message, connection| {
+                let _ = cr.handle_message(message, connection);
+                true
+            }),
+        );
+        connection
+            .request_name(PERMISSION_STORE_BUS_NAME, false, true, false)
+            .await
+            .unwrap();
+        Self { connection, token }
+    }
+}
+
+impl Drop for PermissionStoreStandIn {
+    fn drop(&mut self) {
+        self.connection.stop_receive(self.token);
+    }
+}
+
+/// Opens channels to a software authenticator, whose credentials persist across channels.
+pub struct SoftwareDeviceSelector {
+    path: PathBuf,
+}
+
+impl Default for SoftwareDeviceSelector {
+    fn default() -> Self {
+        let path = std::env::temp_dir().join(format!("portal-test-{}.cbor", Uuid::new_v4()));
+        Self { path }
+    }
+}
+
+#[async_trait]
+impl DeviceSelector for SoftwareDeviceSelector {
+    async fn select(
+        &self,
+        _backend: &dyn Backend,
+        _interaction: &Interaction,
+    ) -> Result<Box<dyn Channel>, Error> {
+        let store = CredentialStore::open(&self.path).unwrap();
+        Ok(Box::new(SoftwareChannel::new(SoftwareAuthenticator::new(
+            store,
+        ))))
+    }
+}
+
+impl Drop for SoftwareDeviceSelector {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+/// Never selects a device, so that ceremonies stay pending until closed.
+pub struct PendingDeviceSelector;
+
+#[async_trait]
+impl DeviceSelector for PendingDeviceSelector {
+    async fn select(
+        &self,
+        _backend: &dyn Backend,
+        _interaction: &Interaction,
+    ) -> Result<Box<dyn Channel>, Error> {
+        std::future::pending().await
+    }
+}
+
+/// Answers as the user would: choosing the first device and account, entering a fixed PIN, and
+/// confirming unless told otherwise. Errors shown are recorded.
+pub struct ScriptedBackend {
+    pub pin: &'static str,
+    pub confirm: bool,
+    pub errors: Mutex<Vec<String>>,
+}
+
+impl Default for ScriptedBackend {
+    fn default() -> Self {
+        Self {
+            pin: "1234",
+            confirm: true,
+            errors: Mutex::new(vec![]),
+        }
+    }
+}
+
+#[async_trait]
+impl Backend for ScriptedBackend {
+    async fn choose_device(
+        &self,
+        _interaction: &Interaction,
+        devices: &[DeviceDescription],
+    ) -> Option<String> {
+        devices.first().map(|device| device.id.clone())
+    }
+
+    async fn enter_pin(&self, _interaction: &Interaction, _request: &PinRequest) -> Option<Pin> {
+        Some(Pin::from(self.pin))
+    }
+
+    async fn confirm_relying_party(
+        &self,
+        _interaction: &Interaction,
+        _operation: Operation,
+        _relying_party_id: &str,
+        _origin: &str,
+    ) -> bool {
+        self.confirm
+    }
+
+    async fn choose_account(
+        &self,
+        _interaction: &Interaction,
+        accounts: &[AccountDescription],
+    ) -> Option<usize> {
+        if accounts.is_empty() {
+            None
+        } else {
+            Some(0)
+        }
+    }
+
+    async fn show_error(&self, _interaction: &Interaction, message: &str) {
+        self.errors.lock().unwrap().push(message.to_owned());
+    }
+}
+
+/// The portal service, its backend, and a client, connected to the same private bus.
+pub struct TestPortal {
+    _service: Service,
+    _backend_service: BackendService,
+    pub backend: Arc<ScriptedBackend>,
+    _permission_store: PermissionStoreStandIn,
+    /// Consent remembered by the portal, in the permission store stand-in.
+    pub consent: ConsentStore,
+    pub client: Arc<SyncConnection>,
+    _responses_match: MsgMatch,
+    responses: UnboundedReceiver<Message>,
+    received: Vec<Message>,
+    // Dropped last, once both connections are closed.
+    _bus: PrivateBus,
+}
+
+impl TestPortal {
+    pub async fn start(devices: Box<dyn DeviceSelector>) -> Self {
+        Self::start_with_backend(devices, ScriptedBackend::default()).await
+    }
+
+    pub async fn start_with_backend(
+        devices: Box<dyn DeviceSelector>,
+        backend: ScriptedBackend,
+    ) -> Self {
+        let bus = PrivateBus::start();
+        let backend = Arc::new(backend);
+        let backend_service = BackendService::export(bus.connect(), backend.clone());
+        backend_service
+            .request_name(TEST_BACKEND_BUS_NAME)
+            .await
+            .unwrap();
+
+        let permission_store = PermissionStoreStandIn::start(bus.connect()).await;
+
+        let connection = bus.connect();
+        let client = BackendClient::new(connection.clone(), TEST_BACKEND_BUS_NAME);
+        let consent = ConsentStore::new(connection.clone(), PERMISSION_STORE_BUS_NAME);
+        let context = Context::new(
+            devices,
+            Arc::new(client),
+            OriginPolicy::default(),
+            consent.clone(),
+        );
+        let service = Service::export(connection, context);
+        service.request_name().await.unwrap();
+
+        let client = bus.connect();
+        let (responses_match, responses) = client
+            .add_match(MatchRule::new_signal(REQUEST_INTERFACE, "Response"))
+            .await
+            .unwrap()
+            .msg_stream();
+        Self {
+            _service: service,
+            _backend_service: backend_service,
+            backend,
+            _permission_store: permission_store,
+            consent,
+            client,
+            _responses_match: responses_match,
+            responses,
+            received: vec![],
+            _bus: bus,
+        }
+    }
+
+    pub fn proxy(&self) -> Proxy<'_, &SyncConnection> {
+        Proxy::new(
+            PORTAL_BUS_NAME,
+            PORTAL_PATH,
+            CALL_TIMEOUT,
+            self.client.as_ref(),
+        )
+    }
+
+    pub fn request_proxy(&self, handle: &Path<'static>) -> Proxy<'_, &SyncConnection> {
+        Proxy::new(
+            PORTAL_BUS_NAME,
+            handle.clone(),
+            CALL_TIMEOUT,
+            self.client.as_ref(),
+        )
+    }
+
+    /// Waits for the `Response` signal of the given request.
+    pub async fn response(&mut self, handle: &Path<'static>) -> (u32, PropMap) {
+        loop {
+            if let Some(index) = self
+                .received
+                .iter()
+                .position(|message| message.path().map_or(false, |path| path == *handle))
+            {
+                return self.received.remove(index).read2().unwrap();
+            }
+            let message = timeout(CALL_TIMEOUT, self.responses.next())
+                .await
+                .expect("Timed out waiting for a response")
+                .unwrap();
+            self.received.push(message);
+        }
+    }
+
+    /// Whether a `Response` signal is received for the given request within the given time.
+    pub async fn responds_within(&mut self, handle: &Path<'static>, duration: Duration) -> bool {
+        timeout(duration, self.response(handle)).await.is_ok()
+    }
+}
@@ -0,0 +1,535 @@
+//! The `org.freedesktop.portal.U2F` interface, for legacy callers of the FIDO U2F JavaScript API.
+//! Results follow the API's RegisterResponse and SignResponse dictionaries, with raw bytes in place
+//! of websafe-base64 strings.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use byteorder::{BigEndian, WriteBytesExt};
+use dbus::arg::{prop_cast, PropMap};
+use dbus::{MethodErr, Path};
+use dbus_crossroads::{Crossroads, IfaceBuilder, IfaceToken};
+use libwebauthn::backend::Operation;
+use libwebauthn::ops::u2f::{RegisterRequest, RegisterResponse, SignRequest, SignResponse};
+use libwebauthn::proto::ctap1::{Ctap1RegisteredKey, Ctap1Transport, Ctap1Version};
+use libwebauthn::u2f::U2F;
+use libwebauthn::webauthn::{CtapError, Error};
+use serde_derive::Serialize;
+use sha2::{Digest, Sha256};
+use tracing::{debug, instrument, warn};
+
+use crate::caller::Caller;
+use crate::policy::origin_host;
+use crate::request::{caller, Outcome, Requests, Results};
+use crate::service::Context;
+
+pub const U2F_INTERFACE: &str = "org.freedesktop.portal.U2F";
+
+const U2F_V2: &str = "U2F_V2";
+
+// Timeouts, in seconds. The U2F JavaScript API defaults to 30 seconds.
+const DEFAULT_TIMEOUT_SECONDS: u32 = 30;
+const MAX_TIMEOUT_SECONDS: u32 = 600;
+
+// Key handles are prefixed by their length, in a single byte.
+const MAX_KEY_HANDLE_LENGTH: usize = 255;
+
+// https://fidoalliance.org/specs/fido-u2f-v1.2-ps-20170411/fido-u2f-raw-message-formats-v1.2-ps-20170411.html#registration-response-message-success
+const REGISTRATION_RESERVED_BYTE: u8 = 0x05;
+
+pub type RegisterArgs = (String, Vec<u8>, Vec<PropMap>, u32);
+pub type SignArgs = (String, Vec<u8>, Vec<PropMap>, u32);
+
+/// A Register call, validated and ready to be sent to an authenticator.
+#[derive(Debug, Clone)]
+pub struct Register {
+    pub app_id: String,
+    pub request: RegisterRequest,
+    pub client_data: Vec<u8>,
+}
+
+/// A Sign call, validated and ready to be sent to an authenticator. One request is prepared for
+/// each registered key, and the first key known to the authenticator is used.
+#[derive(Debug, Clone)]
+pub struct Sign {
+    pub app_id: String,
+    pub requests: Vec<SignRequest>,
+    pub client_data: Vec<u8>,
+}
+
+pub fn register(cr: &mut Crossroads, requests: Requests, context: Arc<Context>) -> IfaceToken<()> {
+    cr.register(U2F_INTERFACE, |b: &mut IfaceBuilder<()>| {
+        let (r, c) = (requests.clone(), context.clone());
+        b.method_with_cr(
+            "Register",
+            ("appId", "challenge", "registeredKeys", "timeoutSeconds"),
+            ("handle",),
+            move |ctx, cr, args: RegisterArgs| -> Result<(Path<'static>,), MethodErr> {
+                let sender = caller(ctx)?;
+                let operation = Register::from_args(args)?;
+                let c = c.clone();
+                let handle = r.spawn(cr, &sender, |handle, caller| {
+                    u2f_register(c, handle, caller, operation)
+                });
+                Ok((handle,))
+            },
+        );
+        let (r, c) = (requests.clone(), context.clone());
+        b.method_with_cr(
+            "Sign",
+            ("appId", "challenge", "registeredKeys", "timeoutSeconds"),
+            ("handle",),
+            move |ctx, cr, args: SignArgs| -> Result<(Path<'static>,), MethodErr> {
+                let sender = caller(ctx)?;
+                let operation = Sign::from_args(args)?;
+                let c = c.clone();
+                let handle = r.spawn(cr, &sender, |handle, caller| {
+                    u2f_sign(c, handle, caller, operation)
+                });
+                Ok((handle,))
+            },
+        );
+    })
+}
+
+impl Register {
+    pub fn from_args(args: RegisterArgs) -> Result<Self, MethodErr> {
+        let (app_id, challenge, registered_keys, timeout) = args;
+        let client_data = client_data("navigator.id.finishEnrollment", &challenge, &app_id)?;
+        let request = RegisterRequest::new_u2f_v2(
+            &app_id,
+            &Sha256::digest(&client_data),
+            registered_keys_from_args(&registered_keys)?,
+            ceremony_timeout(timeout),
+            true,
+        );
+        Ok(Self {
+            app_id,
+            request,
+            client_data,
+        })
+    }
+}
+
+impl Sign {
+    pub fn from_args(args: SignArgs) -> Result<Self, MethodErr> {
+        let (app_id, challenge, registered_keys, timeout) = args;
+        let client_data = client_data("navigator.id.getAssertion", &challenge, &app_id)?;
+        let registered_keys = registered_keys_from_args(&registered_keys)?;
+        if registered_keys.is_empty() {
+            return Err(MethodErr::invalid_arg(&"registeredKeys"));
+        }
+        // Only the top-level AppID is checked against the caller's policy, keys may not be used
+        // with AppIDs of another facet.
+        if registered_keys
+            .iter()
+            .filter_map(|key| key.app_id.as_deref())
+            .any(|key_app_id| facet_id(key_app_id) != facet_id(&app_id))
+        {
+            return Err(MethodErr::invalid_arg(&"registeredKeys"));
+        }
+        let challenge = Sha256::digest(&client_data);
+        let requests = registered_keys
+            .iter()
+            .map(|key| {
+                SignRequest::new(
+                    key.app_id.as_ref().unwrap_or(&app_id),
+                    &challenge,
+                    &key.key_handle,
+                    ceremony_timeout(timeout),
+                    true,
+                )
+            })
+            .collect();
+        Ok(Self {
+            app_id,
+            requests,
+            client_data,
+        })
+    }
+}
+
+#[instrument(skip_all, fields(app_id = %operation.app_id))]
+async fn u2f_register(
+    context: Arc<Context>,
+    handle: Path<'static>,
+    caller: Caller,
+    operation: Register,
+) -> Outcome {
+    let interaction = caller.interaction(handle);
+    let mut channel = match context
+        .begin(
+            &caller,
+            &interaction,
+            Operation::Register,
+            relying_party_id(&operation.app_id),
+            facet_id(&operation.app_id),
+        )
+        .await
+    {
+        Ok(channel) => channel,
+        Err(outcome) => return outcome,
+    };
+    let response = match channel.u2f_register(&operation.request).await {
+        Ok(response) => response,
+        Err(err) => {
+            warn!(%err, "U2F register failed");
+            return context.fail(&interaction, err).await;
+        }
+    };
+    debug!("U2F register succeeded");
+    Outcome::Success(Results::from([
+        ("version", U2F_V2.into()),
+        ("registrationData", registration_data(&response).into()),
+        ("clientData", operation.client_data.into()),
+    ]))
+}
+
+#[instrument(skip_all, fields(app_id = %operation.app_id))]
+async fn u2f_sign(
+    context: Arc<Context>,
+    handle: Path<'static>,
+    caller: Caller,
+    operation: Sign,
+) -> Outcome {
+    let interaction = caller.interaction(handle);
+    let mut channel = match context
+        .begin(
+            &caller,
+            &interaction,
+            Operation::Sign,
+            relying_party_id(&operation.app_id),
+            facet_id(&operation.app_id),
+        )
+        .await
+    {
+        Ok(channel) => channel,
+        Err(outcome) => return outcome,
+    };
+    for request in operation.requests {
+        let response = match channel.u2f_sign(&request).await {
+            Ok(response) => response,
+            Err(Error::Ctap(CtapError::NoCredentials)) => {
+                debug!("Key handle not known to the authenticator, trying the next one");
+                continue;
+            }
+            Err(err) => {
+                warn!(%err, "U2F sign failed");
+                return context.fail(&interaction, err).await;
+            }
+        };
+        debug!("U2F sign succeeded");
+        return Outcome::Success(Results::from([
+            ("keyHandle", request.key_handle.into()),
+            ("signatureData", signature_data(&response).into()),
+            ("clientData", operation.client_data.into()),
+        ]));
+    }
+    warn!("None of the registered keys are known to the authenticator");
+    context
+        .fail(&interaction, Error::Ctap(CtapError::NoCredentials))
+        .await
+}
+
+// https://fidoalliance.org/specs/fido-u2f-v1.2-ps-20170411/fido-u2f-raw-message-formats-v1.2-ps-20170411.html#client-data
+#[derive(Serialize)]
+struct ClientData<'a> {
+    typ: &'a str,
+    challenge: String,
+    origin: &'a str,
+}
+
+fn client_data(typ: &str, challenge: &[u8], app_id: &str) -> Result<Vec<u8>, MethodErr> {
+    if challenge.is_empty() {
+        return Err(MethodErr::invalid_arg(&"challenge"));
+    }
+    if app_id.is_empty() {
+        return Err(MethodErr::invalid_arg(&"appId"));
+    }
+    let client_data = ClientData {
+        typ,
+        challenge: base64_url::encode(challenge),
+        origin: facet_id(app_id),
+    };
+    serde_json::to_vec(&client_data).or(Err(MethodErr::failed(&"Invalid client data")))
+}
+
+/// Facet of an AppID URL, that is its scheme, host and port. AppIDs which are not URLs are used
+/// as they are.
+fn facet_id(app_id: &str) -> &str {
+    let Some(scheme_end) = app_id.find("://") else {
+        return app_id;
+    };
+    let authority_start = scheme_end + "://".len();
+    match app_id[authority_start..].find('/') {
+        Some(path_start) => &app_id[..authority_start + path_start],
+        None => app_id,
+    }
+}
+
+/// The relying party of an AppID is the host of its facet.
+fn relying_party_id(app_id: &str) -> &str {
+    origin_host(facet_id(app_id)).unwrap_or_default()
+}
+
+fn registered_keys_from_args(keys: &[PropMap]) -> Result<Vec<Ctap1RegisteredKey>, MethodErr> {
+    let mut registered_keys = vec![];
+    for key in keys {
+        if let Some(version) = prop_cast::<String>(key, "version") {
+            if version != U2F_V2 {
+                debug!(%version, "Skipping key of unsupported version");
+                continue;
+            }
+        }
+        let Some(key_handle) = prop_cast::<Vec<u8>>(key, "keyHandle").filter(|key_handle| {
+            !key_handle.is_empty() && key_handle.len() <= MAX_KEY_HANDLE_LENGTH
+        }) else {
+            return Err(MethodErr::invalid_arg(&"registeredKeys"));
+        };
+        let transports = prop_cast::<Vec<String>>(key, "transports").map(|transports| {
+            transports
+                .iter()
+                .filter_map(|transport| match transport.as_str() {
+                    "usb" => Some(Ctap1Transport::USB),
+                    "nfc" => Some(Ctap1Transport::NFC),
+                    "ble" => Some(Ctap1Transport::BLE),
+                    "bt" => Some(Ctap1Transport::BT),
+                    _ => None,
+                })
+                .collect()
+        });
+        registered_keys.push(Ctap1RegisteredKey {
+            version: Ctap1Version::U2fV2,
+            key_handle: key_handle.clone(),
+            transports,
+            app_id: prop_cast::<String>(key, "appId").cloned(),
+        });
+    }
+    Ok(registered_keys)
+}
+
+fn ceremony_timeout(timeout_seconds: u32) -> Duration {
+    let timeout_seconds = match timeout_seconds {
+        0 => DEFAULT_TIMEOUT_SECONDS,
+        timeout_seconds => timeout_seconds.min(MAX_TIMEOUT_SECONDS),
+    };
+    Duration::from_secs(timeout_seconds as u64)
+}
+
+/// Raw registration response message: a reserved byte, the user public key, the key handle
+/// prefixed by its length, the attestation certificate and the signature.
+fn registration_data(response: &RegisterResponse) -> Vec<u8> {
+    let mut data = vec![REGISTRATION_RESERVED_BYTE];
+    data.extend(&response.public_key);
+    data.push(response.key_handle.len() as u8);
+    data.extend(&response.key_handle);
+    data.extend(&response.attestation);
+    data.extend(&response.signature);
+    data
+}
+
+/// Raw authentication response message: the user presence flag, the counter and the signature.
+fn signature_data(response: &SignResponse) -> Vec<u8> {
+    let mut data = vec![response.user_presence_verified as u8];
+    data.write_u32::<BigEndian>(response.counter).unwrap();
+    data.extend(&response.signature);
+    data
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use dbus::arg::{PropMap, RefArg, Variant};
+    use serde_json::Value;
+    use sha2::{Digest, Sha256};
+
+    #[cfg(feature = "dbus-daemon-tests")]
+    use crate::request::ResponseCode;
+    #[cfg(feature = "dbus-daemon-tests")]
+    use crate::testing::{SoftwareDeviceSelector, TestPortal};
+    #[cfg(feature = "dbus-daemon-tests")]
+    use crate::u2f::U2F_INTERFACE;
+    use crate::u2f::{ceremony_timeout, facet_id, relying_party_id, Register, Sign};
+    #[cfg(feature = "dbus-daemon-tests")]
+    use dbus::{arg::prop_cast, Path};
+
+    fn registered_key(key_handle: Vec<u8>) -> PropMap {
+        let entries: Vec<(&str, Box<dyn RefArg>)> = vec![
+            ("version", Box::new(String::from("U2F_V2"))),
+            ("keyHandle", Box::new(key_handle)),
+        ];
+        entries
+            .into_iter()
+            .map(|(key, value)| (key.to_owned(), Variant(value)))
+            .collect()
+    }
+
+    #[test]
+    fn register_from_args() {
+        let operation = Register::from_args((
+            String::from("https://example.org/app-id.json"),
+            vec![0x42; 32],
+            vec![registered_key(vec![1; 64])],
+            0,
+        ))
+        .unwrap();
+        let request = &operation.request;
+        assert_eq!(
+            request.app_id_hash,
+            Sha256::digest(b"https://example.org/app-id.json").to_vec()
+        );
+        assert_eq!(
+            request.challenge,
+            Sha256::digest(&operation.client_data).to_vec()
+        );
+        assert_eq!(request.registered_keys[0].key_handle, vec![1; 64]);
+        assert_eq!(request.timeout, Duration::from_secs(30));
+
+        let client_data: Value = serde_json::from_slice(&operation.client_data).unwrap();
+        assert_eq!(client_data["typ"], "navigator.id.finishEnrollment");
+        assert_eq!(client_data["origin"], "https://example.org");
+        assert_eq!(client_data["challenge"], base64_url::encode(&[0x42; 32]));
+    }
+
+    #[test]
+    fn sign_requires_registered_keys() {
+        let args = (String::from("example.org"), vec![0x42; 32], vec![], 10);
+        assert!(Sign::from_args(args).is_err());
+
+        let args = (
+            String::from("example.org"),
+            vec![0x42; 32],
+            vec![registered_key(vec![0; 256])],
+            10,
+        );
+        assert!(Sign::from_args(args).is_err());
+
+        let mut key = registered_key(vec![1; 64]);
+        key.insert(
+            String::from("appId"),
+            Variant(Box::new(String::from("https://example.com"))),
+        );
+        let args = (
+            String::from("https://example.org"),
+            vec![0x42; 32],
+            vec![key],
+            10,
+        );
+        assert!(Sign::from_args(args).is_err());
+    }
+
+    #[test]
+    fn relying_party_from_app_id() {
+        assert_eq!(
+            relying_party_id("https://example.org:8443/u2f/app-id.json"),
+            "example.org"
+        );
+        assert_eq!(relying_party_id("example.org"), "");
+    }
+
+    #[test]
+    fn facet_from_app_id() {
+        assert_eq!(
+            facet_id("https://example.org:8443/u2f/app-id.json"),
+            "https://example.org:8443"
+        );
+        assert_eq!(facet_id("https://example.org"), "https://example.org");
+        assert_eq!(facet_id("example.org"), "example.org");
+    }
+
+    #[test]
+    fn timeout_limited() {
+        assert_eq!(ceremony_timeout(0), Duration::from_secs(30));
+        assert_eq!(ceremony_timeout(u32::MAX), Duration::from_secs(600));
+    }
+
+    #[cfg(feature = "dbus-daemon-tests")]
+    fn bytes(results: &PropMap, key: &str) -> Vec<u8> {
+        prop_cast::<Vec<u8>>(results, key).unwrap().clone()
+    }
+
+    #[cfg(feature = "dbus-daemon-tests")]
+    #[tokio::test]
+    async fn register_and_sign_over_dbus() {
+        let mut portal = TestPortal::start(Box::new(SoftwareDeviceSelector::default())).await;
+        let app_id = String::from("https://example.org/app-id.json");
+
+        let (handle,): (Path<'static>,) = portal
+            .proxy()
+            .method_call(
+                U2F_INTERFACE,
+                "Register",
+                (
+                    app_id.clone(),
+                    vec![0x42u8; 32],
+                    Vec::<PropMap>::new(),
+                    10u32,
+                ),
+            )
+            .await
+            .unwrap();
+        let (code, results) = portal.response(&handle).await;
+        assert_eq!(code, ResponseCode::Success as u32);
+        assert_eq!(
+            prop_cast::<String>(&results, "version").map(String::as_str),
+            Some("U2F_V2")
+        );
+        let registration_data = bytes(&results, "registrationData");
+        assert_eq!(registration_data[0], 0x05);
+        let key_handle_length = registration_data[66] as usize;
+        let key_handle = registration_data[67..67 + key_handle_length].to_vec();
+
+        let registered_keys = vec![
+            registered_key(vec![0xFF; 32]),
+            registered_key(key_handle.clone()),
+        ];
+        let (handle,): (Path<'static>,) = portal
+            .proxy()
+            .method_call(
+                U2F_INTERFACE,
+                "Sign",
+                (app_id, vec![0x24u8; 32], registered_keys, 10u32),
+            )
+            .await
+            .unwrap();
+        let (code, results) = portal.response(&handle).await;
+        assert_eq!(code, ResponseCode::Success as u32);
+        assert_eq!(bytes(&results, "keyHandle"), key_handle);
+        let signature_data = bytes(&results, "signatureData");
+        assert_eq!(signature_data[0], 0x01);
+        let client_data: Value = serde_json::from_slice(&bytes(&results, "clientData")).unwrap();
+        assert_eq!(client_data["typ"], "navigator.id.getAssertion");
+    }
+
+    #[cfg(feature = "dbus-daemon-tests")]
+    #[tokio::test]
+    async fn register_excluded_over_dbus() {
+        let mut portal = TestPortal::start(Box::new(SoftwareDeviceSelector::default())).await;
+        let app_id = String::from("https://example.org");
+        let register = |registered_keys: Vec<PropMap>| {
+            (app_id.clone(), vec![0x42u8; 32], registered_keys, 10u32)
+        };
+
+        let (handle,): (Path<'static>,) = portal
+            .proxy()
+            .method_call(U2F_INTERFACE, "Register", register(vec![]))
+            .await
+            .unwrap();
+        let (_, results) = portal.response(&handle).await;
+        let registration_data = bytes(&results, "registrationData");
+        let key_handle = registration_data[67..67 + registration_data[66] as usize].to_vec();
+
+        let (handle,): (Path<'static>,) = portal
+            .proxy()
+            .method_call(
+                U2F_INTERFACE,
+                "Register",
+                register(vec![registered_key(key_handle)]),
+            )
+            .await
+            .unwrap();
+        let (code, _) = portal.response(&handle).await;
+        assert_eq!(code, ResponseCode::Other as u32);
+    }
+}
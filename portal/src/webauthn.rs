@@ -0,0 +1,856 @@
+//! The `org.freedesktop.portal.WebAuthn` interface, running WebAuthn ceremonies on behalf of
+//! callers. Each method call returns a Request handle, whose `Response` signal carries the
+//! outcome of the ceremony.
+
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+use dbus::arg::{prop_cast, PropMap};
+use dbus::{MethodErr, Path};
+use dbus_crossroads::{Crossroads, IfaceBuilder, IfaceToken};
+use libwebauthn::backend::Operation;
+use libwebauthn::ops::webauthn::{
+    GetAssertionRequest, MakeCredentialRequest, MakeCredentialResponse, ResidentKeyRequirement,
+    UserVerificationRequirement,
+};
+use libwebauthn::proto::ctap2::{
+    Ctap2COSEAlgorithmIdentifier, Ctap2CredentialType, Ctap2PublicKeyCredentialDescriptor,
+    Ctap2PublicKeyCredentialRpEntity, Ctap2PublicKeyCredentialType,
+    Ctap2PublicKeyCredentialUserEntity, Ctap2Transport,
+};
+use libwebauthn::webauthn::{CtapError, Error, WebAuthn};
+use serde_bytes::ByteBuf;
+use serde_cbor::Value;
+use serde_derive::Serialize;
+use sha2::{Digest, Sha256};
+use tracing::{debug, instrument, warn};
+
+use crate::caller::Caller;
+use crate::request::{caller, Outcome, Requests, Results};
+use crate::service::Context;
+
+pub const WEBAUTHN_INTERFACE: &str = "org.freedesktop.portal.WebAuthn";
+
+// Ceremony timeouts, in milliseconds, as recommended by WebAuthn.
+const DEFAULT_TIMEOUT_MS: u32 = 300_000;
+const MIN_TIMEOUT_MS: u32 = 15_000;
+const MAX_TIMEOUT_MS: u32 = 600_000;
+/// Position of the AAGUID in authenticator data with attested credential data.
+const AAGUID_RANGE: std::ops::Range<usize> = 37..53;
+
+// https://www.w3.org/TR/webauthn/#sctn-user-credential-params
+const MAX_USER_ID_LENGTH: usize = 64;
+
+const PUBLIC_KEY_TYPE: &str = "public-key";
+
+// https://www.w3.org/TR/webauthn/#sctn-authenticator-credential-properties-extension
+const CRED_PROPS_EXTENSION: &str = "credProps";
+
+pub type MakeCredentialArgs = (
+    String,
+    PropMap,
+    PropMap,
+    Vec<u8>,
+    Vec<PropMap>,
+    u32,
+    Vec<PropMap>,
+    PropMap,
+    String,
+    PropMap,
+);
+
+pub type GetAssertionArgs = (String, Vec<u8>, u32, String, Vec<PropMap>, String, PropMap);
+
+/// Attestation conveyance preference of the relying party.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Attestation {
+    /// The attestation statement is replaced with a "none" attestation.
+    None,
+    /// The authenticator's attestation statement is returned as is.
+    Direct,
+}
+
+impl Attestation {
+    fn from_preference(preference: &str) -> Self {
+        match preference {
+            // Anonymization CAs are not supported, and enterprise attestation is never requested
+            // from the authenticator: both are treated as direct attestation.
+            "direct" | "indirect" | "enterprise" => Attestation::Direct,
+            _ => Attestation::None,
+        }
+    }
+}
+
+/// A MakeCredential call, validated and ready to be sent to an authenticator.
+#[derive(Debug, Clone)]
+pub struct MakeCredential {
+    pub request: MakeCredentialRequest,
+    pub client_data_json: Vec<u8>,
+    pub attestation: Attestation,
+    /// Whether the relying party asked for the credProps extension output.
+    pub cred_props: bool,
+}
+
+/// A GetAssertion call, validated and ready to be sent to an authenticator.
+#[derive(Debug, Clone)]
+pub struct GetAssertion {
+    pub request: GetAssertionRequest,
+    pub origin: String,
+    pub client_data_json: Vec<u8>,
+}
+
+pub fn register(cr: &mut Crossroads, requests: Requests, context: Arc<Context>) -> IfaceToken<()> {
+    cr.register(WEBAUTHN_INTERFACE, |b: &mut IfaceBuilder<()>| {
+        let (r, c) = (requests.clone(), context.clone());
+        b.method_with_cr(
+            "MakeCredential",
+            (
+                "origin",
+                "relyingParty",
+                "user",
+                "challenge",
+                "pubKeyCredParams",
+                "timeout",
+                "excludeCredentials",
+                "authenticatorSelection",
+                "attestation",
+                "extensions",
+            ),
+            ("handle",),
+            move |ctx, cr, args: MakeCredentialArgs| -> Result<(Path<'static>,), MethodErr> {
+                let sender = caller(ctx)?;
+                let operation = MakeCredential::from_args(args)?;
+                let c = c.clone();
+                let handle = r.spawn(cr, &sender, |handle, caller| {
+                    make_credential(c, handle, caller, operation)
+                });
+                Ok((handle,))
+            },
+        );
+        let (r, c) = (requests.clone(), context.clone());
+        b.method_with_cr(
+            "GetAssertion",
+            (
+                "origin",
+                "challenge",
+                "timeout",
+                "relyingPartyId",
+                "allowCredentials",
+                "userVerification",
+                "extensions",
+            ),
+            ("handle",),
+            move |ctx, cr, args: GetAssertionArgs| -> Result<(Path<'static>,), MethodErr> {
+                let sender = caller(ctx)?;
+                let operation = GetAssertion::from_args(args)?;
+                let c = c.clone();
+                let handle = r.spawn(cr, &sender, |handle, caller| {
+                    get_assertion(c, handle, caller, operation)
+                });
+                Ok((handle,))
+            },
+        );
+    })
+}
+
+impl MakeCredential {
+    pub fn from_args(args: MakeCredentialArgs) -> Result<Self, MethodErr> {
+        let (
+            origin,
+            relying_party,
+            user,
+            challenge,
+            parameters,
+            timeout,
+            exclude,
+            selection,
+            attestation,
+            extensions,
+        ) = args;
+        ignore_extensions(&extensions, &[CRED_PROPS_EXTENSION]);
+        let cred_props = prop_cast::<bool>(&extensions, CRED_PROPS_EXTENSION) == Some(&true);
+
+        let relying_party = relying_party_entity(&relying_party)?;
+        let user = user_entity(&user)?;
+        let algorithms = credential_parameters(&parameters)?;
+        let exclude = credential_descriptors(&exclude, "excludeCredentials")?;
+        let resident_key = resident_key(&selection);
+        let user_verification = prop_cast::<String>(&selection, "userVerification")
+            .map(|requirement| user_verification(requirement))
+            .unwrap_or(UserVerificationRequirement::Preferred);
+
+        let client_data_json = client_data_json("webauthn.create", &challenge, &origin)?;
+        let request = MakeCredentialRequest {
+            hash: Sha256::digest(&client_data_json).to_vec(),
+            origin,
+            relying_party,
+            user,
+            resident_key,
+            user_verification,
+            algorithms,
+            exclude: Some(exclude).filter(|exclude| !exclude.is_empty()),
+            extensions_cbor: vec![],
+            timeout: ceremony_timeout(timeout),
+        };
+        Ok(Self {
+            request,
+            client_data_json,
+            attestation: Attestation::from_preference(&attestation),
+            cred_props,
+        })
+    }
+}
+
+impl GetAssertion {
+    pub fn from_args(args: GetAssertionArgs) -> Result<Self, MethodErr> {
+        let (origin, challenge, timeout, relying_party_id, allow, uv, extensions) = args;
+        ignore_extensions(&extensions, &[]);
+        if relying_party_id.is_empty() {
+            return Err(MethodErr::invalid_arg(&"relyingPartyId"));
+        }
+
+        let client_data_json = client_data_json("webauthn.get", &challenge, &origin)?;
+        let request = GetAssertionRequest {
+            relying_party_id,
+            hash: Sha256::digest(&client_data_json).to_vec(),
+            allow: credential_descriptors(&allow, "allowCredentials")?,
+            extensions_cbor: None,
+            user_verification: user_verification(&uv),
+            timeout: ceremony_timeout(timeout),
+        };
+        Ok(Self {
+            request,
+            origin,
+            client_data_json,
+        })
+    }
+}
+
+#[instrument(skip_all, fields(rp = %operation.request.relying_party.id))]
+async fn make_credential(
+    context: Arc<Context>,
+    handle: Path<'static>,
+    caller: Caller,
+    operation: MakeCredential,
+) -> Outcome {
+    let interaction = caller.interaction(handle);
+    let request = &operation.request;
+    let mut channel = match context
+        .begin(
+            &caller,
+            &interaction,
+            Operation::Create,
+            &request.relying_party.id,
+            &request.origin,
+        )
+        .await
+    {
+        Ok(channel) => channel,
+        Err(outcome) => return outcome,
+    };
+    let pin_provider = context.pin_provider(&interaction);
+    let response = match channel
+        .webauthn_make_credential(request, &pin_provider)
+        .await
+    {
+        Ok(response) => response,
+        Err(err) => {
+            warn!(%err, "MakeCredential ceremony failed");
+            return context.fail(&interaction, err).await;
+        }
+    };
+
+    let credential: Ctap2PublicKeyCredentialDescriptor =
+        match (&response.attestation_object).try_into() {
+            Ok(credential) => credential,
+            Err(err) => return context.fail(&interaction, Error::Ctap(err)).await,
+        };
+    let attestation_object = match attestation_object(&response, operation.attestation) {
+        Ok(attestation_object) => attestation_object,
+        Err(err) => return context.fail(&interaction, err).await,
+    };
+    debug!("MakeCredential ceremony succeeded");
+    let mut results = Results::from([
+        ("clientDataJSON", operation.client_data_json.into()),
+        ("credentialId", credential.id.into_vec().into()),
+        ("attestationObject", attestation_object.into()),
+    ]);
+    if let (true, Some(rk)) = (operation.cred_props, response.cred_props.rk) {
+        results.insert("credProps.rk", rk.into());
+    }
+    Outcome::Success(results)
+}
+
+#[instrument(skip_all, fields(rp = %operation.request.relying_party_id))]
+async fn get_assertion(
+    context: Arc<Context>,
+    handle: Path<'static>,
+    caller: Caller,
+    operation: GetAssertion,
+) -> Outcome {
+    let interaction = caller.interaction(handle);
+    let request = &operation.request;
+    let mut channel = match context
+        .begin(
+            &caller,
+            &interaction,
+            Operation::Get,
+            &request.relying_party_id,
+            &operation.origin,
+        )
+        .await
+    {
+        Ok(channel) => channel,
+        Err(outcome) => return outcome,
+    };
+    let pin_provider = context.pin_provider(&interaction);
+    let account_selector = context.account_selector(&interaction);
+    let assertion = match channel
+        .webauthn_get_assertion_for_account(request, &pin_provider, &account_selector)
+        .await
+    {
+        Ok(assertion) => assertion,
+        Err(err) => {
+            warn!(%err, "GetAssertion ceremony failed");
+            return context.fail(&interaction, err).await;
+        }
+    };
+    // Authenticators may omit the credential if the allow list has a single entry.
+    let credential_id = match (&assertion.credential_id, &request.allow[..]) {
+        (Some(credential), _) => credential.id.to_vec(),
+        (None, [credential]) => credential.id.to_vec(),
+        (None, _) => {
+            warn!("Authenticator did not report which credential was used");
+            return context
+                .fail(&interaction, Error::Ctap(CtapError::InvalidCredential))
+                .await;
+        }
+    };
+    debug!("GetAssertion ceremony succeeded");
+    let mut results = Results::from([
+        ("clientDataJSON", operation.client_data_json.into()),
+        ("credentialId", credential_id.into()),
+        (
+            "authenticatorData",
+            assertion.authenticator_data.into_vec().into(),
+        ),
+        ("signature", assertion.signature.into_vec().into()),
+    ]);
+    if let Some(user) = assertion.user {
+        results.insert("userHandle", user.id.into_vec().into());
+    }
+    Outcome::Success(results)
+}
+
+// https://www.w3.org/TR/webauthn/#dictionary-client-data
+#[derive(Serialize)]
+struct CollectedClientData<'a> {
+    #[serde(rename = "type")]
+    kind: &'a str,
+    challenge: String,
+    origin: &'a str,
+    #[serde(rename = "crossOrigin")]
+    cross_origin: bool,
+}
+
+fn client_data_json(kind: &str, challenge: &[u8], origin: &str) -> Result<Vec<u8>, MethodErr> {
+    if challenge.is_empty() {
+        return Err(MethodErr::invalid_arg(&"challenge"));
+    }
+    if origin.is_empty() {
+        return Err(MethodErr::invalid_arg(&"origin"));
+    }
+    let client_data = CollectedClientData {
+        kind,
+        challenge: base64_url::encode(challenge),
+        origin,
+        cross_origin: false,
+    };
+    serde_json::to_vec(&client_data).or(Err(MethodErr::failed(&"Invalid client data")))
+}
+
+fn attestation_object(
+    response: &MakeCredentialResponse,
+    attestation: Attestation,
+) -> Result<Vec<u8>, Error> {
+    let response = &response.attestation_object;
+    let (format, statement) = match attestation {
+        Attestation::Direct => (
+            response.format.clone(),
+            serde_cbor::value::to_value(&response.attestation_statement)
+                .or(Err(Error::Ctap(CtapError::Other)))?,
+        ),
+        Attestation::None => (String::from("none"), Value::Map(BTreeMap::new())),
+    };
+    let mut authenticator_data = response.authenticator_data.to_vec();
+    if attestation == Attestation::None {
+        // The AAGUID identifies the authenticator model, so it is replaced with zeros as well.
+        if let Some(aaguid) = authenticator_data.get_mut(AAGUID_RANGE) {
+            aaguid.fill(0);
+        }
+    }
+    let attestation_object = Value::Map(BTreeMap::from([
+        (Value::Text(String::from("fmt")), Value::Text(format)),
+        (Value::Text(String::from("attStmt")), statement),
+        (
+            Value::Text(String::from("authData")),
+            Value::Bytes(authenticator_data),
+        ),
+    ]));
+    serde_cbor::to_vec(&attestation_object).or(Err(Error::Ctap(CtapError::Other)))
+}
+
+fn relying_party_entity(dict: &PropMap) -> Result<Ctap2PublicKeyCredentialRpEntity, MethodErr> {
+    let Some(id) = prop_cast::<String>(dict, "id").filter(|id| !id.is_empty()) else {
+        return Err(MethodErr::invalid_arg(&"relyingParty.id"));
+    };
+    let name = prop_cast::<String>(dict, "name").unwrap_or(id);
+    Ok(Ctap2PublicKeyCredentialRpEntity::new(id, name))
+}
+
+fn user_entity(dict: &PropMap) -> Result<Ctap2PublicKeyCredentialUserEntity, MethodErr> {
+    let Some(id) = prop_cast::<Vec<u8>>(dict, "id")
+        .filter(|id| !id.is_empty() && id.len() <= MAX_USER_ID_LENGTH)
+    else {
+        return Err(MethodErr::invalid_arg(&"user.id"));
+    };
+    Ok(Ctap2PublicKeyCredentialUserEntity {
+        id: ByteBuf::from(id.clone()),
+        name: prop_cast::<String>(dict, "name").cloned(),
+        display_name: prop_cast::<String>(dict, "displayName").cloned(),
+    })
+}
+
+/// Supported credential types, in order of preference. Unsupported ones are skipped, and an
+/// empty list defaults to ES256.
+fn credential_parameters(parameters: &[PropMap]) -> Result<Vec<Ctap2CredentialType>, MethodErr> {
+    if parameters.is_empty() {
+        return Ok(vec![Ctap2CredentialType::default()]);
+    }
+    let algorithms: Vec<_> = parameters
+        .iter()
+        .filter(|parameter| {
+            prop_cast::<String>(parameter, "type").map(String::as_str) == Some(PUBLIC_KEY_TYPE)
+        })
+        .filter_map(|parameter| match prop_cast::<i32>(parameter, "alg") {
+            Some(-7) => Some(Ctap2COSEAlgorithmIdentifier::ES256),
+            Some(-8) => Some(Ctap2COSEAlgorithmIdentifier::EDDSA),
+            algorithm => {
+                debug!(?algorithm, "Skipping unsupported algorithm");
+                None
+            }
+        })
+        .map(|algorithm| {
+            Ctap2CredentialType::new(Ctap2PublicKeyCredentialType::PublicKey, algorithm)
+        })
+        .collect();
+    if algorithms.is_empty() {
+        warn!("None of the requested algorithms are supported");
+        return Err(MethodErr::invalid_arg(&"pubKeyCredParams"));
+    }
+    Ok(algorithms)
+}
+
+fn credential_descriptors(
+    descriptors: &[PropMap],
+    arg: &str,
+) -> Result<Vec<Ctap2PublicKeyCredentialDescriptor>, MethodErr> {
+    let mut credentials = vec![];
+    for descriptor in descriptors {
+        if prop_cast::<String>(descriptor, "type").map(String::as_str) != Some(PUBLIC_KEY_TYPE) {
+            debug!("Skipping credential of unsupported type");
+            continue;
+        }
+        let Some(id) = prop_cast::<Vec<u8>>(descriptor, "id").filter(|id| !id.is_empty()) else {
+            return Err(MethodErr::invalid_arg(&arg));
+        };
+        let transports = prop_cast::<Vec<String>>(descriptor, "transports").map(|transports| {
+            transports
+                .iter()
+                .filter_map(|transport| match transport.as_str() {
+                    "usb" => Some(Ctap2Transport::USB),
+                    "nfc" => Some(Ctap2Transport::NFC),
+                    "ble" => Some(Ctap2Transport::BLE),
+                    "internal" => Some(Ctap2Transport::INTERNAL),
+                    _ => None,
+                })
+                .collect()
+        });
+        credentials.push(Ctap2PublicKeyCredentialDescriptor {
+            r#type: Ctap2PublicKeyCredentialType::PublicKey,
+            id: ByteBuf::from(id.clone()),
+            transports,
+        });
+    }
+    Ok(credentials)
+}
+
+/// The residentKey requirement, falling back to the legacy requireResidentKey member when it is
+/// missing or unknown.
+fn resident_key(selection: &PropMap) -> ResidentKeyRequirement {
+    match prop_cast::<String>(selection, "residentKey").map(String::as_str) {
+        Some("required") => ResidentKeyRequirement::Required,
+        Some("preferred") => ResidentKeyRequirement::Preferred,
+        Some("discouraged") => ResidentKeyRequirement::Discouraged,
+        _ => match prop_cast::<bool>(selection, "requireResidentKey") {
+            Some(true) => ResidentKeyRequirement::Required,
+            _ => ResidentKeyRequirement::Discouraged,
+        },
+    }
+}
+
+fn user_verification(requirement: &str) -> UserVerificationRequirement {
+    match requirement {
+        "required" => UserVerificationRequirement::Required,
+        "discouraged" => UserVerificationRequirement::Discouraged,
+        _ => UserVerificationRequirement::Preferred,
+    }
+}
+
+fn ceremony_timeout(timeout_ms: u32) -> Duration {
+    let timeout_ms = match timeout_ms {
+        0 => DEFAULT_TIMEOUT_MS,
+        timeout_ms => timeout_ms.clamp(MIN_TIMEOUT_MS, MAX_TIMEOUT_MS),
+    };
+    Duration::from_millis(timeout_ms as u64)
+}
+
+fn ignore_extensions(extensions: &PropMap, supported: &[&str]) {
+    let names: Vec<_> = extensions
+        .keys()
+        .filter(|name| !supported.contains(&name.as_str()))
+        .collect();
+    if !names.is_empty() {
+        warn!(?names, "Ignoring unsupported extensions");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use dbus::arg::{PropMap, RefArg, Variant};
+    use libwebauthn::ops::webauthn::{
+        CredentialPropertiesOutput, MakeCredentialResponse, ResidentKeyRequirement,
+        UserVerificationRequirement,
+    };
+    use libwebauthn::proto::ctap2::{
+        Ctap2AttestationStatement, Ctap2COSEAlgorithmIdentifier, Ctap2MakeCredentialResponse,
+        Ctap2Transport, FidoU2fAttestationStmt,
+    };
+    use serde_bytes::ByteBuf;
+    use serde_json::Value;
+
+    #[cfg(feature = "dbus-daemon-tests")]
+    use crate::request::{ResponseCode, REQUEST_INTERFACE};
+    #[cfg(feature = "dbus-daemon-tests")]
+    use crate::testing::{
+        PendingDeviceSelector, ScriptedBackend, SoftwareDeviceSelector, TestPortal,
+    };
+    #[cfg(feature = "dbus-daemon-tests")]
+    use crate::webauthn::WEBAUTHN_INTERFACE;
+    use crate::webauthn::{
+        attestation_object, ceremony_timeout, Attestation, GetAssertion, MakeCredential,
+        AAGUID_RANGE,
+    };
+    #[cfg(feature = "dbus-daemon-tests")]
+    use dbus::{arg::prop_cast, Path};
+
+    fn dict(entries: Vec<(&str, Box<dyn RefArg>)>) -> PropMap {
+        entries
+            .into_iter()
+            .map(|(key, value)| (key.to_owned(), Variant(value)))
+            .collect()
+    }
+
+    fn make_credential_args() -> super::MakeCredentialArgs {
+        (
+            String::from("https://example.org"),
+            dict(vec![("id", Box::new(String::from("example.org")))]),
+            dict(vec![
+                ("id", Box::new(vec![1u8, 2, 3])),
+                ("name", Box::new(String::from("mario.rossi"))),
+            ]),
+            vec![0x42; 32],
+            vec![
+                dict(vec![
+                    ("type", Box::new(String::from("public-key"))),
+                    ("alg", Box::new(-257i32)),
+                ]),
+                dict(vec![
+                    ("type", Box::new(String::from("public-key"))),
+                    ("alg", Box::new(-7i32)),
+                ]),
+            ],
+            60_000,
+            vec![dict(vec![
+                ("type", Box::new(String::from("public-key"))),
+                ("id", Box::new(vec![9u8; 16])),
+                ("transports", Box::new(vec![String::from("usb")])),
+            ])],
+            dict(vec![(
+                "userVerification",
+                Box::new(String::from("required")),
+            )]),
+            String::from("none"),
+            PropMap::new(),
+        )
+    }
+
+    #[test]
+    fn make_credential_from_args() {
+        let operation = MakeCredential::from_args(make_credential_args()).unwrap();
+        let request = &operation.request;
+        assert_eq!(request.relying_party.id, "example.org");
+        assert_eq!(request.relying_party.name, "example.org");
+        assert_eq!(request.user.name.as_deref(), Some("mario.rossi"));
+        assert_eq!(request.algorithms.len(), 1);
+        assert_eq!(
+            request.algorithms[0].algorithm,
+            Ctap2COSEAlgorithmIdentifier::ES256
+        );
+        let exclude = request.exclude.as_ref().unwrap();
+        assert_eq!(exclude[0].id.as_slice(), &[9u8; 16]);
+        assert_eq!(exclude[0].transports, Some(vec![Ctap2Transport::USB]));
+        assert!(matches!(
+            request.user_verification,
+            UserVerificationRequirement::Required
+        ));
+        assert_eq!(request.resident_key, ResidentKeyRequirement::Discouraged);
+        assert!(!operation.cred_props);
+        assert_eq!(request.timeout, Duration::from_secs(60));
+        assert_eq!(operation.attestation, Attestation::None);
+
+        let client_data: Value = serde_json::from_slice(&operation.client_data_json).unwrap();
+        assert_eq!(client_data["type"], "webauthn.create");
+        assert_eq!(client_data["origin"], "https://example.org");
+        assert_eq!(client_data["challenge"], base64_url::encode(&[0x42; 32]));
+    }
+
+    #[test]
+    fn make_credential_resident_key() {
+        let mut args = make_credential_args();
+        args.7 = dict(vec![("residentKey", Box::new(String::from("preferred")))]);
+        args.9 = dict(vec![("credProps", Box::new(true))]);
+        let operation = MakeCredential::from_args(args).unwrap();
+        assert_eq!(
+            operation.request.resident_key,
+            ResidentKeyRequirement::Preferred
+        );
+        assert!(operation.cred_props);
+
+        let mut args = make_credential_args();
+        args.7 = dict(vec![("requireResidentKey", Box::new(true))]);
+        let operation = MakeCredential::from_args(args).unwrap();
+        assert_eq!(
+            operation.request.resident_key,
+            ResidentKeyRequirement::Required
+        );
+    }
+
+    #[test]
+    fn make_credential_rejects_invalid_user() {
+        let mut args = make_credential_args();
+        args.2 = dict(vec![("id", Box::new(vec![0u8; 65]))]);
+        assert!(MakeCredential::from_args(args).is_err());
+    }
+
+    #[test]
+    fn make_credential_rejects_unsupported_algorithms() {
+        let mut args = make_credential_args();
+        args.4.remove(1);
+        assert!(MakeCredential::from_args(args).is_err());
+    }
+
+    #[test]
+    fn none_attestation_hides_aaguid() {
+        let mut authenticator_data = vec![0x11; 37];
+        authenticator_data.extend([0xAA; 16]);
+        authenticator_data.extend([0x00, 0x01, 0x42]);
+        let response = MakeCredentialResponse {
+            attestation_object: Ctap2MakeCredentialResponse {
+                format: String::from("fido-u2f"),
+                authenticator_data: ByteBuf::from(authenticator_data.clone()),
+                attestation_statement: Ctap2AttestationStatement::FidoU2F(FidoU2fAttestationStmt {
+                    algorithm: Ctap2COSEAlgorithmIdentifier::ES256,
+                    signature: ByteBuf::from(vec![0x30]),
+                    certificates: vec![],
+                }),
+            },
+            cred_props: CredentialPropertiesOutput::default(),
+        };
+        let auth_data = |attestation| {
+            let encoded = attestation_object(&response, attestation).unwrap();
+            let object: serde_cbor::Value = serde_cbor::from_slice(&encoded).unwrap();
+            let serde_cbor::Value::Map(object) = object else {
+                panic!("Attestation object is not a map");
+            };
+            match &object[&serde_cbor::Value::Text(String::from("authData"))] {
+                serde_cbor::Value::Bytes(bytes) => bytes.clone(),
+                other => panic!("Unexpected authData: {:?}", other),
+            }
+        };
+
+        assert_eq!(auth_data(Attestation::Direct), authenticator_data);
+        let anonymized = auth_data(Attestation::None);
+        assert_eq!(anonymized[AAGUID_RANGE], [0; 16]);
+        assert_eq!(anonymized[..37], authenticator_data[..37]);
+        assert_eq!(anonymized[53..], authenticator_data[53..]);
+    }
+
+    #[test]
+    fn get_assertion_from_args() {
+        let operation = GetAssertion::from_args((
+            String::from("https://example.org"),
+            vec![0x42; 32],
+            0,
+            String::from("example.org"),
+            vec![],
+            String::from("discouraged"),
+            PropMap::new(),
+        ))
+        .unwrap();
+        assert_eq!(operation.request.relying_party_id, "example.org");
+        assert!(operation.request.allow.is_empty());
+        assert!(matches!(
+            operation.request.user_verification,
+            UserVerificationRequirement::Discouraged
+        ));
+        let client_data: Value = serde_json::from_slice(&operation.client_data_json).unwrap();
+        assert_eq!(client_data["type"], "webauthn.get");
+    }
+
+    #[test]
+    fn timeout_clamped() {
+        assert_eq!(ceremony_timeout(0), Duration::from_secs(300));
+        assert_eq!(ceremony_timeout(1), Duration::from_secs(15));
+        assert_eq!(ceremony_timeout(u32::MAX), Duration::from_secs(600));
+    }
+
+    #[cfg(feature = "dbus-daemon-tests")]
+    fn bytes(results: &PropMap, key: &str) -> Vec<u8> {
+        prop_cast::<Vec<u8>>(results, key).unwrap().clone()
+    }
+
+    #[cfg(feature = "dbus-daemon-tests")]
+    #[tokio::test]
+    async fn ceremonies_over_dbus() {
+        let mut portal = TestPortal::start(Box::new(SoftwareDeviceSelector::default())).await;
+
+        let mut args = make_credential_args();
+        args.7 = dict(vec![(
+            "userVerification",
+            Box::new(String::from("discouraged")),
+        )]);
+        let (handle,): (Path<'static>,) = portal
+            .proxy()
+            .method_call(WEBAUTHN_INTERFACE, "MakeCredential", args)
+            .await
+            .unwrap();
+        let (code, results) = portal.response(&handle).await;
+        assert_eq!(code, ResponseCode::Success as u32);
+        let credential_id = bytes(&results, "credentialId");
+        let attestation_object: serde_cbor::Value =
+            serde_cbor::from_slice(&bytes(&results, "attestationObject")).unwrap();
+        let serde_cbor::Value::Map(attestation_object) = attestation_object else {
+            panic!("Attestation object is not a map");
+        };
+        assert_eq!(
+            attestation_object.get(&serde_cbor::Value::Text(String::from("fmt"))),
+            Some(&serde_cbor::Value::Text(String::from("none")))
+        );
+
+        let allow = vec![dict(vec![
+            ("type", Box::new(String::from("public-key"))),
+            ("id", Box::new(credential_id.clone())),
+        ])];
+        let (handle,): (Path<'static>,) = portal
+            .proxy()
+            .method_call(
+                WEBAUTHN_INTERFACE,
+                "GetAssertion",
+                (
+                    String::from("https://example.org"),
+                    vec![0x24u8; 32],
+                    0u32,
+                    String::from("example.org"),
+                    allow,
+                    String::from("discouraged"),
+                    PropMap::new(),
+                ),
+            )
+            .await
+            .unwrap();
+        let (code, results) = portal.response(&handle).await;
+        assert_eq!(code, ResponseCode::Success as u32);
+        assert_eq!(bytes(&results, "credentialId"), credential_id);
+        assert!(!bytes(&results, "signature").is_empty());
+        let client_data: Value =
+            serde_json::from_slice(&bytes(&results, "clientDataJSON")).unwrap();
+        assert_eq!(client_data["type"], "webauthn.get");
+    }
+
+    #[cfg(feature = "dbus-daemon-tests")]
+    #[tokio::test]
+    async fn closed_request_not_responded() {
+        let mut portal = TestPortal::start(Box::new(PendingDeviceSelector)).await;
+        let (handle,): (Path<'static>,) = portal
+            .proxy()
+            .method_call(WEBAUTHN_INTERFACE, "MakeCredential", make_credential_args())
+            .await
+            .unwrap();
+        let () = portal
+            .request_proxy(&handle)
+            .method_call(REQUEST_INTERFACE, "Close", ())
+            .await
+            .unwrap();
+        assert!(
+            !portal
+                .responds_within(&handle, Duration::from_millis(500))
+                .await
+        );
+        // The request is no longer exported.
+        let closed: Result<(), _> = portal
+            .request_proxy(&handle)
+            .method_call(REQUEST_INTERFACE, "Close", ())
+            .await;
+        assert!(closed.is_err());
+    }
+
+    #[cfg(feature = "dbus-daemon-tests")]
+    #[tokio::test]
+    async fn declined_relying_party_cancelled() {
+        let backend = ScriptedBackend {
+            confirm: false,
+            ..ScriptedBackend::default()
+        };
+        let mut portal =
+            TestPortal::start_with_backend(Box::new(SoftwareDeviceSelector::default()), backend)
+                .await;
+        let (handle,): (Path<'static>,) = portal
+            .proxy()
+            .method_call(WEBAUTHN_INTERFACE, "MakeCredential", make_credential_args())
+            .await
+            .unwrap();
+        let (code, _) = portal.response(&handle).await;
+        assert_eq!(code, ResponseCode::Cancelled as u32);
+        assert!(portal.backend.errors.lock().unwrap().is_empty());
+    }
+
+    #[cfg(feature = "dbus-daemon-tests")]
+    #[tokio::test]
+    async fn mismatched_origin_rejected() {
+        // Devices are never selected, the request is rejected beforehand.
+        let mut portal = TestPortal::start(Box::new(PendingDeviceSelector)).await;
+        let mut args = make_credential_args();
+        args.0 = String::from("https://example.com");
+        let (handle,): (Path<'static>,) = portal
+            .proxy()
+            .method_call(WEBAUTHN_INTERFACE, "MakeCredential", args)
+            .await
+            .unwrap();
+        let (code, _) = portal.response(&handle).await;
+        assert_eq!(code, ResponseCode::Other as u32);
+    }
+}